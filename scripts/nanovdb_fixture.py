@@ -0,0 +1,166 @@
+#!/usr/bin/env python3
+# Writes scenes/sphere.nvdb, an uncompressed NanoVDB file in the layout of version 32.3 with
+# one float fog volume named "density". Voxels 0..15 hold a ball around (7, 7.5, 8) with a linear falloff in
+# eight leaves, and the lower node has an active constant tile of 0.5 over voxels
+# 16..23 x 0..7 x 0..7. One voxel is 0.1 units and the grid is centered at the origin.
+#
+#   scripts/nanovdb_fixture.py scenes/sphere.nvdb
+import math
+import struct
+import sys
+
+VERSION = (32 << 21) | (3 << 10) | 3
+MAGIC = 0x304244566f6e614e  # "NanoVDB0"
+NAME = b"density\0"
+VOXEL = 0.1
+OFFSET = -0.8
+TILE = ((2, 0, 0), 0.5)
+
+
+def align32(n):
+    return (n + 31) // 32 * 32
+
+
+def internal_header(log2):
+    return align32(24 + 8 + 2 * (1 << 3 * log2) // 8 + 16)
+
+
+def internal_size(log2):
+    return internal_header(log2) + (1 << 3 * log2) * 8
+
+
+GRID_DATA_SIZE = 672
+TREE_SIZE = 64
+ROOT_SIZE = 64 + 32
+LEAF_SIZE = 96 + 512 * 4
+
+
+def density(i, j, k):
+    r = math.sqrt((i - 7.0) ** 2 + (j - 7.5) ** 2 + (k - 8.0) ** 2)
+    return max(0.0, 1.0 - r / 7.0)
+
+
+class Buffer:
+    def __init__(self, size):
+        self.data = bytearray(size)
+
+    def put(self, pos, fmt, *values):
+        struct.pack_into("<" + fmt, self.data, pos, *values)
+
+
+def set_bit(buf, mask, n):
+    buf.data[mask + n // 8] |= 1 << (n % 8)
+
+
+def main(path):
+    leaves = [(x, y, z) for x in (0, 8) for y in (0, 8) for z in (0, 8)]
+    upper = TREE_SIZE + ROOT_SIZE
+    lower = upper + internal_size(5)
+    leaf = lower + internal_size(4)
+    tree_size = leaf + len(leaves) * LEAF_SIZE
+    grid_size = GRID_DATA_SIZE + tree_size
+    meta = 16
+    grid = meta + 176 + len(NAME)
+    buf = Buffer(grid + grid_size)
+
+    # Active voxels and their statistics
+    values = {}
+    for origin in leaves:
+        for i in range(8):
+            for j in range(8):
+                for k in range(8):
+                    values[(origin[0] + i, origin[1] + j, origin[2] + k)] = density(origin[0] + i, origin[1] + j, origin[2] + k)
+    active = [c for c, v in values.items() if v != 0.0]
+    tile_voxels = [(TILE[0][0] * 8 + i, j, k) for i in range(8) for j in range(8) for k in range(8)]
+    every = active + tile_voxels
+    lo = [min(c[a] for c in every) for a in range(3)]
+    hi = [max(c[a] for c in every) for a in range(3)]
+    voxel_count = len(every)
+    world_lo = [c * VOXEL + OFFSET for c in lo]
+    world_hi = [(c + 1) * VOXEL + OFFSET for c in hi]
+
+    # File header and the meta data of the grid
+    buf.put(0, "QIHH", MAGIC, VERSION, 1, 0)
+    buf.put(meta, "QQQQII", grid_size, grid_size, name_key(NAME), voxel_count, 1, 2)
+    buf.put(meta + 40, "6d", *(world_lo + world_hi))
+    buf.put(meta + 88, "6i", *(lo + hi))
+    buf.put(meta + 112, "3d", VOXEL, VOXEL, VOXEL)
+    buf.put(meta + 136, "I", len(NAME))
+    buf.put(meta + 140, "4I", len(leaves), 1, 1, 1)
+    buf.put(meta + 156, "3I", 1, 0, 0)
+    buf.put(meta + 168, "HHI", 0, 0, VERSION)
+    buf.data[meta + 176:grid] = NAME
+
+    # Grid data, the checksum is disabled
+    buf.put(grid, "QQIIIIQ", MAGIC, 0xffffffffffffffff, VERSION, 0, 0, 1, grid_size)
+    buf.data[grid + 40:grid + 40 + len(NAME)] = NAME
+    diag = lambda v: [v, 0.0, 0.0, 0.0, v, 0.0, 0.0, 0.0, v]
+    buf.put(grid + 296, "9f9f3ff", *(diag(VOXEL) + diag(1.0 / VOXEL) + [OFFSET] * 3 + [1.0]))
+    buf.put(grid + 384, "9d9d3dd", *(diag(VOXEL) + diag(1.0 / VOXEL) + [OFFSET] * 3 + [1.0]))
+    buf.put(grid + 560, "6d", *(world_lo + world_hi))
+    buf.put(grid + 608, "3d", VOXEL, VOXEL, VOXEL)
+    buf.put(grid + 632, "IIqI", 2, 1, 0, 0)
+
+    # Tree, node offsets are relative to it
+    tree = grid + GRID_DATA_SIZE
+    buf.put(tree, "4q3I3IQ", leaf, lower, upper, TREE_SIZE, len(leaves), 1, 1, 1, 0, 0, voxel_count)
+
+    # Root with a single tile pointing to the upper node
+    root = tree + TREE_SIZE
+    buf.put(root, "6iI5f", *(lo + hi + [1, 0.0, 0.0, 1.0, 0.0, 0.0]))
+    buf.put(root + 64, "QqIf", 0, upper - TREE_SIZE, 0, 0.0)
+
+    # Upper node with the lower node as its first child
+    node = tree + upper
+    buf.put(node, "6iQ", *(lo + hi + [0]))
+    set_bit(buf, node + 32 + 4096 // 8, 0)
+    buf.put(node + 32 + 2 * 4096 // 8, "4f", 0.0, 1.0, 0.0, 0.0)
+    buf.put(node + internal_header(5), "q", lower - upper)
+
+    # Lower node with the leaves and the constant tile
+    node = tree + lower
+    buf.put(node, "6iQ", *(lo + hi + [0]))
+    buf.put(node + 32 + 2 * 512 // 8, "4f", 0.0, 1.0, 0.0, 0.0)
+    table = node + internal_header(4)
+    for n, origin in enumerate(leaves):
+        index = ((origin[0] // 8) * 16 + origin[1] // 8) * 16 + origin[2] // 8
+        set_bit(buf, node + 32 + 512 // 8, index)
+        buf.put(table + index * 8, "q", leaf + n * LEAF_SIZE - lower)
+    (tx, ty, tz), value = TILE
+    index = (tx * 16 + ty) * 16 + tz
+    set_bit(buf, node + 32, index)
+    buf.put(table + index * 8, "f", value)
+
+    # Leaves, values are ordered with z varying fastest
+    for n, origin in enumerate(leaves):
+        node = tree + leaf + n * LEAF_SIZE
+        voxels = [c for c in active if all(origin[a] <= c[a] < origin[a] + 8 for a in range(3))]
+        vmin = [min(c[a] for c in voxels) for a in range(3)]
+        vmax = [max(c[a] for c in voxels) for a in range(3)]
+        buf.put(node, "3i3BB", *(vmin + [vmax[a] - vmin[a] for a in range(3)] + [0]))
+        leaf_values = []
+        for i in range(8):
+            for j in range(8):
+                for k in range(8):
+                    v = values[(origin[0] + i, origin[1] + j, origin[2] + k)]
+                    leaf_values.append(v)
+                    if v != 0.0:
+                        set_bit(buf, node + 16, (i * 8 + j) * 8 + k)
+        buf.put(node + 80, "4f", min(leaf_values), max(leaf_values), sum(leaf_values) / 512, 0.0)
+        buf.put(node + 96, "512f", *leaf_values)
+
+    with open(path, "wb") as f:
+        f.write(buf.data)
+
+
+# Hash of the grid name stored with the meta data
+def name_key(name):
+    h = 0
+    for c in name.rstrip(b"\0"):
+        overflow = h >> 56
+        h = (h * 67 + (c ^ overflow)) & 0xffffffffffffffff
+    return h
+
+
+if __name__ == "__main__":
+    main(sys.argv[1] if len(sys.argv) > 1 else "scenes/sphere.nvdb")
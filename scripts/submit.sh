@@ -0,0 +1,31 @@
+#!/bin/sh
+# Stand-in client for the job server: submits a scene, polls until it is finished and
+# downloads the image.
+#
+#   raytracer --server 127.0.0.1:8080 &
+#   scripts/submit.sh scenes/default.scene "spp=4&width=320&height=240" out.png
+set -e
+
+SERVER=${SERVER:-http://127.0.0.1:8080}
+SCENE=$1
+SETTINGS=${2:-spp=1}
+OUT=${3:-rendering.png}
+
+if [ -z "$SCENE" ]; then
+    echo "Usage: $0 SCENE [SETTINGS] [OUTPUT]" >&2
+    exit 1
+fi
+
+ID=$(curl -sf --data-binary @"$SCENE" "$SERVER/jobs?$SETTINGS" | sed 's/.*"id": *\([0-9]*\).*/\1/')
+echo "Submitted job $ID"
+while true; do
+    STATUS=$(curl -sf "$SERVER/jobs/$ID")
+    echo "$STATUS"
+    case "$STATUS" in
+        *'"status": "done"'*) break ;;
+        *'"status": "failed"'*|*'"status": "cancelled"'*) exit 1 ;;
+    esac
+    sleep 1
+done
+curl -sf -o "$OUT" "$SERVER/jobs/$ID/image"
+echo "Wrote $OUT"
@@ -0,0 +1,312 @@
+use std::f64;
+use std::io;
+use std::path::Path;
+use std::vec::Vec;
+use types::*;
+use hit::HitInfo;
+use renderer::Renderer;
+use shader::{ambient_occlusion, Occlusion};
+use film::Film;
+use debug::DebugMode;
+use exr;
+use colorspace::{ColorSpace, convert_film};
+use stats;
+use stats::Counter;
+
+// Arbitrary output variables rendered next to the beauty image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    Beauty,
+    Depth,
+    Position,
+    Normal,
+    Albedo,
+    ObjectId,
+    MaterialId,
+    Uv,
+    AmbientOcclusion,
+    BentNormal,
+    LightGroup(u32),
+    LightGroupOther,    // Light not coming from the scene lights, so that the groups add up to beauty
+    Debug(DebugMode)
+}
+
+impl Aov {
+    pub fn parse(name: &str) -> Option<Aov> {
+        let aov = match name {
+            "beauty" => Aov::Beauty,
+            "depth" => Aov::Depth,
+            "position" => Aov::Position,
+            "normal" => Aov::Normal,
+            "albedo" => Aov::Albedo,
+            "object_id" => Aov::ObjectId,
+            "material_id" => Aov::MaterialId,
+            "uv" => Aov::Uv,
+            "ao" => Aov::AmbientOcclusion,
+            "bent_normal" => Aov::BentNormal,
+            "light_group_other" => Aov::LightGroupOther,
+            _ => {
+                if name.starts_with("light_group_") {
+                    match name["light_group_".len()..].parse() {
+                        Ok(group) => Aov::LightGroup(group),
+                        Err(_) => return None
+                    }
+                } else if name.starts_with("debug_") {
+                    match DebugMode::parse(&name["debug_".len()..]) {
+                        Some(mode) => Aov::Debug(mode),
+                        None => return None
+                    }
+                } else {
+                    return None;
+                }
+            }
+        };
+        Some(aov)
+    }
+
+    pub fn name(&self) -> String {
+        match *self {
+            Aov::Beauty => "beauty".to_string(),
+            Aov::Depth => "depth".to_string(),
+            Aov::Position => "position".to_string(),
+            Aov::Normal => "normal".to_string(),
+            Aov::Albedo => "albedo".to_string(),
+            Aov::ObjectId => "object_id".to_string(),
+            Aov::MaterialId => "material_id".to_string(),
+            Aov::Uv => "uv".to_string(),
+            Aov::AmbientOcclusion => "ao".to_string(),
+            Aov::BentNormal => "bent_normal".to_string(),
+            Aov::LightGroup(group) => format!("light_group_{}", group),
+            Aov::LightGroupOther => "light_group_other".to_string(),
+            Aov::Debug(mode) => format!("debug_{}", mode.name())
+        }
+    }
+
+    pub fn channels(&self) -> &'static [&'static str] {
+        match *self {
+            Aov::Depth => &["Z"],
+            Aov::Position | Aov::Normal | Aov::BentNormal => &["X", "Y", "Z"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::Uv => &["U", "V"],
+            Aov::Debug(mode) => mode.channels(),
+            _ => &["R", "G", "B"]
+        }
+    }
+
+    // Ids are taken from the first sample of a pixel instead of being averaged
+    pub fn is_averaged(&self) -> bool {
+        match *self {
+            Aov::ObjectId | Aov::MaterialId | Aov::Depth => false,
+            Aov::Debug(mode) => mode.is_averaged(),
+            _ => true
+        }
+    }
+
+    // Depth keeps the nearest hit of a pixel, misses would make the mean infinite
+    pub fn is_nearest(&self) -> bool {
+        match *self {
+            Aov::Depth | Aov::Debug(DebugMode::Depth) => true,
+            _ => false
+        }
+    }
+
+    // Passes holding colors in the working space, which are converted on output
+    pub fn is_color(&self) -> bool {
+        match *self {
+            Aov::Beauty | Aov::Albedo | Aov::LightGroup(_) | Aov::LightGroupOther => true,
+            _ => false
+        }
+    }
+
+    // Value of pixels whose camera ray escapes the scene. Debug AOVs are evaluated by the
+    // renderer, which also knows the traversal cost of the ray.
+    pub fn miss(&self) -> Color {
+        match *self {
+            Aov::Depth => Color::new(f64::INFINITY, 0.0, 0.0),
+            Aov::ObjectId | Aov::MaterialId => Color::new(-1.0, 0.0, 0.0),
+            _ => Color::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    pub fn eval(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        match *self {
+            Aov::Beauty => {
+                stats::count(Counter::ShaderCalls);
+                hit.shape.shade(hit, renderer)
+            },
+            Aov::Depth => Color::new(hit.d, 0.0, 0.0),
+            Aov::Position => hit.p.to_vector(),
+            Aov::Normal => hit.n,
+            Aov::Albedo => hit.shape.shader().albedo(hit),
+            Aov::ObjectId => Color::new(hit.object as Float, 0.0, 0.0),
+            Aov::MaterialId => {
+                let id = renderer.material_id(hit.shape).map_or(-1.0, |id| id as Float);
+                Color::new(id, 0.0, 0.0)
+            },
+            Aov::Uv => Color::new(hit.uv.x, hit.uv.y, 0.0),
+            Aov::AmbientOcclusion | Aov::BentNormal => self.occlusion(&ambient_occlusion(hit, renderer, renderer.ao_settings())),
+            Aov::LightGroup(group) => {
+                let mut c = Color::new(0.0, 0.0, 0.0);
+                let contributions = hit.shape.shader().shade_lights(hit, renderer);
+                for (light, contribution) in renderer.lights().iter().zip(contributions) {
+                    if light.group() == group {
+                        c += contribution;
+                    }
+                }
+                c
+            },
+            // The renderer takes the rest of the beauty of the same sample instead
+            Aov::LightGroupOther => {
+                stats::count(Counter::ShaderCalls);
+                hit.shape.shade(hit, renderer) - direct_light(hit, renderer)
+            },
+            Aov::Debug(mode) => mode.eval(Some(hit), 0, renderer)
+        }
+    }
+
+    // The occlusion AOVs share one estimate per hit, which the renderer computes only once
+    pub fn occlusion(&self, occlusion: &Occlusion) -> Color {
+        match *self {
+            Aov::BentNormal => occlusion.bent_normal,
+            _ => Color::new(occlusion.visibility, occlusion.visibility, occlusion.visibility)
+        }
+    }
+
+    // Maps the raw values to [0, 1] for 8 bit image output
+    pub fn to_display(&self, film: &Film) -> Film {
+        let mut out = film.clone();
+        match *self {
+            Aov::Depth => {
+                let max = film.pixels.iter().map(|c| c.x).filter(|d| d.is_finite()).fold(0.0, Float::max);
+                for c in &mut out.pixels {
+                    let v = if c.x.is_finite() && max > 0.0 { c.x/max } else { 1.0 };
+                    *c = Color::new(v, v, v);
+                }
+            },
+            Aov::Position => {
+                let mut min = Color::new(f64::INFINITY, f64::INFINITY, f64::INFINITY);
+                let mut max = -min;
+                for c in &film.pixels {
+                    for i in 0..3 {
+                        min[i] = min[i].min(c[i]);
+                        max[i] = max[i].max(c[i]);
+                    }
+                }
+                for c in &mut out.pixels {
+                    for i in 0..3 {
+                        let extent = max[i] - min[i];
+                        c[i] = if extent > 0.0 { (c[i] - min[i])/extent } else { 0.0 };
+                    }
+                }
+            },
+            Aov::Normal | Aov::BentNormal => {
+                for c in &mut out.pixels {
+                    *c = Color::new(0.5, 0.5, 0.5) + *c*0.5;
+                }
+            },
+            Aov::ObjectId | Aov::MaterialId => {
+                for c in &mut out.pixels {
+                    *c = false_color(c.x);
+                }
+            },
+            Aov::Debug(mode) => return mode.to_display(film),
+            _ => {}
+        }
+        out
+    }
+}
+
+// Sum of the light groups at the hit
+pub fn direct_light(hit: &HitInfo, renderer: &Renderer) -> Color {
+    let mut c = Color::new(0.0, 0.0, 0.0);
+    for contribution in hit.shape.shader().shade_lights(hit, renderer) {
+        c += contribution;
+    }
+    c
+}
+
+// Distinct color per integer id, black for negative ids
+pub fn false_color(id: Float) -> Color {
+    if id < 0.0 {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+    let mut h = (id as u32).wrapping_add(1).wrapping_mul(0x9e3779b9);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    Color::new((h & 0xff) as Float, ((h >> 8) & 0xff) as Float, ((h >> 16) & 0xff) as Float)/255.0
+}
+
+// Writes all passes as layers of one EXR file, the beauty pass is stored without a layer prefix.
+// Color passes are stored as linear values with the primaries of the output space, or of the
+// working space without one.
+pub fn write_exr(path: &Path, aovs: &[Aov], films: &[Film], working: ColorSpace, output: Option<ColorSpace>) -> io::Result<()> {
+    let output = output.unwrap_or(working);
+    let mut channels = Vec::new();
+    let (width, height) = match films.first() {
+        Some(film) => (film.width, film.height),
+        None => (0, 0)
+    };
+    for (aov, film) in aovs.iter().zip(films) {
+        let film = if aov.is_color() { convert_film(film, working, output) } else { film.clone() };
+        for (i, channel) in aov.channels().iter().enumerate() {
+            let name = match *aov {
+                Aov::Beauty => channel.to_string(),
+                _ => format!("{}.{}", aov.name(), channel)
+            };
+            channels.push(exr::Channel {
+                name: name,
+                values: film.pixels.iter().map(|c| c[i] as f32).collect()
+            });
+        }
+    }
+    let c = output.chromaticities();
+    let chromaticities = [
+        [c[0][0] as f32, c[0][1] as f32], [c[1][0] as f32, c[1][1] as f32],
+        [c[2][0] as f32, c[2][1] as f32], [c[3][0] as f32, c[3][1] as f32]
+    ];
+    exr::write(path, width, height, channels, &chromaticities)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use types::*;
+    use film::Film;
+    use colorspace::ColorSpace;
+    use exr;
+    use super::*;
+
+    #[test]
+    fn exr_layers() {
+        let dir = env::temp_dir().join(format!("raytracer-aov-exr-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("passes.exr");
+        let aovs = [Aov::Beauty, Aov::Depth, Aov::ObjectId, Aov::LightGroup(1)];
+        let films: Vec<Film> = (0..aovs.len()).map(|i| {
+            let mut film = Film::new(2, 2);
+            for (k, c) in film.pixels.iter_mut().enumerate() {
+                let v = (10*i + k) as Float;
+                *c = Color::new(v, v + 0.25, v + 0.5);
+            }
+            film
+        }).collect();
+        write_exr(&path, &aovs, &films, ColorSpace::Linear, None).unwrap();
+        let image = exr::tests::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(image.channels, ["B", "G", "R", "depth.Z", "light_group_1.B", "light_group_1.G",
+                                    "light_group_1.R", "object_id.id"]);
+        // Channels of the second pixel of the first scanline
+        let value = |name: &str| {
+            let c = image.channels.iter().position(|n| n == name).unwrap();
+            image.lines[0][c*image.width + 1]
+        };
+        assert_eq!(value("R"), 1.0);
+        assert_eq!(value("B"), 1.5);
+        assert_eq!(value("depth.Z"), 11.0);
+        assert_eq!(value("object_id.id"), 21.0);
+        assert_eq!(value("light_group_1.G"), 31.25);
+    }
+}
@@ -0,0 +1,407 @@
+use nalgebra::{Dot, Norm};
+use std::f64;
+use types::*;
+use ray::Ray;
+use hit::HitInfo;
+use camera::Camera;
+use bsdf::{Bsdf, Transport};
+use film::Splat;
+use light::choose_light;
+use renderer::Renderer;
+use sampling;
+use stats;
+use stats::Counter;
+
+// Bidirectional path tracing. Every camera sample also traces a path from a light and
+// connects all prefixes of both paths, the strategies are weighted by multiple importance
+// sampling with the balance heuristic. Connections of light path vertices to the camera are
+// light tracing, they are splatted to the pixel they reach.
+// Source: Physically based Rendering, Chapter Light Transport III, Section Bidirectional
+// Path Tracing, and Veach, Robust Monte Carlo Methods for Light Transport Simulation, Chapter 10
+//
+// The scene lights are points, camera paths never hit them. Media attenuate the paths but do
+// not scatter them.
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Camera,
+    Light(usize),   // Index of the scene light
+    Surface
+}
+
+#[derive(Clone)]
+struct Vertex<'a> {
+    kind: Kind,
+    p: Pnt3,
+    hit: Option<HitInfo<'a>>,
+    bsdf: Option<Bsdf>,
+    beta: Color,    // Throughput of the path up to the vertex
+    delta: bool,    // Scatters specularly, connections through it carry no light
+    pdf_fwd: Float, // Area density of sampling the vertex from the previous one on its path
+    pdf_rev: Float  // Area density of sampling it from the following one, coming from the other end
+}
+
+impl<'a> Vertex<'a> {
+    fn endpoint(kind: Kind, p: Pnt3, beta: Color, pdf_fwd: Float) -> Vertex<'a> {
+        Vertex {
+            kind: kind,
+            p: p,
+            hit: None,
+            bsdf: None,
+            beta: beta,
+            delta: false,
+            pdf_fwd: pdf_fwd,
+            pdf_rev: 0.0
+        }
+    }
+
+    fn surface(hit: HitInfo<'a>, beta: Color) -> Vertex<'a> {
+        let bsdf = hit.shape.shader().bsdf(&hit);
+        Vertex {
+            kind: Kind::Surface,
+            p: hit.p,
+            bsdf: Some(bsdf),
+            hit: Some(hit),
+            beta: beta,
+            delta: bsdf.is_specular(),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0
+        }
+    }
+
+    // Scattering from the previous vertex of the path towards the other vertex
+    fn f(&self, other: &Vertex) -> Color {
+        match (self.hit.as_ref(), self.bsdf.as_ref()) {
+            (Some(hit), Some(bsdf)) => bsdf.eval(&hit.i, &(other.p - self.p).normalize()),
+            _ => Color::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    // Cosine of the unit direction with the shading normal, one for points
+    fn cos(&self, w: &Vec3) -> Float {
+        match self.bsdf {
+            Some(ref bsdf) => w.dot(&bsdf.n).abs(),
+            None => 1.0
+        }
+    }
+
+    // Solid angle density of a direction from this vertex to the next as area density at next
+    fn convert_density(&self, pdf: Float, next: &Vertex) -> Float {
+        let w = next.p - self.p;
+        let d2 = w.norm_squared();
+        if d2 == 0.0 {
+            return 0.0;
+        }
+        match next.hit {
+            Some(ref hit) => pdf*hit.ng.dot(&w).abs()/(d2*d2.sqrt()),
+            None => pdf/d2
+        }
+    }
+
+    // Area density of sampling next from this vertex, which was reached from prev
+    fn pdf(&self, renderer: &Renderer, camera: &Camera, prev: Option<&Vertex>, next: &Vertex) -> Float {
+        let wn = (next.p - self.p).normalize();
+        let pdf = match self.kind {
+            Kind::Camera => camera.importance(&wn).1,
+            Kind::Light(i) => renderer.lights()[i].emission_pdf(&wn).1,
+            Kind::Surface => match (prev, self.bsdf.as_ref()) {
+                (Some(prev), Some(bsdf)) => bsdf.pdf(&(prev.p - self.p).normalize(), &wn),
+                _ => 0.0
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+}
+
+// Radiance along the camera ray, contributions of light tracing are added to the splats
+pub fn radiance(renderer: &Renderer, camera: &Camera, ray: &Ray, max_depth: u32, splats: &mut Vec<Splat>) -> Color {
+    let max_depth = max_depth as usize;
+    let camera_path = camera_subpath(renderer, camera, ray, max_depth);
+    let light_path = light_subpath(renderer, max_depth);
+    let mut l = Color::new(0.0, 0.0, 0.0);
+    for t in 1..camera_path.len() + 1 {
+        for s in 1..light_path.len() + 1 {
+            if (s == 1 && t == 1) || s + t - 2 > max_depth {
+                continue;
+            }
+            l += connect(renderer, camera, &camera_path, &light_path, s, t, splats);
+        }
+    }
+    l
+}
+
+fn camera_subpath<'a>(renderer: &'a Renderer, camera: &Camera, ray: &Ray, max_depth: usize) -> Vec<Vertex<'a>> {
+    let one = Color::new(1.0, 1.0, 1.0);
+    let mut path = vec![Vertex::endpoint(Kind::Camera, camera.position, one, 1.0)];
+    let (_, pdf_dir) = camera.importance(&ray.dir);
+    random_walk(renderer, ray.clone(), one, pdf_dir, max_depth + 2, Transport::Radiance, &mut path);
+    path
+}
+
+fn light_subpath<'a>(renderer: &'a Renderer, max_depth: usize) -> Vec<Vertex<'a>> {
+    let lights = renderer.lights();
+    if lights.is_empty() {
+        return Vec::new();
+    }
+    let (index, pdf_choice) = choose_light(lights.len(), sampling::next_float());
+    let (s, t) = sampling::next_2d();
+    let emission = lights[index].sample_emission(s, t);
+    if emission.pdf_pos <= 0.0 || emission.pdf_dir <= 0.0 {
+        return Vec::new();
+    }
+    let mut path = vec![Vertex::endpoint(Kind::Light(index), emission.origin, emission.radiance, emission.pdf_pos*pdf_choice)];
+    let mut ray = Ray::new(&emission.origin, emission.dir, 0.0, f64::INFINITY);
+    ray.medium = renderer.fog();
+    let beta = emission.radiance/(pdf_choice*emission.pdf_pos*emission.pdf_dir);
+    random_walk(renderer, ray, beta, emission.pdf_dir, max_depth + 1, Transport::Importance, &mut path);
+    path
+}
+
+// Extends the path by sampling the BSDF at every vertex, pdf is the solid angle density of the
+// direction of the ray
+fn random_walk<'a>(renderer: &'a Renderer, mut ray: Ray, mut beta: Color, pdf: Float, max_vertices: usize,
+                   mode: Transport, path: &mut Vec<Vertex<'a>>) {
+    let mut pdf_fwd = pdf;
+    while path.len() < max_vertices {
+        stats::count(Counter::SecondaryRays);
+        let (hit, tr) = match renderer.next_surface(&ray) {
+            Some(next) => next,
+            None => return
+        };
+        beta = beta*tr;
+        let n = path.len();
+        let mut vertex = Vertex::surface(hit, beta);
+        vertex.pdf_fwd = path[n - 1].convert_density(pdf_fwd, &vertex);
+        path.push(vertex);
+
+        let (wo, bsdf) = (path[n].hit.as_ref().unwrap().i, path[n].bsdf.unwrap());
+        let (s, t) = sampling::next_2d();
+        let sample = match bsdf.sample(&wo, mode, s, t, sampling::next_float()) {
+            Some(sample) if sample.pdf > 0.0 => sample,
+            _ => return
+        };
+        beta = beta*sample.f*(sample.wi.dot(&bsdf.n).abs()/sample.pdf);
+        if beta == Color::new(0.0, 0.0, 0.0) {
+            return;
+        }
+        let pdf_rev = if sample.specular {
+            pdf_fwd = 0.0;
+            0.0
+        } else {
+            pdf_fwd = sample.pdf;
+            bsdf.pdf(&sample.wi, &wo)
+        };
+        path[n - 1].pdf_rev = path[n].convert_density(pdf_rev, &path[n - 1]);
+
+        let hit = path[n].hit.as_ref().unwrap();
+        ray = hit.spawn_ray(sample.wi);
+        ray.medium = renderer.medium_after(hit, &sample.wi);
+    }
+}
+
+// Contribution of the strategy connecting the first s light and the first t camera vertices
+fn connect<'a>(renderer: &'a Renderer, camera: &Camera, camera_path: &[Vertex<'a>], light_path: &[Vertex<'a>],
+               s: usize, t: usize, splats: &mut Vec<Splat>) -> Color {
+    let black = Color::new(0.0, 0.0, 0.0);
+    if t == 1 {
+        // Light tracing, the light path vertex is connected to the camera
+        let qs = &light_path[s - 1];
+        if qs.delta {
+            return black;
+        }
+        let (x, y) = match camera.raster_position(&qs.p) {
+            Some(raster) => raster,
+            None => return black
+        };
+        let d = camera.position - qs.p;
+        let dist = d.norm();
+        let wi = d/dist;
+        let (importance, _) = camera.importance(&-wi);
+        let pdf = dist*dist/(-wi).dot(&camera.front.normalize());
+        // Every pixel measures the importance of the whole image
+        let pixels = (camera.resolution.x*camera.resolution.y) as Float;
+        let sampled = Vertex::endpoint(Kind::Camera, camera.position, Color::new(1.0, 1.0, 1.0)*(pixels*importance/pdf), 0.0);
+        let mut l = qs.beta*qs.f(&sampled)*sampled.beta*qs.cos(&wi);
+        if l == black {
+            return black;
+        }
+        l = l*visibility(renderer, qs, &sampled);
+        let weight = mis_weight(renderer, camera, camera_path, light_path, Some(&sampled), s, t);
+        splats.push(Splat { x: x as u32, y: y as u32, color: l*weight });
+        return black;
+    }
+    let pt = &camera_path[t - 1];
+    if pt.delta {
+        return black;
+    }
+    if s == 1 {
+        // Next event estimation, a light is sampled as seen from the camera path vertex
+        let lights = renderer.lights();
+        let (index, pdf_choice) = choose_light(lights.len(), sampling::next_float());
+        let sample = lights[index].sample(&pt.p);
+        if sample.pdf <= 0.0 {
+            return black;
+        }
+        let (pdf_pos, _) = lights[index].emission_pdf(&-sample.wi);
+        let sampled = Vertex::endpoint(Kind::Light(index), pt.p + sample.wi*sample.dist,
+            sample.radiance/(sample.pdf*pdf_choice), pdf_pos*pdf_choice);
+        let mut l = pt.beta*pt.f(&sampled)*sampled.beta*pt.cos(&sample.wi);
+        if l == black {
+            return black;
+        }
+        l = l*visibility(renderer, pt, &sampled);
+        return l*mis_weight(renderer, camera, camera_path, light_path, Some(&sampled), s, t);
+    }
+    let qs = &light_path[s - 1];
+    if qs.delta {
+        return black;
+    }
+    let mut l = qs.beta*qs.f(pt)*pt.f(qs)*pt.beta;
+    if l == black {
+        return black;
+    }
+    let d = pt.p - qs.p;
+    let d2 = d.norm_squared();
+    let w = d/d2.sqrt();
+    l = l*visibility(renderer, qs, pt)*(qs.cos(&w)*pt.cos(&w)/d2);
+    l*mis_weight(renderer, camera, camera_path, light_path, None, s, t)
+}
+
+// Transmittance between a surface vertex and another vertex
+fn visibility(renderer: &Renderer, from: &Vertex, to: &Vertex) -> Color {
+    match from.hit {
+        Some(ref hit) => {
+            stats::count(Counter::ShadowRays);
+            renderer.transmittance(&hit.spawn_ray_to(&to.p))
+        },
+        None => Color::new(0.0, 0.0, 0.0)
+    }
+}
+
+// Balance heuristic weight of the strategy among all that could have created the same path.
+// The sampled vertex replaces the camera or light endpoint for t = 1 or s = 1.
+fn mis_weight<'a>(renderer: &Renderer, camera: &Camera, camera_path: &[Vertex<'a>], light_path: &[Vertex<'a>],
+                  sampled: Option<&Vertex<'a>>, s: usize, t: usize) -> Float {
+    if s + t == 2 {
+        return 1.0;
+    }
+    // (pdf_fwd, pdf_rev, delta) of the vertices, the connection changes them at its ends
+    let mut cameras: Vec<(Float, Float, bool)> = camera_path[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+    let mut lights: Vec<(Float, Float, bool)> = light_path[..s].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+    let pt = match sampled {
+        Some(v) if t == 1 => v,
+        _ => &camera_path[t - 1]
+    };
+    let qs = match sampled {
+        Some(v) if s == 1 => v,
+        _ => &light_path[s - 1]
+    };
+    let pt_minus = if t > 1 { Some(&camera_path[t - 2]) } else { None };
+    let qs_minus = if s > 1 { Some(&light_path[s - 2]) } else { None };
+
+    cameras[t - 1] = (pt.pdf_fwd, qs.pdf(renderer, camera, qs_minus, pt), false);
+    if let Some(pm) = pt_minus {
+        cameras[t - 2].1 = pt.pdf(renderer, camera, Some(qs), pm);
+    }
+    lights[s - 1] = (qs.pdf_fwd, pt.pdf(renderer, camera, pt_minus, qs), false);
+    if let Some(qm) = qs_minus {
+        lights[s - 2].1 = qs.pdf(renderer, camera, Some(pt), qm);
+    }
+
+    // Densities of specular vertices are zero and cancel in the ratios
+    let remap = |pdf: Float| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+    let mut r = 1.0;
+    for i in (1..t).rev() {
+        r *= remap(cameras[i].1)/remap(cameras[i].0);
+        if !cameras[i].2 && !cameras[i - 1].2 {
+            sum += r;
+        }
+    }
+    r = 1.0;
+    for i in (0..s).rev() {
+        r *= remap(lights[i].1)/remap(lights[i].0);
+        // Point lights can not be reached by camera paths
+        let delta_light = i == 0 || lights[i - 1].2;
+        if !lights[i].2 && !delta_light {
+            sum += r;
+        }
+    }
+    1.0/(1.0 + sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use types::*;
+    use aov::Aov;
+    use renderer::Integrator;
+    use scene::Scene;
+    use window::Window;
+    use testing;
+    use testing::{SceneBuilder, diffuse, grey};
+
+    // Diffuse spheres on a diffuse ground, lit by a point light
+    fn scene(integrator: Integrator) -> Scene {
+        let mut scene = SceneBuilder::new()
+            .sphere(Pnt3::new(-1.2, 0.0, 0.0), 1.0, diffuse(Color::new(0.8, 0.1, 0.1)))
+            .sphere(Pnt3::new(1.2, 1.0, 0.0), 1.0, grey(0.7))
+            .ground(grey(0.5))
+            .light(Pnt3::new(0.0, -5.0, 5.0), 40.0, 0)
+            .build();
+        scene.integrator = integrator;
+        scene
+    }
+
+    // Mean of the beauty pass over blocks of 8x8 pixels
+    fn blocks(scene: &Scene, samples: u32) -> Vec<Color> {
+        let mut window = Window::new(32, 24);
+        window.samples = samples;
+        let film = &testing::draw_aovs(&window, scene, &[Aov::Beauty])[0];
+        let mut blocks = Vec::new();
+        for by in 0..3 {
+            for bx in 0..4 {
+                let mut sum = Color::new(0.0, 0.0, 0.0);
+                for y in 0..8 {
+                    for x in 0..8 {
+                        sum += film.get(bx*8 + x, by*8 + y);
+                    }
+                }
+                blocks.push(sum/64.0);
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn direct_light_matches_whitted() {
+        // With a single bounce both render the direct light, bidirectionally it is the
+        // weighted sum of next event estimation and light tracing
+        let whitted = blocks(&scene(Integrator::Whitted), 16);
+        let bdpt = blocks(&scene(Integrator::Bdpt(1)), 64);
+        for (w, b) in whitted.iter().zip(&bdpt) {
+            for i in 0..3 {
+                assert!((w[i] - b[i]).abs() < 0.05*w[i] + 1e-3, "{:?} {:?}", whitted, bdpt);
+            }
+        }
+    }
+
+    #[test]
+    fn closed_sphere_interreflection() {
+        // A point light in the center of a diffuse sphere gives the irradiance pi at the wall,
+        // every bounce adds the radiance of the previous one times the albedo
+        let albedo = 0.5;
+        let camera = Window::new(1, 1).camera.position;
+        let mut scene = SceneBuilder::new()
+            .sphere(camera, 5.0, grey(albedo))
+            .light(camera, 25.0*PI, 0)
+            .build();
+        for &depth in &[1, 3] {
+            scene.integrator = Integrator::Bdpt(depth);
+            let expected = albedo*(1.0 - albedo.powi(depth as i32))/(1.0 - albedo);
+            for c in blocks(&scene, 16) {
+                assert!((c.x - expected).abs() < 0.03*expected, "{} {:?}", depth, c);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::io;
+
+// Decoding of the binary formats: checkpoints, tile messages and volume files
+
+pub fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Little endian decoding of a byte buffer
+pub struct Reader<'a> {
+    pub data: &'a [u8],
+    pub pos: usize
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data: data, pos: 0 }
+    }
+    pub fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.pos > self.data.len() || n > self.data.len() - self.pos {
+            return Err(invalid("truncated data".to_string()));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+    pub fn i32(&mut self) -> io::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+    pub fn i64(&mut self) -> io::Result<i64> {
+        Ok(self.u64()? as i64)
+    }
+    pub fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+    pub fn f64(&mut self) -> io::Result<f64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(f64::from_le_bytes(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn little_endian_values() {
+        let mut data = vec![0x01, 0x02, 0x03, 0x04, 0xff, 0xfe];
+        data.extend_from_slice(&1.5f64.to_le_bytes());
+        let mut r = Reader::new(&data);
+        assert_eq!(r.u32().unwrap(), 0x04030201);
+        assert_eq!(r.u16().unwrap(), 0xfeff);
+        assert_eq!(r.f64().unwrap(), 1.5);
+        assert_eq!(r.pos, data.len());
+        // Reads past the end fail without moving
+        assert_eq!(r.u32().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(r.pos, data.len());
+    }
+}
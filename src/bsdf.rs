@@ -0,0 +1,173 @@
+use nalgebra::{Cross, Dot, Norm};
+use std::f64::consts::PI;
+use types::*;
+use hit::{HitInfo, refract};
+use shader::fresnel_dielectric;
+use warp::{warp_point, WarpFunction};
+
+// Which quantity a path carries. Refraction compresses radiance into the denser side, but
+// not importance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Radiance,   // Paths starting at the camera
+    Importance  // Paths starting at a light
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Lobe {
+    Lambert(Color),
+    Mirror(Color),
+    Dielectric(Color, Float)    // Tint and index of refraction relative to the outside
+}
+
+// Explicit scattering function of a surface point for the integrators that trace paths from
+// the lights. Directions point away from the surface, n is the outward shading normal.
+#[derive(Debug, Clone, Copy)]
+pub struct Bsdf {
+    pub n: Vec3,
+    pub lobe: Lobe
+}
+
+pub struct BsdfSample {
+    pub wi: Vec3,
+    pub f: Color,
+    pub pdf: Float,     // Solid angle density, the probability of the chosen lobe if specular
+    pub specular: bool
+}
+
+impl Bsdf {
+    pub fn new(hit: &HitInfo, lobe: Lobe) -> Bsdf {
+        Bsdf {
+            n: if hit.inside { -hit.n } else { hit.n },
+            lobe: lobe
+        }
+    }
+
+    // Specular lobes scatter into single directions, their value is zero everywhere else
+    pub fn is_specular(&self) -> bool {
+        match self.lobe {
+            Lobe::Lambert(_) => false,
+            _ => true
+        }
+    }
+
+    pub fn eval(&self, wo: &Vec3, wi: &Vec3) -> Color {
+        match self.lobe {
+            Lobe::Lambert(c) if wo.dot(&self.n)*wi.dot(&self.n) > 0.0 => c/PI,
+            _ => Color::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    pub fn pdf(&self, wo: &Vec3, wi: &Vec3) -> Float {
+        match self.lobe {
+            Lobe::Lambert(_) if wo.dot(&self.n)*wi.dot(&self.n) > 0.0 => wi.dot(&self.n).abs()/PI,
+            _ => 0.0
+        }
+    }
+
+    pub fn sample(&self, wo: &Vec3, mode: Transport, s: Float, t: Float, u: Float) -> Option<BsdfSample> {
+        let cos_o = wo.dot(&self.n);
+        if cos_o == 0.0 {
+            return None;
+        }
+        // Normal on the side of wo
+        let n = if cos_o > 0.0 { self.n } else { -self.n };
+        let reflected = -*wo + n*(2.0*wo.dot(&n));
+        match self.lobe {
+            Lobe::Lambert(c) => {
+                let local = warp_point(s, t, WarpFunction::CosineHemisphere);
+                if local.z <= 0.0 {
+                    return None;
+                }
+                let (tangent, bitangent) = frame(&n);
+                let wi = tangent*local.x + bitangent*local.y + n*local.z;
+                Some(BsdfSample { wi: wi, f: c/PI, pdf: local.z/PI, specular: false })
+            },
+            Lobe::Mirror(c) => {
+                Some(BsdfSample { wi: reflected, f: c/cos_o.abs(), pdf: 1.0, specular: true })
+            },
+            Lobe::Dielectric(c, ior) => {
+                let f = fresnel_dielectric(cos_o, ior);
+                if u < f {
+                    return Some(BsdfSample { wi: reflected, f: c*(f/cos_o.abs()), pdf: f, specular: true });
+                }
+                // Incident over transmitted index
+                let eta = if cos_o > 0.0 { 1.0/ior } else { ior };
+                let wi = match refract(wo, &n, eta) {
+                    Some(wi) => wi,
+                    None => return None
+                };
+                let mut ft = c*((1.0 - f)/wi.dot(&n).abs());
+                if mode == Transport::Radiance {
+                    ft = ft*(eta*eta);
+                }
+                Some(BsdfSample { wi: wi, f: ft, pdf: 1.0 - f, specular: true })
+            }
+        }
+    }
+}
+
+// Orthonormal tangents of a unit vector
+fn frame(n: &Vec3) -> (Vec3, Vec3) {
+    let a = if n.x.abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+    let t = (a - *n*n.dot(&a)).normalize();
+    (t, n.cross(&t))
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Dot, Norm};
+    use types::*;
+    use chi2;
+    use chi2::Domain;
+    use super::*;
+
+    #[test]
+    fn chi2_lambert() {
+        let n = Vec3::new(0.3, -0.5, 0.8).normalize();
+        let bsdf = Bsdf { n: n, lobe: Lobe::Lambert(Color::new(0.5, 0.5, 0.5)) };
+        // Grazing, oblique and normal incidence from both sides
+        let directions = [Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.9, 0.1, 0.2), Vec3::new(-0.2, 0.7, -0.4), -n];
+        for (i, wo) in directions.iter().enumerate() {
+            let wo = wo.normalize();
+            let result = chi2::test(&format!("lambert_{}", i), Domain::Sphere,
+                |s, t| bsdf.sample(&wo, Transport::Radiance, s, t, 0.5).map(|sample| sample.wi),
+                |wi| bsdf.pdf(&wo, wi));
+            if let Err(e) = result {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn lambert_sampling() {
+        let bsdf = Bsdf { n: Vec3::new(0.0, 0.0, 1.0), lobe: Lobe::Lambert(Color::new(0.5, 0.5, 0.5)) };
+        let wo = Vec3::new(0.0, 0.6, -0.8);
+        for &(s, t) in &[(0.1, 0.2), (0.5, 0.9), (0.99, 0.01)] {
+            let sample = bsdf.sample(&wo, Transport::Radiance, s, t, 0.5).unwrap();
+            assert!(sample.wi.z < 0.0);
+            assert!((sample.wi.norm() - 1.0).abs() < 1e-12);
+            assert!((sample.pdf - bsdf.pdf(&wo, &sample.wi)).abs() < 1e-12);
+            assert_eq!(sample.f, bsdf.eval(&wo, &sample.wi));
+        }
+        assert_eq!(bsdf.pdf(&wo, &Vec3::new(0.0, 0.0, 1.0)), 0.0);
+    }
+
+    #[test]
+    fn dielectric_transport() {
+        let n = Vec3::new(0.0, 0.0, 1.0);
+        let bsdf = Bsdf { n: n, lobe: Lobe::Dielectric(Color::new(1.0, 1.0, 1.0), 1.5) };
+        let wo = Vec3::new(0.0, 0.6, 0.8);
+        let reflect = bsdf.sample(&wo, Transport::Radiance, 0.0, 0.0, 0.0).unwrap();
+        assert!((reflect.wi - Vec3::new(0.0, -0.6, 0.8)).norm() < 1e-12);
+        let radiance = bsdf.sample(&wo, Transport::Radiance, 0.0, 0.0, 0.999).unwrap();
+        let importance = bsdf.sample(&wo, Transport::Importance, 0.0, 0.0, 0.999).unwrap();
+        assert!(radiance.wi.z < 0.0 && radiance.specular);
+        // Entering the denser medium scales radiance by the squared index ratio
+        let weight = |s: &BsdfSample| s.f.x*s.wi.dot(&n).abs()/s.pdf;
+        assert!((weight(&radiance) - 1.0/(1.5*1.5)).abs() < 1e-12);
+        assert!((weight(&importance) - 1.0).abs() < 1e-12);
+        // Sampled reflection and refraction weights add up to the tint
+        assert!((reflect.pdf + radiance.pdf - 1.0).abs() < 1e-12);
+    }
+}
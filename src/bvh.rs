@@ -0,0 +1,221 @@
+use std::f64;
+use std::vec::Vec;
+use types::*;
+use ray::Ray;
+use stats;
+use stats::Counter;
+
+// Leaves hold at most this many shapes
+const MAX_LEAF_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Aabb {
+    pub min: Pnt3,
+    pub max: Pnt3
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Pnt3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+            max: Pnt3::new(-f64::INFINITY, -f64::INFINITY, -f64::INFINITY)
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Pnt3::new(self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)),
+            max: Pnt3::new(self.max.x.max(other.max.x), self.max.y.max(other.max.y), self.max.z.max(other.max.z))
+        }
+    }
+
+    pub fn grow(&self, p: &Pnt3) -> Aabb {
+        self.union(&Aabb { min: *p, max: *p })
+    }
+
+    pub fn centroid(&self) -> Pnt3 {
+        Pnt3::new((self.min.x + self.max.x)*0.5, (self.min.y + self.max.y)*0.5, (self.min.z + self.max.z)*0.5)
+    }
+
+    // Axis with the largest extent
+    pub fn largest_axis(&self) -> usize {
+        let d = self.max - self.min;
+        if d.x >= d.y && d.x >= d.z { 0 } else if d.y >= d.z { 1 } else { 2 }
+    }
+
+    // Slab test, true if the box overlaps the ray segment
+    pub fn hit(&self, ray: &Ray, inv_dir: &Vec3) -> bool {
+        let mut t0 = ray.tmin;
+        let mut t1 = ray.tmax;
+        for i in 0..3 {
+            let mut near = (self.min[i] - ray.origin[i])*inv_dir[i];
+            let mut far = (self.max[i] - ray.origin[i])*inv_dir[i];
+            if near > far {
+                let t = near;
+                near = far;
+                far = t;
+            }
+            // NaN from 0*inf leaves the bounds unchanged
+            if near > t0 {
+                t0 = near;
+            }
+            if far < t1 {
+                t1 = far;
+            }
+            if t0 > t1 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Flattened node, children of inner nodes are stored next to each other
+#[derive(Debug, Clone)]
+struct Node {
+    bounds: Aabb,
+    first: usize,   // First shape index of a leaf, left child of an inner node
+    count: usize    // Number of shapes, zero for inner nodes
+}
+
+// Bounding volume hierarchy over the shapes of a scene, split at the median centroid
+#[derive(Debug, Clone)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>
+}
+
+impl Bvh {
+    pub fn new(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            indices: (0..bounds.len()).collect()
+        };
+        if !bounds.is_empty() {
+            bvh.nodes.push(Node { bounds: Aabb::empty(), first: 0, count: bounds.len() });
+            bvh.build(0, bounds);
+        }
+        bvh
+    }
+
+    fn build(&mut self, node: usize, bounds: &[Aabb]) {
+        let first = self.nodes[node].first;
+        let count = self.nodes[node].count;
+        let mut node_bounds = Aabb::empty();
+        let mut centroids = Aabb::empty();
+        for &i in &self.indices[first..first + count] {
+            node_bounds = node_bounds.union(&bounds[i]);
+            centroids = centroids.grow(&bounds[i].centroid());
+        }
+        self.nodes[node].bounds = node_bounds;
+        if count <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let axis = centroids.largest_axis();
+        self.indices[first..first + count].sort_by(|a, b| {
+            let ca = bounds[*a].centroid()[axis];
+            let cb = bounds[*b].centroid()[axis];
+            ca.partial_cmp(&cb).unwrap_or(::std::cmp::Ordering::Equal)
+        });
+        let half = count/2;
+        let left = self.nodes.len();
+        self.nodes.push(Node { bounds: Aabb::empty(), first: first, count: half });
+        self.nodes.push(Node { bounds: Aabb::empty(), first: first + half, count: count - half });
+        self.nodes[node].first = left;
+        self.nodes[node].count = 0;
+        self.build(left, bounds);
+        self.build(left + 1, bounds);
+    }
+
+    // Calls visit with the index of every shape whose leaf the ray reaches. The ray may be
+    // shortened by visit, which culls the nodes behind the closest hit.
+    pub fn traverse<F>(&self, ray: &mut Ray, mut visit: F) where F: FnMut(usize, &mut Ray) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let inv_dir = Vec3::new(1.0/ray.dir.x, 1.0/ray.dir.y, 1.0/ray.dir.z);
+        let mut visited = 0;
+        let mut stack = vec![0];
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            visited += 1;
+            if !node.bounds.hit(ray, &inv_dir) {
+                continue;
+            }
+            if node.count > 0 {
+                for &i in &self.indices[node.first..node.first + node.count] {
+                    visit(i, ray);
+                }
+            } else {
+                stack.push(node.first + 1);
+                stack.push(node.first);
+            }
+        }
+        stats::add(Counter::BvhNodes, visited);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+    use std::rc::Rc;
+    use types::*;
+    use ray::Ray;
+    use renderer::Renderer;
+    use scene::Scene;
+    use shader::{Shader, DiffuseShader};
+    use shape::Sphere;
+    use stats;
+    use stats::Counter;
+    use texture::ConstantTexture;
+
+    struct Lcg(u32);
+
+    impl Lcg {
+        fn next(&mut self) -> Float {
+            self.0 = self.0.wrapping_mul(1664525).wrapping_add(1013904223);
+            (self.0 >> 8) as Float/(1 << 24) as Float
+        }
+        fn point(&mut self, extent: Float) -> Pnt3 {
+            Pnt3::new((self.next() - 0.5)*extent, (self.next() - 0.5)*extent, (self.next() - 0.5)*extent)
+        }
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let shader: Rc<Shader> = Rc::new(DiffuseShader { color: Rc::new(ConstantTexture { color: Color::new(0.5, 0.5, 0.5) }) });
+        let mut rng = Lcg(7);
+        let mut scene = Scene::empty();
+        for _ in 0..200 {
+            let position = rng.point(20.0);
+            let radius = 0.1 + rng.next()*0.8;
+            scene.shapes.push(Box::new(Sphere { position: position, radius: radius, shader: shader.clone() }));
+        }
+        let renderer = Renderer::new(&scene);
+        let rays = 500;
+        let mut hits = 0;
+        let tests = stats::local(Counter::SphereTests);
+        for _ in 0..rays {
+            let origin = rng.point(30.0);
+            let target = rng.point(10.0);
+            let ray = Ray::new(&origin, target - origin, 0.0, f64::INFINITY);
+            let hit = renderer.intersect(&mut ray.clone()).map(|hit| (hit.object, hit.d));
+
+            let mut nearest: Option<(usize, Float)> = None;
+            for (i, shape) in scene.shapes.iter().enumerate() {
+                if let Some(hit) = shape.intersect(&mut ray.clone()) {
+                    if nearest.map_or(true, |n| hit.d < n.1) {
+                        nearest = Some((i, hit.d));
+                    }
+                }
+            }
+            assert_eq!(hit, nearest);
+            hits += hit.is_some() as u32;
+        }
+        assert!(hits > rays/4);
+        // Brute force tested every sphere, the hierarchy only a fraction of them
+        let tests = stats::local(Counter::SphereTests) - tests - rays as u64*200;
+        assert!(tests < rays as u64*200/4, "{}", tests);
+    }
+}
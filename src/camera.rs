@@ -3,7 +3,7 @@ use num_traits::identities::{Zero};
 use std::f64;
 
 use types::*;
-use ray::Ray;
+use ray::{Ray, RayDifferential};
 // use std::ops::Sub;
 // use core::marker::Copy;
 // 
@@ -27,6 +27,24 @@ pub struct Camera {
     ndc_to_window: Matrix4<f64>
 }
 
+// Placement of a camera as given in a scene file
+#[derive(Debug, Clone)]
+pub struct View {
+    pub position: Pnt3,
+    pub front: Vec3,
+    pub up: Vec3,
+    pub angle: f64
+}
+
+impl View {
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.angle = self.angle;
+        camera.up = self.up;
+        camera.front = self.front;
+        camera.set_position(&self.position);
+    }
+}
+
 impl Camera {
     pub fn new(width: u32, height: u32) -> Camera {
         let mut camera = Camera { 
@@ -54,8 +72,26 @@ impl Camera {
     }
 
     pub fn generate_ray(&self, x: u32, y: u32) -> Ray {
-        let xd = x as f64;
-        let yd = y as f64;
+        self.generate_sample_ray(x, y, 0.0, 0.0)
+    }
+
+    // Ray through the point (u, v) in [0, 1)^2 of the pixel
+    pub fn generate_sample_ray(&self, x: u32, y: u32, u: f64, v: f64) -> Ray {
+        let xd = x as f64 + u;
+        let yd = y as f64 + v;
+        let mut ray = self.generate_ray_at(xd, yd);
+        let rx = self.generate_ray_at(xd + 1.0, yd);
+        let ry = self.generate_ray_at(xd, yd + 1.0);
+        ray.differential = Some(RayDifferential {
+            rx_origin: rx.origin,
+            rx_dir: rx.dir,
+            ry_origin: ry.origin,
+            ry_dir: ry.dir
+        });
+        ray
+    }
+
+    fn generate_ray_at(&self, xd: f64, yd: f64) -> Ray {
         let pixel_pos = self.screen_to_world*Pnt4::new(xd, yd, 0.0, 1.0 );
         let pixel_pos = pixel_pos/pixel_pos.w;
         let pixel_pos = Pnt3::new(pixel_pos.x, pixel_pos.y, pixel_pos.z);
@@ -71,6 +107,34 @@ impl Camera {
         Ray::new(&self.position, dir, tmin, tmax)
     }
 
+    // Position of the point on the image in pixels, None if it is not seen by the camera
+    pub fn raster_position(&self, p: &Pnt3) -> Option<(f64, f64)> {
+        if (*p - self.position).dot(&self.front) <= 0.0 {
+            return None;
+        }
+        let screen = self.world_to_screen*Pnt4::new(p.x, p.y, p.z, 1.0);
+        let (x, y) = (screen.x/screen.w, screen.y/screen.w);
+        if x < 0.0 || y < 0.0 || x >= self.resolution.x as f64 || y >= self.resolution.y as f64 {
+            return None;
+        }
+        Some((x, y))
+    }
+
+    // Importance the whole image receives along the unit direction leaving the camera and the
+    // solid angle density of camera rays in that direction, the image plane at distance one
+    // has the area a
+    // Source: Physically based Rendering, Chapter Light Transport III, Section Sampling Cameras
+    pub fn importance(&self, dir: &Vec3) -> (f64, f64) {
+        let cos = dir.dot(&self.front.normalize());
+        if cos <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let tan = f64::tan(f64::to_radians(self.angle)*0.5);
+        let a = 4.0*tan*tan*self.aspect;
+        let cos3 = cos*cos*cos;
+        (1.0/(a*cos3*cos), 1.0/(a*cos3))
+    }
+
     pub fn update_camera(&mut self) {
         let lookat = self.position+self.front;
         self.world_to_camera = Camera::create_lookat(&self.position, &lookat, &self.up);
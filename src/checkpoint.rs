@@ -0,0 +1,238 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+use std::vec::Vec;
+use types::*;
+use aov::Aov;
+use film::{Accumulator, Pixel};
+use binary::{Reader, invalid};
+
+// Binary snapshot of a progressive rendering, all numbers are little endian.
+// The random sequence of a sample only depends on the seed, the pixel and the sample index,
+// so the per pixel sample counts together with the seed are the complete sampler state.
+
+const MAGIC: &'static [u8; 8] = b"RTCHKPT2";
+
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub seed: u32,
+    pub pass: u32,
+    pub pass_samples: u32,    // Samples per pixel the last pass sampled up to
+    pub elapsed: Duration,
+    pub accumulator: Accumulator
+}
+
+impl Checkpoint {
+    pub fn new(width: u32, height: u32, aovs: &[Aov], seed: u32) -> Checkpoint {
+        Checkpoint {
+            seed: seed,
+            pass: 0,
+            pass_samples: 0,
+            elapsed: Duration::from_secs(0),
+            accumulator: Accumulator::new(width, height, aovs)
+        }
+    }
+
+    // Checks that the checkpoint belongs to a rendering with these settings
+    pub fn matches(&self, width: u32, height: u32, aovs: &[Aov], seed: u32) -> Result<(), String> {
+        let accumulator = &self.accumulator;
+        if accumulator.width != width || accumulator.height != height {
+            return Err(format!("Checkpoint is {}x{}, rendering is {}x{}",
+                accumulator.width, accumulator.height, width, height));
+        }
+        if accumulator.aovs.as_slice() != aovs {
+            return Err("Checkpoint was rendered with different AOVs".to_string());
+        }
+        if self.seed != seed {
+            return Err(format!("Checkpoint was rendered with seed {}", self.seed));
+        }
+        Ok(())
+    }
+
+    // Writes to a temporary file first, so a crash while writing keeps the previous checkpoint
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let accumulator = &self.accumulator;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        for v in &[self.seed, self.pass, self.pass_samples, accumulator.width, accumulator.height] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&self.elapsed.as_secs().to_le_bytes());
+        buf.extend_from_slice(&self.elapsed.subsec_nanos().to_le_bytes());
+        buf.extend_from_slice(&(accumulator.aovs.len() as u32).to_le_bytes());
+        for aov in &accumulator.aovs {
+            let name = aov.name();
+            buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            buf.extend_from_slice(name.as_bytes());
+        }
+        for pixel in &accumulator.pixels {
+            write_pixel(&mut buf, pixel);
+        }
+
+        let tmp = path.with_extension("tmp");
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            out.write_all(&buf)?;
+            out.flush()?;
+        }
+        fs::rename(&tmp, path)
+    }
+
+    // Reads the checkpoint of a rendering of the given size
+    pub fn read(path: &Path, expected_width: u32, expected_height: u32) -> io::Result<Checkpoint> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        let mut r = Reader::new(&data);
+        if r.bytes(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a checkpoint file".to_string()));
+        }
+        let seed = r.u32()?;
+        let pass = r.u32()?;
+        let pass_samples = r.u32()?;
+        let width = r.u32()?;
+        let height = r.u32()?;
+        let secs = r.u64()?;
+        let nanos = r.u32()?;
+        // More nanoseconds than a second would overflow the seconds of the duration
+        if nanos >= 1_000_000_000 {
+            return Err(invalid(format!("invalid elapsed time {}s {}ns", secs, nanos)));
+        }
+        let mut aovs = Vec::new();
+        for _ in 0..r.u32()? {
+            let len = r.u32()? as usize;
+            let name = String::from_utf8_lossy(r.bytes(len)?).into_owned();
+            match Aov::parse(&name) {
+                Some(aov) => aovs.push(aov),
+                None => return Err(invalid(format!("unknown AOV {}", name)))
+            }
+        }
+
+        if width != expected_width || height != expected_height {
+            return Err(invalid(format!("checkpoint is {}x{}, rendering is {}x{}",
+                width, height, expected_width, expected_height)));
+        }
+        // Samples, mean, m2, light paths, splat and one sum per pass
+        let pixel_size = 4 + 8 + 8 + 8 + 24 + 24*aovs.len();
+        let size = (width as usize).checked_mul(height as usize).and_then(|n| n.checked_mul(pixel_size));
+        if size.map_or(true, |size| size > data.len() - r.pos) {
+            return Err(invalid("truncated data".to_string()));
+        }
+        let mut accumulator = Accumulator::new(width, height, &aovs);
+        for pixel in &mut accumulator.pixels {
+            read_pixel(&mut r, pixel)?;
+        }
+        let checkpoint = Checkpoint {
+            seed: seed,
+            pass: pass,
+            pass_samples: pass_samples,
+            elapsed: Duration::new(secs, nanos),
+            accumulator: accumulator
+        };
+        Ok(checkpoint)
+    }
+}
+
+pub fn write_pixel(buf: &mut Vec<u8>, pixel: &Pixel) {
+    buf.extend_from_slice(&pixel.samples.to_le_bytes());
+    buf.extend_from_slice(&pixel.mean.to_le_bytes());
+    buf.extend_from_slice(&pixel.m2.to_le_bytes());
+    buf.extend_from_slice(&pixel.light_paths.to_le_bytes());
+    for i in 0..3 {
+        buf.extend_from_slice(&pixel.splat[i].to_le_bytes());
+    }
+    for sum in &pixel.sums {
+        for i in 0..3 {
+            buf.extend_from_slice(&sum[i].to_le_bytes());
+        }
+    }
+}
+
+// Reads into a pixel that already has one sum per pass
+pub fn read_pixel(r: &mut Reader, pixel: &mut Pixel) -> io::Result<()> {
+    pixel.samples = r.u32()?;
+    pixel.mean = r.f64()?;
+    pixel.m2 = r.f64()?;
+    pixel.light_paths = r.u64()?;
+    pixel.splat = Color::new(r.f64()?, r.f64()?, r.f64()?);
+    for sum in &mut pixel.sums {
+        *sum = Color::new(r.f64()?, r.f64()?, r.f64()?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use aov::Aov;
+    use scene::Scene;
+    use window::Window;
+    use testing::{SceneBuilder, ambient_occlusion, grey};
+    use super::*;
+
+    fn scene() -> Scene {
+        SceneBuilder::lit_sphere(ambient_occlusion(0.8)).ground(grey(0.5)).build()
+    }
+
+    #[test]
+    fn resume_matches_uninterrupted() {
+        let dir = env::temp_dir().join(format!("raytracer-checkpoint-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("render.chk");
+        let half = dir.join("half.chk");
+        let aovs = [Aov::Beauty, Aov::Depth, Aov::ObjectId];
+        let scene = scene();
+        let mut window = Window::new(12, 8);
+        window.samples = 8;
+        window.progressive.pass_samples = 2;
+        window.progressive.checkpoint = Some(path.clone());
+        // Keep the checkpoint written after half of the samples
+        let full = window.draw_progressive(&scene, &aovs, |_, progress| {
+            if progress.pass == 2 {
+                fs::copy(&path, &half).unwrap();
+            }
+        });
+
+        let checkpoint = Checkpoint::read(&half, 12, 8).unwrap();
+        assert_eq!(checkpoint.pass_samples, 4);
+        assert!(checkpoint.matches(12, 8, &aovs, 0).is_ok());
+        let resumed = window.resume_progressive(&scene, checkpoint, |_, _| {});
+        fs::remove_dir_all(&dir).unwrap();
+        for (a, b) in full.pixels.iter().zip(&resumed.pixels) {
+            assert_eq!(a.samples, b.samples);
+            assert_eq!(a.mean, b.mean);
+            assert_eq!(a.m2, b.m2);
+            assert_eq!(a.sums, b.sums);
+        }
+    }
+
+    #[test]
+    fn reject_other_sizes_and_truncated_files() {
+        let dir = env::temp_dir().join(format!("raytracer-checkpoint-size-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("render.chk");
+        Checkpoint::new(4, 3, &[Aov::Beauty], 0).write(&path).unwrap();
+        assert!(Checkpoint::read(&path, 4, 3).is_ok());
+        assert!(Checkpoint::read(&path, 3, 4).is_err());
+
+        // A huge size in the header fails before anything is allocated
+        let mut data = fs::read(&path).unwrap();
+        data[20..28].copy_from_slice(&[0xff; 8]);
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        assert!(Checkpoint::read(&path, 4, 3).is_err());
+        assert!(Checkpoint::read(&path, 0xffffffff, 0xffffffff).is_err());
+
+        // Elapsed time of u64::MAX seconds and a whole second in nanoseconds
+        Checkpoint::new(4, 3, &[Aov::Beauty], 0).write(&path).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        data[28..36].copy_from_slice(&[0xff; 8]);
+        data[36..40].copy_from_slice(&1_000_000_000u32.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        assert!(Checkpoint::read(&path, 4, 3).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
@@ -0,0 +1,333 @@
+use std::env;
+use std::f64;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::path::Path;
+use image;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use types::*;
+
+// Chi-square goodness of fit test for sampling routines. Samples are histogrammed on a
+// grid over their domain, the claimed density is integrated over every cell to get the
+// expected counts and the difference is tested at a fixed significance level. Setting
+// CHI2_IMAGES to a directory writes the observed and expected histograms as images.
+// Source: Mitsuba, Warp Test
+
+const SAMPLES: usize = 500000;
+const SIGNIFICANCE: Float = 0.01;
+// Cells with fewer expected samples are pooled
+const MIN_EXPECTED: Float = 5.0;
+
+// Domain of the samples and its parametrization by [0, 1]^2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Domain {
+    Sphere,     // Unit directions, by azimuth and cos theta, which preserves area
+    Plane,      // [-1, 1]^2 in the z = 0 plane
+    Cylinder    // Unit radius, z in [-1, 1]
+}
+
+impl Domain {
+    fn resolution(&self) -> (usize, usize) {
+        match *self {
+            Domain::Plane => (30, 30),
+            _ => (40, 20)
+        }
+    }
+
+    // Area of the domain, the Jacobian of the parametrization
+    fn area(&self) -> Float {
+        match *self {
+            Domain::Plane => 4.0,
+            _ => 4.0*PI
+        }
+    }
+
+    fn to_unit(&self, v: &Vec3) -> Option<(Float, Float)> {
+        match *self {
+            Domain::Sphere => Some((azimuth(v), (v.z + 1.0)*0.5)),
+            Domain::Plane => {
+                if v.z.abs() > 1e-9 {
+                    return None;
+                }
+                Some(((v.x + 1.0)*0.5, (v.y + 1.0)*0.5))
+            },
+            Domain::Cylinder => Some((azimuth(v), (v.z + 1.0)*0.5))
+        }
+    }
+
+    fn from_unit(&self, x: Float, y: Float) -> Vec3 {
+        match *self {
+            Domain::Sphere => {
+                let z = 2.0*y - 1.0;
+                let r = (1.0 - z*z).max(0.0).sqrt();
+                let phi = 2.0*PI*x;
+                Vec3::new(r*phi.cos(), r*phi.sin(), z)
+            },
+            Domain::Plane => Vec3::new(2.0*x - 1.0, 2.0*y - 1.0, 0.0),
+            Domain::Cylinder => {
+                let phi = 2.0*PI*x;
+                Vec3::new(phi.cos(), phi.sin(), 2.0*y - 1.0)
+            }
+        }
+    }
+}
+
+fn azimuth(v: &Vec3) -> Float {
+    let phi = v.y.atan2(v.x);
+    (if phi < 0.0 { phi + 2.0*PI } else { phi })/(2.0*PI)
+}
+
+// Draws samples from sample, which maps a uniform point of [0, 1]^2 into the domain or
+// fails, and compares them against the density pdf. Returns a description of the failure.
+pub fn test<S, P>(name: &str, domain: Domain, mut sample: S, pdf: P) -> Result<(), String>
+    where S: FnMut(Float, Float) -> Option<Vec3>, P: Fn(&Vec3) -> Float
+{
+    let (width, height) = domain.resolution();
+    let cells = width*height;
+    let mut rng = XorShiftRng::from_seed([0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb]);
+
+    let mut observed = vec![0.0; cells];
+    for _ in 0..SAMPLES {
+        let v = match sample(rng.next_f64(), rng.next_f64()) {
+            Some(v) => v,
+            None => continue
+        };
+        if !(v.x.is_finite() && v.y.is_finite() && v.z.is_finite()) {
+            return Err(format!("{}: invalid sample {:?}", name, v));
+        }
+        if !(pdf(&v) > 0.0) {
+            return Err(format!("{}: sample {:?} where the density is zero", name, v));
+        }
+        let (x, y) = match domain.to_unit(&v) {
+            Some(p) => p,
+            None => return Err(format!("{}: sample {:?} outside of the domain", name, v))
+        };
+        let i = ((x*width as Float) as usize).min(width - 1);
+        let j = ((y*height as Float) as usize).min(height - 1);
+        observed[j*width + i] += 1.0;
+    }
+
+    let mut expected = vec![0.0; cells];
+    for j in 0..height {
+        for i in 0..width {
+            let x0 = i as Float/width as Float;
+            let y0 = j as Float/height as Float;
+            let integral = integrate(&|x, y| pdf(&domain.from_unit(x, y)), x0, y0, 1.0/width as Float, 1.0/height as Float);
+            expected[j*width + i] = integral*domain.area()*SAMPLES as Float;
+        }
+    }
+
+    if let Ok(dir) = env::var("CHI2_IMAGES") {
+        let path = Path::new(&dir).join(format!("{}.png", name));
+        if let Err(e) = write_histograms(&path, width, height, &observed, &expected) {
+            return Err(format!("{}: could not write {}: {}", name, path.display(), e));
+        }
+    }
+
+    let total = expected.iter().fold(0.0, |a, b| a + b)/SAMPLES as Float;
+    if (total - 1.0).abs() > 1e-2 {
+        return Err(format!("{}: density integrates to {} instead of 1", name, total));
+    }
+
+    // Pool the cells with low expected counts, starting from the smallest
+    let mut order: Vec<usize> = (0..cells).collect();
+    order.sort_by(|a, b| expected[*a].partial_cmp(&expected[*b]).unwrap());
+    let mut pooled_observed = 0.0;
+    let mut pooled_expected = 0.0;
+    let mut chi2 = 0.0;
+    let mut dof = 0;
+    for &k in &order {
+        if expected[k] < MIN_EXPECTED {
+            pooled_observed += observed[k];
+            pooled_expected += expected[k];
+        } else if pooled_expected > 0.0 && pooled_expected < MIN_EXPECTED {
+            pooled_observed += observed[k];
+            pooled_expected += expected[k];
+        } else {
+            let d = observed[k] - expected[k];
+            chi2 += d*d/expected[k];
+            dof += 1;
+        }
+    }
+    if pooled_expected > 0.0 {
+        let d = pooled_observed - pooled_expected;
+        chi2 += d*d/pooled_expected;
+        dof += 1;
+    }
+    if dof < 2 {
+        return Err(format!("{}: too few cells with samples", name));
+    }
+    let dof = dof - 1;
+    let p = 1.0 - gamma_p(dof as Float*0.5, chi2*0.5);
+    if p < SIGNIFICANCE || !p.is_finite() {
+        return Err(format!("{}: rejected with chi^2 = {:.1} for {} degrees of freedom, p = {:e}", name, chi2, dof, p));
+    }
+    Ok(())
+}
+
+// Integral of f over the cell, refined until two resolutions agree. Cells where the density
+// is zero in parts are integrated at the finest resolution right away, coarse resolutions
+// can agree while both missing the edge of the domain.
+fn integrate<F: Fn(Float, Float) -> Float>(f: &F, x0: Float, y0: Float, w: Float, h: Float) -> Float {
+    let mut n = 8;
+    let (mut previous, partial) = gauss_legendre(f, x0, y0, w, h, n);
+    if partial {
+        return gauss_legendre(f, x0, y0, w, h, 128).0;
+    }
+    while n < 128 {
+        n *= 4;
+        let current = gauss_legendre(f, x0, y0, w, h, n).0;
+        if (current - previous).abs() <= 1e-6*current.abs() + 1e-12 {
+            return current;
+        }
+        previous = current;
+    }
+    previous
+}
+
+// Composite two point Gauss-Legendre rule with n intervals in each dimension. Unlike closed
+// rules it never evaluates f on the cell border, where domains like the hemisphere end.
+// Also tells if f vanishes at some of the nodes but not all.
+fn gauss_legendre<F: Fn(Float, Float) -> Float>(f: &F, x0: Float, y0: Float, w: Float, h: Float, n: usize) -> (Float, bool) {
+    let offset = 0.5/(3.0 as Float).sqrt();
+    let nodes: Vec<Float> = (0..2*n).map(|i| {
+        let center = (i/2) as Float + 0.5;
+        (if i % 2 == 0 { center - offset } else { center + offset })/n as Float
+    }).collect();
+    let mut sum = 0.0;
+    let mut zeros = 0;
+    for v in &nodes {
+        for u in &nodes {
+            let value = f(x0 + w*u, y0 + h*v);
+            if value == 0.0 {
+                zeros += 1;
+            }
+            sum += value;
+        }
+    }
+    let count = nodes.len()*nodes.len();
+    (sum*w*h/count as Float, zeros > 0 && zeros < count)
+}
+
+// Regularized lower incomplete gamma function P(a, x)
+// Source: Numerical Recipes, Chapter Incomplete Gamma Function
+fn gamma_p(a: Float, x: Float) -> Float {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let gln = ln_gamma(a);
+    if x < a + 1.0 {
+        // Series representation
+        let mut ap = a;
+        let mut del = 1.0/a;
+        let mut sum = del;
+        for _ in 0..1000 {
+            ap += 1.0;
+            del *= x/ap;
+            sum += del;
+            if del.abs() < sum.abs()*1e-15 {
+                break;
+            }
+        }
+        sum*(-x + a*x.ln() - gln).exp()
+    } else {
+        // Continued fraction for the complement
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0/tiny;
+        let mut d = 1.0/b;
+        let mut h = d;
+        for i in 1..1000 {
+            let an = -(i as Float)*(i as Float - a);
+            b += 2.0;
+            d = an*d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an/c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0/d;
+            let del = d*c;
+            h *= del;
+            if (del - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+        1.0 - (-x + a*x.ln() - gln).exp()*h
+    }
+}
+
+// Source: Numerical Recipes, Lanczos approximation
+fn ln_gamma(x: Float) -> Float {
+    let coefficients = [
+        57.1562356658629235, -59.5979603554754912, 14.1360979747417471, -0.491913816097620199,
+        0.339946499848118887e-4, 0.465236289270485756e-4, -0.983744753048795646e-4,
+        0.158088703224912494e-3, -0.210264441724104883e-3, 0.217439618115212643e-3,
+        -0.164318106536763890e-3, 0.844182239838527433e-4, -0.261908384015814087e-4,
+        0.368991826595316234e-5
+    ];
+    let mut y = x;
+    let tmp = x + 5.24218750000000000;
+    let tmp = (x + 0.5)*tmp.ln() - tmp;
+    let mut ser = 0.999999999999997092;
+    for c in coefficients.iter() {
+        y += 1.0;
+        ser += c/y;
+    }
+    tmp + (2.5066282746310005*ser/x).ln()
+}
+
+// Observed counts on the left, expected counts on the right
+fn write_histograms(path: &Path, width: usize, height: usize, observed: &[Float], expected: &[Float]) -> image::ImageResult<()> {
+    let scale = 8;
+    let max = observed.iter().chain(expected.iter()).fold(0.0, |a: Float, b| a.max(*b));
+    let buffer = image::ImageBuffer::from_fn((2*width*scale + scale) as u32, (height*scale) as u32, |x, y| {
+        let (x, y) = (x as usize/scale, height - 1 - y as usize/scale);
+        let value = if x < width {
+            observed[y*width + x]
+        } else if x > width {
+            expected[y*width + x - width - 1]
+        } else {
+            return image::Rgb([255, 0, 0]);
+        };
+        let v = if max > 0.0 { (value/max*255.0) as u8 } else { 0 };
+        image::Rgb([v, v, v])
+    });
+    File::create(path).map_err(image::ImageError::from)
+        .and_then(|ref mut f| image::ImageRgb8(buffer).save(f, image::PNG))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use types::*;
+    use warp::*;
+    use super::*;
+
+    #[test]
+    fn rejects_wrong_density() {
+        let result = test("wrong_density", Domain::Sphere,
+            |s, t| Some(warp_point(s, t, WarpFunction::UniformHemisphere)),
+            |v| if v.z >= 0.0 { v.z/PI } else { 0.0 });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_samples_outside_of_the_density() {
+        let result = test("outside", Domain::Sphere,
+            |s, t| Some(warp_point(s, t, WarpFunction::UniformSphere)),
+            |v| if v.z >= 0.0 { 1.0/(2.0*PI) } else { 0.0 });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn incomplete_gamma() {
+        // Chi-square distribution with 2 degrees of freedom, P = 1 - exp(-x/2)
+        for &x in &[0.1, 1.0, 3.0, 10.0] {
+            assert!((gamma_p(1.0, x*0.5) - (1.0 - (-x*0.5).exp())).abs() < 1e-12);
+        }
+        assert!((ln_gamma(5.0) - (24.0 as Float).ln()).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::vec::Vec;
+use nalgebra::{Matrix3, Inverse};
+use types::*;
+use film::Film;
+
+// RGB color spaces of textures, of the rendering and of written images. Colors in the scene
+// file are given in the working space, images are converted to it when they are loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Srgb,       // Rec. 709 primaries with the sRGB transfer function
+    Linear,     // Linear Rec. 709 primaries, what the renderer used to assume everywhere
+    AcesCg,     // ACES AP1 primaries, linear with the ACES white point
+    Rec2020     // Linear Rec. 2020 primaries
+}
+
+impl ColorSpace {
+    pub fn parse(name: &str) -> Option<ColorSpace> {
+        match name {
+            "srgb" => Some(ColorSpace::Srgb),
+            "linear" => Some(ColorSpace::Linear),
+            "acescg" => Some(ColorSpace::AcesCg),
+            "rec2020" => Some(ColorSpace::Rec2020),
+            _ => None
+        }
+    }
+
+    // CIE xy of the red, green and blue primaries and of the white point
+    pub fn chromaticities(&self) -> [[Float; 2]; 4] {
+        match *self {
+            ColorSpace::Srgb | ColorSpace::Linear => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06], [0.3127, 0.3290]],
+            ColorSpace::AcesCg => [[0.713, 0.293], [0.165, 0.830], [0.128, 0.044], [0.32168, 0.33767]],
+            ColorSpace::Rec2020 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046], [0.3127, 0.3290]]
+        }
+    }
+
+    pub fn is_linear(&self) -> bool {
+        *self != ColorSpace::Srgb
+    }
+
+    // Linear RGB to CIE XYZ, the white point maps to Y = 1
+    pub fn to_xyz(&self) -> Matrix3<Float> {
+        let c = self.chromaticities();
+        let xyz = |xy: [Float; 2]| Vec3::new(xy[0]/xy[1], 1.0, (1.0 - xy[0] - xy[1])/xy[1]);
+        let (r, g, b) = (xyz(c[0]), xyz(c[1]), xyz(c[2]));
+        let primaries = Matrix3::new(r.x, g.x, b.x, r.y, g.y, b.y, r.z, g.z, b.z);
+        let s = primaries.inverse().expect("Primaries are linearly independent")*xyz(c[3]);
+        Matrix3::new(r.x*s.x, g.x*s.y, b.x*s.z, r.y*s.x, g.y*s.y, b.y*s.z, r.z*s.x, g.z*s.y, b.z*s.z)
+    }
+
+    // Encoded values to linear ones
+    pub fn decode(&self, c: &Color) -> Color {
+        match *self {
+            ColorSpace::Srgb => Color::new(srgb_to_linear(c.x), srgb_to_linear(c.y), srgb_to_linear(c.z)),
+            _ => *c
+        }
+    }
+
+    pub fn encode(&self, c: &Color) -> Color {
+        match *self {
+            ColorSpace::Srgb => Color::new(linear_to_srgb(c.x), linear_to_srgb(c.y), linear_to_srgb(c.z)),
+            _ => *c
+        }
+    }
+}
+
+fn srgb_to_linear(v: Float) -> Float {
+    if v <= 0.04045 { v/12.92 } else { ((v + 0.055)/1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(v: Float) -> Float {
+    if v <= 0.0031308 { v*12.92 } else { 1.055*v.powf(1.0/2.4) - 0.055 }
+}
+
+// Linear RGB in one space to linear RGB in another, with a Bradford adaptation between
+// differing white points
+pub fn conversion(from: ColorSpace, to: ColorSpace) -> Matrix3<Float> {
+    let bradford = Matrix3::new(0.8951, 0.2664, -0.1614, -0.7502, 1.7135, 0.0367, 0.0389, -0.0685, 1.0296);
+    let inverse = |m: Matrix3<Float>| m.inverse().expect("Color matrices are invertible");
+    let white = |space: ColorSpace| {
+        let w = space.chromaticities()[3];
+        bradford*Vec3::new(w[0]/w[1], 1.0, (1.0 - w[0] - w[1])/w[1])
+    };
+    let (source, target) = (white(from), white(to));
+    let scale = Matrix3::new(target.x/source.x, 0.0, 0.0, 0.0, target.y/source.y, 0.0, 0.0, 0.0, target.z/source.z);
+    inverse(to.to_xyz())*inverse(bradford)*scale*bradford*from.to_xyz()
+}
+
+// Rendered film in the working space as linear values in the output space
+pub fn convert_film(film: &Film, working: ColorSpace, output: ColorSpace) -> Film {
+    let m = conversion(working, output);
+    let mut out = film.clone();
+    for c in &mut out.pixels {
+        *c = m*(*c);
+    }
+    out
+}
+
+// 8 bit PNG in the output space, tagged with its primaries and transfer function. Without an
+// output space the values are linear sRGB and the file is not tagged, as it always was.
+pub fn to_png(film: &Film, working: ColorSpace, output: Option<ColorSpace>) -> io::Result<Vec<u8>> {
+    let space = output.unwrap_or(ColorSpace::Linear);
+    let mut out = convert_film(film, working, space);
+    for c in &mut out.pixels {
+        *c = space.encode(&Color::new(c.x.max(0.0).min(1.0), c.y.max(0.0).min(1.0), c.z.max(0.0).min(1.0)));
+    }
+    let png = out.to_png()?;
+    Ok(match output {
+        Some(space) => tag_png(&png, space),
+        None => png
+    })
+}
+
+pub fn write_png(path: &Path, film: &Film, working: ColorSpace, output: Option<ColorSpace>) -> io::Result<()> {
+    let png = to_png(film, working, output)?;
+    File::create(path)?.write_all(&png)
+}
+
+// Inserts cHRM, gAMA and for sRGB the sRGB chunk after the header chunk, which always has 13
+// bytes of data. Readers honour sRGB over the other two.
+// Source: PNG Specification, Second Edition, Chapter 11.3.3
+pub fn tag_png(png: &[u8], space: ColorSpace) -> Vec<u8> {
+    let header_end = 8 + 8 + 13 + 4;
+    let mut out = png[..header_end].to_vec();
+    let mut chrm = Vec::new();
+    let c = space.chromaticities();
+    for xy in &[c[3], c[0], c[1], c[2]] {
+        for v in xy {
+            chrm.extend_from_slice(&((v*100000.0).round() as u32).to_be_bytes());
+        }
+    }
+    write_chunk(&mut out, b"cHRM", &chrm);
+    // The gamma of the encoding times 100000, 1/2.2 approximates the sRGB curve
+    let gamma: u32 = if space.is_linear() { 100000 } else { 45455 };
+    write_chunk(&mut out, b"gAMA", &gamma.to_be_bytes());
+    if space == ColorSpace::Srgb {
+        write_chunk(&mut out, b"sRGB", &[0]);   // Perceptual rendering intent
+    }
+    out.extend_from_slice(&png[header_end..]);
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use film::Film;
+    use super::*;
+
+    fn close(a: &Color, b: &Color, eps: Float) -> bool {
+        (a.x - b.x).abs() < eps && (a.y - b.y).abs() < eps && (a.z - b.z).abs() < eps
+    }
+
+    #[test]
+    fn matrices() {
+        // Published matrices of linear sRGB and ACEScg to XYZ
+        let m = ColorSpace::Linear.to_xyz();
+        assert!(close(&(m*Color::new(1.0, 0.0, 0.0)), &Color::new(0.4124, 0.2126, 0.0193), 1e-4));
+        assert!(close(&(m*Color::new(0.0, 0.0, 1.0)), &Color::new(0.1805, 0.0722, 0.9505), 1e-4));
+        let m = ColorSpace::AcesCg.to_xyz();
+        assert!(close(&(m*Color::new(1.0, 0.0, 0.0)), &Color::new(0.6624, 0.2722, -0.0056), 1e-4));
+
+        // Whites stay white across white points, conversions are invertible
+        let spaces = [ColorSpace::Linear, ColorSpace::AcesCg, ColorSpace::Rec2020];
+        let c = Color::new(0.3, 0.6, 0.1);
+        for &from in &spaces {
+            for &to in &spaces {
+                let white = conversion(from, to)*Color::new(1.0, 1.0, 1.0);
+                assert!(close(&white, &Color::new(1.0, 1.0, 1.0), 1e-9), "{:?} {:?} {:?}", from, to, white);
+                let back = conversion(to, from)*(conversion(from, to)*c);
+                assert!(close(&back, &c, 1e-9));
+            }
+        }
+        // sRGB red in ACEScg, as listed by the ACES reference configuration
+        let red = conversion(ColorSpace::Linear, ColorSpace::AcesCg)*Color::new(1.0, 0.0, 0.0);
+        assert!(close(&red, &Color::new(0.6131, 0.0702, 0.0206), 1e-3), "{:?}", red);
+    }
+
+    #[test]
+    fn transfer() {
+        for &v in &[0.0, 0.002, 0.04, 0.2, 0.5, 1.0] {
+            let c = Color::new(v, v, v);
+            assert!(close(&ColorSpace::Srgb.decode(&ColorSpace::Srgb.encode(&c)), &c, 1e-12));
+        }
+        assert!((linear_to_srgb(0.5) - 0.7354).abs() < 1e-4);
+        assert_eq!(ColorSpace::AcesCg.encode(&Color::new(0.5, 0.5, 0.5)), Color::new(0.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn png_chunks() {
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+        let film = Film::new(2, 2);
+        let png = to_png(&film, ColorSpace::Linear, Some(ColorSpace::Srgb)).unwrap();
+        let plain = film.to_png().unwrap();
+        assert_eq!(to_png(&film, ColorSpace::Linear, None).unwrap(), plain);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[33 + 4..33 + 8], b"cHRM");
+        assert_eq!(png.len(), plain.len() + (12 + 32) + (12 + 4) + (12 + 1));
+        let tagged = tag_png(&plain, ColorSpace::AcesCg);
+        assert_eq!(tagged.len(), plain.len() + (12 + 32) + (12 + 4));
+        assert_eq!(&tagged[tagged.len() - 12..], &plain[plain.len() - 12..]);
+    }
+}
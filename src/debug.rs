@@ -0,0 +1,250 @@
+use std::f64;
+use nalgebra::Dot;
+use types::*;
+use hit::HitInfo;
+use renderer::Renderer;
+use film::{Film, luminance};
+use aov::false_color;
+use texture::ColorRamp;
+use stats;
+use stats::Counter;
+
+// Debug integrators, rendered as AOVs so they can be selected next to or instead of the
+// beauty pass. Values are kept raw where they are meaningful numbers (depth, ids, traversal
+// cost) and only mapped to colors for display.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugMode {
+    GeometricNormal,
+    ShadingNormal,
+    NormalDifference,   // Angle between geometric and shading normal
+    Uv,
+    Depth,
+    Barycentric,
+    PrimitiveId,
+    InstanceId,
+    BvhCost,            // BVH nodes visited by the camera ray
+    Invalid             // NaN and infinite values of the beauty pass
+}
+
+const MODES: [DebugMode; 10] = [
+    DebugMode::GeometricNormal,
+    DebugMode::ShadingNormal,
+    DebugMode::NormalDifference,
+    DebugMode::Uv,
+    DebugMode::Depth,
+    DebugMode::Barycentric,
+    DebugMode::PrimitiveId,
+    DebugMode::InstanceId,
+    DebugMode::BvhCost,
+    DebugMode::Invalid
+];
+
+impl DebugMode {
+    pub fn parse(name: &str) -> Option<DebugMode> {
+        MODES.iter().find(|mode| mode.name() == name).cloned()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DebugMode::GeometricNormal => "normal_geometric",
+            DebugMode::ShadingNormal => "normal_shading",
+            DebugMode::NormalDifference => "normal_difference",
+            DebugMode::Uv => "uv",
+            DebugMode::Depth => "depth",
+            DebugMode::Barycentric => "barycentric",
+            DebugMode::PrimitiveId => "primitive_id",
+            DebugMode::InstanceId => "instance_id",
+            DebugMode::BvhCost => "bvh_cost",
+            DebugMode::Invalid => "invalid"
+        }
+    }
+
+    pub fn channels(&self) -> &'static [&'static str] {
+        match *self {
+            DebugMode::GeometricNormal | DebugMode::ShadingNormal => &["X", "Y", "Z"],
+            DebugMode::NormalDifference => &["angle"],
+            DebugMode::Uv => &["U", "V"],
+            DebugMode::Depth => &["Z"],
+            DebugMode::PrimitiveId | DebugMode::InstanceId => &["id"],
+            DebugMode::BvhCost => &["cost"],
+            _ => &["R", "G", "B"]
+        }
+    }
+
+    pub fn is_averaged(&self) -> bool {
+        match *self {
+            DebugMode::PrimitiveId | DebugMode::InstanceId | DebugMode::Depth => false,
+            _ => true
+        }
+    }
+
+    // Value for the camera ray, hit is None if it escapes the scene. The cost is the number
+    // of BVH nodes the ray visited.
+    pub fn eval(&self, hit: Option<&HitInfo>, cost: u64, renderer: &Renderer) -> Color {
+        if *self == DebugMode::BvhCost {
+            let c = cost as Float;
+            return Color::new(c, c, c);
+        }
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return match *self {
+                DebugMode::Depth => Color::new(f64::INFINITY, 0.0, 0.0),
+                DebugMode::PrimitiveId | DebugMode::InstanceId => Color::new(-1.0, 0.0, 0.0),
+                _ => Color::new(0.0, 0.0, 0.0)
+            }
+        };
+        match *self {
+            DebugMode::GeometricNormal => hit.ng,
+            DebugMode::ShadingNormal => hit.n,
+            DebugMode::NormalDifference => {
+                let angle = hit.ng.dot(&hit.n).max(-1.0).min(1.0).acos();
+                Color::new(angle, 0.0, 0.0)
+            },
+            DebugMode::Uv => Color::new(hit.uv.x, hit.uv.y, 0.0),
+            DebugMode::Depth => Color::new(hit.d, 0.0, 0.0),
+            DebugMode::Barycentric => {
+                let b = hit.barycentric;
+                Color::new(1.0 - b.x - b.y, b.x, b.y)
+            },
+            DebugMode::PrimitiveId => Color::new(hit.primitive as Float, 0.0, 0.0),
+            DebugMode::InstanceId => Color::new(hit.object as Float, 0.0, 0.0),
+            DebugMode::Invalid => {
+                stats::count(Counter::ShaderCalls);
+                let c = hit.shape.shade(hit, renderer);
+                if c.x.is_nan() || c.y.is_nan() || c.z.is_nan() {
+                    Color::new(1.0, 0.0, 1.0)
+                } else if c.x.is_infinite() || c.y.is_infinite() || c.z.is_infinite() {
+                    Color::new(0.0, 1.0, 1.0)
+                } else {
+                    let l = luminance(&c).max(0.0).min(1.0)*0.25;
+                    Color::new(l, l, l)
+                }
+            },
+            DebugMode::BvhCost => unreachable!()
+        }
+    }
+
+    pub fn to_display(&self, film: &Film) -> Film {
+        let mut out = film.clone();
+        match *self {
+            DebugMode::GeometricNormal | DebugMode::ShadingNormal => {
+                for c in &mut out.pixels {
+                    *c = Color::new(0.5, 0.5, 0.5) + *c*0.5;
+                }
+            },
+            DebugMode::NormalDifference => {
+                let ramp = heat_ramp();
+                for c in &mut out.pixels {
+                    *c = ramp.eval(c.x/(0.5*f64::consts::PI));
+                }
+            },
+            DebugMode::Uv => {
+                for c in &mut out.pixels {
+                    *c = Color::new(c.x - c.x.floor(), c.y - c.y.floor(), 0.0);
+                }
+            },
+            DebugMode::Depth => {
+                let max = film.pixels.iter().map(|c| c.x).filter(|d| d.is_finite()).fold(0.0, Float::max);
+                for c in &mut out.pixels {
+                    let v = if c.x.is_finite() && max > 0.0 { 1.0 - c.x/max } else { 0.0 };
+                    *c = Color::new(v, v, v);
+                }
+            },
+            DebugMode::PrimitiveId | DebugMode::InstanceId => {
+                for c in &mut out.pixels {
+                    *c = false_color(c.x);
+                }
+            },
+            DebugMode::BvhCost => {
+                let max = film.pixels.iter().map(|c| c.x).fold(0.0, Float::max);
+                let ramp = heat_ramp();
+                for c in &mut out.pixels {
+                    *c = ramp.eval(if max > 0.0 { c.x/max } else { 0.0 });
+                }
+            },
+            _ => {}
+        }
+        out
+    }
+}
+
+// Blue over green and yellow to red
+fn heat_ramp() -> ColorRamp {
+    ColorRamp::new(vec![
+        (0.0, Color::new(0.0, 0.0, 0.5)),
+        (0.25, Color::new(0.0, 0.5, 1.0)),
+        (0.5, Color::new(0.0, 1.0, 0.0)),
+        (0.75, Color::new(1.0, 1.0, 0.0)),
+        (1.0, Color::new(1.0, 0.0, 0.0))
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+    use types::*;
+    use aov::Aov;
+    use ray::Ray;
+    use renderer::Renderer;
+    use scene::Scene;
+    use shape::{Triangle, Mesh};
+    use window::Window;
+    use testing::{SceneBuilder, grey};
+    use super::*;
+
+    // A sphere next to a square made of two triangles, whose shading normals are tilted
+    fn scene() -> Scene {
+        let p = |x: Float, y: Float| Pnt3::new(x, y, 0.0);
+        let mut tilted = Triangle::new(p(0.0, 0.0), p(2.0, 0.0), p(2.0, 2.0), grey(0.5));
+        let n = Vec3::new(0.0, 0.6, 0.8);
+        tilted.normals = Some([n, n, n]);
+        let triangles = vec![tilted, Triangle::new(p(0.0, 0.0), p(2.0, 2.0), p(0.0, 2.0), grey(0.5))];
+        SceneBuilder::new()
+            .sphere(Pnt3::new(-3.0, 1.0, 0.0), 1.0, grey(0.5))
+            .shape(Box::new(Mesh::new(triangles, grey(0.5))))
+            .build()
+    }
+
+    fn eval(renderer: &Renderer, x: Float, y: Float, mode: DebugMode) -> Color {
+        let camera = Window::new(1, 1).camera;
+        let mut ray = Ray::new(&Pnt3::new(x, y, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0, f64::INFINITY);
+        renderer.render_aovs(&mut ray, &[Aov::Debug(mode)], &camera, &mut Vec::new(), None)[0]
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for mode in MODES.iter() {
+            assert_eq!(DebugMode::parse(mode.name()), Some(*mode));
+            assert_eq!(Aov::parse(&Aov::Debug(*mode).name()), Some(Aov::Debug(*mode)));
+        }
+        assert_eq!(DebugMode::parse("normals"), None);
+    }
+
+    #[test]
+    fn values_of_hits_and_misses() {
+        let scene = scene();
+        let renderer = Renderer::new(&scene);
+        // Lower right triangle of the square, then its upper left one
+        assert_eq!(eval(&renderer, 1.5, 0.5, DebugMode::InstanceId).x, 1.0);
+        assert_eq!(eval(&renderer, 1.5, 0.5, DebugMode::PrimitiveId).x, 0.0);
+        assert_eq!(eval(&renderer, 0.5, 1.5, DebugMode::PrimitiveId).x, 1.0);
+        assert_eq!(eval(&renderer, -3.0, 1.0, DebugMode::InstanceId).x, 0.0);
+        assert_eq!(eval(&renderer, 1.5, 0.5, DebugMode::Depth).x, 5.0);
+        assert!((eval(&renderer, -3.0, 1.0, DebugMode::Depth).x - 4.0).abs() < 1e-9);
+
+        let b = eval(&renderer, 1.5, 0.5, DebugMode::Barycentric);
+        assert!((b.x + b.y + b.z - 1.0).abs() < 1e-12 && b.x >= 0.0 && b.y >= 0.0 && b.z >= 0.0);
+        assert!((b.x - 0.25).abs() < 1e-12, "{:?}", b);
+
+        let angle = eval(&renderer, 1.5, 0.5, DebugMode::NormalDifference).x;
+        assert!((angle - 0.6f64.asin()).abs() < 1e-9, "{}", angle);
+        assert_eq!(eval(&renderer, 0.5, 1.5, DebugMode::NormalDifference).x, 0.0);
+        assert!(eval(&renderer, 1.5, 0.5, DebugMode::BvhCost).x > 0.0);
+        assert_eq!(eval(&renderer, 1.5, 0.5, DebugMode::Invalid), Color::new(0.0, 0.0, 0.0));
+
+        // Misses
+        assert_eq!(eval(&renderer, 10.0, 0.0, DebugMode::Depth).x, f64::INFINITY);
+        assert_eq!(eval(&renderer, 10.0, 0.0, DebugMode::PrimitiveId).x, -1.0);
+        assert_eq!(eval(&renderer, 10.0, 0.0, DebugMode::GeometricNormal), Color::new(0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,154 @@
+use nalgebra::Norm;
+use scoped_threadpool::Pool;
+use types::*;
+use film::Film;
+
+// Source: Dammertz et al., Edge-Avoiding A-Trous Wavelet Transform for fast Global Illumination Filtering
+
+const KERNEL: [Float; 5] = [1.0/16.0, 1.0/4.0, 3.0/8.0, 1.0/4.0, 1.0/16.0];
+
+// Smallest albedo the beauty image is divided by before filtering
+const ALBEDO_EPSILON: Float = 1e-3;
+
+// Edge avoiding a-trous wavelet filter guided by the albedo and normal feature buffers
+#[derive(Debug, Clone)]
+pub struct Denoiser {
+    pub iterations: u32,
+    pub sigma_color: Float,
+    pub sigma_normal: Float,
+    pub sigma_albedo: Float
+}
+
+impl Denoiser {
+    pub fn new() -> Denoiser {
+        Denoiser {
+            iterations: 5,
+            sigma_color: 1.0,
+            sigma_normal: 0.1,
+            sigma_albedo: 0.1
+        }
+    }
+
+    pub fn denoise(&self, beauty: &Film, albedo: &Film, normal: &Film) -> Film {
+        // Filter the illumination only, so texture detail is kept
+        let mut illumination = beauty.clone();
+        for (c, a) in illumination.pixels.iter_mut().zip(&albedo.pixels) {
+            for i in 0..3 {
+                if a[i] > ALBEDO_EPSILON {
+                    c[i] /= a[i];
+                }
+            }
+        }
+
+        let mut sigma_color = self.sigma_color;
+        for i in 0..self.iterations {
+            illumination = self.filter(&illumination, albedo, normal, 1 << i, sigma_color);
+            sigma_color *= 0.7;
+        }
+
+        for (c, a) in illumination.pixels.iter_mut().zip(&albedo.pixels) {
+            for i in 0..3 {
+                if a[i] > ALBEDO_EPSILON {
+                    c[i] *= a[i];
+                }
+            }
+        }
+        illumination
+    }
+
+    fn filter(&self, input: &Film, albedo: &Film, normal: &Film, step: i64, sigma_color: Float) -> Film {
+        let mut output = Film::new(input.width, input.height);
+        let width = input.width as i64;
+        let height = input.height as i64;
+        let inv_color = 1.0/(sigma_color*sigma_color).max(1e-12);
+        let inv_normal = 1.0/(self.sigma_normal*self.sigma_normal).max(1e-12);
+        let inv_albedo = 1.0/(self.sigma_albedo*self.sigma_albedo).max(1e-12);
+
+        let mut pool = Pool::new(4);
+        pool.scoped(|scope| {
+            for (y, row) in output.pixels.chunks_mut(input.width as usize).enumerate() {
+                let y = y as i64;
+                scope.execute(move || {
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        let x = x as i64;
+                        let p = (y*width + x) as usize;
+                        let c_p = input.pixels[p];
+                        let n_p = normal.pixels[p];
+                        let a_p = albedo.pixels[p];
+                        let mut sum = Color::new(0.0, 0.0, 0.0);
+                        let mut sum_weights = 0.0;
+                        for (j, kj) in KERNEL.iter().enumerate() {
+                            let qy = y + (j as i64 - 2)*step;
+                            if qy < 0 || qy >= height {
+                                continue;
+                            }
+                            for (i, ki) in KERNEL.iter().enumerate() {
+                                let qx = x + (i as i64 - 2)*step;
+                                if qx < 0 || qx >= width {
+                                    continue;
+                                }
+                                let q = (qy*width + qx) as usize;
+                                let c_q = input.pixels[q];
+                                let w_color = (-(c_p - c_q).norm_squared()*inv_color).exp();
+                                let w_normal = (-(n_p - normal.pixels[q]).norm_squared()*inv_normal).exp();
+                                let w_albedo = (-(a_p - albedo.pixels[q]).norm_squared()*inv_albedo).exp();
+                                let w = kj*ki*w_color*w_normal*w_albedo;
+                                sum += c_q*w;
+                                sum_weights += w;
+                            }
+                        }
+                        *pixel = if sum_weights > 0.0 { sum/sum_weights } else { c_p };
+                    }
+                });
+            }
+        });
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use film::Film;
+    use super::*;
+
+    fn constant(c: Color) -> Film {
+        let mut film = Film::new(32, 32);
+        for p in &mut film.pixels {
+            *p = c;
+        }
+        film
+    }
+
+    fn variance(film: &Film) -> Float {
+        let n = film.pixels.len() as Float;
+        let mean = film.pixels.iter().map(|c| c.x).sum::<Float>()/n;
+        film.pixels.iter().map(|c| (c.x - mean)*(c.x - mean)).sum::<Float>()/n
+    }
+
+    #[test]
+    fn constant_image_is_unchanged() {
+        let beauty = constant(Color::new(0.3, 0.5, 0.7));
+        let albedo = constant(Color::new(0.5, 0.5, 0.5));
+        let normal = constant(Color::new(0.0, 0.0, 1.0));
+        let out = Denoiser::new().denoise(&beauty, &albedo, &normal);
+        for (a, b) in out.pixels.iter().zip(&beauty.pixels) {
+            assert!((*a - *b).norm() < 1e-9, "{:?} {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn noise_is_reduced() {
+        let mut beauty = constant(Color::new(0.5, 0.5, 0.5));
+        let mut state = 1u32;
+        for p in &mut beauty.pixels {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            let v = 0.5 + 0.2*((state >> 8) as Float/(1 << 24) as Float - 0.5);
+            *p = Color::new(v, v, v);
+        }
+        let albedo = constant(Color::new(1.0, 1.0, 1.0));
+        let normal = constant(Color::new(0.0, 0.0, 1.0));
+        let out = Denoiser::new().denoise(&beauty, &albedo, &normal);
+        assert!(variance(&out) < 0.1*variance(&beauty), "{} {}", variance(&out), variance(&beauty));
+    }
+}
@@ -0,0 +1,697 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use scoped_threadpool::Pool;
+use types::*;
+use aov::Aov;
+use binary::{Reader, invalid};
+use checkpoint::{read_pixel, write_pixel};
+use film::{Accumulator, SplatBuffer};
+use renderer::Renderer;
+use sampling::AdaptiveSampler;
+use scene::Scene;
+use window::Window;
+
+// A coordinator splits a frame into tiles and sample ranges and hands them to workers over
+// TCP. Workers load the scene file themselves and send back the accumulated pixels, which
+// the coordinator merges into its film, along with the splats their light paths left
+// anywhere in the image.
+//
+// Every message is a little endian u32 length followed by a tag byte and the payload.
+
+const JOB: u8 = 1;
+const TILE: u8 = 2;
+const DONE: u8 = 3;
+const RESULT: u8 = 4;
+const ERROR: u8 = 5;
+
+// Everything a worker needs to render pixels identical to the coordinator's
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub scene: String,
+    pub width: u32,
+    pub height: u32,
+    pub seed: u32,
+    pub samples: u32,
+    pub adaptive: Option<AdaptiveSampler>,
+    pub aovs: Vec<Aov>
+}
+
+impl Job {
+    pub fn window(&self, scene: &Scene) -> Window {
+        let mut window = Window::new(self.width, self.height);
+        if let Some(ref view) = scene.view {
+            view.apply(&mut window.camera);
+        }
+        window.seed = self.seed;
+        window.samples = self.samples;
+        window.adaptive = self.adaptive.clone();
+        window
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![JOB];
+        write_string(&mut buf, &self.scene);
+        for v in &[self.width, self.height, self.seed, self.samples] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        match self.adaptive {
+            Some(ref adaptive) => {
+                buf.push(1);
+                buf.extend_from_slice(&adaptive.min_samples.to_le_bytes());
+                buf.extend_from_slice(&adaptive.max_samples.to_le_bytes());
+                buf.extend_from_slice(&adaptive.threshold.to_le_bytes());
+            },
+            None => buf.push(0)
+        }
+        buf.extend_from_slice(&(self.aovs.len() as u32).to_le_bytes());
+        for aov in &self.aovs {
+            write_string(&mut buf, &aov.name());
+        }
+        buf
+    }
+
+    fn decode(r: &mut Reader) -> io::Result<Job> {
+        let scene = read_string(r)?;
+        let width = r.u32()?;
+        let height = r.u32()?;
+        let seed = r.u32()?;
+        let samples = r.u32()?;
+        let adaptive = match r.bytes(1)?[0] {
+            0 => None,
+            _ => Some(AdaptiveSampler {
+                min_samples: r.u32()?,
+                max_samples: r.u32()?,
+                threshold: r.f64()?
+            })
+        };
+        let mut aovs = Vec::new();
+        for _ in 0..r.u32()? {
+            let name = read_string(r)?;
+            aovs.push(Aov::parse(&name).ok_or(invalid(format!("unknown AOV {}", name)))?);
+        }
+        let job = Job {
+            scene: scene,
+            width: width,
+            height: height,
+            seed: seed,
+            samples: samples,
+            adaptive: adaptive,
+            aovs: aovs
+        };
+        Ok(job)
+    }
+}
+
+// Pixels [x0, x0 + width) x [y0, y0 + height) with samples [first_sample, first_sample + samples)
+#[derive(Debug, Clone, Copy)]
+pub struct Tile {
+    pub id: u32,
+    pub x0: u32,
+    pub y0: u32,
+    pub width: u32,
+    pub height: u32,
+    pub first_sample: u32,
+    pub samples: u32
+}
+
+impl Tile {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![TILE];
+        for v in &[self.id, self.x0, self.y0, self.width, self.height, self.first_sample, self.samples] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf
+    }
+
+    fn decode(r: &mut Reader) -> io::Result<Tile> {
+        let tile = Tile {
+            id: r.u32()?,
+            x0: r.u32()?,
+            y0: r.u32()?,
+            width: r.u32()?,
+            height: r.u32()?,
+            first_sample: r.u32()?,
+            samples: r.u32()?
+        };
+        Ok(tile)
+    }
+}
+
+// Splits the frame into square tiles, each rendered in ranges of at most tile_samples samples.
+// Adaptive sampling needs all samples of a pixel in one place, so it always uses a single range.
+pub fn tiles(job: &Job, tile_size: u32, tile_samples: Option<u32>) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let total = match job.adaptive {
+        Some(ref adaptive) => adaptive.max_samples.max(1),
+        None => job.samples.max(1)
+    };
+    let range = match (tile_samples, &job.adaptive) {
+        (Some(n), &None) => n.max(1).min(total),
+        _ => total
+    };
+    let mut tiles = Vec::new();
+    let mut first_sample = 0;
+    while first_sample < total {
+        let samples = range.min(total - first_sample);
+        for y0 in (0..job.height).step_by(tile_size as usize) {
+            for x0 in (0..job.width).step_by(tile_size as usize) {
+                tiles.push(Tile {
+                    id: tiles.len() as u32,
+                    x0: x0,
+                    y0: y0,
+                    width: tile_size.min(job.width - x0),
+                    height: tile_size.min(job.height - y0),
+                    first_sample: first_sample,
+                    samples: samples
+                });
+            }
+        }
+        first_sample += samples;
+    }
+    tiles
+}
+
+// Tiles waiting for a worker, and the tiles handed out with the worker they were handed to
+// and the time they were handed out at. A stalled tile may be in flight at two workers.
+struct Queue {
+    pending: VecDeque<Tile>,
+    in_flight: Vec<(usize, Tile, Instant)>,
+    workers: usize,
+    next_worker: usize,
+    finished: bool
+}
+
+impl Queue {
+    fn hand_out(&mut self, worker: usize, now: Instant) -> Option<Tile> {
+        let tile = self.pending.pop_front();
+        if let Some(tile) = tile {
+            self.in_flight.push((worker, tile, now));
+        }
+        tile
+    }
+
+    // Takes the tile off the worker and returns how long it had it
+    fn finish(&mut self, worker: usize, tile: &Tile, now: Instant) -> Option<Duration> {
+        let i = self.in_flight.iter().position(|t| t.0 == worker && t.1.id == tile.id);
+        i.map(|i| now.duration_since(self.in_flight.swap_remove(i).2))
+    }
+
+    // Puts the tile of a failed worker back, unless another worker has it as well
+    fn fail(&mut self, worker: usize, tile: Tile) {
+        self.in_flight.retain(|t| !(t.0 == worker && t.1.id == tile.id));
+        if !self.in_flight.iter().any(|t| t.1.id == tile.id) && !self.pending.iter().any(|t| t.id == tile.id) {
+            self.pending.push_back(tile);
+        }
+    }
+
+    // Puts tiles that were not merged within the stall time back, the stalled workers keep
+    // rendering them but are no longer waited for
+    fn requeue_stalled(&mut self, now: Instant, stall: Duration, merged: &[bool]) {
+        let mut stalled = Vec::new();
+        self.in_flight.retain(|&(_, tile, handed_out)| {
+            let stale = now.duration_since(handed_out) > stall && !merged[tile.id as usize];
+            if stale {
+                stalled.push(tile);
+            }
+            !stale
+        });
+        for tile in stalled {
+            if !self.in_flight.iter().any(|t| t.1.id == tile.id) && !self.pending.iter().any(|t| t.id == tile.id) {
+                self.pending.push_back(tile);
+            }
+        }
+    }
+}
+
+// Sums of the splats of the light paths of a tile, by pixel index in the image
+struct TileSplats {
+    light_paths: u64,
+    pixels: Vec<(usize, Color)>
+}
+
+enum Message {
+    Result(usize, Tile, Accumulator, TileSplats),
+    Failed(String, io::Error)
+}
+
+// How often the coordinator checks for stalled tiles
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+// A tile is handed to another worker once it takes this much longer than the slowest tile so far
+const STALL_FACTOR: u32 = 4;
+const MIN_STALL: Duration = Duration::from_secs(30);
+// The coordinator gives up once it had no worker for this long
+const WORKER_TIMEOUT: Duration = Duration::from_secs(300);
+// Results of large tiles stay far below this
+const MAX_FRAME: usize = 1 << 28;
+
+// Hands out tiles to the workers connecting to the listener until every tile has been merged
+// into the returned accumulator. Progress and worker failures are passed to log.
+pub fn coordinate<F>(listener: TcpListener, job: Job, tile_size: u32, tile_samples: Option<u32>, mut log: F) -> Result<Accumulator, String>
+    where F: FnMut(&str)
+{
+    let tiles = tiles(&job, tile_size, tile_samples);
+    let total = tiles.len();
+    let queue = Arc::new(Mutex::new(Queue {
+        pending: tiles.into_iter().collect(),
+        in_flight: Vec::new(),
+        workers: 0,
+        next_worker: 0,
+        finished: false
+    }));
+    let (messages, received) = channel();
+    if let Ok(address) = listener.local_addr() {
+        log(&format!("Coordinator listening on {}, {} tiles", address, total));
+    }
+
+    {
+        let queue = queue.clone();
+        let job = job.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let queue = queue.clone();
+                    let job = job.clone();
+                    let messages = messages.clone();
+                    thread::spawn(move || serve(stream, &job, &queue, &messages));
+                }
+            }
+        });
+    }
+
+    let start = Instant::now();
+    let mut accumulator = Accumulator::new(job.width, job.height, &job.aovs);
+    let mut splats = SplatBuffer::new(job.width, job.height);
+    let mut merged = vec![false; total];
+    let mut done = 0;
+    let mut slowest = Duration::from_secs(0);
+    let mut last_worker = start;
+    while done < total {
+        match received.recv_timeout(WATCHDOG_INTERVAL) {
+            Ok(Message::Result(worker, tile, pixels, tile_splats)) => {
+                let mut queue = queue.lock().unwrap();
+                if let Some(duration) = queue.finish(worker, &tile, Instant::now()) {
+                    slowest = slowest.max(duration);
+                }
+                // A stalled tile may be rendered twice
+                if merged[tile.id as usize] {
+                    continue;
+                }
+                merged[tile.id as usize] = true;
+                queue.pending.retain(|t| t.id != tile.id);
+                drop(queue);
+                accumulator.merge_tile(tile.x0, tile.y0, &pixels, tile.first_sample == 0);
+                for &(i, c) in &tile_splats.pixels {
+                    splats.pixels[i] += c;
+                }
+                splats.light_paths += tile_splats.light_paths;
+                done += 1;
+                let elapsed = start.elapsed();
+                let seconds = elapsed.as_secs() as Float + elapsed.subsec_nanos() as Float*1e-9;
+                log(&format!("Tile {}/{}, {:.1}s", done, total, seconds));
+            },
+            Ok(Message::Failed(peer, e)) => log(&format!("Error: Worker {} failed: {}", peer, e)),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return Err("Listener stopped".to_string())
+        }
+
+        // Watchdog for workers that stopped answering without closing the connection
+        let now = Instant::now();
+        let mut queue = queue.lock().unwrap();
+        queue.requeue_stalled(now, (slowest*STALL_FACTOR).max(MIN_STALL), &merged);
+        if queue.workers > 0 {
+            last_worker = now;
+        } else if now.duration_since(last_worker) > WORKER_TIMEOUT {
+            queue.finished = true;
+            return Err(format!("No worker for {}s, {} of {} tiles done", WORKER_TIMEOUT.as_secs(), done, total));
+        }
+    }
+    queue.lock().unwrap().finished = true;
+    accumulator.add_splats(&splats);
+    Ok(accumulator)
+}
+
+// Feeds tiles to one worker, tiles of a failed worker go back into the queue
+fn serve(mut stream: TcpStream, job: &Job, queue: &Mutex<Queue>, messages: &Sender<Message>) {
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or("worker".to_string());
+    if write_frame(&mut stream, &job.encode()).is_err() {
+        return;
+    }
+    let worker = {
+        let mut queue = queue.lock().unwrap();
+        queue.workers += 1;
+        queue.next_worker += 1;
+        queue.next_worker
+    };
+    loop {
+        let tile = loop {
+            {
+                let mut queue = queue.lock().unwrap();
+                if let Some(tile) = queue.hand_out(worker, Instant::now()) {
+                    break Some(tile);
+                }
+                if queue.finished {
+                    break None;
+                }
+            }
+            // Tiles of other workers may still come back
+            thread::sleep(Duration::from_millis(20));
+        };
+        let tile = match tile {
+            Some(tile) => tile,
+            None => {
+                let _ = write_frame(&mut stream, &[DONE]);
+                break;
+            }
+        };
+        match render_remote(&mut stream, job, &tile) {
+            Ok((pixels, splats)) => {
+                if messages.send(Message::Result(worker, tile, pixels, splats)).is_err() {
+                    break;
+                }
+            },
+            Err(e) => {
+                queue.lock().unwrap().fail(worker, tile);
+                let _ = messages.send(Message::Failed(peer, e));
+                break;
+            }
+        }
+    }
+    queue.lock().unwrap().workers -= 1;
+}
+
+fn render_remote(stream: &mut TcpStream, job: &Job, tile: &Tile) -> io::Result<(Accumulator, TileSplats)> {
+    write_frame(stream, &tile.encode())?;
+    let frame = read_frame(stream)?;
+    let mut r = Reader::new(&frame);
+    match r.bytes(1)?[0] {
+        RESULT => {
+            if r.u32()? != tile.id {
+                return Err(invalid("result for wrong tile".to_string()));
+            }
+            let mut pixels = Accumulator::new(tile.width, tile.height, &job.aovs);
+            for pixel in &mut pixels.pixels {
+                read_pixel(&mut r, pixel)?;
+            }
+            let light_paths = r.u64()?;
+            let count = r.u32()? as usize;
+            let size = (job.width*job.height) as usize;
+            if count > size {
+                return Err(invalid(format!("{} splat pixels", count)));
+            }
+            let mut splats = Vec::with_capacity(count);
+            for _ in 0..count {
+                let i = r.u32()? as usize;
+                if i >= size {
+                    return Err(invalid(format!("splat at pixel {}", i)));
+                }
+                splats.push((i, Color::new(r.f64()?, r.f64()?, r.f64()?)));
+            }
+            Ok((pixels, TileSplats { light_paths: light_paths, pixels: splats }))
+        },
+        ERROR => Err(invalid(read_string(&mut r)?)),
+        tag => Err(invalid(format!("unexpected message {}", tag)))
+    }
+}
+
+// Renders tiles for the coordinator at the address until it has no more work
+pub fn work<F>(address: &str, mut log: F) -> Result<(), String>
+    where F: FnMut(&str)
+{
+    // The coordinator may not be up yet
+    let mut attempts = 0;
+    let mut stream = loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => break stream,
+            Err(e) => {
+                attempts += 1;
+                if attempts >= 50 {
+                    return Err(format!("Could not connect to {}: {}", address, e));
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+        }
+    };
+    let _ = stream.set_nodelay(true);
+    let error = |e: io::Error| format!("Connection to {} failed: {}", address, e);
+
+    let frame = read_frame(&mut stream).map_err(&error)?;
+    let mut r = Reader::new(&frame);
+    if r.bytes(1).map_err(&error)?[0] != JOB {
+        return Err(format!("Unexpected message from {}", address));
+    }
+    let job = Job::decode(&mut r).map_err(&error)?;
+    let scene = match Scene::load(Path::new(&job.scene)) {
+        Ok(scene) => scene,
+        Err(e) => {
+            let mut buf = vec![ERROR];
+            write_string(&mut buf, &e);
+            let _ = write_frame(&mut stream, &buf);
+            return Err(e);
+        }
+    };
+    let window = job.window(&scene);
+    let renderer = Renderer::new(&scene);
+    let mut pool = Pool::new(4);
+    let mut splats = SplatBuffer::new(job.width, job.height);
+    log(&format!("Worker rendering {}", job.scene));
+
+    loop {
+        let frame = read_frame(&mut stream).map_err(&error)?;
+        let mut r = Reader::new(&frame);
+        match r.bytes(1).map_err(&error)?[0] {
+            TILE => {
+                let tile = Tile::decode(&mut r).map_err(&error)?;
+                let mut pixels = Accumulator::new(tile.width, tile.height, &job.aovs);
+                window.render_tile(&mut pool, &renderer, &mut pixels, &mut splats, tile.x0, tile.y0, tile.first_sample, tile.samples);
+                let mut buf = vec![RESULT];
+                buf.extend_from_slice(&tile.id.to_le_bytes());
+                for pixel in &pixels.pixels {
+                    write_pixel(&mut buf, pixel);
+                }
+                // Only the pixels the light paths reached
+                let zero = Color::new(0.0, 0.0, 0.0);
+                buf.extend_from_slice(&splats.light_paths.to_le_bytes());
+                let count = splats.pixels.iter().filter(|c| **c != zero).count();
+                buf.extend_from_slice(&(count as u32).to_le_bytes());
+                for (i, c) in splats.pixels.iter().enumerate().filter(|&(_, c)| *c != zero) {
+                    buf.extend_from_slice(&(i as u32).to_le_bytes());
+                    for v in &[c.x, c.y, c.z] {
+                        buf.extend_from_slice(&v.to_le_bytes());
+                    }
+                }
+                splats.clear();
+                write_frame(&mut stream, &buf).map_err(&error)?;
+            },
+            DONE => return Ok(()),
+            tag => return Err(format!("Unexpected message {} from {}", tag, address))
+        }
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_le_bytes())?;
+    stream.write_all(payload)?;
+    stream.flush()
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(invalid(format!("message of {} bytes", len)));
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    if payload.is_empty() {
+        return Err(invalid("empty message".to_string()));
+    }
+    Ok(payload)
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_string(r: &mut Reader) -> io::Result<String> {
+    let len = r.u32()? as usize;
+    Ok(String::from_utf8_lossy(r.bytes(len)?).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use film::Accumulator;
+    use renderer::Renderer;
+    use scene::Scene;
+    use super::*;
+
+    const SCENE: &'static str = "camera position 0 -10 3 front 0 1 -0.3 up 0 0 -1 fov 45
+shader grey diffuse color 0.74 0.74 0.74
+shader dark ao samples 4 distance 2 color 0.5 0.5 0.5
+sphere position -1.5 0 0 radius 1 shader grey
+sphere position 1.5 0 0.5 radius 1 shader dark
+sphere position 0 0 -10000001 radius 10000000 shader grey
+light point position 1.5 -1 5 intensity 40 40 40
+";
+
+    fn job(name: &str) -> Job {
+        job_with_scene(name, SCENE)
+    }
+
+    fn job_with_scene(name: &str, scene: &str) -> Job {
+        let dir = env::temp_dir().join(format!("raytracer-{}-{}", name, ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.scene");
+        fs::write(&path, scene).unwrap();
+        Job {
+            scene: path.to_string_lossy().into_owned(),
+            width: 24,
+            height: 16,
+            seed: 3,
+            samples: 4,
+            adaptive: None,
+            aovs: vec![Aov::Beauty, Aov::Depth, Aov::ObjectId]
+        }
+    }
+
+    fn local(job: &Job) -> Accumulator {
+        let scene = Scene::load(Path::new(&job.scene)).unwrap();
+        let window = job.window(&scene);
+        let mut accumulator = Accumulator::new(job.width, job.height, &job.aovs);
+        window.render_pass(&Renderer::new(&scene), &mut accumulator, job.samples);
+        accumulator
+    }
+
+    fn assert_close(a: &Accumulator, b: &Accumulator) {
+        for (p, q) in a.pixels.iter().zip(&b.pixels) {
+            assert_eq!(p.samples, q.samples);
+            assert_eq!(p.light_paths, q.light_paths);
+            for i in 0..3 {
+                assert!((p.splat[i] - q.splat[i]).abs() <= 1e-9*p.splat[i].abs().max(1.0), "{:?} {:?}", p.splat, q.splat);
+            }
+            for (s, t) in p.sums.iter().zip(&q.sums) {
+                for i in 0..3 {
+                    assert!(s[i] == t[i] || (s[i] - t[i]).abs() <= 1e-12*s[i].abs().max(1.0), "{:?} {:?}", s, t);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn two_workers_match_local_rendering() {
+        let job = job("two-workers");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let workers: Vec<_> = (0..2).map(|_| {
+            let address = address.clone();
+            thread::spawn(move || work(&address, |_| {}))
+        }).collect();
+        let distributed = coordinate(listener, job.clone(), 8, Some(2), |_| {}).unwrap();
+        for worker in workers {
+            assert!(worker.join().unwrap().is_ok());
+        }
+        assert_close(&distributed, &local(&job));
+        fs::remove_dir_all(Path::new(&job.scene).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn splats_reach_pixels_of_other_tiles() {
+        let job = job_with_scene("splats", &format!("integrator bdpt depth 3\n{}", SCENE));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let worker = thread::spawn(move || work(&address, |_| {}));
+        let distributed = coordinate(listener, job.clone(), 8, Some(2), |_| {}).unwrap();
+        assert!(worker.join().unwrap().is_ok());
+        let local = local(&job);
+        assert!(local.pixels.iter().any(|p| p.splat.x > 0.0));
+        assert_close(&distributed, &local);
+        fs::remove_dir_all(Path::new(&job.scene).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tiles_of_a_dead_worker_are_rendered_again() {
+        let job = job("dead-worker");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let worker = thread::spawn(move || {
+            // Takes a tile and disconnects without answering
+            {
+                let mut stream = TcpStream::connect(&address).unwrap();
+                assert_eq!(read_frame(&mut stream).unwrap()[0], JOB);
+                assert_eq!(read_frame(&mut stream).unwrap()[0], TILE);
+            }
+            work(&address, |_| {})
+        });
+        let mut failures = 0;
+        let distributed = coordinate(listener, job.clone(), 8, None, |line| {
+            if line.starts_with("Error") {
+                failures += 1;
+            }
+        }).unwrap();
+        assert!(worker.join().unwrap().is_ok());
+        assert_eq!(failures, 1);
+        assert_close(&distributed, &local(&job));
+        fs::remove_dir_all(Path::new(&job.scene).parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn stalled_tiles_stay_watched() {
+        let tile = Tile { id: 0, x0: 0, y0: 0, width: 8, height: 8, first_sample: 0, samples: 1 };
+        let mut queue = Queue {
+            pending: vec![tile].into_iter().collect(),
+            in_flight: Vec::new(),
+            workers: 0,
+            next_worker: 0,
+            finished: false
+        };
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let stall = Duration::from_secs(30);
+        assert_eq!(queue.hand_out(1, at(0)).unwrap().id, 0);
+        // Worker 1 stalls and the tile goes to worker 2, which still has it once worker 1 fails
+        queue.requeue_stalled(at(31), stall, &[false]);
+        assert_eq!(queue.hand_out(2, at(32)).unwrap().id, 0);
+        queue.fail(1, tile);
+        assert!(queue.pending.is_empty());
+        assert_eq!(queue.in_flight.len(), 1);
+        // Worker 2 stalls as well
+        queue.requeue_stalled(at(40), stall, &[false]);
+        assert!(queue.pending.is_empty());
+        queue.requeue_stalled(at(63), stall, &[false]);
+        assert_eq!(queue.pending.len(), 1);
+        assert!(queue.in_flight.is_empty());
+        queue.fail(2, tile);
+        assert_eq!(queue.pending.len(), 1);
+
+        assert!(queue.hand_out(3, at(64)).is_some());
+        assert_eq!(queue.finish(1, &tile, at(65)), None);
+        assert_eq!(queue.finish(3, &tile, at(66)), Some(Duration::from_secs(2)));
+        assert!(queue.in_flight.is_empty() && queue.hand_out(4, at(67)).is_none());
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let sender = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(&u32::max_value().to_le_bytes()).unwrap();
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+        assert!(read_frame(&mut stream).is_err());
+        sender.join().unwrap();
+    }
+}
@@ -0,0 +1,215 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::vec::Vec;
+
+// Minimal OpenEXR writer: single part, scanline, uncompressed, 32 bit float channels
+// Source: OpenEXR File Layout, https://www.openexr.com/documentation/openexrfilelayout.pdf
+
+const MAGIC: u32 = 20000630;
+const VERSION: u32 = 2;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+pub struct Channel {
+    pub name: String,
+    pub values: Vec<f32>   // Row major, width*height values
+}
+
+fn write_attribute(buf: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(kind.as_bytes());
+    buf.push(0);
+    buf.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn box2i(width: u32, height: u32) -> Vec<u8> {
+    let mut v = Vec::new();
+    for c in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        v.extend_from_slice(&c.to_le_bytes());
+    }
+    v
+}
+
+// Chromaticities are the CIE xy of the red, green and blue primaries and of the white point
+pub fn write(path: &Path, width: u32, height: u32, mut channels: Vec<Channel>, chromaticities: &[[f32; 2]; 4]) -> io::Result<()> {
+    // Channels have to be stored in alphabetical order
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    for c in &channels {
+        if c.values.len() != (width*height) as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("channel {} has {} values, expected {}", c.name, c.values.len(), width*height)));
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&VERSION.to_le_bytes());
+
+    let mut chlist = Vec::new();
+    for c in &channels {
+        chlist.extend_from_slice(c.name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        chlist.extend_from_slice(&[0, 0, 0, 0]);    // pLinear and reserved
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+    write_attribute(&mut header, "channels", "chlist", &chlist);
+    let mut xy = Vec::new();
+    for v in chromaticities.iter().flat_map(|c| c.iter()) {
+        xy.extend_from_slice(&v.to_le_bytes());
+    }
+    write_attribute(&mut header, "chromaticities", "chromaticities", &xy);
+    write_attribute(&mut header, "compression", "compression", &[0]);
+    write_attribute(&mut header, "dataWindow", "box2i", &box2i(width, height));
+    write_attribute(&mut header, "displayWindow", "box2i", &box2i(width, height));
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    write_attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    let mut center = Vec::new();
+    center.extend_from_slice(&0.0f32.to_le_bytes());
+    center.extend_from_slice(&0.0f32.to_le_bytes());
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &center);
+    write_attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    header.push(0);
+
+    let line_size = (channels.len()*width as usize*4) as u64;
+    let block_size = 8 + line_size;
+    let table_start = header.len() as u64;
+    let data_start = table_start + 8*height as u64;
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&header)?;
+    for y in 0..height as u64 {
+        out.write_all(&(data_start + y*block_size).to_le_bytes())?;
+    }
+    for y in 0..height {
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&(line_size as i32).to_le_bytes())?;
+        let row = (y*width) as usize;
+        for c in &channels {
+            for v in &c.values[row..row + width as usize] {
+                out.write_all(&v.to_le_bytes())?;
+            }
+        }
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::Path;
+    use binary::Reader;
+    use super::*;
+
+    // Header attributes, channel names and pixel data of a file written by write
+    pub struct Image {
+        pub attributes: Vec<(String, String, Vec<u8>)>,
+        pub channels: Vec<String>,
+        pub width: usize,
+        pub height: usize,
+        pub lines: Vec<Vec<f32>>    // Values of all channels of every scanline block
+    }
+
+    fn string(r: &mut Reader) -> io::Result<String> {
+        let mut s = Vec::new();
+        loop {
+            match r.bytes(1)?[0] {
+                0 => return Ok(String::from_utf8(s).unwrap()),
+                c => s.push(c)
+            }
+        }
+    }
+
+    pub fn read(path: &Path) -> io::Result<Image> {
+        let data = fs::read(path)?;
+        let mut r = Reader::new(&data);
+        assert_eq!(r.u32()?, MAGIC);
+        assert_eq!(r.u32()?, VERSION);
+        let mut attributes = Vec::new();
+        loop {
+            let name = string(&mut r)?;
+            if name.is_empty() {
+                break;
+            }
+            let kind = string(&mut r)?;
+            let size = r.u32()? as usize;
+            attributes.push((name, kind, r.bytes(size)?.to_vec()));
+        }
+
+        let value = |name: &str| attributes.iter().find(|a| a.0 == name).unwrap().2.clone();
+        let mut channels = Vec::new();
+        let chlist = value("channels");
+        let mut c = Reader::new(&chlist);
+        loop {
+            let name = string(&mut c)?;
+            if name.is_empty() {
+                break;
+            }
+            assert_eq!(c.u32()?, PIXEL_TYPE_FLOAT as u32);
+            c.bytes(4)?;
+            assert_eq!((c.u32()?, c.u32()?), (1, 1));
+            channels.push(name);
+        }
+        let window = value("dataWindow");
+        let mut w = Reader::new(&window);
+        let (x0, y0, x1, y1) = (w.u32()?, w.u32()?, w.u32()?, w.u32()?);
+        assert_eq!((x0, y0), (0, 0));
+        let (width, height) = ((x1 + 1) as usize, (y1 + 1) as usize);
+
+        // Every offset points at the block of its scanline
+        let offsets: Vec<u64> = (0..height).map(|_| r.u64()).collect::<io::Result<_>>()?;
+        let mut lines = Vec::new();
+        for (y, offset) in offsets.iter().enumerate() {
+            let mut b = Reader::new(&data);
+            b.pos = *offset as usize;
+            assert_eq!(b.u32()?, y as u32);
+            let size = b.u32()? as usize;
+            assert_eq!(size, channels.len()*width*4);
+            lines.push((0..size/4).map(|_| b.u32().map(f32::from_bits)).collect::<io::Result<_>>()?);
+            if y + 1 == height {
+                assert_eq!(b.pos, data.len());
+            }
+        }
+        Ok(Image { attributes: attributes, channels: channels, width: width, height: height, lines: lines })
+    }
+
+    #[test]
+    fn header_and_scanlines() {
+        let dir = env::temp_dir().join(format!("raytracer-exr-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("layers.exr");
+        let (width, height) = (3, 2);
+        let channel = |name: &str, base: f32| Channel {
+            name: name.to_string(),
+            values: (0..width*height).map(|i| base + i as f32).collect()
+        };
+        let channels = vec![channel("R", 0.0), channel("depth.Z", 100.0), channel("G", 10.0), channel("B", 20.0)];
+        let chromaticities = [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06], [0.3127, 0.3290]];
+        write(&path, width, height, channels, &chromaticities).unwrap();
+        let image = read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<&str> = image.attributes.iter().map(|a| a.0.as_str()).collect();
+        assert_eq!(names, ["channels", "chromaticities", "compression", "dataWindow", "displayWindow",
+                           "lineOrder", "pixelAspectRatio", "screenWindowCenter", "screenWindowWidth"]);
+        assert_eq!(image.channels, ["B", "G", "R", "depth.Z"]);
+        assert_eq!((image.width, image.height), (3, 2));
+        // Scanlines hold the rows of the channels one after the other
+        assert_eq!(image.lines[1], [23.0, 24.0, 25.0, 13.0, 14.0, 15.0, 3.0, 4.0, 5.0, 103.0, 104.0, 105.0]);
+    }
+
+    #[test]
+    fn reject_channels_of_other_sizes() {
+        let path = env::temp_dir().join("raytracer-exr-never-written.exr");
+        let channels = vec![Channel { name: "R".to_string(), values: vec![0.0; 5] }];
+        assert!(write(&path, 3, 2, channels, &[[0.0; 2]; 4]).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,350 @@
+use image;
+use image::{ImageBuffer, Rgb};
+use std::io;
+use std::vec::Vec;
+use std::f64;
+use types::*;
+use aov::Aov;
+use texture::ColorRamp;
+
+// Floating point image, row major starting at the top left
+#[derive(Debug, Clone)]
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film {
+            width: width,
+            height: height,
+            pixels: vec![Color::new(0.0, 0.0, 0.0); (width*height) as usize]
+        }
+    }
+    pub fn get(&self, x: u32, y: u32) -> Color {
+        self.pixels[(y*self.width + x) as usize]
+    }
+    pub fn to_image(&self) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let mut imgbuf = ImageBuffer::new(self.width, self.height);
+        for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
+            let color = self.get(x, y)*255.0;
+            *pixel = Rgb([color.x as u8, color.y as u8, color.z as u8]);
+        }
+        imgbuf
+    }
+    pub fn to_png(&self) -> io::Result<Vec<u8>> {
+        let mut png = Vec::new();
+        image::png::PNGEncoder::new(&mut png)
+            .encode(&self.to_image().into_raw(), self.width, self.height, image::ColorType::RGB(8))?;
+        Ok(png)
+    }
+}
+
+pub fn luminance(c: &Color) -> Float {
+    0.2126*c.x + 0.7152*c.y + 0.0722*c.z
+}
+
+// Contribution of a light path to the pixel it reaches
+#[derive(Debug, Clone)]
+pub struct Splat {
+    pub x: u32,
+    pub y: u32,
+    pub color: Color
+}
+
+// Splats of light paths over the whole image and the number of light paths they came from.
+// Tiles splat into one buffer for the image, which is added to the accumulator of the image.
+#[derive(Debug, Clone)]
+pub struct SplatBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>,
+    pub light_paths: u64
+}
+
+impl SplatBuffer {
+    pub fn new(width: u32, height: u32) -> SplatBuffer {
+        SplatBuffer {
+            width: width,
+            height: height,
+            pixels: vec![Color::new(0.0, 0.0, 0.0); (width*height) as usize],
+            light_paths: 0
+        }
+    }
+
+    pub fn add(&mut self, splat: &Splat) {
+        if splat.x < self.width && splat.y < self.height {
+            self.pixels[(splat.y*self.width + splat.x) as usize] += splat.color;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for c in &mut self.pixels {
+            *c = Color::new(0.0, 0.0, 0.0);
+        }
+        self.light_paths = 0;
+    }
+}
+
+// Running sums of all passes of a pixel, with mean and variance of the luminance of the first pass.
+// Light paths may splat into any pixel of the image, their sum is divided by the number of
+// light paths traced for the whole image.
+#[derive(Debug, Clone)]
+pub struct Pixel {
+    pub samples: u32,
+    pub sums: Vec<Color>,
+    pub mean: Float,
+    pub m2: Float,
+    pub splat: Color,
+    pub light_paths: u64
+}
+
+impl Pixel {
+    pub fn new(passes: usize) -> Pixel {
+        Pixel {
+            samples: 0,
+            sums: vec![Color::new(0.0, 0.0, 0.0); passes],
+            mean: 0.0,
+            m2: 0.0,
+            splat: Color::new(0.0, 0.0, 0.0),
+            light_paths: 0
+        }
+    }
+
+    pub fn add(&mut self, aovs: &[Aov], values: &[Color]) {
+        for ((sum, aov), value) in self.sums.iter_mut().zip(aovs).zip(values) {
+            if aov.is_nearest() {
+                if self.samples == 0 || value.x < sum.x {
+                    *sum = *value;
+                }
+            } else if aov.is_averaged() || self.samples == 0 {
+                *sum += *value;
+            }
+        }
+        self.samples += 1;
+        if let Some(value) = values.first() {
+            // Welford's online algorithm
+            let l = luminance(value);
+            let delta = l - self.mean;
+            self.mean += delta/self.samples as Float;
+            self.m2 += delta*(l - self.mean);
+        }
+    }
+
+    // Adds the samples of another range of the same pixel. The pixel whose range starts with
+    // sample zero provides the values of the passes that are not averaged.
+    pub fn merge(&mut self, aovs: &[Aov], other: &Pixel, other_first: bool) {
+        for ((sum, aov), value) in self.sums.iter_mut().zip(aovs).zip(&other.sums) {
+            if aov.is_nearest() {
+                if other.samples > 0 && (self.samples == 0 || value.x < sum.x) {
+                    *sum = *value;
+                }
+            } else if aov.is_averaged() {
+                *sum += *value;
+            } else if other_first {
+                *sum = *value;
+            }
+        }
+        // Chan et al., parallel variance
+        let n = self.samples + other.samples;
+        if n > 0 {
+            let delta = other.mean - self.mean;
+            let (na, nb) = (self.samples as Float, other.samples as Float);
+            self.mean += delta*nb/n as Float;
+            self.m2 += other.m2 + delta*delta*na*nb/n as Float;
+        }
+        self.samples = n;
+        self.splat += other.splat;
+        self.light_paths += other.light_paths;
+    }
+
+    pub fn variance(&self) -> Float {
+        if self.samples < 2 {
+            return 0.0;
+        }
+        self.m2/(self.samples - 1) as Float
+    }
+
+    // Standard error of the mean relative to the mean
+    pub fn relative_error(&self) -> Float {
+        if self.samples < 2 {
+            return f64::INFINITY;
+        }
+        let error = (self.variance()/self.samples as Float).sqrt();
+        error/self.mean.abs().max(1e-3)
+    }
+
+    pub fn value(&self, aovs: &[Aov], i: usize) -> Color {
+        let splat = if aovs[i] == Aov::Beauty && self.light_paths > 0 {
+            self.splat/self.light_paths as Float
+        } else {
+            Color::new(0.0, 0.0, 0.0)
+        };
+        if self.samples == 0 || !aovs[i].is_averaged() {
+            return self.sums[i] + splat;
+        }
+        self.sums[i]/self.samples as Float + splat
+    }
+}
+
+// Per pixel accumulation of all passes of a rendering
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    pub width: u32,
+    pub height: u32,
+    pub aovs: Vec<Aov>,
+    pub pixels: Vec<Pixel>
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32, aovs: &[Aov]) -> Accumulator {
+        Accumulator {
+            width: width,
+            height: height,
+            aovs: aovs.to_vec(),
+            pixels: vec![Pixel::new(aovs.len()); (width*height) as usize]
+        }
+    }
+
+    pub fn films(&self) -> Vec<Film> {
+        (0..self.aovs.len()).map(|i| {
+            let mut film = Film::new(self.width, self.height);
+            for (c, pixel) in film.pixels.iter_mut().zip(&self.pixels) {
+                *c = pixel.value(&self.aovs, i);
+            }
+            film
+        }).collect()
+    }
+
+    // Adds the splats of light paths traced for any part of the image, the light paths count
+    // for every pixel
+    pub fn add_splats(&mut self, splats: &SplatBuffer) {
+        for (pixel, splat) in self.pixels.iter_mut().zip(&splats.pixels) {
+            pixel.splat += *splat;
+            pixel.light_paths += splats.light_paths;
+        }
+    }
+
+    // Merges the pixels of a tile whose top left pixel is (x0, y0)
+    pub fn merge_tile(&mut self, x0: u32, y0: u32, tile: &Accumulator, first: bool) {
+        for y in 0..tile.height {
+            for x in 0..tile.width {
+                let pixel = &tile.pixels[(y*tile.width + x) as usize];
+                let i = ((y0 + y)*self.width + x0 + x) as usize;
+                self.pixels[i].merge(&self.aovs, pixel, first);
+            }
+        }
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.pixels.iter().map(|p| p.samples as u64).sum()
+    }
+
+    // Mean relative error over the pixels with at least two samples, infinite if there are none
+    pub fn relative_error(&self) -> Float {
+        if self.pixels.is_empty() {
+            return 0.0;
+        }
+        let errors: Vec<Float> = self.pixels.iter().filter(|p| p.samples >= 2).map(|p| p.relative_error()).collect();
+        if errors.is_empty() {
+            return f64::INFINITY;
+        }
+        errors.iter().sum::<Float>()/errors.len() as Float
+    }
+
+    // Number of samples spent per pixel, mapped from blue (fewest) to red (most)
+    pub fn heatmap(&self) -> Film {
+        let max = self.pixels.iter().map(|p| p.samples).max().unwrap_or(0).max(1);
+        let ramp = ColorRamp::new(vec![
+            (0.0, Color::new(0.0, 0.0, 1.0)),
+            (0.5, Color::new(0.0, 1.0, 0.0)),
+            (1.0, Color::new(1.0, 0.0, 0.0))
+        ]);
+        let mut film = Film::new(self.width, self.height);
+        for (c, pixel) in film.pixels.iter_mut().zip(&self.pixels) {
+            *c = ramp.eval(pixel.samples as Float/max as Float);
+        }
+        film
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use aov::Aov;
+    use sampling::AdaptiveSampler;
+    use super::*;
+
+    const AOVS: [Aov; 3] = [Aov::Beauty, Aov::Depth, Aov::ObjectId];
+
+    fn sample(l: Float, depth: Float, id: Float) -> [Color; 3] {
+        [Color::new(l, l, l), Color::new(depth, 0.0, 0.0), Color::new(id, 0.0, 0.0)]
+    }
+
+    #[test]
+    fn depth_keeps_the_nearest_hit() {
+        let mut pixel = Pixel::new(AOVS.len());
+        pixel.add(&AOVS, &sample(1.0, f64::INFINITY, -1.0));
+        pixel.add(&AOVS, &sample(1.0, 4.0, 2.0));
+        pixel.add(&AOVS, &sample(1.0, 3.0, 1.0));
+        assert_eq!(pixel.value(&AOVS, 1).x, 3.0);
+        // Ids come from the first sample
+        assert_eq!(pixel.value(&AOVS, 2).x, -1.0);
+
+        let mut other = Pixel::new(AOVS.len());
+        other.add(&AOVS, &sample(1.0, 2.0, 5.0));
+        let mut merged = Pixel::new(AOVS.len());
+        merged.merge(&AOVS, &other, false);
+        merged.merge(&AOVS, &pixel, true);
+        assert_eq!(merged.value(&AOVS, 1).x, 2.0);
+        assert_eq!(merged.value(&AOVS, 2).x, -1.0);
+    }
+
+    #[test]
+    fn merged_variance_matches_sequential() {
+        let values = [0.1, 0.7, 0.3, 0.9, 0.4, 0.2];
+        let mut all = Pixel::new(AOVS.len());
+        let mut a = Pixel::new(AOVS.len());
+        let mut b = Pixel::new(AOVS.len());
+        for (i, v) in values.iter().enumerate() {
+            all.add(&AOVS, &sample(*v, 1.0, 0.0));
+            if i < 2 { &mut a } else { &mut b }.add(&AOVS, &sample(*v, 1.0, 0.0));
+        }
+        a.merge(&AOVS, &b, false);
+        assert_eq!(a.samples, all.samples);
+        assert!((a.mean - all.mean).abs() < 1e-12);
+        assert!((a.variance() - all.variance()).abs() < 1e-12);
+        assert!((a.value(&AOVS, 0).x - all.value(&AOVS, 0).x).abs() < 1e-12);
+    }
+
+    #[test]
+    fn adaptive_sampler_stops_at_threshold() {
+        let sampler = AdaptiveSampler { min_samples: 4, max_samples: 64, threshold: 0.01 };
+        let mut constant = Pixel::new(AOVS.len());
+        let mut noisy = Pixel::new(AOVS.len());
+        for i in 0..4 {
+            assert!(!sampler.done(&constant));
+            constant.add(&AOVS, &sample(0.5, 1.0, 0.0));
+            noisy.add(&AOVS, &sample((i % 2) as Float, 1.0, 0.0));
+        }
+        assert!(sampler.done(&constant));
+        assert!(!sampler.done(&noisy));
+        while noisy.samples < 64 {
+            noisy.add(&AOVS, &sample((noisy.samples % 2) as Float, 1.0, 0.0));
+        }
+        assert!(sampler.done(&noisy));
+    }
+
+    #[test]
+    fn relative_error_skips_single_samples() {
+        let mut accumulator = Accumulator::new(2, 1, &AOVS);
+        assert_eq!(accumulator.relative_error(), f64::INFINITY);
+        for v in &[0.4, 0.6] {
+            accumulator.pixels[0].add(&AOVS, &sample(*v, 1.0, 0.0));
+        }
+        accumulator.pixels[1].add(&AOVS, &sample(0.5, 1.0, 0.0));
+        assert_eq!(accumulator.relative_error(), accumulator.pixels[0].relative_error());
+    }
+}
@@ -0,0 +1,36 @@
+use std::f64;
+use types::*;
+
+// Floating point error bounds.
+// Source: Physically based Rendering, Chapter Shapes, Section Managing Rounding Error
+
+// Bound on the relative rounding error of a single operation
+pub const MACHINE_EPSILON: Float = f64::EPSILON*0.5;
+
+// Bound on the relative error accumulated by n operations
+pub fn gamma(n: u32) -> Float {
+    let n = n as Float;
+    (n*MACHINE_EPSILON)/(1.0 - n*MACHINE_EPSILON)
+}
+
+// Smallest representable value greater than v
+pub fn next_float_up(v: Float) -> Float {
+    if v.is_infinite() && v > 0.0 {
+        return v;
+    }
+    let v = if v == -0.0 { 0.0 } else { v };
+    let bits = v.to_bits();
+    let bits = if v >= 0.0 { bits + 1 } else { bits - 1 };
+    Float::from_bits(bits)
+}
+
+// Largest representable value less than v
+pub fn next_float_down(v: Float) -> Float {
+    if v.is_infinite() && v < 0.0 {
+        return v;
+    }
+    let v = if v == 0.0 { -0.0 } else { v };
+    let bits = v.to_bits();
+    let bits = if v > 0.0 { bits - 1 } else { bits + 1 };
+    Float::from_bits(bits)
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::vec::Vec;
+use types::*;
+use binary::{Reader, invalid};
+
+// Scalar voxel grids. Voxel values sit at integer index coordinates and are interpolated
+// trilinearly in between, voxels that are not stored are zero.
+
+pub trait Grid: Sync {
+    fn voxel(&self, i: i32, j: i32, k: i32) -> Float;
+    // Smallest and largest index of the stored voxels, None if the grid is empty
+    fn index_bounds(&self) -> Option<([i32; 3], [i32; 3])>;
+    // Calls f with the index and value of every stored voxel
+    fn for_each(&self, f: &mut FnMut([i32; 3], Float));
+}
+
+// Trilinear interpolation at a point in index space
+pub fn lookup(grid: &Grid, p: &Pnt3) -> Float {
+    let (x, y, z) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (fx, fy, fz) = (p.x - x, p.y - y, p.z - z);
+    let (i, j, k) = (x as i32, y as i32, z as i32);
+    let lerp = |a: Float, b: Float, t: Float| a + (b - a)*t;
+    let c00 = lerp(grid.voxel(i, j, k), grid.voxel(i + 1, j, k), fx);
+    let c10 = lerp(grid.voxel(i, j + 1, k), grid.voxel(i + 1, j + 1, k), fx);
+    let c01 = lerp(grid.voxel(i, j, k + 1), grid.voxel(i + 1, j, k + 1), fx);
+    let c11 = lerp(grid.voxel(i, j + 1, k + 1), grid.voxel(i + 1, j + 1, k + 1), fx);
+    lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+}
+
+// All voxels of a box starting at index zero, x varies fastest
+pub struct DenseGrid {
+    pub resolution: [usize; 3],
+    pub values: Vec<f32>
+}
+
+impl Grid for DenseGrid {
+    fn voxel(&self, i: i32, j: i32, k: i32) -> Float {
+        let r = &self.resolution;
+        if i < 0 || j < 0 || k < 0 || i as usize >= r[0] || j as usize >= r[1] || k as usize >= r[2] {
+            return 0.0;
+        }
+        self.values[(k as usize*r[1] + j as usize)*r[0] + i as usize] as Float
+    }
+    fn index_bounds(&self) -> Option<([i32; 3], [i32; 3])> {
+        let r = &self.resolution;
+        if r[0] == 0 || r[1] == 0 || r[2] == 0 {
+            return None;
+        }
+        Some(([0, 0, 0], [r[0] as i32 - 1, r[1] as i32 - 1, r[2] as i32 - 1]))
+    }
+    fn for_each(&self, f: &mut FnMut([i32; 3], Float)) {
+        let r = &self.resolution;
+        for k in 0..r[2] {
+            for j in 0..r[1] {
+                for i in 0..r[0] {
+                    f([i as i32, j as i32, k as i32], self.values[(k*r[1] + j)*r[0] + i] as Float);
+                }
+            }
+        }
+    }
+}
+
+// Log2 of the brick edge length
+const BRICK_LOG2: i32 = 3;
+pub const BRICK_SIZE: usize = 1 << BRICK_LOG2;
+const BRICK_VOXELS: usize = BRICK_SIZE*BRICK_SIZE*BRICK_SIZE;
+// Log2 of the edge length of constant tiles
+const TILE_LOG2: i32 = 7;
+pub const TILE_SIZE: usize = 1 << TILE_LOG2;
+
+// Only the bricks of 8x8x8 voxels that contain values are stored. Large regions of one
+// value are stored as tiles of 128x128x128 voxels instead of as thousands of bricks.
+pub struct SparseGrid {
+    bricks: HashMap<(i32, i32, i32), usize>,    // Brick coordinates to offset in values
+    values: Vec<f32>,
+    tiles: HashMap<(i32, i32, i32), f32>,       // Tile coordinates to their value
+    bounds: Option<([i32; 3], [i32; 3])>
+}
+
+impl SparseGrid {
+    pub fn new() -> SparseGrid {
+        SparseGrid {
+            bricks: HashMap::new(),
+            values: Vec::new(),
+            tiles: HashMap::new(),
+            bounds: None
+        }
+    }
+
+    // Stores the values of the brick whose first voxel is at origin, x varies fastest
+    pub fn insert_brick(&mut self, origin: [i32; 3], values: &[f32]) {
+        let key = (origin[0] >> BRICK_LOG2, origin[1] >> BRICK_LOG2, origin[2] >> BRICK_LOG2);
+        let offset = match self.bricks.get(&key) {
+            Some(&offset) => offset,
+            None => {
+                let offset = self.values.len();
+                self.values.resize(offset + BRICK_VOXELS, 0.0);
+                self.bricks.insert(key, offset);
+                offset
+            }
+        };
+        self.values[offset..offset + BRICK_VOXELS].copy_from_slice(&values[..BRICK_VOXELS]);
+        self.extend([key.0 << BRICK_LOG2, key.1 << BRICK_LOG2, key.2 << BRICK_LOG2], BRICK_SIZE as i32);
+    }
+
+    // Sets all voxels of the tile whose first voxel is at origin to one value, tiles must
+    // not overlap bricks
+    pub fn insert_tile(&mut self, origin: [i32; 3], value: f32) {
+        let key = (origin[0] >> TILE_LOG2, origin[1] >> TILE_LOG2, origin[2] >> TILE_LOG2);
+        self.tiles.insert(key, value);
+        self.extend([key.0 << TILE_LOG2, key.1 << TILE_LOG2, key.2 << TILE_LOG2], TILE_SIZE as i32);
+    }
+
+    fn extend(&mut self, lo: [i32; 3], size: i32) {
+        let hi = [lo[0] + size - 1, lo[1] + size - 1, lo[2] + size - 1];
+        self.bounds = Some(match self.bounds {
+            None => (lo, hi),
+            Some((min, max)) => (
+                [min[0].min(lo[0]), min[1].min(lo[1]), min[2].min(lo[2])],
+                [max[0].max(hi[0]), max[1].max(hi[1]), max[2].max(hi[2])]
+            )
+        });
+    }
+}
+
+impl Grid for SparseGrid {
+    fn voxel(&self, i: i32, j: i32, k: i32) -> Float {
+        let key = (i >> BRICK_LOG2, j >> BRICK_LOG2, k >> BRICK_LOG2);
+        match self.bricks.get(&key) {
+            None => {
+                let key = (i >> TILE_LOG2, j >> TILE_LOG2, k >> TILE_LOG2);
+                self.tiles.get(&key).map_or(0.0, |&v| v as Float)
+            },
+            Some(&offset) => {
+                let m = BRICK_SIZE as i32 - 1;
+                let index = (((k & m) << BRICK_LOG2 | (j & m)) << BRICK_LOG2 | (i & m)) as usize;
+                self.values[offset + index] as Float
+            }
+        }
+    }
+    fn index_bounds(&self) -> Option<([i32; 3], [i32; 3])> {
+        self.bounds
+    }
+    fn for_each(&self, f: &mut FnMut([i32; 3], Float)) {
+        for (key, &offset) in &self.bricks {
+            let origin = [key.0 << BRICK_LOG2, key.1 << BRICK_LOG2, key.2 << BRICK_LOG2];
+            for (n, v) in self.values[offset..offset + BRICK_VOXELS].iter().enumerate() {
+                let (i, j, k) = (n % BRICK_SIZE, (n/BRICK_SIZE) % BRICK_SIZE, n/(BRICK_SIZE*BRICK_SIZE));
+                f([origin[0] + i as i32, origin[1] + j as i32, origin[2] + k as i32], *v as Float);
+            }
+        }
+        for (key, &value) in &self.tiles {
+            let origin = [key.0 << TILE_LOG2, key.1 << TILE_LOG2, key.2 << TILE_LOG2];
+            for k in 0..TILE_SIZE as i32 {
+                for j in 0..TILE_SIZE as i32 {
+                    for i in 0..TILE_SIZE as i32 {
+                        f([origin[0] + i, origin[1] + j, origin[2] + k], value as Float);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Simple binary grid file, all numbers are little endian:
+//
+//   magic "VGRID001"
+//   u32 kind, 0 for dense and 1 for sparse
+//   dense:  u32 nx, ny, nz followed by nx*ny*nz f32 values, x varies fastest
+//   sparse: u32 brick count, then per brick i32 x, y, z of its first voxel followed by
+//           8*8*8 f32 values
+const MAGIC: &'static [u8; 8] = b"VGRID001";
+
+pub fn load(path: &Path) -> io::Result<Box<Grid>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    read(&data)
+}
+
+pub fn read(data: &[u8]) -> io::Result<Box<Grid>> {
+    let mut r = Reader::new(data);
+    if r.bytes(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a grid file".to_string()));
+    }
+    match r.u32()? {
+        0 => {
+            let resolution = [r.u32()? as usize, r.u32()? as usize, r.u32()? as usize];
+            // The values have to be in the file before anything is allocated for them
+            let count = resolution[0].checked_mul(resolution[1]).and_then(|n| n.checked_mul(resolution[2]));
+            let count = match count {
+                Some(count) if count.checked_mul(4).map_or(false, |size| size <= data.len() - r.pos) => count,
+                _ => return Err(invalid("truncated data".to_string()))
+            };
+            let mut values = Vec::with_capacity(count);
+            for _ in 0..count {
+                values.push(r.f32()?);
+            }
+            Ok(Box::new(DenseGrid { resolution: resolution, values: values }))
+        },
+        1 => {
+            let mut grid = SparseGrid::new();
+            let mut values = vec![0.0; BRICK_VOXELS];
+            for _ in 0..r.u32()? {
+                let origin = [r.i32()?, r.i32()?, r.i32()?];
+                for v in &mut values {
+                    *v = r.f32()?;
+                }
+                grid.insert_brick(origin, &values);
+            }
+            Ok(Box::new(grid))
+        },
+        kind => Err(invalid(format!("unknown grid kind {}", kind)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use super::*;
+
+    fn value(i: usize, j: usize, k: usize) -> f32 {
+        (i + 10*j + 100*k) as f32
+    }
+
+    fn dense_file(resolution: [usize; 3]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        for n in &resolution {
+            buf.extend_from_slice(&(*n as u32).to_le_bytes());
+        }
+        for k in 0..resolution[2] {
+            for j in 0..resolution[1] {
+                for i in 0..resolution[0] {
+                    buf.extend_from_slice(&value(i, j, k).to_le_bytes());
+                }
+            }
+        }
+        buf
+    }
+
+    // The same voxels as the dense file, split into bricks
+    fn sparse_file(resolution: [usize; 3]) -> Vec<u8> {
+        let bricks: Vec<usize> = resolution.iter().map(|n| (n + BRICK_SIZE - 1)/BRICK_SIZE).collect();
+        let mut buf = Vec::new();
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&((bricks[0]*bricks[1]*bricks[2]) as u32).to_le_bytes());
+        for bz in 0..bricks[2] {
+            for by in 0..bricks[1] {
+                for bx in 0..bricks[0] {
+                    let origin = [bx*BRICK_SIZE, by*BRICK_SIZE, bz*BRICK_SIZE];
+                    for c in &origin {
+                        buf.extend_from_slice(&(*c as i32).to_le_bytes());
+                    }
+                    for k in origin[2]..origin[2] + BRICK_SIZE {
+                        for j in origin[1]..origin[1] + BRICK_SIZE {
+                            for i in origin[0]..origin[0] + BRICK_SIZE {
+                                let inside = i < resolution[0] && j < resolution[1] && k < resolution[2];
+                                let v = if inside { value(i, j, k) } else { 0.0 };
+                                buf.extend_from_slice(&v.to_le_bytes());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn dense_and_sparse_files_agree() {
+        let resolution = [10, 9, 12];
+        let dense = read(&dense_file(resolution)).unwrap();
+        let sparse = read(&sparse_file(resolution)).unwrap();
+        assert_eq!(dense.index_bounds(), Some(([0, 0, 0], [9, 8, 11])));
+        assert_eq!(sparse.index_bounds(), Some(([0, 0, 0], [15, 15, 15])));
+        for k in -1..13 {
+            for j in -1..10 {
+                for i in -1..11 {
+                    assert_eq!(dense.voxel(i, j, k), sparse.voxel(i, j, k));
+                }
+            }
+        }
+        assert_eq!(dense.voxel(3, 2, 1), 123.0);
+        let mut sum = 0.0;
+        sparse.for_each(&mut |_, v| sum += v);
+        let mut expected = 0.0;
+        dense.for_each(&mut |_, v| expected += v);
+        assert_eq!(sum, expected);
+    }
+
+    #[test]
+    fn trilinear_lookup() {
+        let grid = read(&dense_file([4, 4, 4])).unwrap();
+        assert_eq!(lookup(&*grid, &Pnt3::new(1.0, 2.0, 3.0)), 321.0);
+        // The values are linear in the index, so interpolation reproduces them
+        assert!((lookup(&*grid, &Pnt3::new(1.25, 2.5, 0.75)) - (1.25 + 25.0 + 75.0)).abs() < 1e-9);
+        // Halfway to the missing voxels outside
+        assert!((lookup(&*grid, &Pnt3::new(-0.5, 0.0, 0.0)) - 0.0).abs() < 1e-9);
+        assert!((lookup(&*grid, &Pnt3::new(3.5, 0.0, 0.0)) - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let file = dense_file([4, 4, 4]);
+        assert!(read(b"VGRID002").is_err());
+        assert!(read(&file[..100]).is_err());
+        assert!(read(&file[..file.len() - 1]).is_err());
+        // Resolutions whose voxel count overflows
+        let mut huge = dense_file([1, 1, 1]);
+        for n in 0..3 {
+            huge[12 + n*4..16 + n*4].copy_from_slice(&u32::max_value().to_le_bytes());
+        }
+        assert!(read(&huge).is_err());
+        // and counts that do not overflow but are larger than the file
+        huge[12..24].copy_from_slice(&[0, 0, 16, 0, 0, 0, 16, 0, 1, 0, 0, 0]);
+        assert!(read(&huge).is_err());
+    }
+}
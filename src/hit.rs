@@ -1,15 +1,43 @@
 use nalgebra::{Vector3, Vector4, Matrix4, Norm, Cross, Dot, Inverse, Eye};
-use ray::Ray;
+use ray::{Ray, RayDifferential, offset_ray_origin};
 use types::*;
+use spectrum::Wavelengths;
 use shape::Shape;
+use std::f64;
 
+// Shadow rays stop this fraction short of their target
+const SHADOW_EPSILON: Float = 1e-4;
+
+#[derive(Clone)]
 pub struct HitInfo<'a> {
     pub shape: &'a Shape,
+    pub object: usize,  // Index of the shape in the scene
+    pub primitive: usize,   // Index of the triangle within a mesh
+    pub barycentric: Vec2,  // Barycentric coordinates of the second and third triangle vertex
     pub d: Float,     // Hit distance
     pub i: Vec3,    // Incident vector
     pub p: Pnt3,    // Hit point
+    pub p_error: Vec3,  // Absolute error bound of the hit point
     pub o: Pnt3,    // Hit origin
-    pub n: Vec3     // Normal vector
+    pub n: Vec3,    // Shading normal vector
+    pub ng: Vec3,   // Geometric normal vector
+    pub inside: bool,   // Hit from the inside of a closed shape, the normals are flipped
+    pub uv: Vec2,   // Surface coordinates
+    pub lp: Pnt3,   // Hit point in object space
+    pub dpdu: Vec3, // Surface tangent along u
+    pub dpdv: Vec3, // Surface tangent along v
+    pub dndu: Vec3, // Normal derivative along u
+    pub dndv: Vec3, // Normal derivative along v
+    pub dpdx: Vec3, // Hit point derivative along screen x
+    pub dpdy: Vec3, // Hit point derivative along screen y
+    pub dudx: Float,
+    pub dvdx: Float,
+    pub dudy: Float,
+    pub dvdy: Float,
+    pub rd: Option<RayDifferential>, // Differentials of the incoming ray
+    pub depth: u32, // Number of bounces of the incoming ray
+    pub medium: Option<usize>,  // Medium of the incoming ray
+    pub wavelengths: Option<Wavelengths>    // Wavelengths of the incoming ray
 }
 
 impl<'a>  HitInfo<'a> {
@@ -19,17 +47,205 @@ impl<'a>  HitInfo<'a> {
         incident: Vec3,
         hit_point: Pnt3,
         hit_origin: Pnt3,
-        normal: Vec3
+        normal: Vec3,
+        uv: Vec2,
+        local_point: Pnt3,
+        dpdu: Vec3,
+        dpdv: Vec3
         ) -> HitInfo
     {
         let hit = HitInfo {
             shape: shape,
+            object: 0,
+            primitive: 0,
+            barycentric: Vec2::new(0.0, 0.0),
             d: distance,
             i: incident,
             p: hit_point,
+            p_error: Vec3::new(0.0, 0.0, 0.0),
             o: hit_origin,
-            n: normal
+            n: normal,
+            ng: normal,
+            inside: false,
+            uv: uv,
+            lp: local_point,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            dndu: Vec3::new(0.0, 0.0, 0.0),
+            dndv: Vec3::new(0.0, 0.0, 0.0),
+            dpdx: Vec3::new(0.0, 0.0, 0.0),
+            dpdy: Vec3::new(0.0, 0.0, 0.0),
+            dudx: 0.0,
+            dvdx: 0.0,
+            dudy: 0.0,
+            dvdy: 0.0,
+            rd: None,
+            depth: 0,
+            medium: None,
+            wavelengths: None
         };
         hit
     }
+
+    // Source: Physically based Rendering, Chapter Texture, Section Sampling and Antialiasing
+    pub fn compute_differentials(&mut self, ray: &Ray) {
+        self.depth = ray.depth;
+        self.rd = ray.differential;
+        let rd = match ray.differential {
+            Some(rd) => rd,
+            None => return
+        };
+        let n = self.ng;
+        let d = n.dot(&self.p.to_vector());
+        let tx = -(n.dot(&rd.rx_origin.to_vector()) - d) / n.dot(&rd.rx_dir);
+        let ty = -(n.dot(&rd.ry_origin.to_vector()) - d) / n.dot(&rd.ry_dir);
+        if !tx.is_finite() || !ty.is_finite() {
+            return;
+        }
+        let px = rd.rx_origin + rd.rx_dir*tx;
+        let py = rd.ry_origin + rd.ry_dir*ty;
+        self.dpdx = px - self.p;
+        self.dpdy = py - self.p;
+        let (dudx, dvdx) = solve_tangent_plane(&self.dpdu, &self.dpdv, &self.dpdx);
+        let (dudy, dvdy) = solve_tangent_plane(&self.dpdu, &self.dpdv, &self.dpdy);
+        self.dudx = dudx;
+        self.dvdx = dvdx;
+        self.dudy = dudy;
+        self.dvdy = dvdy;
+    }
+
+    // Orthonormal tangent frame around the shading normal
+    pub fn shading_frame(&self) -> (Vec3, Vec3) {
+        let mut t = self.dpdu - self.n*self.n.dot(&self.dpdu);
+        if t.norm_squared() < 1e-16 {
+            t = if self.n.x.abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+            t = t - self.n*self.n.dot(&t);
+        }
+        let t = t.normalize();
+        let b = self.n.cross(&t);
+        (t, b)
+    }
+
+    // Secondary ray leaving the surface, its origin is moved out of the error bounds of the
+    // hit point along the geometric normal
+    pub fn spawn_ray(&self, dir: Vec3) -> Ray {
+        let origin = offset_ray_origin(&self.p, &self.p_error, &self.ng, &dir);
+        let mut ray = Ray::new(&origin, dir, 0.0, f64::INFINITY);
+        ray.depth = self.depth + 1;
+        ray.medium = self.medium;
+        ray.wavelengths = self.wavelengths;
+        ray
+    }
+
+    // Ray from the surface that ends just before the target point
+    pub fn spawn_ray_to(&self, target: &Pnt3) -> Ray {
+        let origin = offset_ray_origin(&self.p, &self.p_error, &self.ng, &(*target - self.p));
+        let d = *target - origin;
+        let mut ray = Ray::new(&origin, d, 0.0, d.norm()*(1.0 - SHADOW_EPSILON));
+        ray.depth = self.depth + 1;
+        ray.medium = self.medium;
+        ray.wavelengths = self.wavelengths;
+        ray
+    }
+
+    // Outward facing geometric normal
+    pub fn outward(&self) -> Vec3 {
+        if self.inside { -self.ng } else { self.ng }
+    }
+
+    // True if the incoming ray travels from the inside of the surface to the outside, either
+    // hitting a closed shape from within or the back of an open surface
+    pub fn is_exiting(&self) -> bool {
+        self.inside != (self.i.dot(&self.n) < 0.0)
+    }
+
+    // Mirror reflection of the incoming ray about the shading normal
+    pub fn spawn_reflected(&self) -> Ray {
+        let wo = self.i;
+        let n = self.n;
+        let wi = -wo + n*(2.0*wo.dot(&n));
+        let mut ray = self.spawn_ray(wi);
+        if let Some(rd) = self.rd {
+            let dndx = self.dndu*self.dudx + self.dndv*self.dvdx;
+            let dndy = self.dndu*self.dudy + self.dndv*self.dvdy;
+            let dwodx = -rd.rx_dir - wo;
+            let dwody = -rd.ry_dir - wo;
+            let ddndx = dwodx.dot(&n) + wo.dot(&dndx);
+            let ddndy = dwody.dot(&n) + wo.dot(&dndy);
+            ray.differential = Some(RayDifferential {
+                rx_origin: self.p + self.dpdx,
+                rx_dir: (wi - dwodx + (dndx*wo.dot(&n) + n*ddndx)*2.0).normalize(),
+                ry_origin: self.p + self.dpdy,
+                ry_dir: (wi - dwody + (dndy*wo.dot(&n) + n*ddndy)*2.0).normalize()
+            });
+        }
+        ray
+    }
+
+    // Refraction into a medium with relative index of refraction ior (inside over outside),
+    // None on total internal reflection
+    pub fn spawn_refracted(&self, ior: Float) -> Option<Ray> {
+        let wo = self.i;
+        let mut n = self.n;
+        let mut dndx = self.dndu*self.dudx + self.dndv*self.dvdx;
+        let mut dndy = self.dndu*self.dudy + self.dndv*self.dvdy;
+        let eta = if self.is_exiting() { ior } else { 1.0/ior };
+        if wo.dot(&n) < 0.0 {
+            n = -n;
+            dndx = -dndx;
+            dndy = -dndy;
+        }
+        let wi = match refract(&wo, &n, eta) {
+            Some(wi) => wi,
+            None => return None
+        };
+        let mut ray = self.spawn_ray(wi);
+        if let Some(rd) = self.rd {
+            let dwodx = -rd.rx_dir - wo;
+            let dwody = -rd.ry_dir - wo;
+            let ddndx = dwodx.dot(&n) + wo.dot(&dndx);
+            let ddndy = dwody.dot(&n) + wo.dot(&dndy);
+            let mu = eta*wo.dot(&n) - wi.dot(&n).abs();
+            let dmudx = (eta - (eta*eta*wo.dot(&n))/wi.dot(&n).abs())*ddndx;
+            let dmudy = (eta - (eta*eta*wo.dot(&n))/wi.dot(&n).abs())*ddndy;
+            ray.differential = Some(RayDifferential {
+                rx_origin: self.p + self.dpdx,
+                rx_dir: (wi - dwodx*eta + (dndx*mu + n*dmudx)).normalize(),
+                ry_origin: self.p + self.dpdy,
+                ry_dir: (wi - dwody*eta + (dndy*mu + n*dmudy)).normalize()
+            });
+        }
+        Some(ray)
+    }
+}
+
+// Refracts wo about n, eta is the ratio of the indices of refraction (incident over transmitted)
+pub fn refract(wo: &Vec3, n: &Vec3, eta: Float) -> Option<Vec3> {
+    let cos_i = n.dot(wo);
+    let sin2_i = (1.0 - cos_i*cos_i).max(0.0);
+    let sin2_t = eta*eta*sin2_i;
+    if sin2_t >= 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    Some(-*wo*eta + *n*(eta*cos_i - cos_t))
+}
+
+// Least squares solution of a*x + b*y = c
+fn solve_tangent_plane(a: &Vec3, b: &Vec3, c: &Vec3) -> (Float, Float) {
+    let ata00 = a.dot(a);
+    let ata01 = a.dot(b);
+    let ata11 = b.dot(b);
+    let det = ata00*ata11 - ata01*ata01;
+    if det.abs() < 1e-20 {
+        return (0.0, 0.0);
+    }
+    let atb0 = a.dot(c);
+    let atb1 = b.dot(c);
+    let x = (ata11*atb0 - ata01*atb1)/det;
+    let y = (ata00*atb1 - ata01*atb0)/det;
+    if !x.is_finite() || !y.is_finite() {
+        return (0.0, 0.0);
+    }
+    (x, y)
 }
\ No newline at end of file
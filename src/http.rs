@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::vec::Vec;
+
+// Just enough HTTP/1.1 for the local job server and viewer: one request per connection,
+// bodies only with Content-Length.
+
+const MAX_BODY: usize = 64*1024*1024;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub body: Vec<u8>
+}
+
+impl Request {
+    // Path split at the slashes, without empty parts
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+}
+
+pub fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("/").to_string();
+    if method.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty request"));
+    }
+
+    let mut length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let header = line.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(i) = header.find(':') {
+            if header[..i].eq_ignore_ascii_case("content-length") {
+                length = header[i + 1..].trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request body too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let (path, query) = match target.find('?') {
+        Some(i) => (target[..i].to_string(), parse_query(&target[i + 1..])),
+        None => (target.clone(), HashMap::new())
+    };
+    let request = Request {
+        method: method,
+        path: path,
+        query: query,
+        body: body
+    };
+    Ok(request)
+}
+
+pub fn respond(stream: &mut TcpStream, status: u16, content_type: &str, body: &[u8]) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Error"
+    };
+    let header = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status, reason, content_type, body.len());
+    stream.write_all(header.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+pub fn respond_json(stream: &mut TcpStream, status: u16, json: &str) -> io::Result<()> {
+    respond(stream, status, "application/json", json.as_bytes())
+}
+
+pub fn respond_error(stream: &mut TcpStream, status: u16, message: &str) -> io::Result<()> {
+    respond_json(stream, status, &format!("{{\"error\": {}}}", json_string(message)))
+}
+
+pub fn parse_query(query: &str) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, "")
+        };
+        values.insert(decode(key), decode(value));
+    }
+    values
+}
+
+// Percent decoding, with + standing for a space
+fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]).into_owned();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) => {
+                        out.push(b);
+                        i += 2;
+                    },
+                    Err(_) => out.push(b'%')
+                }
+            },
+            b => out.push(b)
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c)
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_values_are_decoded() {
+        let query = parse_query("name=two+spheres&dir=%2Ftmp%2Fscenes&adaptive&&spp=16");
+        assert_eq!(query.len(), 4);
+        assert_eq!(query["name"], "two spheres");
+        assert_eq!(query["dir"], "/tmp/scenes");
+        assert_eq!(query["adaptive"], "");
+        assert_eq!(query["spp"], "16");
+    }
+
+    #[test]
+    fn malformed_escapes_are_kept() {
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%2"), "%2");
+        assert_eq!(decode("%zz%41"), "%zzA");
+        assert_eq!(decode("a%20b+c"), "a b c");
+        assert_eq!(decode("%C3%A9"), "\u{e9}");
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("say \"hi\"\\"), "\"say \\\"hi\\\"\\\\\"");
+        assert_eq!(json_string("a\nb\tc\r"), "\"a\\nb\\tc\\r\"");
+        assert_eq!(json_string("\u{1}\u{e9}"), "\"\\u0001\u{e9}\"");
+    }
+}
@@ -1,7 +1,68 @@
-use std::vec::Vec;
+use nalgebra::Norm;
+use std::f64::consts::PI;
 use types::*;
+use warp::{warp_point, WarpFunction};
+
+pub struct LightSample {
+    pub wi: Vec3,       // Direction towards the light
+    pub dist: Float,    // Distance to the light
+    pub radiance: Color,
+    pub pdf: Float
+}
+
+// Ray leaving a light, for tracing paths from the lights
+pub struct EmissionSample {
+    pub origin: Pnt3,
+    pub dir: Vec3,
+    pub radiance: Color,
+    pub pdf_pos: Float, // Area density of the origin, one for point lights
+    pub pdf_dir: Float  // Solid angle density of the direction
+}
 
 pub trait Light: Sync {
-    fn get_sample(&self) -> Color;
-    fn get_samples(&self) -> Vec<Color>;
+    fn sample(&self, p: &Pnt3) -> LightSample;
+    fn sample_emission(&self, s: Float, t: Float) -> EmissionSample;
+    // Densities of sample_emission for light leaving into the direction, (pdf_pos, pdf_dir)
+    fn emission_pdf(&self, dir: &Vec3) -> (Float, Float);
+    // Light group the contribution is accounted to in the AOVs
+    fn group(&self) -> u32;
+}
+
+// Index of a light chosen uniformly among count lights, with its probability
+pub fn choose_light(count: usize, u: Float) -> (usize, Float) {
+    (((u*count as Float) as usize).min(count - 1), 1.0/count as Float)
+}
+
+pub struct PointLight {
+    pub position: Pnt3,
+    pub intensity: Color,
+    pub group: u32
+}
+
+impl Light for PointLight {
+    fn sample(&self, p: &Pnt3) -> LightSample {
+        let d = self.position - *p;
+        let dist = d.norm();
+        LightSample {
+            wi: d/dist,
+            dist: dist,
+            radiance: self.intensity/(dist*dist),
+            pdf: 1.0
+        }
+    }
+    fn sample_emission(&self, s: Float, t: Float) -> EmissionSample {
+        EmissionSample {
+            origin: self.position,
+            dir: warp_point(s, t, WarpFunction::UniformSphere),
+            radiance: self.intensity,
+            pdf_pos: 1.0,
+            pdf_dir: 1.0/(4.0*PI)
+        }
+    }
+    fn emission_pdf(&self, _dir: &Vec3) -> (Float, Float) {
+        (1.0, 1.0/(4.0*PI))
+    }
+    fn group(&self) -> u32 {
+        self.group
+    }
 }
@@ -0,0 +1,729 @@
+use std::collections::HashMap;
+use std::f64;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::vec::Vec;
+use types::*;
+use aov::Aov;
+use camera::View;
+use colorspace::ColorSpace;
+use light::{Light, PointLight};
+use medium::{Medium, HomogeneousMedium, GridMedium, HenyeyGreenstein};
+use grid;
+use grid::Grid;
+use nanovdb;
+use nalgebra::{Matrix4, Rotation3, ToHomogeneous};
+use mipmap::FilterMode;
+use obj;
+use renderer::Integrator;
+use photon::PhotonSettings;
+use scene::Scene;
+use spectrum::Ior;
+use shader::*;
+use shape::*;
+use texture::*;
+
+// Text scene description with one statement per line, for example
+//
+//   camera position 0 -10 0.5 front 0 1 0 up 0 0 -1 fov 45
+//   colorspace working acescg output srgb
+//   texture checks checker even 1 1 1 odd 0.1 0.1 0.1 scale 8 space uv
+//   texture wood image path wood.png colorspace srgb
+//   texture veins marble scale 4 ramp 0 0.9 0.9 0.85 0.6 0.5 0.45 0.4 1 0.1 0.1 0.1
+//   shader floor diffuse color checks
+//   shader grey ao samples 64 distance 2 falloff smooth color 0.74 0.74 0.74
+//   medium haze homogeneous absorption 0.01 0.01 0.01 scattering 0.05 0.05 0.05 g 0.3
+//   medium cloud grid density cloud.vgrid scattering 4 4 4 position 0 0 1 rotate 0 0 30 size 2
+//   medium fire nanovdb path fire.nvdb density density temperature temperature blackbody 0.1
+//   shader smoke medium interior haze
+//   shader prism glass ior sellmeier 1.03961212 0.231792344 1.01046945 0.00600069867 0.0200179144 103.560653
+//   shader wax subsurface albedo 0.9 0.8 0.6 mfp 1 0.5 0.25 scale 0.1 ior 1.4
+//   fog haze
+//   volume depth 8
+//   spectral
+//   integrator bdpt depth 8
+//   integrator ppm photons 100000 radius 0.1 alpha 0.7 depth 8
+//   sphere position 0 0 0 radius 1 shader grey
+//   triangle v1 -1 0 0 v2 1 0 0 v3 0 0 1 shader grey
+//   mesh path bunny.obj shader grey
+//   light point position 0 -5 5 intensity 20 20 20 group 1
+//   ambient_occlusion samples 64 distance 2 falloff linear
+//   aov depth normal bent_normal light_group_1 light_group_other
+//
+// Parameters are given as key followed by its values, colors are either three numbers or the
+// name of a texture. Colors are given in the working space, which like images without a
+// colorspace defaults to linear sRGB. Without an output colorspace PNGs hold untagged linear
+// values. Lines starting with # are comments. Relative paths are resolved against the
+// directory of the scene file, scenes parsed without a directory only take absolute paths.
+
+pub fn load(path: &Path) -> Result<Scene, String> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut source))
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    parse(&source, Some(dir)).map_err(|e| format!("{}:{}", path.display(), e))
+}
+
+pub fn parse(source: &str, dir: Option<&Path>) -> Result<Scene, String> {
+    let mut loader = Loader {
+        dir: dir,
+        textures: HashMap::new(),
+        shaders: HashMap::new(),
+        media: HashMap::new(),
+        scene: Scene::empty()
+    };
+    for (i, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        loader.statement(&tokens).map_err(|e| format!("{}: {}", i + 1, e))?;
+    }
+    // The upsampling of colors to spectra is fitted to the Rec. 709 primaries
+    if loader.scene.spectral && loader.scene.working != ColorSpace::Linear {
+        return Err("Spectral rendering needs the linear working space".to_string());
+    }
+    if loader.scene.spectral && loader.scene.integrator != Integrator::Whitted {
+        return Err("Spectral rendering is only supported by the whitted integrator".to_string());
+    }
+    Ok(loader.scene)
+}
+
+struct Loader<'a> {
+    dir: Option<&'a Path>,
+    textures: HashMap<String, Rc<Texture>>,
+    shaders: HashMap<String, Rc<Shader>>,
+    media: HashMap<String, usize>,  // Index in the scene media
+    scene: Scene
+}
+
+impl<'a> Loader<'a> {
+    fn statement(&mut self, tokens: &[&str]) -> Result<(), String> {
+        match tokens[0] {
+            "camera" => {
+                let s = Statement { tokens: tokens, start: 1 };
+                let view = View {
+                    position: s.vector("position", Vec3::new(0.0, -10.0, 0.5))?.to_point(),
+                    front: s.vector("front", Vec3::new(0.0, 1.0, 0.0))?,
+                    up: s.vector("up", Vec3::new(0.0, 0.0, -1.0))?,
+                    angle: s.float("fov", 45.0)?
+                };
+                self.scene.view = Some(view);
+            },
+            "texture" => {
+                let (name, s) = named(tokens)?;
+                let texture = self.texture(&s)?;
+                self.textures.insert(name.to_string(), texture);
+            },
+            "shader" => {
+                let (name, s) = named(tokens)?;
+                let shader = self.shader(&s)?;
+                self.shaders.insert(name.to_string(), shader);
+            },
+            "medium" => {
+                let (name, s) = named(tokens)?;
+                let medium = self.medium(&s)?;
+                self.media.insert(name.to_string(), self.scene.media.len());
+                self.scene.media.push(medium);
+            },
+            "fog" => {
+                let name = tokens.get(1).ok_or("Expected fog MEDIUM".to_string())?;
+                self.scene.fog = Some(*self.media.get(*name).ok_or(format!("Unknown medium {}", name))?);
+            },
+            "volume" => {
+                let s = Statement { tokens: tokens, start: 1 };
+                self.scene.volume_depth = s.float("depth", 8.0)? as u32;
+            },
+            "spectral" => self.scene.spectral = true,
+            "integrator" => {
+                let s = Statement { tokens: tokens, start: 2 };
+                self.scene.integrator = match tokens.get(1).map(|t| *t) {
+                    Some("whitted") => Integrator::Whitted,
+                    Some("bdpt") => Integrator::Bdpt(s.float("depth", 8.0)? as u32),
+                    Some("photon") => Integrator::Photon(photon_settings(&s)?),
+                    Some("ppm") => Integrator::ProgressivePhoton(photon_settings(&s)?),
+                    kind => return Err(format!("Unknown integrator {}", kind.unwrap_or("")))
+                };
+            },
+            "colorspace" => {
+                let s = Statement { tokens: tokens, start: 1 };
+                if let Some(name) = s.word("working")? {
+                    let working = color_space(name)?;
+                    if !working.is_linear() {
+                        return Err(format!("Working space {} is not linear", name));
+                    }
+                    // Images and black bodies are converted when they are loaded
+                    if !self.textures.is_empty() || !self.media.is_empty() {
+                        return Err("The working space has to be set before textures and media".to_string());
+                    }
+                    self.scene.working = working;
+                }
+                if let Some(name) = s.word("output")? {
+                    self.scene.output = Some(color_space(name)?);
+                }
+            },
+            "sphere" => {
+                let s = Statement { tokens: tokens, start: 1 };
+                self.scene.shapes.push(Box::new(
+                    Sphere {
+                        position: s.vector("position", Vec3::new(0.0, 0.0, 0.0))?.to_point(),
+                        radius: s.float("radius", 1.0)?,
+                        shader: self.shader_ref(&s, "shader")?
+                    }
+                ));
+            },
+            "triangle" => {
+                let s = Statement { tokens: tokens, start: 1 };
+                self.scene.shapes.push(Box::new(
+                    Triangle::new(
+                        s.vector("v1", Vec3::new(0.0, 0.0, 0.0))?.to_point(),
+                        s.vector("v2", Vec3::new(1.0, 0.0, 0.0))?.to_point(),
+                        s.vector("v3", Vec3::new(0.0, 1.0, 0.0))?.to_point(),
+                        self.shader_ref(&s, "shader")?
+                    )
+                ));
+            },
+            "mesh" => {
+                let s = Statement { tokens: tokens, start: 1 };
+                let file = s.word("path")?.ok_or("Missing path".to_string())?;
+                let mesh = obj::load(&self.path(file)?, self.shader_ref(&s, "shader")?)?;
+                self.scene.shapes.push(Box::new(mesh));
+            },
+            "light" => {
+                let s = Statement { tokens: tokens, start: 2 };
+                let light: Box<Light> = match tokens.get(1).map(|t| *t) {
+                    Some("point") => Box::new(
+                        PointLight {
+                            position: s.vector("position", Vec3::new(0.0, 0.0, 0.0))?.to_point(),
+                            intensity: s.vector("intensity", Vec3::new(1.0, 1.0, 1.0))?,
+                            group: s.float("group", 0.0)? as u32
+                        }
+                    ),
+                    kind => return Err(format!("Unknown light {}", kind.unwrap_or("")))
+                };
+                self.scene.lights.push(light);
+            },
+            "ambient_occlusion" => {
+                let s = Statement { tokens: tokens, start: 1 };
+                self.scene.ao = ao_settings(&s)?;
+            },
+            "aov" => {
+                for name in &tokens[1..] {
+                    match Aov::parse(name) {
+                        Some(aov) => self.scene.aovs.push(aov),
+                        None => return Err(format!("Unknown AOV {}", name))
+                    }
+                }
+            },
+            other => return Err(format!("Unknown statement {}", other))
+        }
+        Ok(())
+    }
+
+    fn texture(&self, s: &Statement) -> Result<Rc<Texture>, String> {
+        let kind = s.tokens[s.start - 1];
+        let space = match s.word("space")? {
+            None => TextureSpace::Object,
+            Some("uv") => TextureSpace::UV,
+            Some("object") => TextureSpace::Object,
+            Some("world") => TextureSpace::World,
+            Some(other) => return Err(format!("Unknown texture space {}", other))
+        };
+        let scale = s.float("scale", 1.0)?;
+        let ramp = ramp(&s)?;
+        let octaves = s.float("octaves", 6.0)? as u32;
+        let texture: Rc<Texture> = match kind {
+            "constant" => Rc::new(ConstantTexture { color: s.vector("color", Color::new(1.0, 1.0, 1.0))? }),
+            "image" => {
+                let file = s.word("path")?.ok_or("Missing path".to_string())?;
+                let filter = match s.word("filter")? {
+                    None | Some("trilinear") => FilterMode::Trilinear,
+                    Some("bilinear") => FilterMode::Bilinear,
+                    Some("ewa") => FilterMode::Ewa,
+                    Some(other) => return Err(format!("Unknown filter {}", other))
+                };
+                let path = self.path(file)?;
+                let colorspace = match s.word("colorspace")? {
+                    None => ColorSpace::Linear,
+                    Some(name) => color_space(name)?
+                };
+                let texture = ImageTexture::open(&path, filter, colorspace, self.scene.working)
+                    .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+                Rc::new(texture)
+            },
+            "checker" => Rc::new(
+                CheckerTexture {
+                    even: self.texture_ref(s, "even", Color::new(1.0, 1.0, 1.0))?,
+                    odd: self.texture_ref(s, "odd", Color::new(0.0, 0.0, 0.0))?,
+                    space: space,
+                    scale: scale
+                }
+            ),
+            "noise" => Rc::new(NoiseTexture { space: space, scale: scale, ramp: ramp }),
+            "fbm" => Rc::new(
+                FbmTexture {
+                    space: space,
+                    scale: scale,
+                    octaves: octaves,
+                    lacunarity: s.float("lacunarity", 2.0)?,
+                    gain: s.float("gain", 0.5)?,
+                    ramp: ramp
+                }
+            ),
+            "turbulence" => Rc::new(
+                TurbulenceTexture {
+                    space: space,
+                    scale: scale,
+                    octaves: octaves,
+                    lacunarity: s.float("lacunarity", 2.0)?,
+                    gain: s.float("gain", 0.5)?,
+                    ramp: ramp
+                }
+            ),
+            "marble" => Rc::new(
+                MarbleTexture {
+                    space: space,
+                    scale: scale,
+                    frequency: s.float("frequency", 4.0)?,
+                    distortion: s.float("distortion", 4.0)?,
+                    octaves: octaves,
+                    ramp: ramp
+                }
+            ),
+            "wood" => Rc::new(
+                WoodTexture {
+                    space: space,
+                    scale: scale,
+                    rings: s.float("rings", 8.0)?,
+                    distortion: s.float("distortion", 0.5)?,
+                    ramp: ramp
+                }
+            ),
+            "voronoi" => {
+                let feature = match s.word("feature")? {
+                    None | Some("f1") => VoronoiFeature::F1,
+                    Some("f2") => VoronoiFeature::F2,
+                    Some("f2-f1") => VoronoiFeature::F2MinusF1,
+                    Some(other) => return Err(format!("Unknown voronoi feature {}", other))
+                };
+                let jitter = s.float("jitter", 1.0)?;
+                if !(jitter >= 0.0 && jitter <= 1.0) {
+                    return Err("Voronoi jitter must be between 0 and 1".to_string());
+                }
+                Rc::new(
+                    VoronoiTexture {
+                        space: space,
+                        scale: scale,
+                        jitter: jitter,
+                        feature: feature,
+                        ramp: ramp
+                    }
+                )
+            },
+            other => return Err(format!("Unknown texture {}", other))
+        };
+        Ok(texture)
+    }
+
+    fn shader(&self, s: &Statement) -> Result<Rc<Shader>, String> {
+        let kind = s.tokens[s.start - 1];
+        let white = Color::new(1.0, 1.0, 1.0);
+        let shader: Rc<Shader> = match kind {
+            "gouraud" => Rc::new(GouraudShader { color: self.texture_ref(s, "color", white)? }),
+            "diffuse" => Rc::new(DiffuseShader { color: self.texture_ref(s, "color", white)? }),
+            "ao" => Rc::new(
+                AmbientOcculusionShader {
+                    settings: ao_settings(s)?,
+                    color: self.texture_ref(s, "color", white)?
+                }
+            ),
+            "medium" => Rc::new(MediumShader { medium: self.medium_ref(s, "interior")? }),
+            "mirror" => Rc::new(MirrorShader { color: self.texture_ref(s, "color", white)? }),
+            "glass" => Rc::new(
+                GlassShader {
+                    ior: ior(s)?,
+                    color: self.texture_ref(s, "color", white)?
+                }
+            ),
+            "subsurface" => Rc::new(
+                SubsurfaceShader {
+                    albedo: self.texture_ref(s, "albedo", Color::new(0.8, 0.8, 0.8))?,
+                    mfp: s.vector("mfp", Color::new(0.1, 0.1, 0.1))?*s.float("scale", 1.0)?,
+                    ior: s.float("ior", 1.4)?,
+                    phase: HenyeyGreenstein { g: s.float("g", 0.0)?.max(-0.99).min(0.99) }
+                }
+            ),
+            "normal_map" => Rc::new(
+                NormalMapShader {
+                    shader: self.shader_ref(s, "shader")?,
+                    normal_map: self.texture_ref(s, "map", Color::new(0.5, 0.5, 1.0))?,
+                    strength: s.float("strength", 1.0)?
+                }
+            ),
+            "bump" => Rc::new(
+                BumpMapShader {
+                    shader: self.shader_ref(s, "shader")?,
+                    height: self.texture_ref(s, "height", Color::new(0.0, 0.0, 0.0))?,
+                    scale: s.float("scale", 1.0)?
+                }
+            ),
+            other => return Err(format!("Unknown shader {}", other))
+        };
+        Ok(shader)
+    }
+
+    // Named texture or a constant color given as three numbers
+    fn texture_ref(&self, s: &Statement, key: &str, default: Color) -> Result<Rc<Texture>, String> {
+        match s.values(key) {
+            Some(v) if v.first().map_or(false, |t| t.parse::<Float>().is_err()) => {
+                self.textures.get(v[0]).cloned().ok_or(format!("Unknown texture {}", v[0]))
+            },
+            _ => Ok(Rc::new(ConstantTexture { color: s.vector(key, default)? }))
+        }
+    }
+
+    fn shader_ref(&self, s: &Statement, key: &str) -> Result<Rc<Shader>, String> {
+        let name = s.word(key)?.ok_or(format!("Missing {}", key))?;
+        self.shaders.get(name).cloned().ok_or(format!("Unknown shader {}", name))
+    }
+
+    fn medium(&self, s: &Statement) -> Result<Box<Medium>, String> {
+        let kind = s.tokens[s.start - 1];
+        let scale = s.float("scale", 1.0)?;
+        let phase = HenyeyGreenstein { g: s.float("g", 0.0)?.max(-0.99).min(0.99) };
+        let sigma_a = s.vector("absorption", Color::new(0.0, 0.0, 0.0))?*scale;
+        let sigma_s = s.vector("scattering", Color::new(0.1, 0.1, 0.1))?*scale;
+        let emission = s.vector("emission_color", Color::new(1.0, 1.0, 1.0))?;
+        let blackbody = s.float("blackbody", 1.0)?;
+        let medium: Box<Medium> = match kind {
+            "homogeneous" => Box::new(HomogeneousMedium { sigma_a: sigma_a, sigma_s: sigma_s, phase: phase }),
+            "grid" => {
+                let load = |key: &str| -> Result<Option<Box<Grid>>, String> {
+                    match s.word(key)? {
+                        None => Ok(None),
+                        Some(file) => {
+                            let path = self.path(file)?;
+                            grid::load(&path).map(Some).map_err(|e| format!("Could not read {}: {}", path.display(), e))
+                        }
+                    }
+                };
+                let density = load("density")?.ok_or("Missing density".to_string())?;
+                // The grid is centered at the origin, its longest side has unit length
+                let (lo, hi) = density.index_bounds().unwrap_or(([0, 0, 0], [0, 0, 0]));
+                let extent = (0..3).map(|a| (hi[a] - lo[a] + 1) as Float).fold(1.0, Float::max);
+                let c = (0..3).map(|a| (lo[a] + hi[a]) as Float*0.5/extent).collect::<Vec<Float>>();
+                let index_to_local = Matrix4::new(
+                    1.0/extent, 0.0, 0.0, -c[0],
+                    0.0, 1.0/extent, 0.0, -c[1],
+                    0.0, 0.0, 1.0/extent, -c[2],
+                    0.0, 0.0, 0.0, 1.0
+                );
+                let mut medium = GridMedium::new(density, sigma_a, sigma_s, phase, &(transform(s)?*index_to_local))?;
+                if let Some(grid) = load("emission")? {
+                    medium.set_emission(grid, emission);
+                }
+                if let Some(grid) = load("temperature")? {
+                    medium.set_temperature(grid, blackbody, self.scene.working);
+                }
+                Box::new(medium)
+            },
+            "nanovdb" => {
+                let file = s.word("path")?.ok_or("Missing path".to_string())?;
+                let path = self.path(file)?;
+                let load = |name: Option<&str>| nanovdb::load(&path, name)
+                    .map_err(|e| format!("Could not read {}: {}", path.display(), e));
+                let (density, index_to_local) = load(s.word("density")?)?;
+                let mut medium = GridMedium::new(Box::new(density), sigma_a, sigma_s, phase, &(transform(s)?*index_to_local))?;
+                if let Some(name) = s.word("emission")? {
+                    medium.set_emission(Box::new(load(Some(name))?.0), emission);
+                }
+                if let Some(name) = s.word("temperature")? {
+                    medium.set_temperature(Box::new(load(Some(name))?.0), blackbody, self.scene.working);
+                }
+                Box::new(medium)
+            },
+            other => return Err(format!("Unknown medium {}", other))
+        };
+        Ok(medium)
+    }
+
+    fn path(&self, file: &str) -> Result<PathBuf, String> {
+        let path = Path::new(file);
+        match self.dir {
+            Some(dir) => Ok(dir.join(path)),
+            None if path.is_absolute() => Ok(path.to_path_buf()),
+            None => Err(format!("Relative path {} needs a base directory", file))
+        }
+    }
+
+    fn medium_ref(&self, s: &Statement, key: &str) -> Result<usize, String> {
+        let name = s.word(key)?.ok_or(format!("Missing {}", key))?;
+        self.media.get(name).cloned().ok_or(format!("Unknown medium {}", name))
+    }
+}
+
+// Splits "texture NAME TYPE ..." into the name and the parameters following the type
+fn named<'a, 'b>(tokens: &'b [&'a str]) -> Result<(&'a str, Statement<'a, 'b>), String> {
+    if tokens.len() < 3 {
+        return Err(format!("Expected {} NAME TYPE", tokens[0]));
+    }
+    Ok((tokens[1], Statement { tokens: tokens, start: 3 }))
+}
+
+struct Statement<'a: 'b, 'b> {
+    tokens: &'b [&'a str],
+    start: usize
+}
+
+impl<'a, 'b> Statement<'a, 'b> {
+    fn values(&self, key: &str) -> Option<&'b [&'a str]> {
+        self.tokens[self.start..].iter().position(|t| *t == key).map(|i| &self.tokens[self.start + i + 1..])
+    }
+
+    fn word(&self, key: &str) -> Result<Option<&'a str>, String> {
+        match self.values(key) {
+            None => Ok(None),
+            Some(v) => v.first().map(|t| Some(*t)).ok_or(format!("Missing value for {}", key))
+        }
+    }
+
+    fn float(&self, key: &str, default: Float) -> Result<Float, String> {
+        match self.values(key) {
+            None => Ok(default),
+            Some(v) => number(key, v.first())
+        }
+    }
+
+    fn vector(&self, key: &str, default: Vec3) -> Result<Vec3, String> {
+        match self.values(key) {
+            None => Ok(default),
+            Some(v) => Ok(Vec3::new(number(key, v.get(0))?, number(key, v.get(1))?, number(key, v.get(2))?))
+        }
+    }
+}
+
+// Placement of an object given by position, rotate (degrees around x, y and z, applied in
+// that order) and a uniform size
+fn transform(s: &Statement) -> Result<Matrix4<Float>, String> {
+    let position = s.vector("position", Vec3::new(0.0, 0.0, 0.0))?;
+    let angles = s.vector("rotate", Vec3::new(0.0, 0.0, 0.0))?*(f64::consts::PI/180.0);
+    let size = s.float("size", 1.0)?;
+    let rotation = Rotation3::new(Vec3::new(0.0, 0.0, angles.z))
+        *Rotation3::new(Vec3::new(0.0, angles.y, 0.0))
+        *Rotation3::new(Vec3::new(angles.x, 0.0, 0.0));
+    let mut m = rotation.to_homogeneous()*size;
+    m.m14 = position.x;
+    m.m24 = position.y;
+    m.m34 = position.z;
+    m.m44 = 1.0;
+    Ok(m)
+}
+
+// Constant index of refraction, a named glass or a dispersion model with its coefficients
+fn ior(s: &Statement) -> Result<Ior, String> {
+    let v = match s.values("ior") {
+        None => return Ok(Ior::Constant(1.5)),
+        Some(v) => v
+    };
+    let name = v.first().ok_or("Missing value for ior".to_string())?;
+    if let Ok(n) = name.parse::<Float>() {
+        return Ok(Ior::Constant(n));
+    }
+    let mut coefficients = Vec::new();
+    for t in &v[1..] {
+        match t.parse::<Float>() {
+            Ok(c) => coefficients.push(c),
+            Err(_) => break
+        }
+    }
+    Ior::parse(name, &coefficients).ok_or(format!("Unknown ior {}", name))
+}
+
+// Stops given as position and color after ramp, otherwise a linear ramp between from and to
+fn ramp(s: &Statement) -> Result<ColorRamp, String> {
+    let values = match s.values("ramp") {
+        None => return Ok(ColorRamp::between(s.vector("from", Color::new(0.0, 0.0, 0.0))?,
+                                             s.vector("to", Color::new(1.0, 1.0, 1.0))?)),
+        Some(v) => v
+    };
+    let mut numbers = Vec::new();
+    for t in values {
+        match t.parse::<Float>() {
+            Ok(v) => numbers.push(v),
+            Err(_) => break
+        }
+    }
+    if numbers.is_empty() || numbers.len() % 4 != 0 {
+        return Err("Ramp stops need a position and a color each".to_string());
+    }
+    if numbers.iter().any(|v| !v.is_finite()) {
+        return Err("Ramp stops must be finite".to_string());
+    }
+    Ok(ColorRamp::new(numbers.chunks(4).map(|c| (c[0], Color::new(c[1], c[2], c[3]))).collect()))
+}
+
+fn color_space(name: &str) -> Result<ColorSpace, String> {
+    ColorSpace::parse(name).ok_or(format!("Unknown color space {}", name))
+}
+
+fn ao_settings(s: &Statement) -> Result<AoSettings, String> {
+    let falloff = match s.word("falloff")? {
+        None => Falloff::Constant,
+        Some(name) => Falloff::parse(name).ok_or(format!("Unknown falloff {}", name))?
+    };
+    Ok(AoSettings {
+        samples: s.float("samples", 64.0)? as u32,
+        max_distance: s.float("distance", f64::INFINITY)?,
+        falloff: falloff
+    })
+}
+
+fn photon_settings(s: &Statement) -> Result<PhotonSettings, String> {
+    let defaults = PhotonSettings::new();
+    let settings = PhotonSettings {
+        photons: s.float("photons", defaults.photons as Float)? as u32,
+        radius: s.float("radius", defaults.radius)?,
+        max_depth: s.float("depth", defaults.max_depth as Float)? as u32,
+        alpha: s.float("alpha", defaults.alpha)?
+    };
+    if settings.radius <= 0.0 {
+        return Err("Photon radius must be positive".to_string());
+    }
+    if settings.alpha <= 0.0 || settings.alpha >= 1.0 {
+        return Err("Photon alpha must be between 0 and 1".to_string());
+    }
+    Ok(settings)
+}
+
+fn number(key: &str, token: Option<&&str>) -> Result<Float, String> {
+    match token {
+        None => Err(format!("Missing value for {}", key)),
+        Some(t) => t.parse().map_err(|_| format!("Invalid value {} for {}", t, key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::f64;
+    use std::path::Path;
+    use types::*;
+    use bsdf::Lobe;
+    use ray::Ray;
+    use renderer::Integrator;
+    use scene::Scene;
+    use super::*;
+
+    fn load(source: &str) -> Scene {
+        match parse(source, Some(Path::new("."))) {
+            Ok(scene) => scene,
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    fn error(source: &str) -> String {
+        match parse(source, Some(Path::new("."))) {
+            Ok(_) => panic!("{} was accepted", source),
+            Err(e) => e
+        }
+    }
+
+    #[test]
+    fn shapes_and_shaders() {
+        let scene = load("# Two spheres
+texture checks checker even 1 1 1 odd 0 0 0 scale 8 space uv
+shader floor diffuse color 0.2 0.4 0.6
+shader glossy mirror color checks
+
+sphere position 1 2 3 radius 0.5 shader floor
+sphere shader glossy
+triangle v1 -1 0 0 v2 1 0 0 v3 0 0 1 shader floor
+");
+        assert_eq!(scene.shapes.len(), 3);
+        let bounds = scene.shapes[0].bounds();
+        assert_eq!(bounds.min, Pnt3::new(0.5, 1.5, 2.5));
+        assert_eq!(bounds.max, Pnt3::new(1.5, 2.5, 3.5));
+        // Defaults of the second sphere
+        assert_eq!(scene.shapes[1].bounds().max, Pnt3::new(1.0, 1.0, 1.0));
+
+        let mut ray = Ray::new(&Pnt3::new(1.0, 2.0, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0, f64::INFINITY);
+        let hit = scene.shapes[0].intersect(&mut ray).unwrap();
+        assert_eq!(scene.shapes[0].shader().albedo(&hit), Color::new(0.2, 0.4, 0.6));
+        let mut ray = Ray::new(&Pnt3::new(0.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0), 0.0, f64::INFINITY);
+        let hit = scene.shapes[1].intersect(&mut ray).unwrap();
+        match scene.shapes[1].shader().bsdf(&hit).lobe {
+            Lobe::Mirror(_) => {},
+            lobe => panic!("{:?}", lobe)
+        }
+    }
+
+    #[test]
+    fn lights_and_settings() {
+        let scene = load("light point position 0 -5 5 intensity 20 10 5 group 1
+light point
+camera position 0 -10 3 front 0 1 -0.3 fov 30
+ambient_occlusion samples 16 distance 2 falloff linear
+aov depth light_group_1
+colorspace working acescg output srgb
+");
+        assert_eq!(scene.lights.len(), 2);
+        assert_eq!(scene.lights[0].group(), 1);
+        assert_eq!(scene.lights[1].group(), 0);
+        let sample = scene.lights[0].sample(&Pnt3::new(0.0, -5.0, 3.0));
+        assert_eq!(sample.dist, 2.0);
+        assert_eq!(sample.wi, Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(sample.radiance, Color::new(5.0, 2.5, 1.25));
+
+        let view = scene.view.unwrap();
+        assert_eq!(view.position, Pnt3::new(0.0, -10.0, 3.0));
+        assert_eq!(view.angle, 30.0);
+        assert_eq!(scene.ao.samples, 16);
+        assert_eq!(scene.ao.max_distance, 2.0);
+        assert_eq!(scene.aovs, vec![Aov::Depth, Aov::LightGroup(1)]);
+        assert_eq!(scene.working, ColorSpace::AcesCg);
+        assert_eq!(scene.output, Some(ColorSpace::Srgb));
+    }
+
+    #[test]
+    fn integrators() {
+        assert_eq!(load("").integrator, Integrator::Whitted);
+        assert_eq!(load("integrator bdpt depth 3").integrator, Integrator::Bdpt(3));
+        assert_eq!(load("integrator bdpt").integrator, Integrator::Bdpt(8));
+        let defaults = PhotonSettings::new();
+        assert_eq!(load("integrator photon photons 1000").integrator,
+                   Integrator::Photon(PhotonSettings { photons: 1000, ..defaults }));
+        assert_eq!(load("integrator ppm radius 0.2 alpha 0.5 depth 4").integrator,
+                   Integrator::ProgressivePhoton(PhotonSettings { radius: 0.2, alpha: 0.5, max_depth: 4, ..defaults }));
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(error("shader grey diffuse\n\nsphere shader red\n"), "3: Unknown shader red");
+        assert_eq!(error("sphere radius\n"), "1: Missing value for radius");
+        assert_eq!(error("# comment\nlight point position 0 x 0\n"), "2: Invalid value x for position");
+        assert_eq!(error("light spot\n"), "1: Unknown light spot");
+        assert_eq!(error("integrator path\n"), "1: Unknown integrator path");
+        assert_eq!(error("texture cells voronoi jitter inf\n"), "1: Voronoi jitter must be between 0 and 1");
+        assert_eq!(error("texture cells voronoi jitter -0.5\n"), "1: Voronoi jitter must be between 0 and 1");
+        assert_eq!(error("integrator ppm alpha 1\n"), "1: Photon alpha must be between 0 and 1");
+        assert_eq!(error("shader\n"), "1: Expected shader NAME TYPE");
+        assert_eq!(error("shader grey plastic\n"), "1: Unknown shader plastic");
+        assert_eq!(error("shader grey diffuse color wood\n"), "1: Unknown texture wood");
+        assert_eq!(error("cube\n"), "1: Unknown statement cube");
+        assert_eq!(error("aov depth shadows\n"), "1: Unknown AOV shadows");
+        assert_eq!(error("colorspace working srgb\n"), "1: Working space srgb is not linear");
+        assert_eq!(error("spectral\nintegrator bdpt\n"), "Spectral rendering is only supported by the whitted integrator");
+    }
+
+    #[test]
+    fn relative_paths_need_a_directory() {
+        assert_eq!(parse("mesh path teapot.obj\n", None).err().unwrap(), "1: Relative path teapot.obj needs a base directory");
+        let missing = env::temp_dir().join("raytracer-no-such-mesh.obj");
+        let e = parse(&format!("mesh path {}\n", missing.display()), None).err().unwrap();
+        assert!(!e.contains("base directory"), "{}", e);
+    }
+}
@@ -5,36 +5,300 @@ extern crate rand;
 // extern crate threadpool;
 extern crate scoped_threadpool;
 
+mod aov;
+mod bdpt;
+mod binary;
+mod bsdf;
+mod bvh;
 mod camera;
+mod checkpoint;
+#[cfg(test)]
+mod chi2;
+mod colorspace;
+mod debug;
+mod denoise;
+mod distributed;
+mod exr;
+mod film;
+mod float;
+mod grid;
 mod hit;
+mod http;
 mod light;
+mod loader;
+mod medium;
+mod mipmap;
+mod nanovdb;
+mod noise;
+mod obj;
+mod options;
+mod photon;
 mod ray;
 mod renderer;
 mod sampling;
 mod scene;
+mod server;
 mod shader;
 mod shape;
+mod spectrum;
+mod stats;
+#[cfg(test)]
+mod testing;
+mod texture;
 mod types;
+mod viewer;
 mod warp;
 mod window;
 
-use window::Window;
+use std::env;
+use std::net::TcpListener;
+use std::process;
+use std::path::PathBuf;
+use std::time::Duration;
+use window::{Window, Progress};
 use scene::Scene;
+use aov::Aov;
+use options::Options;
+use denoise::Denoiser;
+use sampling::AdaptiveSampler;
+use film::Accumulator;
+use checkpoint::Checkpoint;
+use distributed::Job;
 
 fn draw_png(window: &Window, scene: &Scene) {
     use std::fs::File;
+    use std::io::Write;
     use std::path::Path;
 
-    let imgbuf = window.draw_as_image(&scene);
+    window.draw_as_png(&scene, |png, progress| {
+        print_progress(progress);
+        let _ = File::create(&Path::new("rendering.png")).and_then(|mut f| f.write_all(&png));
+    });
+}
+
+fn print_progress(progress: &Progress) {
+    let seconds = progress.elapsed.as_secs() as f64 + progress.elapsed.subsec_nanos() as f64*1e-9;
+    println!("Pass {}: {} samples, {:.1}s, error {:.4}{}", progress.pass, progress.samples, seconds, progress.error,
+             if progress.done { ", done" } else { "" });
+    if let Some(ref e) = progress.checkpoint_error {
+        println!("Error: {}", e);
+    }
+}
+
+// AOVs written to disk and the passes rendered for them
+fn passes(scene: &Scene, options: &Options) -> (Vec<Aov>, Vec<Aov>) {
+    let mut aovs = vec![options.debug.map_or(Aov::Beauty, Aov::Debug)];
+    for aov in &scene.aovs {
+        if !aovs.contains(aov) {
+            aovs.push(*aov);
+        }
+    }
+    // The denoiser needs the feature buffers even if they are not written
+    let mut passes = aovs.clone();
+    if options.denoise {
+        for aov in &[Aov::Albedo, Aov::Normal] {
+            if !passes.contains(aov) {
+                passes.push(*aov);
+            }
+        }
+    }
+    (aovs, passes)
+}
+
+fn draw_aovs(window: &Window, scene: &Scene, options: &Options) {
+    use std::io;
+    use std::path::Path;
+
+    let (aovs, passes) = passes(scene, options);
+    let checkpoint = match options.checkpoint {
+        Some(ref path) if options.resume && Path::new(path).exists() => {
+            let checkpoint = Checkpoint::read(&Path::new(path), window.width, window.height).and_then(|checkpoint| {
+                checkpoint.matches(window.width, window.height, &passes, window.seed)
+                    .map(|_| checkpoint)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            });
+            match checkpoint {
+                Ok(checkpoint) => checkpoint,
+                Err(e) => {
+                    println!("Error: Could not resume from {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        },
+        _ => Checkpoint::new(window.width, window.height, &passes, window.seed)
+    };
+    window.resume_progressive(&scene, checkpoint, |accumulator, progress| {
+        print_progress(progress);
+        write_aovs(scene, &aovs, &passes, accumulator, options);
+    });
+}
+
+fn write_aovs(scene: &Scene, aovs: &[Aov], passes: &[Aov], accumulator: &Accumulator, options: &Options) {
+    use std::fs::File;
+    use std::path::Path;
+
+    if let Some(ref path) = options.heatmap {
+        let ref mut fout = File::create(&Path::new(path)).unwrap();
+        let _ = image::ImageRgb8(accumulator.heatmap().to_image()).save(fout, image::PNG);
+    }
+    let mut films = accumulator.films();
+    if options.denoise {
+        let albedo = passes.iter().position(|aov| *aov == Aov::Albedo).unwrap();
+        let normal = passes.iter().position(|aov| *aov == Aov::Normal).unwrap();
+        films[0] = Denoiser::new().denoise(&films[0], &films[albedo], &films[normal]);
+    }
+    films.truncate(aovs.len());
+
+    match options.exr {
+        Some(ref path) => {
+            if let Err(e) = aov::write_exr(&Path::new(path), aovs, &films, scene.working, scene.output) {
+                println!("Error: Could not write {}: {}", path, e);
+            }
+        },
+        None => {
+            for (aov, film) in aovs.iter().zip(&films) {
+                let name = match *aov {
+                    Aov::Beauty => "rendering.png".to_string(),
+                    _ => format!("rendering_{}.png", aov.name())
+                };
+                if aov.is_color() {
+                    let _ = colorspace::write_png(&Path::new(&name), film, scene.working, scene.output);
+                } else {
+                    let ref mut fout = File::create(&Path::new(&name)).unwrap();
+                    let _ = image::ImageRgb8(aov.to_display(film).to_image()).save(fout, image::PNG);
+                }
+            }
+        }
+    }
+}
+
+fn report_stats(options: &Options) {
+    use std::fs::File;
+    use std::io::Write;
+
+    let stats = stats::collect();
+    print!("{}", stats.summary());
+    if let Some(ref path) = options.stats_json {
+        if let Err(e) = File::create(path).and_then(|mut f| f.write_all(stats.to_json().as_bytes())) {
+            println!("Error: Could not write {}: {}", path, e);
+        }
+    }
+}
+
+fn duration(seconds: f64) -> Duration {
+    Duration::from_millis((seconds*1000.0) as u64)
+}
+
+fn listen(address: &str) -> TcpListener {
+    match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Error: Could not listen on {}: {}", address, e);
+            process::exit(1);
+        }
+    }
+}
 
+fn coordinate(address: &str, window: &Window, scene: &Scene, options: &Options) {
+    use std::fs;
 
-    let ref mut fout = File::create(&Path::new("rendering.png")).unwrap();
-    let _ = image::ImageRgb8(imgbuf).save(fout, image::PNG);
+    let path = match options.scene {
+        Some(ref path) => path,
+        None => {
+            println!("Error: --coordinator requires --scene, the workers load the same file");
+            process::exit(1);
+        }
+    };
+    // Workers started from another directory still find the scene
+    let path = fs::canonicalize(path).map(|p| p.to_string_lossy().into_owned()).unwrap_or(path.clone());
+    let (aovs, passes) = passes(scene, options);
+    let job = Job {
+        scene: path,
+        width: window.width,
+        height: window.height,
+        seed: window.seed,
+        samples: window.samples,
+        adaptive: window.adaptive.clone(),
+        aovs: passes.clone()
+    };
+    match distributed::coordinate(listen(address), job, options.tile_size, options.tile_samples, |line| println!("{}", line)) {
+        Ok(accumulator) => write_aovs(scene, &aovs, &passes, &accumulator, options),
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    }
 }
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(ref address) = options.server {
+        server::serve(listen(address), |line| println!("{}", line));
+        return;
+    }
+    if let Some(ref address) = options.worker {
+        if let Err(e) = distributed::work(address, |line| println!("{}", line)) {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+        report_stats(&options);
+        return;
+    }
     let mut window = Window::new(1024, 768);
     // let window = Window::new(3, 3);
-    let mut scene = Scene::new();
-    draw_png(&window, &scene)
+    let mut scene = match options.scene {
+        Some(ref path) => match Scene::load(&PathBuf::from(path)) {
+            Ok(scene) => scene,
+            Err(e) => {
+                println!("Error: {}", e);
+                process::exit(1);
+            }
+        },
+        None => Scene::new()
+    };
+    if let Some(ref view) = scene.view {
+        view.apply(&mut window.camera);
+    }
+    scene.aovs.extend(options.aovs.iter().cloned());
+    if let Some(samples) = options.samples {
+        window.samples = samples;
+    } else if (options.time_budget.is_some() || options.noise_target.is_some()) && options.coordinator.is_none() {
+        // Without a sample count the time and noise targets alone end the rendering
+        window.samples = ::std::u32::MAX;
+    }
+    if let Some(threshold) = options.adaptive {
+        window.adaptive = Some(AdaptiveSampler {
+            min_samples: options.min_samples,
+            max_samples: window.samples.max(options.min_samples),
+            threshold: threshold
+        });
+    }
+    window.seed = options.seed;
+    window.progressive.pass_samples = options.pass_samples;
+    window.progressive.time_budget = options.time_budget.map(duration);
+    window.progressive.noise_target = options.noise_target;
+    window.progressive.write_interval = options.write_interval.map(duration);
+    window.progressive.checkpoint = options.checkpoint.as_ref().map(PathBuf::from);
+    window.progressive.checkpoint_interval = options.checkpoint_interval.map(duration);
+    if let Some(ref address) = options.viewer {
+        if options.samples.is_none() {
+            window.samples = viewer::DEFAULT_SAMPLES;
+        }
+        viewer::view(listen(address), window, &scene, |line| println!("{}", line));
+    } else if let Some(ref address) = options.coordinator {
+        coordinate(address, &window, &scene, &options)
+    } else if scene.aovs.is_empty() && options.exr.is_none() && !options.denoise && options.heatmap.is_none()
+        && options.checkpoint.is_none() && options.debug.is_none() {
+        draw_png(&window, &scene)
+    } else {
+        draw_aovs(&window, &scene, &options)
+    }
+    report_stats(&options);
 }
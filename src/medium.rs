@@ -0,0 +1,626 @@
+use std::f64;
+use std::f64::consts::PI;
+use nalgebra::{Norm, Cross, Inverse, Matrix4};
+use types::*;
+use ray::Ray;
+use grid::{Grid, lookup};
+use spectrum::{Wavelengths, blackbody, blackbody_spectrum, unbounded, illuminant};
+use colorspace::{ColorSpace, conversion};
+use sampling;
+
+// Participating media. Rays refer to the medium they travel through by its index in the
+// scene, the segment between tmin and tmax of a ray lies within that medium.
+// Source: Physically based Rendering, Chapter Volume Scattering and Light Transport
+
+pub struct MediumSample {
+    pub t: Option<Float>,   // Distance of the scattering event, None if the ray reaches tmax
+    pub weight: Color,      // Throughput up to the event over its sampling density
+    pub emission: Color     // Light emitted towards the ray origin before the event
+}
+
+pub trait Medium: Sync {
+    // Fraction of light passing the ray segment
+    fn transmittance(&self, ray: &Ray) -> Color;
+    // Free flight distance along the ray segment
+    fn sample(&self, ray: &Ray) -> MediumSample;
+    fn phase(&self) -> &HenyeyGreenstein;
+}
+
+// Henyey-Greenstein phase function, g is the mean cosine of the scattering angle. Positive
+// values scatter forward, negative values backward.
+#[derive(Debug, Clone, Copy)]
+pub struct HenyeyGreenstein {
+    pub g: Float
+}
+
+impl HenyeyGreenstein {
+    // Density of scattering by the angle whose cosine is cos_theta to the direction of travel
+    pub fn eval(&self, cos_theta: Float) -> Float {
+        let g = self.g;
+        let denom = 1.0 + g*g - 2.0*g*cos_theta;
+        (1.0 - g*g)/(4.0*PI*denom*denom.max(0.0).sqrt())
+    }
+
+    // Scattered direction of light traveling along dir, and its density
+    pub fn sample(&self, dir: &Vec3, s: Float, t: Float) -> (Vec3, Float) {
+        let g = self.g;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0*s
+        } else {
+            let sq = (1.0 - g*g)/(1.0 - g + 2.0*g*s);
+            (1.0 + g*g - sq*sq)/(2.0*g)
+        }.max(-1.0).min(1.0);
+        let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+        let phi = 2.0*PI*t;
+        let (u, v) = frame(dir);
+        let wi = u*(sin_theta*phi.cos()) + v*(sin_theta*phi.sin()) + *dir*cos_theta;
+        (wi, self.eval(cos_theta))
+    }
+}
+
+// Medium with constant coefficients, given per unit length
+pub struct HomogeneousMedium {
+    pub sigma_a: Color, // Absorption
+    pub sigma_s: Color, // Scattering
+    pub phase: HenyeyGreenstein
+}
+
+impl HomogeneousMedium {
+    // Absorption and scattering at the wavelengths of the ray
+    pub fn coefficients(&self, wavelengths: Option<&Wavelengths>) -> (Color, Color) {
+        (unbounded(&self.sigma_a, wavelengths), unbounded(&self.sigma_s, wavelengths))
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn transmittance(&self, ray: &Ray) -> Color {
+        let (sigma_a, sigma_s) = self.coefficients(ray.wavelengths.as_ref());
+        attenuation(&(sigma_a + sigma_s), ray.tmax - ray.tmin)
+    }
+
+    // The distance is sampled for a randomly chosen channel, the density is averaged over
+    // all channels so chromatic media stay unbiased
+    fn sample(&self, ray: &Ray) -> MediumSample {
+        let (sigma_a, sigma_s) = self.coefficients(ray.wavelengths.as_ref());
+        let sigma_t = sigma_a + sigma_s;
+        let channel = ((sampling::next_float()*3.0) as usize).min(2);
+        let u = sampling::next_float();
+        let length = ray.tmax - ray.tmin;
+        let t = if sigma_t[channel] > 0.0 { -(1.0 - u).ln()/sigma_t[channel] } else { f64::INFINITY };
+        let scattered = t < length;
+        let t = t.min(length);
+        let tr = attenuation(&sigma_t, t);
+        let density = if scattered { sigma_t*tr } else { tr };
+        let pdf = (density.x + density.y + density.z)/3.0;
+        if pdf <= 0.0 {
+            return MediumSample { t: None, weight: Color::new(0.0, 0.0, 0.0), emission: Color::new(0.0, 0.0, 0.0) };
+        }
+        let emission = Color::new(0.0, 0.0, 0.0);
+        if scattered {
+            MediumSample { t: Some(ray.tmin + t), weight: tr*sigma_s/pdf, emission: emission }
+        } else {
+            MediumSample { t: None, weight: tr/pdf, emission: emission }
+        }
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+// Heterogeneous medium whose coefficients are scaled by the density of a voxel grid. Emission
+// comes from an emission grid and from black body radiation of a temperature grid, both in
+// the index space of the density grid.
+pub struct GridMedium {
+    density: Box<Grid>,
+    sigma_a: Color,     // Absorption at unit density
+    sigma_s: Color,     // Scattering at unit density
+    phase: HenyeyGreenstein,
+    world_to_index: Matrix4<Float>,
+    majorant: MajorantGrid,
+    emission: Option<(Box<Grid>, Color)>,   // Grid and color it scales
+    temperature: Option<(Box<Grid>, Vec<Color>)>,   // Grid and black body colors per Kelvin
+    blackbody_scale: Float
+}
+
+// Probability of ending ratio tracking once the transmittance falls below 0.1
+const ROULETTE: Float = 0.75;
+
+// Temperatures are clamped to this many Kelvin, which also bounds the black body table
+const MAX_TEMPERATURE: Float = 50000.0;
+
+impl GridMedium {
+    pub fn new(density: Box<Grid>, sigma_a: Color, sigma_s: Color, phase: HenyeyGreenstein,
+               index_to_world: &Matrix4<Float>) -> Result<GridMedium, String> {
+        let world_to_index = index_to_world.inverse().ok_or("Grid transform is not invertible".to_string())?;
+        let majorant = MajorantGrid::new(&*density);
+        Ok(GridMedium {
+            density: density,
+            sigma_a: sigma_a,
+            sigma_s: sigma_s,
+            phase: phase,
+            world_to_index: world_to_index,
+            majorant: majorant,
+            emission: None,
+            temperature: None,
+            blackbody_scale: 1.0
+        })
+    }
+
+    pub fn set_emission(&mut self, grid: Box<Grid>, color: Color) {
+        self.emission = Some((grid, color));
+    }
+
+    // The grid holds temperatures in Kelvin, the black body radiation is multiplied by scale
+    pub fn set_temperature(&mut self, grid: Box<Grid>, scale: Float, working: ColorSpace) {
+        let mut hottest: Float = 0.0;
+        grid.for_each(&mut |_, t| hottest = hottest.max(t.min(MAX_TEMPERATURE)));
+        let m = conversion(ColorSpace::Linear, working);
+        let colors = (0..hottest.ceil() as usize + 2).map(|t| m*blackbody(t as Float)).collect();
+        self.temperature = Some((grid, colors));
+        self.blackbody_scale = scale;
+    }
+
+    // Ray origin and direction in index space, the ray parameter stays the same
+    fn to_index(&self, ray: &Ray) -> (Pnt3, Vec3) {
+        let o = self.world_to_index*Pnt4::new(ray.origin.x, ray.origin.y, ray.origin.z, 1.0);
+        let d = self.world_to_index*Vec4::new(ray.dir.x, ray.dir.y, ray.dir.z, 0.0);
+        (Pnt3::new(o.x, o.y, o.z), Vec3::new(d.x, d.y, d.z))
+    }
+
+    fn emitted(&self, p: &Pnt3, wavelengths: Option<&Wavelengths>) -> Color {
+        let mut le = Color::new(0.0, 0.0, 0.0);
+        if let Some((ref grid, color)) = self.emission {
+            le += illuminant(&color, wavelengths)*lookup(&**grid, p).max(0.0);
+        }
+        if let Some((ref grid, ref colors)) = self.temperature {
+            let t = lookup(&**grid, p).max(0.0).min(MAX_TEMPERATURE);
+            le += match wavelengths {
+                Some(wl) => blackbody_spectrum(t, wl),
+                None => {
+                    let i = (t as usize).min(colors.len() - 2);
+                    let f = (t - i as Float).min(1.0);
+                    colors[i]*(1.0 - f) + colors[i + 1]*f
+                }
+            }*self.blackbody_scale;
+        }
+        le
+    }
+
+    // Absorption and scattering at unit density and the wavelengths of the ray
+    fn coefficients(&self, ray: &Ray) -> (Color, Color) {
+        let wavelengths = ray.wavelengths.as_ref();
+        (unbounded(&self.sigma_a, wavelengths), unbounded(&self.sigma_s, wavelengths))
+    }
+}
+
+// Largest extinction coefficient of any channel
+fn max_component(c: &Color) -> Float {
+    c.x.max(c.y).max(c.z)
+}
+
+impl Medium for GridMedium {
+    // Ratio tracking
+    // Source: Novak et al., Residual Ratio Tracking for Estimating Attenuation in Participating Media
+    fn transmittance(&self, ray: &Ray) -> Color {
+        let (o, d) = self.to_index(ray);
+        let (sigma_a, sigma_s) = self.coefficients(ray);
+        let sigma_t = sigma_a + sigma_s;
+        let max_sigma_t = max_component(&sigma_t);
+        let white = Color::new(1.0, 1.0, 1.0);
+        let mut tr = white;
+        self.majorant.march(&o, &d, ray.tmin, ray.tmax, |t0, t1, max_density| {
+            let sigma_maj = max_density*max_sigma_t;
+            if sigma_maj <= 0.0 {
+                return true;
+            }
+            let mut t = t0;
+            loop {
+                t -= (1.0 - sampling::next_float()).ln()/sigma_maj;
+                if t >= t1 {
+                    return true;
+                }
+                let density = lookup(&*self.density, &(o + d*t)).max(0.0);
+                tr = tr*(white - sigma_t*(density/sigma_maj));
+                if tr.x.max(tr.y).max(tr.z) < 0.1 {
+                    if sampling::next_float() < ROULETTE {
+                        tr = Color::new(0.0, 0.0, 0.0);
+                        return false;
+                    }
+                    tr = tr/(1.0 - ROULETTE);
+                }
+            }
+        });
+        tr
+    }
+
+    // Delta tracking, with event probabilities averaged over the channels so chromatic media
+    // stay unbiased. Emission is gathered at every tentative collision.
+    // Source: Kutz et al., Spectral and Decomposition Tracking for Rendering Heterogeneous Volumes
+    fn sample(&self, ray: &Ray) -> MediumSample {
+        let (o, d) = self.to_index(ray);
+        let (unit_sigma_a, unit_sigma_s) = self.coefficients(ray);
+        let max_sigma_t = max_component(&(unit_sigma_a + unit_sigma_s));
+        let white = Color::new(1.0, 1.0, 1.0);
+        let mut weight = white;
+        let mut emission = Color::new(0.0, 0.0, 0.0);
+        let mut event = None;
+        self.majorant.march(&o, &d, ray.tmin, ray.tmax, |t0, t1, max_density| {
+            let sigma_maj = max_density*max_sigma_t;
+            if sigma_maj <= 0.0 {
+                return true;
+            }
+            let mut t = t0;
+            loop {
+                t -= (1.0 - sampling::next_float()).ln()/sigma_maj;
+                if t >= t1 {
+                    return true;
+                }
+                let p = o + d*t;
+                let density = lookup(&*self.density, &p).max(0.0);
+                let sigma_a = unit_sigma_a*density;
+                let sigma_s = unit_sigma_s*density;
+                let sigma_n = white*sigma_maj - sigma_a - sigma_s;
+                if self.emission.is_some() || self.temperature.is_some() {
+                    emission += weight*sigma_a*self.emitted(&p, ray.wavelengths.as_ref())/sigma_maj;
+                }
+                let p_a = average(&sigma_a)/sigma_maj;
+                let p_s = average(&sigma_s)/sigma_maj;
+                let p_n = (1.0 - p_a - p_s).max(0.0);
+                let u = sampling::next_float();
+                if u < p_a {
+                    weight = Color::new(0.0, 0.0, 0.0);
+                    return false;
+                } else if u < p_a + p_s {
+                    weight = weight*sigma_s/(sigma_maj*p_s);
+                    event = Some(t);
+                    return false;
+                } else if p_n > 0.0 {
+                    weight = weight*sigma_n/(sigma_maj*p_n);
+                }
+            }
+        });
+        MediumSample { t: event, weight: weight, emission: emission }
+    }
+
+    fn phase(&self) -> &HenyeyGreenstein {
+        &self.phase
+    }
+}
+
+// Cells of the majorant grid per axis
+const MAJORANT_RESOLUTION: usize = 16;
+
+// Upper bound of the density over the cells of a coarse grid in index space
+struct MajorantGrid {
+    lo: Pnt3,
+    hi: Pnt3,
+    cell: Vec3,     // Cell size
+    max: Vec<Float>
+}
+
+impl MajorantGrid {
+    fn new(grid: &Grid) -> MajorantGrid {
+        let n = MAJORANT_RESOLUTION;
+        let (min, max) = match grid.index_bounds() {
+            Some(bounds) => bounds,
+            None => return MajorantGrid {
+                lo: Pnt3::new(0.0, 0.0, 0.0),
+                hi: Pnt3::new(0.0, 0.0, 0.0),
+                cell: Vec3::new(1.0, 1.0, 1.0),
+                max: vec![0.0; n*n*n]
+            }
+        };
+        // Interpolation spreads a voxel over one voxel in every direction
+        let lo = Pnt3::new(min[0] as Float - 1.0, min[1] as Float - 1.0, min[2] as Float - 1.0);
+        let hi = Pnt3::new(max[0] as Float + 1.0, max[1] as Float + 1.0, max[2] as Float + 1.0);
+        let cell = (hi - lo)/n as Float;
+        let mut majorant = vec![0.0; n*n*n];
+        grid.for_each(&mut |v, value| {
+            if value <= 0.0 {
+                return;
+            }
+            let mut c0 = [0; 3];
+            let mut c1 = [0; 3];
+            for a in 0..3 {
+                let cell_of = |x: Float| (((x - lo[a])/cell[a]).floor().max(0.0) as usize).min(n - 1);
+                c0[a] = cell_of(v[a] as Float - 1.0);
+                c1[a] = cell_of(v[a] as Float + 1.0);
+            }
+            for z in c0[2]..c1[2] + 1 {
+                for y in c0[1]..c1[1] + 1 {
+                    for x in c0[0]..c1[0] + 1 {
+                        let m = &mut majorant[(z*n + y)*n + x];
+                        *m = Float::max(*m, value);
+                    }
+                }
+            }
+        });
+        MajorantGrid { lo: lo, hi: hi, cell: cell, max: majorant }
+    }
+
+    // Calls f with the segments of the ray within the cells it passes and their majorant,
+    // until f returns false
+    fn march<F: FnMut(Float, Float, Float) -> bool>(&self, o: &Pnt3, d: &Vec3, tmin: Float, tmax: Float, mut f: F) {
+        let n = MAJORANT_RESOLUTION as i64;
+        let mut t0 = tmin;
+        let mut t1 = tmax;
+        for a in 0..3 {
+            let mut near = (self.lo[a] - o[a])/d[a];
+            let mut far = (self.hi[a] - o[a])/d[a];
+            if near > far {
+                ::std::mem::swap(&mut near, &mut far);
+            }
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+        }
+        if !(t0 < t1) {
+            return;
+        }
+        let p = *o + *d*t0;
+        let mut cell = [0i64; 3];
+        let mut step = [0i64; 3];
+        let mut next = [f64::INFINITY; 3];
+        let mut delta = [f64::INFINITY; 3];
+        for a in 0..3 {
+            cell[a] = (((p[a] - self.lo[a])/self.cell[a]).floor() as i64).max(0).min(n - 1);
+            if d[a] > 0.0 {
+                step[a] = 1;
+                next[a] = t0 + (self.lo[a] + (cell[a] + 1) as Float*self.cell[a] - p[a])/d[a];
+                delta[a] = self.cell[a]/d[a];
+            } else if d[a] < 0.0 {
+                step[a] = -1;
+                next[a] = t0 + (self.lo[a] + cell[a] as Float*self.cell[a] - p[a])/d[a];
+                delta[a] = -self.cell[a]/d[a];
+            }
+        }
+        let mut t = t0;
+        loop {
+            let axis = if next[0] < next[1] { if next[0] < next[2] { 0 } else { 2 } } else if next[1] < next[2] { 1 } else { 2 };
+            let end = next[axis].min(t1);
+            let index = ((cell[2]*n + cell[1])*n + cell[0]) as usize;
+            if end > t && !f(t, end, self.max[index]) {
+                return;
+            }
+            if end >= t1 {
+                return;
+            }
+            t = end;
+            cell[axis] += step[axis];
+            if cell[axis] < 0 || cell[axis] >= n {
+                return;
+            }
+            next[axis] += delta[axis];
+        }
+    }
+}
+
+fn average(c: &Color) -> Float {
+    (c.x + c.y + c.z)/3.0
+}
+
+// Beer-Lambert attenuation over distance d, channels without extinction are not attenuated
+// even over infinite distances
+pub fn attenuation(sigma_t: &Color, d: Float) -> Color {
+    let tr = |sigma: Float| if sigma > 0.0 { (-sigma*d).exp() } else { 1.0 };
+    Color::new(tr(sigma_t.x), tr(sigma_t.y), tr(sigma_t.z))
+}
+
+// Two unit vectors orthogonal to w and each other
+fn frame(w: &Vec3) -> (Vec3, Vec3) {
+    let a = if w.x.abs() > 0.9 { Vec3::new(0.0, 1.0, 0.0) } else { Vec3::new(1.0, 0.0, 0.0) };
+    let u = w.cross(&a).normalize();
+    let v = w.cross(&u);
+    (u, v)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Norm, Dot};
+    use types::*;
+    use chi2;
+    use chi2::Domain;
+    use std::f64;
+    use std::rc::Rc;
+    use ray::Ray;
+    use scene::Scene;
+    use renderer::Renderer;
+    use shader::{Shader, MediumShader};
+    use shape::Sphere;
+    use grid::DenseGrid;
+    use nalgebra::new_identity;
+    use super::*;
+
+    #[test]
+    fn chi2_henyey_greenstein() {
+        let dir = Vec3::new(0.2, 0.6, -0.4).normalize();
+        for &g in &[0.0, 0.3, 0.8, -0.5] {
+            let phase = HenyeyGreenstein { g: g };
+            let result = chi2::test(&format!("henyey_greenstein_{}", g), Domain::Sphere,
+                |s, t| {
+                    let (wi, pdf) = phase.sample(&dir, s, t);
+                    assert!((pdf - phase.eval(dir.dot(&wi))).abs() < 1e-6*pdf.max(1.0));
+                    Some(wi)
+                },
+                |v| phase.eval(dir.dot(v)));
+            if let Err(e) = result {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn free_flight_is_unbiased() {
+        // The expected weight of passing the segment is its transmittance
+        let medium = HomogeneousMedium {
+            sigma_a: Color::new(0.1, 0.2, 0.05),
+            sigma_s: Color::new(0.3, 0.6, 0.9),
+            phase: HenyeyGreenstein { g: 0.0 }
+        };
+        let ray = Ray::new(&Pnt3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.5, 2.5);
+        let n = 200000;
+        let mut passed = Color::new(0.0, 0.0, 0.0);
+        for _ in 0..n {
+            let sample = medium.sample(&ray);
+            if sample.t.is_none() {
+                passed += sample.weight;
+            }
+        }
+        let expected = medium.transmittance(&ray);
+        for i in 0..3 {
+            assert!((passed[i]/n as Float - expected[i]).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn transmittance_through_boundary() {
+        // Unit sphere of smoke inside thin fog, the shadow ray passes through its center
+        let mut scene = Scene::empty();
+        scene.media.push(Box::new(HomogeneousMedium {
+            sigma_a: Color::new(0.01, 0.01, 0.01),
+            sigma_s: Color::new(0.0, 0.0, 0.0),
+            phase: HenyeyGreenstein { g: 0.0 }
+        }));
+        scene.media.push(Box::new(HomogeneousMedium {
+            sigma_a: Color::new(0.5, 1.0, 0.0),
+            sigma_s: Color::new(0.5, 0.0, 0.0),
+            phase: HenyeyGreenstein { g: 0.0 }
+        }));
+        scene.fog = Some(0);
+        let shader: Rc<Shader> = Rc::new(MediumShader { medium: 1 });
+        scene.shapes.push(Box::new(Sphere { position: Pnt3::new(0.0, 0.0, 0.0), radius: 1.0, shader: shader }));
+        let renderer = Renderer::new(&scene);
+        let mut ray = Ray::new(&Pnt3::new(-5.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0, 10.0);
+        ray.medium = scene.fog;
+        let tr = renderer.transmittance(&ray);
+        let expected = Color::new((-0.08 - 2.0f64).exp(), (-0.08 - 2.0f64).exp(), (-0.08f64).exp());
+        for i in 0..3 {
+            assert!((tr[i] - expected[i]).abs() < 1e-6, "{:?} {:?}", tr, expected);
+        }
+        // Unbounded rays leave the fog with nothing left
+        ray.tmax = f64::INFINITY;
+        assert_eq!(renderer.transmittance(&ray), Color::new(0.0, 0.0, 0.0));
+    }
+
+    fn smoke_grid() -> DenseGrid {
+        let resolution = [6, 5, 7];
+        let mut values = Vec::new();
+        for k in 0..resolution[2] {
+            for j in 0..resolution[1] {
+                for i in 0..resolution[0] {
+                    values.push((0.6 + 0.5*((i + 2*j) as f32).sin()*(k as f32).cos()).max(0.0));
+                }
+            }
+        }
+        DenseGrid { resolution: resolution, values: values }
+    }
+
+    // Smoke grid scaled to half size and moved along x, glowing where it is dense
+    fn smoke() -> GridMedium {
+        let index_to_world = Matrix4::new(
+            0.5, 0.0, 0.0, 1.0,
+            0.0, 0.5, 0.0, 0.0,
+            0.0, 0.0, 0.5, 0.0,
+            0.0, 0.0, 0.0, 1.0
+        );
+        let mut medium = GridMedium::new(Box::new(smoke_grid()), Color::new(0.5, 1.0, 0.2),
+            Color::new(1.0, 0.5, 0.0), HenyeyGreenstein { g: 0.0 }, &index_to_world).unwrap();
+        medium.set_emission(Box::new(smoke_grid()), Color::new(1.0, 2.0, 3.0));
+        medium
+    }
+
+    fn smoke_ray() -> Ray {
+        Ray::new(&Pnt3::new(-1.0, 1.1, 1.3), Vec3::new(1.0, 0.1, 0.05).normalize(), 0.0, 6.0)
+    }
+
+    // Transmittance and emission reaching the ray origin by midpoint quadrature
+    fn quadrature(medium: &GridMedium, ray: &Ray) -> (Color, Color) {
+        let grid = smoke_grid();
+        let steps = 20000;
+        let dt = (ray.tmax - ray.tmin)/steps as Float;
+        let mut depth = 0.0;
+        let mut emission = Color::new(0.0, 0.0, 0.0);
+        for n in 0..steps {
+            let t = ray.tmin + (n as Float + 0.5)*dt;
+            let p = ray.origin + ray.dir*t;
+            let p = Pnt3::new((p.x - 1.0)*2.0, p.y*2.0, p.z*2.0);
+            let density = lookup(&grid, &p);
+            let tr = attenuation(&(medium.sigma_a + medium.sigma_s), depth + density*dt*0.5);
+            emission += tr*medium.sigma_a*density*medium.emitted(&p, None)*dt;
+            depth += density*dt;
+        }
+        (attenuation(&(medium.sigma_a + medium.sigma_s), depth), emission)
+    }
+
+    #[test]
+    fn ratio_tracking_is_unbiased() {
+        let medium = smoke();
+        let ray = smoke_ray();
+        let (expected, _) = quadrature(&medium, &ray);
+        let n = 50000;
+        let mut tr = Color::new(0.0, 0.0, 0.0);
+        for _ in 0..n {
+            tr += medium.transmittance(&ray);
+        }
+        for i in 0..3 {
+            assert!((tr[i]/n as Float - expected[i]).abs() < 0.01, "{:?} {:?}", tr/n as Float, expected);
+        }
+    }
+
+    #[test]
+    fn delta_tracking_is_unbiased() {
+        // Passing weight matches the transmittance, gathered emission the emitted radiance
+        let medium = smoke();
+        let ray = smoke_ray();
+        let (tr, le) = quadrature(&medium, &ray);
+        let n = 300000;
+        let mut passed = Color::new(0.0, 0.0, 0.0);
+        let mut emission = Color::new(0.0, 0.0, 0.0);
+        for _ in 0..n {
+            let sample = medium.sample(&ray);
+            if sample.t.is_none() {
+                passed += sample.weight;
+            }
+            emission += sample.emission;
+        }
+        for i in 0..3 {
+            assert!((passed[i]/n as Float - tr[i]).abs() < 0.01, "{:?} {:?}", passed/n as Float, tr);
+            assert!((emission[i]/n as Float - le[i]).abs() < 0.02*le[i].max(1.0), "{:?} {:?}", emission/n as Float, le);
+        }
+    }
+
+    #[test]
+    fn majorant_bounds_density() {
+        let grid = smoke_grid();
+        let majorant = MajorantGrid::new(&grid);
+        let o = Pnt3::new(-3.0, 0.3, 7.5);
+        let d = Vec3::new(1.0, 0.4, -0.8);
+        let mut covered = 0.0;
+        let mut last = 0.0;
+        majorant.march(&o, &d, 0.0, 100.0, |t0, t1, max| {
+            assert!(t0 >= last && t1 > t0);
+            last = t1;
+            covered += t1 - t0;
+            for n in 0..10 {
+                let t = t0 + (t1 - t0)*(n as Float + 0.5)/10.0;
+                assert!(lookup(&grid, &(o + d*t)) <= max + 1e-9);
+            }
+            true
+        });
+        assert!(covered > 0.0);
+    }
+
+    #[test]
+    fn temperatures_are_clamped() {
+        let hot = || Box::new(DenseGrid { resolution: [1, 1, 1], values: vec![1e30] });
+        let mut medium = GridMedium::new(hot(), Color::new(1.0, 1.0, 1.0), Color::new(0.0, 0.0, 0.0),
+            HenyeyGreenstein { g: 0.0 }, &new_identity(4)).unwrap();
+        medium.set_temperature(hot(), 1.0, ColorSpace::Linear);
+        assert!(medium.temperature.as_ref().unwrap().1.len() <= MAX_TEMPERATURE as usize + 2);
+        let le = medium.emitted(&Pnt3::new(0.0, 0.0, 0.0), None);
+        let expected = blackbody(MAX_TEMPERATURE);
+        for i in 0..3 {
+            assert!((le[i] - expected[i]).abs() <= 1e-9*expected[i], "{:?} {:?}", le, expected);
+        }
+    }
+}
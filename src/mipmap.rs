@@ -0,0 +1,259 @@
+use nalgebra::Norm;
+use std::vec::Vec;
+use types::*;
+
+// Source: Physically based Rendering, Chapter Texture, Section Image Texture
+
+const EWA_MAX_ANISOTROPY: Float = 8.0;
+const EWA_ALPHA: Float = 2.0;
+
+#[derive(Debug, Clone, Copy)]
+pub enum FilterMode {
+    Bilinear,
+    Trilinear,
+    Ewa
+}
+
+struct Level {
+    width: u32,
+    height: u32,
+    pixels: Vec<Color>
+}
+
+impl Level {
+    fn texel(&self, x: i64, y: i64) -> Color {
+        let w = self.width as i64;
+        let h = self.height as i64;
+        let x = ((x % w) + w) % w;
+        let y = ((y % h) + h) % h;
+        self.pixels[(y*w + x) as usize]
+    }
+
+    fn bilinear(&self, uv: &Vec2) -> Color {
+        let x = uv.x*self.width as Float - 0.5;
+        let y = uv.y*self.height as Float - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        self.texel(x0, y0)*((1.0 - fx)*(1.0 - fy)) + self.texel(x0 + 1, y0)*(fx*(1.0 - fy)) +
+            self.texel(x0, y0 + 1)*((1.0 - fx)*fy) + self.texel(x0 + 1, y0 + 1)*(fx*fy)
+    }
+
+    // Box filtered half resolution copy. Odd sizes do not halve evenly, every texel of the
+    // next level averages the area of this level it covers, cutting texels on the boundary.
+    fn downsample(&self) -> Level {
+        let width = (self.width/2).max(1);
+        let height = (self.height/2).max(1);
+        let wx = box_weights(self.width, width);
+        let wy = box_weights(self.height, height);
+        let mut pixels = Vec::with_capacity((width*height) as usize);
+        for row in &wy {
+            for column in &wx {
+                let mut c = Color::new(0.0, 0.0, 0.0);
+                for &(sy, fy) in row {
+                    for &(sx, fx) in column {
+                        c += self.texel(sx, sy)*(fx*fy);
+                    }
+                }
+                pixels.push(c);
+            }
+        }
+        Level {
+            width: width,
+            height: height,
+            pixels: pixels
+        }
+    }
+}
+
+// Source texels and their weights for every texel when resampling a row of size texels to
+// target texels with a box filter
+fn box_weights(size: u32, target: u32) -> Vec<Vec<(i64, Float)>> {
+    let scale = size as Float/target as Float;
+    (0..target).map(|i| {
+        let (a, b) = (i as Float*scale, (i + 1) as Float*scale);
+        (a.floor() as i64..b.ceil() as i64).map(|j| {
+            let overlap = b.min((j + 1) as Float) - a.max(j as Float);
+            (j, overlap/scale)
+        }).filter(|&(_, w)| w > 0.0).collect()
+    }).collect()
+}
+
+pub struct MipMap {
+    levels: Vec<Level>
+}
+
+impl MipMap {
+    pub fn new(width: u32, height: u32, pixels: Vec<Color>) -> MipMap {
+        let mut levels = vec![Level { width: width, height: height, pixels: pixels }];
+        loop {
+            let next = {
+                let last = &levels[levels.len() - 1];
+                if last.width == 1 && last.height == 1 {
+                    break;
+                }
+                last.downsample()
+            };
+            levels.push(next);
+        }
+        MipMap {
+            levels: levels
+        }
+    }
+
+    pub fn texel(&self, level: usize, x: i64, y: i64) -> Color {
+        self.levels[level.min(self.levels.len() - 1)].texel(x, y)
+    }
+
+    pub fn bilinear(&self, level: usize, uv: &Vec2) -> Color {
+        self.levels[level.min(self.levels.len() - 1)].bilinear(uv)
+    }
+
+    // Filter width is given in texture space, i.e. [0, 1]
+    pub fn trilinear(&self, uv: &Vec2, width: Float) -> Color {
+        let n = self.levels.len();
+        let level = (n - 1) as Float + width.max(1e-8).log2();
+        if level <= 0.0 {
+            return self.bilinear(0, uv);
+        }
+        if level >= (n - 1) as Float {
+            return self.texel(n - 1, 0, 0);
+        }
+        let i = level.floor();
+        let f = level - i;
+        let i = i as usize;
+        self.bilinear(i, uv)*(1.0 - f) + self.bilinear(i + 1, uv)*f
+    }
+
+    // Elliptically weighted average over the footprint spanned by the two axes
+    pub fn ewa(&self, uv: &Vec2, dst0: &Vec2, dst1: &Vec2) -> Color {
+        let (mut dst0, mut dst1) = (*dst0, *dst1);
+        if dst0.norm_squared() < dst1.norm_squared() {
+            let tmp = dst0;
+            dst0 = dst1;
+            dst1 = tmp;
+        }
+        let major = dst0.norm();
+        let mut minor = dst1.norm();
+        if minor*EWA_MAX_ANISOTROPY < major && minor > 0.0 {
+            let scale = major/(minor*EWA_MAX_ANISOTROPY);
+            dst1 = dst1*scale;
+            minor *= scale;
+        }
+        if minor == 0.0 {
+            return self.bilinear(0, uv);
+        }
+        let n = self.levels.len();
+        let lod = ((n - 1) as Float + minor.log2()).max(0.0);
+        let ilod = lod.floor();
+        let f = lod - ilod;
+        let ilod = ilod as usize;
+        self.ewa_level(ilod, uv, &dst0, &dst1)*(1.0 - f) + self.ewa_level(ilod + 1, uv, &dst0, &dst1)*f
+    }
+
+    fn ewa_level(&self, level: usize, uv: &Vec2, dst0: &Vec2, dst1: &Vec2) -> Color {
+        if level >= self.levels.len() {
+            return self.texel(self.levels.len() - 1, 0, 0);
+        }
+        let l = &self.levels[level];
+        let w = l.width as Float;
+        let h = l.height as Float;
+        let s = uv.x*w - 0.5;
+        let t = uv.y*h - 0.5;
+        let d0 = Vec2::new(dst0.x*w, dst0.y*h);
+        let d1 = Vec2::new(dst1.x*w, dst1.y*h);
+
+        let mut a = d0.y*d0.y + d1.y*d1.y + 1.0;
+        let mut b = -2.0*(d0.x*d0.y + d1.x*d1.y);
+        let mut c = d0.x*d0.x + d1.x*d1.x + 1.0;
+        let inv_f = 1.0/(a*c - b*b*0.25);
+        a *= inv_f;
+        b *= inv_f;
+        c *= inv_f;
+
+        let det = -b*b + 4.0*a*c;
+        let inv_det = 1.0/det;
+        let u_sqrt = (det*c).sqrt();
+        let v_sqrt = (a*det).sqrt();
+        let s0 = (s - 2.0*inv_det*u_sqrt).ceil() as i64;
+        let s1 = (s + 2.0*inv_det*u_sqrt).floor() as i64;
+        let t0 = (t - 2.0*inv_det*v_sqrt).ceil() as i64;
+        let t1 = (t + 2.0*inv_det*v_sqrt).floor() as i64;
+
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        let mut sum_weights = 0.0;
+        for it in t0..t1 + 1 {
+            let tt = it as Float - t;
+            for is in s0..s1 + 1 {
+                let ss = is as Float - s;
+                let r2 = a*ss*ss + b*ss*tt + c*tt*tt;
+                if r2 < 1.0 {
+                    let weight = (-EWA_ALPHA*r2).exp() - (-EWA_ALPHA).exp();
+                    sum += l.texel(is, it)*weight;
+                    sum_weights += weight;
+                }
+            }
+        }
+        if sum_weights <= 0.0 {
+            return l.bilinear(uv);
+        }
+        sum/sum_weights
+    }
+
+    pub fn lookup(&self, filter: FilterMode, uv: &Vec2, duvdx: &Vec2, duvdy: &Vec2) -> Color {
+        match filter {
+            FilterMode::Bilinear => self.bilinear(0, uv),
+            FilterMode::Trilinear => {
+                let width = 2.0*duvdx.x.abs().max(duvdx.y.abs()).max(duvdy.x.abs()).max(duvdy.y.abs());
+                self.trilinear(uv, width)
+            },
+            FilterMode::Ewa => self.ewa(uv, duvdx, duvdy)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use super::*;
+
+    fn average(level: &Level) -> Color {
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        for c in &level.pixels {
+            sum += *c;
+        }
+        sum/level.pixels.len() as Float
+    }
+
+    #[test]
+    fn levels_keep_the_average() {
+        for &(width, height) in &[(8, 8), (7, 5), (13, 1), (3, 10)] {
+            let pixels = (0..width*height).map(|i| {
+                let v = ((i*37 + i/width*11) % 17) as Float;
+                Color::new(v, 2.0*v, i as Float)
+            }).collect();
+            let mipmap = MipMap::new(width, height, pixels);
+            let expected = average(&mipmap.levels[0]);
+            let last = &mipmap.levels[mipmap.levels.len() - 1];
+            assert_eq!((last.width, last.height), (1, 1));
+            for level in &mipmap.levels {
+                let a = average(level);
+                for i in 0..3 {
+                    assert!((a[i] - expected[i]).abs() < 1e-9*expected[i].max(1.0), "{}x{} {:?} {:?}", width, height, a, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn odd_rows_are_weighted_in() {
+        // The middle texel of five is shared by both texels of the next level
+        let pixels = (0..5).map(|i| Color::new(3.0*i as Float, 0.0, 0.0)).collect();
+        let mipmap = MipMap::new(5, 1, pixels);
+        let level = &mipmap.levels[1];
+        assert!((level.pixels[0].x - (0.0 + 3.0 + 0.5*6.0)/2.5).abs() < 1e-12);
+        assert!((level.pixels[1].x - (0.5*6.0 + 9.0 + 12.0)/2.5).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,349 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::vec::Vec;
+use nalgebra::Matrix4;
+use types::*;
+use binary::{Reader, invalid};
+use grid::{SparseGrid, BRICK_SIZE, TILE_SIZE};
+
+// Reader for uncompressed NanoVDB files with float grids. The leaf nodes of the tree are
+// 8x8x8 voxel bricks, so the tree is flattened into a sparse grid. Active tiles of the
+// lower internal nodes become bricks, those of the upper nodes tiles of the sparse grid.
+// Source: NanoVDB.h of OpenVDB, file and grid layout of version 32
+
+// "NanoVDB0" to "NanoVDB2", the magic changed between versions
+const MAGIC: u64 = 0x304244566f6e614e;
+const MAGIC_MASK: u64 = 0xfcffffffffffffff;
+
+const FILE_HEADER_SIZE: usize = 16;
+const META_DATA_SIZE: usize = 176;
+const META_NAME_SIZE: usize = 136;
+const META_CODEC: usize = 168;
+const GRID_DATA_SIZE: usize = 672;
+const GRID_TYPE_FLOAT: u32 = 1;
+
+// Offsets within the grid data
+const GRID_NAME: usize = 40;
+const MAP_MATRIX: usize = 384;  // Index to world matrix, row major doubles
+const MAP_TRANSLATION: usize = 528;
+const GRID_TYPE: usize = 636;
+
+// Node layouts of float grids, the tables of the internal nodes are aligned to 32 bytes
+const LEAF_SIZE: usize = 96 + 512*4;
+const LOWER_LOG2: u32 = 4;
+const UPPER_LOG2: u32 = 5;
+
+fn internal_header(log2: u32) -> usize {
+    let mask = (1 << 3*log2)/8;
+    (24 + 8 + 2*mask + 16 + 31)/32*32
+}
+
+fn internal_size(log2: u32) -> usize {
+    internal_header(log2) + (1 << 3*log2)*8
+}
+
+pub fn load(path: &Path, name: Option<&str>) -> io::Result<(SparseGrid, Matrix4<Float>)> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    read(&data, name)
+}
+
+// Reads the first grid of the file, or the one with the given name. Returns the grid and
+// the transform from index to world space stored with it.
+pub fn read(data: &[u8], name: Option<&str>) -> io::Result<(SparseGrid, Matrix4<Float>)> {
+    let mut r = Reader::new(data);
+    if r.u64()? & MAGIC_MASK != MAGIC {
+        return Err(invalid("not a NanoVDB file".to_string()));
+    }
+    let _version = r.u32()?;
+    let grid_count = r.u16()?;
+    let codec = r.u16()?;
+    if codec != 0 {
+        return Err(invalid("compressed NanoVDB files are not supported".to_string()));
+    }
+    let mut pos = FILE_HEADER_SIZE;
+    for _ in 0..grid_count {
+        r.pos = pos;
+        let grid_size = r.u64()? as usize;
+        r.pos = pos + META_NAME_SIZE;
+        let name_size = r.u32()? as usize;
+        r.pos = pos + META_CODEC;
+        if r.u16()? != 0 {
+            return Err(invalid("compressed NanoVDB grids are not supported".to_string()));
+        }
+        let start = pos + META_DATA_SIZE + name_size;
+        let end = match start.checked_add(grid_size) {
+            Some(end) if end <= data.len() => end,
+            _ => return Err(invalid("truncated data".to_string()))
+        };
+        pos = end;
+        let grid = &data[start..end];
+        if grid.len() < GRID_DATA_SIZE + 64 {
+            return Err(invalid("invalid NanoVDB grid".to_string()));
+        }
+        if name.map_or(true, |name| grid_name(grid) == name) {
+            return read_grid(grid);
+        }
+    }
+    Err(invalid(match name {
+        Some(name) => format!("no grid named {}", name),
+        None => "no grids".to_string()
+    }))
+}
+
+fn grid_name(grid: &[u8]) -> String {
+    let name = &grid[GRID_NAME..GRID_NAME + 256];
+    let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+fn read_grid(data: &[u8]) -> io::Result<(SparseGrid, Matrix4<Float>)> {
+    let mut r = Reader::new(data);
+    if r.u64()? & MAGIC_MASK != MAGIC {
+        return Err(invalid("invalid NanoVDB grid".to_string()));
+    }
+    r.pos = GRID_TYPE;
+    if r.u32()? != GRID_TYPE_FLOAT {
+        return Err(invalid(format!("grid {} does not hold floats", grid_name(data))));
+    }
+    r.pos = MAP_MATRIX;
+    let mut m = [0.0; 9];
+    for v in &mut m {
+        *v = r.f64()?;
+    }
+    r.pos = MAP_TRANSLATION;
+    let t = [r.f64()?, r.f64()?, r.f64()?];
+    let index_to_world = Matrix4::new(
+        m[0], m[1], m[2], t[0],
+        m[3], m[4], m[5], t[1],
+        m[6], m[7], m[8], t[2],
+        0.0, 0.0, 0.0, 1.0
+    );
+
+    // Tree data follows the grid data, node offsets are relative to it
+    r.pos = GRID_DATA_SIZE;
+    let leaf_offset = r.i64()?;
+    let lower_offset = r.i64()?;
+    let upper_offset = r.i64()?;
+    let _root_offset = r.i64()?;
+    let counts = [r.u32()? as usize, r.u32()? as usize, r.u32()? as usize];
+
+    let mut grid = SparseGrid::new();
+    let tree = GRID_DATA_SIZE;
+    for n in 0..counts[2] {
+        read_tiles(&mut r, &mut grid, node(tree, upper_offset, n, internal_size(UPPER_LOG2))?, UPPER_LOG2)?;
+    }
+    for n in 0..counts[1] {
+        read_tiles(&mut r, &mut grid, node(tree, lower_offset, n, internal_size(LOWER_LOG2))?, LOWER_LOG2)?;
+    }
+    let mut values = vec![0.0; BRICK_SIZE*BRICK_SIZE*BRICK_SIZE];
+    for n in 0..counts[0] {
+        let node = node(tree, leaf_offset, n, LEAF_SIZE)?;
+        r.pos = node;
+        let origin = [r.i32()? & !7, r.i32()? & !7, r.i32()? & !7];
+        r.pos = node + 96;
+        // Leaf values are ordered with z varying fastest, bricks with x
+        for i in 0..BRICK_SIZE {
+            for j in 0..BRICK_SIZE {
+                for k in 0..BRICK_SIZE {
+                    values[(k*BRICK_SIZE + j)*BRICK_SIZE + i] = r.f32()?;
+                }
+            }
+        }
+        grid.insert_brick(origin, &values);
+    }
+    Ok((grid, index_to_world))
+}
+
+// Position of the nth node of a level, the offsets of broken files must not wrap around
+fn node(tree: usize, offset: i64, n: usize, size: usize) -> io::Result<usize> {
+    if offset < 0 {
+        return Err(invalid("invalid NanoVDB node offset".to_string()));
+    }
+    n.checked_mul(size).and_then(|n| n.checked_add(offset as usize)).and_then(|n| n.checked_add(tree))
+        .ok_or(invalid("invalid NanoVDB node offset".to_string()))
+}
+
+// Stores the active constant tiles of an internal node in the grid
+fn read_tiles(r: &mut Reader, grid: &mut SparseGrid, node: usize, log2: u32) -> io::Result<()> {
+    let dim = 1usize << log2;
+    // Children of upper nodes are as large as tiles, those of lower nodes as bricks
+    let child_size = if log2 == UPPER_LOG2 { TILE_SIZE } else { BRICK_SIZE };
+    let mask_bytes = dim*dim*dim/8;
+    r.pos = node;
+    let extent = (dim*child_size) as i32;
+    let origin = [r.i32()? & !(extent - 1), r.i32()? & !(extent - 1), r.i32()? & !(extent - 1)];
+    let value_mask = node + 32;
+    let child_mask = value_mask + mask_bytes;
+    let table = node + internal_header(log2);
+    let bit = |r: &mut Reader, mask: usize, n: usize| -> io::Result<bool> {
+        r.pos = mask + n/8;
+        Ok(r.bytes(1)?[0] >> (n % 8) & 1 == 1)
+    };
+    for n in 0..dim*dim*dim {
+        if bit(r, child_mask, n)? || !bit(r, value_mask, n)? {
+            continue;
+        }
+        r.pos = table + n*8;
+        let value = r.f32()?;
+        if value == 0.0 {
+            continue;
+        }
+        // Table index n is (x*dim + y)*dim + z
+        let tile = [(n/(dim*dim)) as i32, ((n/dim) % dim) as i32, (n % dim) as i32];
+        let size = child_size as i32;
+        let corner = [origin[0] + tile[0]*size, origin[1] + tile[1]*size, origin[2] + tile[2]*size];
+        if log2 == UPPER_LOG2 {
+            grid.insert_tile(corner, value);
+        } else {
+            grid.insert_brick(corner, &[value; BRICK_SIZE*BRICK_SIZE*BRICK_SIZE]);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Matrix4;
+    use types::*;
+    use grid::Grid;
+    use super::*;
+
+    fn put(buf: &mut Vec<u8>, pos: usize, bytes: &[u8]) {
+        buf[pos..pos + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // File with one grid made of a single upper, lower and leaf node. The leaf holds
+    // i + 10j + 100k, next to it the lower node has an active tile of value 2 and next to
+    // the lower node the upper node one of value 3.
+    fn file() -> Vec<u8> {
+        let upper = 64;
+        let lower = upper + internal_size(UPPER_LOG2);
+        let leaf = lower + internal_size(LOWER_LOG2);
+        let grid_size = GRID_DATA_SIZE + leaf + LEAF_SIZE;
+        let name = b"density\0";
+
+        let mut buf = vec![0u8; FILE_HEADER_SIZE + META_DATA_SIZE + name.len() + grid_size];
+        put(&mut buf, 0, &0x324244566f6e614eu64.to_le_bytes());
+        put(&mut buf, 12, &1u16.to_le_bytes());
+        let meta = FILE_HEADER_SIZE;
+        put(&mut buf, meta, &(grid_size as u64).to_le_bytes());
+        put(&mut buf, meta + META_NAME_SIZE, &(name.len() as u32).to_le_bytes());
+        put(&mut buf, meta + META_DATA_SIZE, name);
+
+        let g = meta + META_DATA_SIZE + name.len();
+        put(&mut buf, g, &0x314244566f6e614eu64.to_le_bytes());
+        put(&mut buf, g + GRID_NAME, name);
+        let m = [0.5, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5];
+        for (n, v) in m.iter().enumerate() {
+            put(&mut buf, g + MAP_MATRIX + n*8, &(*v as f64).to_le_bytes());
+        }
+        for (n, v) in [1.0f64, 2.0, 3.0].iter().enumerate() {
+            put(&mut buf, g + MAP_TRANSLATION + n*8, &v.to_le_bytes());
+        }
+        put(&mut buf, g + GRID_TYPE, &GRID_TYPE_FLOAT.to_le_bytes());
+
+        let tree = g + GRID_DATA_SIZE;
+        for (n, offset) in [leaf, lower, upper].iter().enumerate() {
+            put(&mut buf, tree + n*8, &(*offset as i64).to_le_bytes());
+            put(&mut buf, tree + 32 + n*4, &1u32.to_le_bytes());
+        }
+
+        // Tile (1, 0, 0) of the lower and the upper node
+        let n = 16*16;
+        put(&mut buf, tree + lower + 32 + n/8, &[1 << (n % 8)]);
+        put(&mut buf, tree + lower + internal_header(LOWER_LOG2) + n*8, &2.0f32.to_le_bytes());
+        let n = 32*32;
+        put(&mut buf, tree + upper + 32 + n/8, &[1 << (n % 8)]);
+        put(&mut buf, tree + upper + internal_header(UPPER_LOG2) + n*8, &3.0f32.to_le_bytes());
+
+        // The bounding box minimum of the leaf is not its origin
+        for (n, c) in [3i32, 1, 2].iter().enumerate() {
+            put(&mut buf, tree + leaf + n*4, &c.to_le_bytes());
+        }
+        for i in 0..8 {
+            for j in 0..8 {
+                for k in 0..8 {
+                    let v = (i + 10*j + 100*k) as f32;
+                    put(&mut buf, tree + leaf + 96 + ((i*8 + j)*8 + k)*4, &v.to_le_bytes());
+                }
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn reads_leaves_and_tiles() {
+        let (grid, index_to_world) = read(&file(), None).unwrap();
+        assert_eq!(grid.voxel(1, 2, 3), 321.0);
+        assert_eq!(grid.voxel(7, 0, 7), 707.0);
+        assert_eq!(grid.voxel(8, 0, 0), 2.0);
+        assert_eq!(grid.voxel(15, 7, 7), 2.0);
+        assert_eq!(grid.voxel(16, 0, 0), 0.0);
+        assert_eq!(grid.voxel(0, 8, 0), 0.0);
+        assert_eq!(grid.voxel(128, 0, 0), 3.0);
+        assert_eq!(grid.voxel(255, 127, 127), 3.0);
+        assert_eq!(grid.voxel(256, 0, 0), 0.0);
+        assert_eq!(grid.voxel(127, 0, 0), 0.0);
+        assert_eq!(grid.index_bounds(), Some(([0, 0, 0], [255, 127, 127])));
+        let mut voxels = 0;
+        grid.for_each(&mut |_, _| voxels += 1);
+        assert_eq!(voxels, 2*512 + 128*128*128);
+        let expected = Matrix4::new(
+            0.5, 0.0, 0.0, 1.0,
+            0.0, 0.5, 0.0, 2.0,
+            0.0, 0.0, 0.5, 3.0,
+            0.0, 0.0, 0.0, 1.0
+        );
+        assert_eq!(index_to_world, expected);
+    }
+
+    // Written by scripts/nanovdb_fixture.py in the full layout of version 32.3, with a root
+    // node, statistics and child offsets that the reader skips
+    #[test]
+    fn reads_fixture() {
+        let (grid, index_to_world) = read(include_bytes!("../scenes/sphere.nvdb"), Some("density")).unwrap();
+        let density = |i: Float, j: Float, k: Float| {
+            let r = ((i - 7.0).powi(2) + (j - 7.5).powi(2) + (k - 8.0).powi(2)).sqrt();
+            (1.0 - r/7.0).max(0.0) as f32 as Float
+        };
+        for &(i, j, k) in &[(7, 7, 7), (8, 3, 12), (0, 7, 7), (15, 15, 15), (2, 9, 6)] {
+            assert_eq!(grid.voxel(i, j, k), density(i as Float, j as Float, k as Float));
+        }
+        assert_eq!(grid.voxel(16, 0, 0), 0.5);
+        assert_eq!(grid.voxel(23, 7, 7), 0.5);
+        assert_eq!(grid.voxel(24, 0, 0), 0.0);
+        assert_eq!(grid.voxel(16, 8, 0), 0.0);
+        assert_eq!(grid.index_bounds(), Some(([0, 0, 0], [23, 15, 15])));
+        let center = index_to_world*Pnt4::new(8.0, 8.0, 8.0, 1.0);
+        assert!(center.x.abs() < 1e-6 && center.y.abs() < 1e-6 && center.z.abs() < 1e-6);
+        assert!((index_to_world.m11 - 0.1).abs() < 1e-12);
+        assert!(read(include_bytes!("../scenes/sphere.nvdb"), Some("temperature")).is_err());
+    }
+
+    #[test]
+    fn rejects_broken_offsets() {
+        let mut data = include_bytes!("../scenes/sphere.nvdb").to_vec();
+        let tree = FILE_HEADER_SIZE + META_DATA_SIZE + 8 + GRID_DATA_SIZE;
+        put(&mut data, tree, &(-1i64).to_le_bytes());
+        assert!(read(&data, None).is_err());
+        put(&mut data, tree, &(i64::max_value()).to_le_bytes());
+        assert!(read(&data, None).is_err());
+        let mut data = include_bytes!("../scenes/sphere.nvdb").to_vec();
+        put(&mut data, FILE_HEADER_SIZE, &u64::max_value().to_le_bytes());
+        assert!(read(&data, None).is_err());
+        // Too short to hold a name
+        put(&mut data, FILE_HEADER_SIZE, &100u64.to_le_bytes());
+        assert!(read(&data, Some("density")).is_err());
+        assert!(read(&data, None).is_err());
+    }
+
+    #[test]
+    fn selects_grids_by_name() {
+        assert!(read(&file(), Some("density")).is_ok());
+        assert!(read(&file(), Some("temperature")).is_err());
+        assert!(read(b"NanoVDB3________", None).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+use nalgebra::Norm;
+use types::*;
+use std::f64;
+
+// Source: Ken Perlin, Improving Noise (2002) and Steven Worley, A Cellular Texture Basis Function (1996)
+
+fn hash(x: i64, y: i64, z: i64) -> u32 {
+    let mut h = (x as u32).wrapping_mul(73856093)
+        ^ (y as u32).wrapping_mul(19349663)
+        ^ (z as u32).wrapping_mul(83492791);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h
+}
+
+fn fade(t: Float) -> Float {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: Float, a: Float, b: Float) -> Float {
+    a + t * (b - a)
+}
+
+fn grad(hash: u32, x: Float, y: Float, z: Float) -> Float {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+    let u = if h & 1 == 0 { u } else { -u };
+    let v = if h & 2 == 0 { v } else { -v };
+    u + v
+}
+
+// Gradient noise in [-1, 1]
+pub fn noise(p: &Pnt3) -> Float {
+    let xi = p.x.floor();
+    let yi = p.y.floor();
+    let zi = p.z.floor();
+    let x = p.x - xi;
+    let y = p.y - yi;
+    let z = p.z - zi;
+    let (xi, yi, zi) = (xi as i64, yi as i64, zi as i64);
+    let u = fade(x);
+    let v = fade(y);
+    let w = fade(z);
+
+    let g = |dx: i64, dy: i64, dz: i64| {
+        grad(hash(xi + dx, yi + dy, zi + dz), x - dx as Float, y - dy as Float, z - dz as Float)
+    };
+    lerp(w,
+        lerp(v, lerp(u, g(0, 0, 0), g(1, 0, 0)), lerp(u, g(0, 1, 0), g(1, 1, 0))),
+        lerp(v, lerp(u, g(0, 0, 1), g(1, 0, 1)), lerp(u, g(0, 1, 1), g(1, 1, 1))))
+}
+
+// Fractional brownian motion
+pub fn fbm(p: &Pnt3, octaves: u32, lacunarity: Float, gain: Float) -> Float {
+    let mut sum = 0.0;
+    let mut frequency = 1.0;
+    let mut amplitude = 1.0;
+    for _ in 0..octaves {
+        let q = Pnt3::new(p.x * frequency, p.y * frequency, p.z * frequency);
+        sum += amplitude * noise(&q);
+        frequency *= lacunarity;
+        amplitude *= gain;
+    }
+    sum
+}
+
+pub fn turbulence(p: &Pnt3, octaves: u32, lacunarity: Float, gain: Float) -> Float {
+    let mut sum = 0.0;
+    let mut frequency = 1.0;
+    let mut amplitude = 1.0;
+    for _ in 0..octaves {
+        let q = Pnt3::new(p.x * frequency, p.y * frequency, p.z * frequency);
+        sum += amplitude * noise(&q).abs();
+        frequency *= lacunarity;
+        amplitude *= gain;
+    }
+    sum
+}
+
+// Feature point of a cell, jittered around its center
+fn feature_point(cx: i64, cy: i64, cz: i64, jitter: Float) -> Pnt3 {
+    let h = hash(cx, cy, cz);
+    let fx = 0.5 + jitter * ((h & 0x3ff) as Float / 1023.0 - 0.5);
+    let fy = 0.5 + jitter * (((h >> 10) & 0x3ff) as Float / 1023.0 - 0.5);
+    let fz = 0.5 + jitter * (((h >> 20) & 0x3ff) as Float / 1023.0 - 0.5);
+    Pnt3::new(cx as Float + fx, cy as Float + fy, cz as Float + fz)
+}
+
+// Distance along one axis from x to the range the feature point of cell c jitters in
+fn gap(c: i64, x: Float, jitter: Float) -> Float {
+    let lo = c as Float + 0.5 - 0.5*jitter;
+    let hi = c as Float + 0.5 + 0.5*jitter;
+    (lo - x).max(x - hi).max(0.0)
+}
+
+// Distances to the closest and second closest feature point (F1, F2). Cells are searched in
+// shells around the cell of p until no cell of the next shell can be closer than F2, a shell of
+// radius r is at least r - 1 away. Cells whose feature point cannot beat F2 are skipped.
+pub fn worley(p: &Pnt3, jitter: Float) -> (Float, Float) {
+    let xi = p.x.floor() as i64;
+    let yi = p.y.floor() as i64;
+    let zi = p.z.floor() as i64;
+    let mut f1 = f64::INFINITY;
+    let mut f2 = f64::INFINITY;
+    let mut r: i64 = 0;
+    while ((r - 1) as Float) < f2 {
+        for dz in -r..r + 1 {
+            for dy in -r..r + 1 {
+                for dx in -r..r + 1 {
+                    let (cx, cy, cz) = (xi + dx, yi + dy, zi + dz);
+                    if dx.abs().max(dy.abs()).max(dz.abs()) != r || gap(cx, p.x, jitter).powi(2)
+                        + gap(cy, p.y, jitter).powi(2) + gap(cz, p.z, jitter).powi(2) >= f2*f2 {
+                        continue;
+                    }
+                    let d = (feature_point(cx, cy, cz, jitter) - *p).norm();
+                    if d < f1 {
+                        f2 = f1;
+                        f1 = d;
+                    } else if d < f2 {
+                        f2 = d;
+                    }
+                }
+            }
+        }
+        r += 1;
+    }
+    (f1, f2)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Norm;
+    use types::*;
+    use super::*;
+
+    #[test]
+    fn worley_finds_nearest_points() {
+        for i in 0..200 {
+            let p = Pnt3::new(i as Float*0.731 - 40.0, (i*i % 97) as Float*0.37, i as Float*-0.113);
+            for &jitter in &[0.0, 0.5, 1.0] {
+                // All cells that could hold one of the two nearest points
+                let mut d: Vec<Float> = Vec::new();
+                for dz in -4..5 {
+                    for dy in -4..5 {
+                        for dx in -4..5 {
+                            let c = feature_point(p.x.floor() as i64 + dx, p.y.floor() as i64 + dy, p.z.floor() as i64 + dz, jitter);
+                            d.push((c - p).norm());
+                        }
+                    }
+                }
+                d.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                assert_eq!(worley(&p, jitter), (d[0], d[1]), "{:?} {}", p, jitter);
+            }
+        }
+    }
+
+    #[test]
+    fn noise_range() {
+        for i in 0..1000 {
+            let p = Pnt3::new(i as Float*0.173, i as Float*0.291 - 50.0, (i % 13) as Float*0.77);
+            let n = noise(&p);
+            assert!(n >= -1.0 && n <= 1.0);
+            assert!(fbm(&p, 4, 2.0, 0.5).abs() <= 1.0 + 0.5 + 0.25 + 0.125);
+            assert!(turbulence(&p, 4, 2.0, 0.5) >= 0.0);
+        }
+        // Lattice points have zero gradient noise
+        assert_eq!(noise(&Pnt3::new(3.0, -2.0, 7.0)), 0.0);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::rc::Rc;
+use std::vec::Vec;
+use types::*;
+use shader::Shader;
+use shape::{Mesh, Triangle};
+
+// Wavefront OBJ reader for triangle meshes. Only vertex positions, normals, texture
+// coordinates and faces are read, polygons are split into fans.
+
+pub fn load(path: &Path, shader: Rc<Shader>) -> Result<Mesh, String> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut source))
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    parse(&source, shader).map_err(|e| format!("{}:{}", path.display(), e))
+}
+
+pub fn parse(source: &str, shader: Rc<Shader>) -> Result<Mesh, String> {
+    let mut positions: Vec<Pnt3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut triangles = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let error = |e: String| format!("{}: {}", i + 1, e);
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") => {
+                let v = floats(tokens, 3).map_err(&error)?;
+                positions.push(Pnt3::new(v[0], v[1], v[2]));
+            },
+            Some("vn") => {
+                let v = floats(tokens, 3).map_err(&error)?;
+                normals.push(Vec3::new(v[0], v[1], v[2]));
+            },
+            Some("vt") => {
+                let v = floats(tokens, 2).map_err(&error)?;
+                uvs.push(Vec2::new(v[0], v[1]));
+            },
+            Some("f") => {
+                let mut corners = Vec::new();
+                for corner in tokens {
+                    corners.push(vertex(corner, positions.len(), uvs.len(), normals.len()).map_err(&error)?);
+                }
+                if corners.len() < 3 {
+                    return Err(error("Face with less than three vertices".to_string()));
+                }
+                for k in 1..corners.len() - 1 {
+                    let c = [corners[0], corners[k], corners[k + 1]];
+                    let mut triangle = Triangle::new(positions[c[0].0], positions[c[1].0], positions[c[2].0], shader.clone());
+                    if let (Some(a), Some(b), Some(d)) = (c[0].1, c[1].1, c[2].1) {
+                        triangle.uvs = Some([uvs[a], uvs[b], uvs[d]]);
+                    }
+                    if let (Some(a), Some(b), Some(d)) = (c[0].2, c[1].2, c[2].2) {
+                        triangle.normals = Some([normals[a], normals[b], normals[d]]);
+                    }
+                    triangles.push(triangle);
+                }
+            },
+            _ => {}
+        }
+    }
+    Ok(Mesh::new(triangles, shader))
+}
+
+fn floats<'a, I: Iterator<Item=&'a str>>(tokens: I, count: usize) -> Result<Vec<Float>, String> {
+    let values: Vec<Float> = tokens.take(count).map(|t| t.parse().map_err(|_| format!("Invalid number {}", t)))
+        .collect::<Result<Vec<Float>, String>>()?;
+    if values.len() < count {
+        return Err(format!("Expected {} numbers", count));
+    }
+    Ok(values)
+}
+
+// Position, texture coordinate and normal index of a face corner "v/vt/vn"
+fn vertex(corner: &str, positions: usize, uvs: usize, normals: usize) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let mut parts = corner.split('/');
+    let v = index(parts.next(), positions)?.ok_or(format!("Missing vertex index in {}", corner))?;
+    let vt = index(parts.next(), uvs)?;
+    let vn = index(parts.next(), normals)?;
+    Ok((v, vt, vn))
+}
+
+// One based index, negative indices count back from the last element
+fn index(part: Option<&str>, count: usize) -> Result<Option<usize>, String> {
+    let part = match part {
+        Some(p) if !p.is_empty() => p,
+        _ => return Ok(None)
+    };
+    let i: i64 = part.parse().map_err(|_| format!("Invalid index {}", part))?;
+    let i = if i < 0 { count as i64 + i } else { i - 1 };
+    if i < 0 || i >= count as i64 {
+        return Err(format!("Index {} out of range", part));
+    }
+    Ok(Some(i as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use testing::grey;
+    use super::*;
+
+    #[test]
+    fn quad_is_split_into_a_fan() {
+        let source = "# unit quad
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 0
+vt 1 1
+vt 0 1
+vn 0 0 1
+o quad
+f 1/1/1 2/2/1 3/3/1 4/4/1
+";
+        let mesh = parse(source, grey(0.5)).unwrap();
+        assert_eq!(mesh.triangles.len(), 2);
+        let t = &mesh.triangles[1];
+        assert_eq!((t.v1, t.v2, t.v3), (Pnt3::new(0.0, 0.0, 0.0), Pnt3::new(1.0, 1.0, 0.0), Pnt3::new(0.0, 1.0, 0.0)));
+        assert_eq!(t.uvs.unwrap()[2], Vec2::new(0.0, 1.0));
+        assert_eq!(t.normals.unwrap()[0], Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn negative_and_missing_indices() {
+        let mesh = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf -3//-1 -2//-1 -1//-1\nf 1 2 3\n", grey(0.5)).unwrap();
+        assert_eq!(mesh.triangles.len(), 2);
+        assert_eq!(mesh.triangles[0].v1, Pnt3::new(0.0, 0.0, 0.0));
+        assert!(mesh.triangles[0].uvs.is_none() && mesh.triangles[0].normals.is_some());
+        assert!(mesh.triangles[1].normals.is_none());
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let errors = [
+            ("v 0 0 0\nv 1 0 0\nf 1 2\n", "3: Face with less than three vertices"),
+            ("v 0 0 0\nv 1 0 0\nf 1 2 3\n", "3: Index 3 out of range"),
+            ("v 0 0 x\n", "1: Invalid number x"),
+            ("vt 0\n", "1: Expected 2 numbers"),
+            ("v 0 0 0\nf 1 1 a\n", "2: Invalid index a")
+        ];
+        for &(source, error) in &errors {
+            assert_eq!(parse(source, grey(0.5)).err(), Some(error.to_string()));
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::vec::Vec;
+use std::str::FromStr;
+use types::*;
+use aov::Aov;
+use debug::DebugMode;
+
+// Command line options
+#[derive(Debug)]
+pub struct Options {
+    pub aovs: Vec<Aov>,
+    pub exr: Option<String>,
+    pub denoise: bool,
+    pub samples: Option<u32>,
+    pub min_samples: u32,
+    pub adaptive: Option<Float>,    // Relative error threshold
+    pub heatmap: Option<String>,
+    pub seed: u32,
+    pub pass_samples: u32,
+    pub time_budget: Option<Float>,     // Seconds
+    pub noise_target: Option<Float>,
+    pub write_interval: Option<Float>,  // Seconds
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: Option<Float>, // Seconds
+    pub resume: bool,
+    pub scene: Option<String>,
+    pub coordinator: Option<String>,    // Address to listen for workers on
+    pub worker: Option<String>,         // Address of the coordinator
+    pub tile_size: u32,
+    pub tile_samples: Option<u32>,      // Samples per pixel of one work unit
+    pub server: Option<String>,         // Address of the HTTP job server
+    pub viewer: Option<String>,         // Address of the web viewer
+    pub stats_json: Option<String>,
+    pub debug: Option<DebugMode>        // Rendered instead of the beauty pass
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            aovs: Vec::new(),
+            exr: None,
+            denoise: false,
+            samples: None,
+            min_samples: 4,
+            adaptive: None,
+            heatmap: None,
+            seed: 0,
+            pass_samples: 1,
+            time_budget: None,
+            noise_target: None,
+            write_interval: None,
+            checkpoint: None,
+            checkpoint_interval: None,
+            resume: false,
+            scene: None,
+            coordinator: None,
+            worker: None,
+            tile_size: 64,
+            tile_samples: None,
+            server: None,
+            viewer: None,
+            stats_json: None,
+            debug: None
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--aov" => {
+                    for name in value(arg, args.next())?.split(',') {
+                        match Aov::parse(name) {
+                            Some(aov) => options.aovs.push(aov),
+                            None => return Err(format!("Unknown AOV {}", name))
+                        }
+                    }
+                },
+                "--exr" => options.exr = Some(value(arg, args.next())?.clone()),
+                "--denoise" => options.denoise = true,
+                "--spp" => options.samples = Some(number(arg, args.next())?),
+                "--min-spp" => options.min_samples = number(arg, args.next())?,
+                "--adaptive" => options.adaptive = Some(number(arg, args.next())?),
+                "--heatmap" => options.heatmap = Some(value(arg, args.next())?.clone()),
+                "--seed" => options.seed = number(arg, args.next())?,
+                "--pass-spp" => options.pass_samples = number(arg, args.next())?,
+                "--time-budget" => options.time_budget = Some(number(arg, args.next())?),
+                "--noise-target" => options.noise_target = Some(number(arg, args.next())?),
+                "--write-interval" => options.write_interval = Some(number(arg, args.next())?),
+                "--checkpoint" => options.checkpoint = Some(value(arg, args.next())?.clone()),
+                "--checkpoint-interval" => options.checkpoint_interval = Some(number(arg, args.next())?),
+                "--resume" => options.resume = true,
+                "--scene" => options.scene = Some(value(arg, args.next())?.clone()),
+                "--coordinator" => options.coordinator = Some(value(arg, args.next())?.clone()),
+                "--worker" => options.worker = Some(value(arg, args.next())?.clone()),
+                "--tile-size" => options.tile_size = number(arg, args.next())?,
+                "--tile-spp" => options.tile_samples = Some(number(arg, args.next())?),
+                "--server" => options.server = Some(value(arg, args.next())?.clone()),
+                "--viewer" => options.viewer = Some(value(arg, args.next())?.clone()),
+                "--stats-json" => options.stats_json = Some(value(arg, args.next())?.clone()),
+                "--debug" => {
+                    let name = value(arg, args.next())?;
+                    match DebugMode::parse(name) {
+                        Some(mode) => options.debug = Some(mode),
+                        None => return Err(format!("Unknown debug mode {}", name))
+                    }
+                },
+                _ => return Err(format!("Unknown argument {}", arg))
+            }
+        }
+        if options.resume && options.checkpoint.is_none() {
+            return Err("--resume requires --checkpoint".to_string());
+        }
+        Ok(options)
+    }
+}
+
+fn value<'a>(arg: &str, value: Option<&'a String>) -> Result<&'a String, String> {
+    value.ok_or(format!("Missing value for {}", arg))
+}
+
+fn number<T: FromStr>(arg: &str, v: Option<&String>) -> Result<T, String> {
+    let v = value(arg, v)?;
+    v.parse().map_err(|_| format!("Invalid value {} for {}", v, arg))
+}
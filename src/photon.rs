@@ -0,0 +1,423 @@
+use nalgebra::{Dot, Norm};
+use std::f64;
+use std::f64::consts::PI;
+use std::vec::Vec;
+use types::*;
+use ray::Ray;
+use hit::HitInfo;
+use bsdf::{Bsdf, Transport};
+use light::choose_light;
+use renderer::Renderer;
+use sampling;
+use stats;
+use stats::{Counter, Timer};
+use std::time::Instant;
+use scoped_threadpool::Pool;
+
+// Photon mapping. Photons are traced from the lights and stored where they hit surfaces that
+// are not specular. Camera rays follow specular surfaces to the first other surface, where the
+// direct light is sampled and the indirect light, caustics included, is estimated from the
+// density of the photons around the hit.
+// Source: Jensen, Realistic Image Synthesis Using Photon Mapping
+//
+// Progressive photon mapping traces a new photon map for every sample and shrinks the radius
+// of the density estimation from sample to sample, so the average over all samples converges
+// to the correct image. The radius is the same for all pixels, which keeps the samples
+// independent of each other like those of the other integrators. This is not stochastic
+// progressive photon mapping (SPPM), which keeps a radius for every pixel.
+// Source: Knaus and Zwicker, Progressive Photon Mapping: A Probabilistic Approach
+//
+// Like for bidirectional path tracing, media only attenuate photons and camera rays.
+
+// Row of the random sequences of the photons, no pixel has it
+const PHOTON_STREAM: u32 = 0xffffffff;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhotonSettings {
+    pub photons: u32,   // Photons traced per photon map
+    pub radius: Float,  // Radius of the density estimation, of the first sample if progressive
+    pub max_depth: u32, // Surface hits of paths from the lights to the camera
+    pub alpha: Float    // Fraction of the photons of a sample kept by the progressive radius
+}
+
+impl PhotonSettings {
+    pub fn new() -> PhotonSettings {
+        PhotonSettings {
+            photons: 100000,
+            radius: 0.1,
+            max_depth: 8,
+            alpha: 0.7
+        }
+    }
+
+    // Radius of the density estimation of the sample with the index when progressive,
+    // r_{i+1}^2 = r_i^2 (i + alpha)/(i + 1)
+    pub fn progressive_radius(&self, sample: u32) -> Float {
+        let mut r2 = self.radius*self.radius;
+        for i in 1..sample + 1 {
+            r2 *= (i as Float + self.alpha)/(i as Float + 1.0);
+        }
+        r2.sqrt()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Photon {
+    pub p: Pnt3,
+    pub wi: Vec3,   // Direction the photon came from
+    pub power: Color
+}
+
+// Photons as a balanced kd-tree. Every range of the array is split at its middle photon, the
+// median along the axis of the largest extent of the range.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,   // Split axis of the photon at the same index
+    pub emitted: u32,   // Photon paths traced, including those never stored
+    pub radius: Float
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>, emitted: u32, radius: Float) -> PhotonMap {
+        // A photon with a NaN position cannot be ordered in the tree
+        photons.retain(|photon| {
+            let (p, c) = (&photon.p, &photon.power);
+            p.x.is_finite() && p.y.is_finite() && p.z.is_finite() && c.x.is_finite() && c.y.is_finite() && c.z.is_finite()
+        });
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        PhotonMap {
+            photons: photons,
+            axes: axes,
+            emitted: emitted,
+            radius: radius
+        }
+    }
+
+    // Traces photons from the lights with the threads of the pool, the random sequences depend
+    // on the seed and the sample
+    pub fn trace(renderer: &Renderer, pool: &mut Pool, settings: &PhotonSettings, seed: u32, sample: u32,
+                 radius: Float) -> PhotonMap {
+        let start = Instant::now();
+        let threads = pool.thread_count() as usize;
+        let mut results: Vec<Vec<Photon>> = vec![Vec::new(); threads];
+        pool.scoped(|scope| {
+            for (thread, photons) in results.iter_mut().enumerate() {
+                scope.execute(move || {
+                    let mut i = thread as u32;
+                    while i < settings.photons {
+                        sampling::start_sample(seed, i, PHOTON_STREAM, sample);
+                        trace_photon(renderer, settings.max_depth, photons);
+                        i += threads as u32;
+                    }
+                });
+            }
+        });
+        let photons: Vec<Photon> = results.into_iter().flat_map(|photons| photons).collect();
+        stats::add(Counter::SecondaryRays, settings.photons as u64);
+        let map = PhotonMap::new(photons, settings.photons, radius);
+        stats::time(Timer::Build, start.elapsed());
+        map
+    }
+
+
+    // Calls f for every photon within the radius around p
+    pub fn lookup<F>(&self, p: &Pnt3, radius: Float, f: &mut F) where F: FnMut(&Photon) {
+        self.lookup_range(0, self.photons.len(), p, radius*radius, f);
+    }
+
+    fn lookup_range<F>(&self, lo: usize, hi: usize, p: &Pnt3, r2: Float, f: &mut F) where F: FnMut(&Photon) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi)/2;
+        let photon = &self.photons[mid];
+        if (photon.p - *p).norm_squared() <= r2 {
+            f(photon);
+        }
+        let axis = self.axes[mid];
+        let d = p[axis] - photon.p[axis];
+        let (near, far) = if d <= 0.0 { ((lo, mid), (mid + 1, hi)) } else { ((mid + 1, hi), (lo, mid)) };
+        self.lookup_range(near.0, near.1, p, r2, f);
+        if d*d <= r2 {
+            self.lookup_range(far.0, far.1, p, r2, f);
+        }
+    }
+
+    // Light scattered towards wo by the photons within the radius of the hit
+    pub fn estimate(&self, hit: &HitInfo, bsdf: &Bsdf) -> Color {
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        self.lookup(&hit.p, self.radius, &mut |photon| {
+            sum += bsdf.eval(&hit.i, &photon.wi)*photon.power;
+        });
+        sum/(PI*self.radius*self.radius*self.emitted as Float)
+    }
+}
+
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let mut min = photons[0].p;
+    let mut max = photons[0].p;
+    for photon in photons.iter() {
+        for i in 0..3 {
+            min[i] = min[i].min(photon.p[i]);
+            max[i] = max[i].max(photon.p[i]);
+        }
+    }
+    let extent = max - min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 } else if extent.y >= extent.z { 1 } else { 2 };
+    let mid = photons.len()/2;
+    photons.select_nth_unstable_by(mid, |a, b| a.p[axis].partial_cmp(&b.p[axis]).unwrap());
+    axes[mid] = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+// Follows a photon from a light, storing it at every surface that is not specular except the
+// first, whose light is sampled directly from the camera side
+fn trace_photon(renderer: &Renderer, max_depth: u32, photons: &mut Vec<Photon>) {
+    let lights = renderer.lights();
+    if lights.is_empty() {
+        return;
+    }
+    let (index, pdf_choice) = choose_light(lights.len(), sampling::next_float());
+    let (s, t) = sampling::next_2d();
+    let emission = lights[index].sample_emission(s, t);
+    if emission.pdf_pos <= 0.0 || emission.pdf_dir <= 0.0 {
+        return;
+    }
+    let mut power = emission.radiance/(pdf_choice*emission.pdf_pos*emission.pdf_dir);
+    let mut ray = Ray::new(&emission.origin, emission.dir, 0.0, f64::INFINITY);
+    ray.medium = renderer.fog();
+    for depth in 0..max_depth {
+        let (hit, tr) = match renderer.next_surface(&ray) {
+            Some(next) => next,
+            None => return
+        };
+        power = power*tr;
+        let bsdf = hit.shape.shader().bsdf(&hit);
+        if !bsdf.is_specular() && depth > 0 {
+            photons.push(Photon { p: hit.p, wi: hit.i, power: power });
+        }
+        let (s, t) = sampling::next_2d();
+        let sample = match bsdf.sample(&hit.i, Transport::Importance, s, t, sampling::next_float()) {
+            Some(sample) if sample.pdf > 0.0 => sample,
+            _ => return
+        };
+        power = power*sample.f*(sample.wi.dot(&bsdf.n).abs()/sample.pdf);
+        if power == Color::new(0.0, 0.0, 0.0) {
+            return;
+        }
+        ray = hit.spawn_ray(sample.wi);
+        ray.medium = renderer.medium_after(&hit, &sample.wi);
+    }
+}
+
+// Radiance along the camera ray, estimated at the first surface that is not specular
+pub fn radiance(renderer: &Renderer, ray: &Ray, photons: &PhotonMap, max_depth: u32) -> Color {
+    let mut beta = Color::new(1.0, 1.0, 1.0);
+    let mut ray = ray.clone();
+    for _ in 0..max_depth {
+        let (hit, tr) = match renderer.next_surface(&ray) {
+            Some(next) => next,
+            None => break
+        };
+        beta = beta*tr;
+        let bsdf = hit.shape.shader().bsdf(&hit);
+        if !bsdf.is_specular() {
+            return beta*(direct(renderer, &hit, &bsdf) + photons.estimate(&hit, &bsdf));
+        }
+        let (s, t) = sampling::next_2d();
+        let sample = match bsdf.sample(&hit.i, Transport::Radiance, s, t, sampling::next_float()) {
+            Some(sample) if sample.pdf > 0.0 => sample,
+            _ => break
+        };
+        beta = beta*sample.f*(sample.wi.dot(&bsdf.n).abs()/sample.pdf);
+        stats::count(Counter::SecondaryRays);
+        ray = hit.spawn_ray(sample.wi);
+        ray.medium = renderer.medium_after(&hit, &sample.wi);
+    }
+    Color::new(0.0, 0.0, 0.0)
+}
+
+// Light of the scene lights scattered towards the camera
+fn direct(renderer: &Renderer, hit: &HitInfo, bsdf: &Bsdf) -> Color {
+    let mut c = Color::new(0.0, 0.0, 0.0);
+    for light in renderer.lights() {
+        let sample = light.sample(&hit.p);
+        if sample.pdf <= 0.0 {
+            continue;
+        }
+        let f = bsdf.eval(&hit.i, &sample.wi);
+        if f == Color::new(0.0, 0.0, 0.0) {
+            continue;
+        }
+        stats::count(Counter::ShadowRays);
+        let tr = renderer.transmittance(&hit.spawn_ray_to(&(hit.p + sample.wi*sample.dist)));
+        c += f*sample.radiance*tr*(sample.wi.dot(&bsdf.n).abs()/sample.pdf);
+    }
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use std::rc::Rc;
+    use nalgebra::Norm;
+    use types::*;
+    use aov::Aov;
+    use light::PointLight;
+    use renderer::{Integrator, Renderer};
+    use scene::Scene;
+    use shader::{Shader, DiffuseShader, GlassShader};
+    use spectrum::Ior;
+    use scoped_threadpool::Pool;
+    use std::sync::Arc;
+    use shape::Sphere;
+    use texture::ConstantTexture;
+    use window::Window;
+    use testing;
+    use super::*;
+
+    #[test]
+    fn lookup_matches_brute_force() {
+        let mut state = 12345u64;
+        let mut next = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 11) as Float/(1u64 << 53) as Float
+        };
+        let mut photons = Vec::new();
+        for _ in 0..1000 {
+            let p = Pnt3::new(next(), next()*2.0, next()*0.5);
+            photons.push(Photon { p: p, wi: Vec3::new(0.0, 0.0, 1.0), power: Color::new(1.0, 1.0, 1.0) });
+        }
+        let map = PhotonMap::new(photons.clone(), 1000, 0.2);
+        for _ in 0..50 {
+            let p = Pnt3::new(next(), next()*2.0, next()*0.5);
+            let mut found = Vec::new();
+            map.lookup(&p, 0.2, &mut |photon| found.push(photon.p));
+            let expected = photons.iter().filter(|photon| (photon.p - p).norm() <= 0.2).count();
+            assert_eq!(found.len(), expected);
+            assert!(found.iter().all(|q| (*q - p).norm() <= 0.2));
+        }
+    }
+
+    #[test]
+    fn non_finite_photons_are_dropped() {
+        let photon = |x: Float, power: Float| Photon { p: Pnt3::new(x, 0.0, 0.0), wi: Vec3::new(0.0, 0.0, 1.0), power: Color::new(power, 1.0, 1.0) };
+        let photons = vec![photon(0.0, 1.0), photon(f64::NAN, 1.0), photon(0.1, f64::INFINITY), photon(0.2, 1.0), photon(f64::NAN, 1.0)];
+        let map = PhotonMap::new(photons, 5, 1.0);
+        let mut found = 0;
+        map.lookup(&Pnt3::new(0.0, 0.0, 0.0), 1.0, &mut |_| found += 1);
+        assert_eq!(found, 2);
+    }
+
+    #[test]
+    fn progressive_radius_shrinks() {
+        let settings = PhotonSettings::new();
+        assert_eq!(settings.progressive_radius(0), settings.radius);
+        let r1 = settings.progressive_radius(1);
+        assert!((r1*r1 - settings.radius*settings.radius*(1.0 + settings.alpha)/2.0).abs() < 1e-12);
+        assert!(settings.progressive_radius(100) < r1);
+    }
+
+    #[test]
+    fn closed_sphere_interreflection() {
+        // Same setup as for bidirectional path tracing. On the inside of a sphere the part of
+        // the wall within distance r of a point has the area pi r^2, so the estimate only has
+        // the noise of the photon counts.
+        let albedo = 0.5;
+        let mut scene = Scene::empty();
+        let mut window = Window::new(16, 12);
+        window.samples = 4;
+        let camera = window.camera.position;
+        let shader: Rc<Shader> = Rc::new(DiffuseShader { color: Rc::new(ConstantTexture { color: Color::new(albedo, albedo, albedo) }) });
+        scene.shapes.push(Box::new(Sphere { position: camera, radius: 5.0, shader: shader }));
+        scene.lights.push(Box::new(PointLight { position: camera, intensity: Color::new(1.0, 1.0, 1.0)*(25.0*PI), group: 0 }));
+        let settings = PhotonSettings { photons: 50000, radius: 1.0, max_depth: 3, alpha: 0.7 };
+        let expected = albedo*(1.0 - albedo.powi(3))/(1.0 - albedo);
+        for integrator in &[Integrator::Photon(settings), Integrator::ProgressivePhoton(settings)] {
+            scene.integrator = *integrator;
+            let film = &testing::draw_aovs(&window, &scene, &[Aov::Beauty])[0];
+            let mut sum = Color::new(0.0, 0.0, 0.0);
+            for y in 0..12 {
+                for x in 0..16 {
+                    sum += film.get(x, y);
+                }
+            }
+            let mean = sum.x/(16.0*12.0);
+            assert!((mean - expected).abs() < 0.03*expected, "{:?} {}", integrator, mean);
+        }
+    }
+
+    #[test]
+    fn glass_sphere_focuses_a_caustic() {
+        // A point light above a glass ball, which is a lens with its focus about three radii
+        // behind it for an index of 1.5. The floor under the ball only receives light through
+        // it, which the shadow rays of the Whitted integrator cannot follow.
+        let mut scene = Scene::empty();
+        let floor: Rc<Shader> = Rc::new(DiffuseShader { color: Rc::new(ConstantTexture { color: Color::new(0.5, 0.5, 0.5) }) });
+        let glass: Rc<Shader> = Rc::new(GlassShader { ior: Ior::Constant(1.5), color: Rc::new(ConstantTexture { color: Color::new(1.0, 1.0, 1.0) }) });
+        scene.shapes.push(Box::new(Sphere { position: Pnt3::new(0.0, 2.0, -1001.5), radius: 1000.0, shader: floor }));
+        scene.shapes.push(Box::new(Sphere { position: Pnt3::new(0.0, 2.0, 1.5), radius: 1.0, shader: glass }));
+        let intensity = 10.0;
+        scene.lights.push(Box::new(PointLight { position: Pnt3::new(0.0, 2.0, 4.5), intensity: Color::new(1.0, 1.0, 1.0)*intensity, group: 0 }));
+        let mut window = Window::new(32, 24);
+        window.samples = 4;
+        let focus = Pnt3::new(0.0, 2.0, -1.5);
+        window.camera.set_position(&Pnt3::new(0.0, -6.0, 4.0));
+        window.camera.set_front((focus - window.camera.position).normalize());
+        let (x, y) = window.camera.raster_position(&focus).unwrap();
+        // Floor radiance if the ball was not there
+        let unblocked = 0.5*intensity/(PI*36.0);
+        let settings = PhotonSettings { photons: 50000, radius: 0.1, max_depth: 4, alpha: 0.7 };
+        for integrator in &[Integrator::Whitted, Integrator::Photon(settings), Integrator::ProgressivePhoton(settings)] {
+            scene.integrator = *integrator;
+            let film = &testing::draw_aovs(&window, &scene, &[Aov::Beauty])[0];
+            let c = film.get(x as u32, y as u32).x;
+            if *integrator == Integrator::Whitted {
+                assert_eq!(c, 0.0);
+            } else {
+                assert!(c > 5.0*unblocked, "{:?} {}", integrator, c);
+            }
+        }
+    }
+
+    #[test]
+    fn photon_maps_are_shared_by_the_tiles_of_a_pass() {
+        let mut scene = Scene::empty();
+        let shader: Rc<Shader> = Rc::new(DiffuseShader { color: Rc::new(ConstantTexture { color: Color::new(0.5, 0.5, 0.5) }) });
+        scene.shapes.push(Box::new(Sphere { position: Pnt3::new(0.0, 0.0, 0.0), radius: 5.0, shader: shader }));
+        scene.lights.push(Box::new(PointLight { position: Pnt3::new(0.0, 0.0, 0.0), intensity: Color::new(1.0, 1.0, 1.0), group: 0 }));
+        let settings = PhotonSettings { photons: 100, radius: 1.0, max_depth: 2, alpha: 0.7 };
+        scene.integrator = Integrator::ProgressivePhoton(settings);
+        let renderer = Renderer::new(&scene);
+        let mut pool = Pool::new(2);
+        let first = renderer.photon_maps(&mut pool, 1, 0, 2);
+        let second = renderer.photon_maps(&mut pool, 1, 0, 2);
+        assert_eq!(first.len(), 2);
+        assert!(first.iter().zip(&second).all(|(a, b)| Arc::ptr_eq(a, b)));
+        // The next pass drops the maps of the previous one
+        let next = renderer.photon_maps(&mut pool, 1, 2, 4);
+        assert!(next.iter().all(|map| first.iter().all(|old| !Arc::ptr_eq(map, old))));
+        assert_eq!(next[1].radius, settings.progressive_radius(3));
+    }
+
+    #[test]
+    fn photon_map_follows_the_seed() {
+        let origin = Pnt3::new(0.0, 0.0, 0.0);
+        let mut scene = testing::SceneBuilder::new().sphere(origin, 5.0, testing::grey(0.5)).light(origin, 1.0, 0).build();
+        scene.integrator = Integrator::Photon(PhotonSettings { photons: 100, radius: 1.0, max_depth: 2, alpha: 0.7 });
+        let renderer = Renderer::new(&scene);
+        let mut pool = Pool::new(2);
+        let first = renderer.photon_maps(&mut pool, 1, 0, 4);
+        assert_eq!(first.len(), 1);
+        assert!(Arc::ptr_eq(&first[0], &renderer.photon_maps(&mut pool, 1, 4, 8)[0]));
+        let other = renderer.photon_maps(&mut pool, 2, 0, 4);
+        assert!(first[0].photons.iter().zip(&other[0].photons).any(|(a, b)| a.p != b.p));
+    }
+}
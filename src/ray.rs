@@ -1,12 +1,27 @@
 use nalgebra::{Vector3, Vector4, Matrix4, Norm, Cross, Dot, Inverse, Eye};
 use types::*;
+use spectrum::Wavelengths;
+use float::{next_float_up, next_float_down};
 
-#[derive(Debug)]
+// Offset rays through the neighbouring pixels in x and y
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferential {
+    pub rx_origin: Pnt3,
+    pub rx_dir: Vec3,
+    pub ry_origin: Pnt3,
+    pub ry_dir: Vec3
+}
+
+#[derive(Debug, Clone)]
 pub struct Ray {
     pub tmin: Float,
     pub tmax: Float,
     pub origin: Pnt3,
-    pub dir: Vec3
+    pub dir: Vec3,
+    pub differential: Option<RayDifferential>,
+    pub depth: u32,
+    pub medium: Option<usize>,  // Index of the scene medium the ray travels through
+    pub wavelengths: Option<Wavelengths>    // Wavelengths of the color channels in spectral mode
 }
 
 impl Ray {
@@ -15,10 +30,45 @@ impl Ray {
             tmin: tmin,
             tmax: tmax,
             origin: origin.clone(),
-            dir: dir.normalize()
+            dir: dir.normalize(),
+            differential: None,
+            depth: 0,
+            medium: None,
+            wavelengths: None
         };
         // println!("{:?}", ray);
         ray
     }
+
+    // Shrinks the footprint when a pixel is covered by several samples
+    pub fn scale_differentials(&mut self, s: Float) {
+        if let Some(ref mut rd) = self.differential {
+            rd.rx_origin = self.origin + (rd.rx_origin - self.origin)*s;
+            rd.ry_origin = self.origin + (rd.ry_origin - self.origin)*s;
+            rd.rx_dir = self.dir + (rd.rx_dir - self.dir)*s;
+            rd.ry_dir = self.dir + (rd.ry_dir - self.dir)*s;
+        }
+    }
     
+}
+
+// Moves p along the normal n far enough to leave the error bounds of the surface point,
+// towards the side of w. Rounding is pushed away from p so the new origin never falls
+// back into the error box.
+// Source: Physically based Rendering, Chapter Shapes, Section Managing Rounding Error
+pub fn offset_ray_origin(p: &Pnt3, p_error: &Vec3, n: &Vec3, w: &Vec3) -> Pnt3 {
+    let d = n.x.abs()*p_error.x + n.y.abs()*p_error.y + n.z.abs()*p_error.z;
+    let mut offset = *n*d;
+    if w.dot(n) < 0.0 {
+        offset = -offset;
+    }
+    let mut po = *p + offset;
+    for i in 0..3 {
+        if offset[i] > 0.0 {
+            po[i] = next_float_up(po[i]);
+        } else if offset[i] < 0.0 {
+            po[i] = next_float_down(po[i]);
+        }
+    }
+    po
 }
\ No newline at end of file
@@ -2,47 +2,438 @@ use types::*;
 use scene::Scene;
 use ray::Ray;
 use hit::HitInfo;
+use light::Light;
+use shape::Shape;
+use shader::{AoSettings, ambient_occlusion};
+use aov;
+use aov::Aov;
+use bvh::{Aabb, Bvh};
+use stats;
+use stats::{Counter, Timer};
+use std::time::Instant;
+use std::f64;
+use nalgebra::Dot;
+use medium::Medium;
+use sampling;
+use spectrum;
+use camera::Camera;
+use film::Splat;
+use bdpt;
+use photon;
+use photon::{PhotonMap, PhotonSettings};
+use std::sync::{Arc, Mutex};
+use scoped_threadpool::Pool;
+
+// Medium boundaries a shadow ray passes before it is considered blocked
+const MAX_BOUNDARIES: u32 = 64;
+
+// Algorithm computing the beauty pass
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Integrator {
+    Whitted,    // Recursive ray tracing by the shaders
+    Bdpt(u32),  // Bidirectional path tracing up to a number of bounces
+    Photon(PhotonSettings), // Photon mapping with one photon map for all samples
+    ProgressivePhoton(PhotonSettings)   // Progressive photon mapping with a photon map per sample and a shrinking radius
+}
 
 pub struct Renderer<'a> {
-    scene: &'a Scene
+    scene: &'a Scene,
+    materials: Vec<usize>,
+    bvh: Bvh,
+    // Photon maps by seed and sample index, kept for the other tiles of the same samples
+    photons: Mutex<Vec<(u32, u32, Arc<PhotonMap>)>>
 }
 
 impl<'a> Renderer<'a> {
     pub fn new(scene: &'a Scene) -> Renderer
     {
+        let start = Instant::now();
+        let mut materials = Vec::new();
+        for s in &scene.shapes {
+            let shader = shader_ptr(&**s);
+            if !materials.contains(&shader) {
+                materials.push(shader);
+            }
+        }
+        let bounds: Vec<Aabb> = scene.shapes.iter().map(|s| s.bounds()).collect();
         let renderer = Renderer {
-            scene: scene
+            scene: scene,
+            materials: materials,
+            bvh: Bvh::new(&bounds),
+            photons: Mutex::new(Vec::new())
         };
+        if scene.spectral {
+            spectrum::prepare();
+        }
+        stats::time(Timer::Build, start.elapsed());
         renderer
     }
+
+    // Whether the samples of a pixel have to be taken in order of their index, one index for
+    // all pixels at a time
+    pub fn progressive(&self) -> bool {
+        match self.scene.integrator {
+            Integrator::ProgressivePhoton(_) => true,
+            _ => false
+        }
+    }
+
+    // Photon maps of the samples with the indices first..last, traced with the pool the first
+    // time they are asked for. Progressive photon mapping has a map per sample index and drops
+    // the maps of other samples, photon mapping has one map for all samples. Empty for the
+    // other integrators.
+    pub fn photon_maps(&self, pool: &mut Pool, seed: u32, first: u32, last: u32) -> Vec<Arc<PhotonMap>>
+    {
+        let (settings, keys): (PhotonSettings, Vec<(u32, u32)>) = match self.scene.integrator {
+            Integrator::Photon(settings) => (settings, vec![(seed, 0)]),
+            Integrator::ProgressivePhoton(settings) => (settings, (first..last).map(|sample| (seed, sample)).collect()),
+            _ => return Vec::new()
+        };
+        let mut cache = self.photons.lock().unwrap();
+        cache.retain(|&(seed, sample, _)| keys.contains(&(seed, sample)));
+        let mut maps = Vec::new();
+        for &(seed, sample) in &keys {
+            if let Some(&(_, _, ref map)) = cache.iter().find(|entry| entry.0 == seed && entry.1 == sample) {
+                maps.push(map.clone());
+                continue;
+            }
+            let radius = if self.progressive() { settings.progressive_radius(sample) } else { settings.radius };
+            let map = Arc::new(PhotonMap::trace(self, pool, &settings, seed, sample, radius));
+            cache.push((seed, sample, map.clone()));
+            maps.push(map);
+        }
+        maps
+    }
     pub fn render(&self, ray: &mut Ray) -> Color
     {
-        let mut c = Color::new(0.0, 0.0, 0.0);        
-        match self.intersect(ray) {
-            None => {
-                return c;
+        let hit = self.intersect(ray);
+        self.radiance(ray, hit.as_ref())
+    }
+
+    // Radiance arriving along the ray, whose segment ends at the closest surface hit
+    fn radiance(&self, ray: &Ray, hit: Option<&HitInfo>) -> Color
+    {
+        let mut weight = Color::new(1.0, 1.0, 1.0);
+        let mut emission = Color::new(0.0, 0.0, 0.0);
+        if let Some(medium) = ray.medium.map(|i| &*self.scene.media[i]) {
+            let sample = medium.sample(ray);
+            if let Some(t) = sample.t {
+                return sample.emission + sample.weight*self.scatter(ray, t, medium);
+            }
+            weight = sample.weight;
+            emission = sample.emission;
+        }
+        match hit {
+            Some(hit) if weight != Color::new(0.0, 0.0, 0.0) => {
+                stats::count(Counter::ShaderCalls);
+                emission + weight*hit.shape.shade(hit, &self)
             },
-            Some(hit) => {
-                c = hit.shape.shade(&hit, &self);
+            _ => emission
+        }
+    }
+
+    // Light scattered towards the ray origin at distance t in the medium, the direct light
+    // of the scene lights and further scattered light up to the volume depth
+    fn scatter(&self, ray: &Ray, t: Float, medium: &Medium) -> Color
+    {
+        let p = ray.origin + ray.dir*t;
+        let phase = medium.phase();
+        let mut c = Color::new(0.0, 0.0, 0.0);
+        for light in self.lights() {
+            let sample = light.sample(&p);
+            if sample.pdf <= 0.0 {
+                continue;
             }
+            let mut shadow = Ray::new(&p, sample.wi, 0.0, sample.dist);
+            shadow.depth = ray.depth + 1;
+            shadow.medium = ray.medium;
+            shadow.wavelengths = ray.wavelengths;
+            stats::count(Counter::ShadowRays);
+            let tr = self.transmittance(&shadow);
+            let radiance = spectrum::illuminant(&sample.radiance, ray.wavelengths.as_ref());
+            c += radiance*tr*(phase.eval(ray.dir.dot(&sample.wi))/sample.pdf);
+        }
+        if ray.depth + 1 < self.scene.volume_depth {
+            let (s, t) = sampling::next_2d();
+            let (wi, _) = phase.sample(&ray.dir, s, t);
+            let mut scattered = Ray::new(&p, wi, 0.0, f64::INFINITY);
+            scattered.depth = ray.depth + 1;
+            scattered.medium = ray.medium;
+            scattered.wavelengths = ray.wavelengths;
+            stats::count(Counter::SecondaryRays);
+            // The phase function is sampled exactly, so its value and density cancel
+            c += self.render(&mut scattered);
         }
-        // ToDo: Fog
         c
     }
-    
+
+    // Fraction of light passing along the ray. Surfaces block the light, except for medium
+    // boundaries which only change the medium.
+    pub fn transmittance(&self, ray: &Ray) -> Color
+    {
+        let mut ray = ray.clone();
+        let mut tr = Color::new(1.0, 1.0, 1.0);
+        for _ in 0..MAX_BOUNDARIES {
+            let end = ray.origin + ray.dir*ray.tmax;
+            let unbounded = ray.tmax.is_infinite();
+            let hit = self.intersect(&mut ray);
+            if let Some(i) = ray.medium {
+                tr = tr*self.scene.media[i].transmittance(&ray);
+            }
+            let hit = match hit {
+                None => return tr,
+                Some(hit) => hit
+            };
+            if hit.shape.shader().interior().is_none() {
+                return Color::new(0.0, 0.0, 0.0);
+            }
+            let mut next = hit.spawn_ray(ray.dir);
+            next.depth = ray.depth;
+            next.medium = self.medium_after(&hit, &ray.dir);
+            if !unbounded {
+                next.tmax = (end - next.origin).dot(&ray.dir);
+            }
+            ray = next;
+        }
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    // Closest surface along the ray that is not a medium boundary, with the transmittance up
+    // to it
+    pub fn next_surface(&self, ray: &Ray) -> Option<(HitInfo, Color)>
+    {
+        let mut ray = ray.clone();
+        let mut tr = Color::new(1.0, 1.0, 1.0);
+        for _ in 0..MAX_BOUNDARIES {
+            let hit = self.intersect(&mut ray);
+            if let Some(i) = ray.medium {
+                tr = tr*self.scene.media[i].transmittance(&ray);
+            }
+            let hit = match hit {
+                None => return None,
+                Some(hit) => hit
+            };
+            if hit.shape.shader().interior().is_none() {
+                return Some((hit, tr));
+            }
+            let mut next = hit.spawn_ray(ray.dir);
+            next.depth = ray.depth;
+            next.medium = self.medium_after(&hit, &ray.dir);
+            ray = next;
+        }
+        None
+    }
+
+    // Medium on the side of the surface the direction points to. Media do not nest, leaving
+    // a medium boundary returns to the global medium.
+    pub fn medium_after(&self, hit: &HitInfo, dir: &Vec3) -> Option<usize>
+    {
+        match hit.shape.shader().interior() {
+            None => hit.medium,
+            Some(interior) => if dir.dot(&hit.outward()) < 0.0 { Some(interior) } else { self.scene.fog }
+        }
+    }
+
+    // Light paths of the integrator add their contributions to other pixels to splats. The
+    // photon map of the sample is given for photon mapping.
+    pub fn render_aovs(&self, ray: &mut Ray, aovs: &[Aov], camera: &Camera, splats: &mut Vec<Splat>,
+                       photons: Option<&PhotonMap>) -> Vec<Color>
+    {
+        // Camera rays start in the global medium
+        ray.medium = self.scene.fog;
+        // The integrators tracing their own paths start from the camera ray before the
+        // intersection shortened it
+        let camera_ray = ray.clone();
+        let visited = stats::local(Counter::BvhNodes);
+        let hit = self.intersect(ray);
+        let cost = stats::local(Counter::BvhNodes).saturating_sub(visited);
+        let mut beauty = None;
+        let mut occlusion = None;
+        let mut values: Vec<Color> = aovs.iter().map(|aov| match (*aov, hit.as_ref()) {
+            (Aov::Debug(mode), hit) => mode.eval(hit, cost, &self),
+            (Aov::Beauty, hit) => {
+                let c = self.beauty(ray, &camera_ray, hit, camera, splats, photons);
+                beauty = Some(c);
+                c
+            },
+            (Aov::LightGroupOther, _) => Color::new(0.0, 0.0, 0.0),
+            (Aov::AmbientOcclusion, Some(hit)) | (Aov::BentNormal, Some(hit)) => {
+                if occlusion.is_none() {
+                    occlusion = Some(ambient_occlusion(hit, &self, self.ao_settings()));
+                }
+                aov.occlusion(occlusion.as_ref().unwrap())
+            },
+            (_, None) => aov.miss(),
+            (_, Some(hit)) => aov.eval(hit, &self)
+        }).collect();
+        // Whatever the light groups miss of the beauty of this sample, so they always add up to it
+        if let Some(i) = aovs.iter().position(|aov| *aov == Aov::LightGroupOther) {
+            let beauty = match beauty {
+                Some(c) => c,
+                None => self.beauty(ray, &camera_ray, hit.as_ref(), camera, splats, photons)
+            };
+            values[i] = beauty - hit.as_ref().map_or(Color::new(0.0, 0.0, 0.0), |hit| aov::direct_light(hit, &self));
+        }
+        values
+    }
+
+    fn beauty(&self, ray: &Ray, camera_ray: &Ray, hit: Option<&HitInfo>, camera: &Camera, splats: &mut Vec<Splat>,
+              photons: Option<&PhotonMap>) -> Color
+    {
+        match self.scene.integrator {
+            Integrator::Bdpt(max_depth) => bdpt::radiance(&self, camera, camera_ray, max_depth, splats),
+            Integrator::Photon(settings) | Integrator::ProgressivePhoton(settings) => match photons {
+                Some(photons) => photon::radiance(&self, camera_ray, photons, settings.max_depth),
+                None => Color::new(0.0, 0.0, 0.0)
+            },
+            Integrator::Whitted if self.scene.spectral => self.spectral_radiance(ray, hit),
+            Integrator::Whitted => self.radiance(ray, hit)
+        }
+    }
+
+    // Color of the radiance arriving along the ray at randomly sampled wavelengths
+    fn spectral_radiance(&self, ray: &Ray, hit: Option<&HitInfo>) -> Color
+    {
+        let wavelengths = spectrum::sample_wavelengths(sampling::next_float());
+        let mut ray = ray.clone();
+        ray.wavelengths = Some(wavelengths);
+        let hit = hit.map(|hit| {
+            let mut hit = hit.clone();
+            hit.wavelengths = Some(wavelengths);
+            hit
+        });
+        let radiance = self.radiance(&ray, hit.as_ref());
+        spectrum::to_rgb(&spectrum::to_xyz(&radiance, &wavelengths))
+    }
+
     pub fn intersect(&self, ray: &mut Ray) -> Option<HitInfo>
     {
         let mut value = None;
-        for s in &self.scene.shapes {
-            match s.intersect(&ray) {
+        let shapes = &self.scene.shapes;
+        self.bvh.traverse(ray, |i, ray| {
+            match shapes[i].intersect(ray) {
                 None => {},
-                Some(hit) => {
+                Some(mut hit) => {
                     ray.tmax = hit.d;
-                    value = Some(hit);               
+                    hit.object = i;
+                    value = Some(hit);
                 }
             }
+        });
+        if let Some(ref mut hit) = value {
+            hit.compute_differentials(ray);
+            hit.medium = ray.medium;
+            hit.wavelengths = ray.wavelengths;
         }
         value
     }
+
+    pub fn lights(&self) -> &Vec<Box<Light>>
+    {
+        &self.scene.lights
+    }
+
+    // Medium filling the scene outside of medium boundaries
+    pub fn fog(&self) -> Option<usize>
+    {
+        self.scene.fog
+    }
+
+    // Ambient occlusion settings of the AOVs
+    pub fn ao_settings(&self) -> &AoSettings
+    {
+        &self.scene.ao
+    }
+
+    // Index of the shader of the shape, in order of first use in the scene
+    pub fn material_id(&self, shape: &Shape) -> Option<usize>
+    {
+        let shader = shader_ptr(shape);
+        self.materials.iter().position(|m| *m == shader)
+    }
 }
 unsafe impl<'a> Send for Renderer<'a> {}
+
+fn shader_ptr(shape: &Shape) -> usize {
+    shape.shader() as *const _ as *const u8 as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Norm;
+    use types::*;
+    use aov::Aov;
+    use renderer::Renderer;
+    use scene::Scene;
+    use window::Window;
+    use testing;
+    use testing::{SceneBuilder, ambient_occlusion, grey};
+    use sampling;
+    use stats;
+    use stats::Counter;
+
+    // Diffuse ground lit by two light groups next to a sphere only lit by ambient occlusion
+    fn scene() -> Scene {
+        SceneBuilder::new()
+            .sphere(Pnt3::new(0.0, 0.0, 0.0), 1.0, ambient_occlusion(0.8))
+            .ground(grey(0.5))
+            .light(Pnt3::new(-3.0, -5.0, 5.0), 20.0, 0)
+            .light(Pnt3::new(3.0, -5.0, 5.0), 10.0, 1)
+            .build()
+    }
+
+    #[test]
+    fn light_groups_add_up_to_beauty() {
+        let mut window = Window::new(16, 12);
+        window.samples = 2;
+        let aovs = [Aov::Beauty, Aov::LightGroup(0), Aov::LightGroup(1), Aov::LightGroupOther];
+        let films = testing::draw_aovs(&window, &scene(), &aovs);
+        let mut other = 0.0;
+        for i in 0..films[0].pixels.len() {
+            let sum = films[1].pixels[i] + films[2].pixels[i] + films[3].pixels[i];
+            for c in 0..3 {
+                assert!((sum[c] - films[0].pixels[i][c]).abs() < 1e-9, "{:?} {:?}", sum, films[0].pixels[i]);
+            }
+            other += films[3].pixels[i].x;
+        }
+        // The ambient occlusion sphere is in none of the light groups
+        assert!(other > 0.0);
+    }
+
+    #[test]
+    fn other_group_without_beauty() {
+        let window = Window::new(8, 6);
+        let films = testing::draw_aovs(&window, &scene(), &[Aov::LightGroupOther]);
+        assert!(films[0].pixels.iter().any(|c| c.x > 0.0));
+    }
+
+    #[test]
+    fn single_sample_goes_through_the_pixel_corner() {
+        let scene = scene();
+        let window = Window::new(16, 12);
+        let renderer = Renderer::new(&scene);
+        let film = &testing::draw_aovs(&window, &scene, &[Aov::Depth])[0];
+        for y in 0..12 {
+            for x in 0..16 {
+                let mut ray = window.camera.generate_ray(x, y);
+                let depth = renderer.intersect(&mut ray).map_or(f64::INFINITY, |hit| hit.d);
+                assert_eq!(film.get(x, y).x, depth);
+            }
+        }
+    }
+
+    #[test]
+    fn occlusion_aovs_share_the_rays() {
+        let scene = scene();
+        let window = Window::new(16, 12);
+        let renderer = Renderer::new(&scene);
+        sampling::start_sample(0, 8, 6, 0);
+        let mut ray = window.camera.generate_ray(8, 6);
+        let rays = stats::local(Counter::AoRays);
+        let values = renderer.render_aovs(&mut ray, &[Aov::AmbientOcclusion, Aov::BentNormal], &window.camera,
+                                          &mut Vec::new(), None);
+        assert_eq!(stats::local(Counter::AoRays) - rays, renderer.ao_settings().samples as u64);
+        assert!(values[0].x > 0.0 && values[0].x <= 1.0);
+        assert!((values[1].norm() - 1.0).abs() < 1e-9);
+    }
+}
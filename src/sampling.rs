@@ -0,0 +1,120 @@
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::cell::RefCell;
+use types::*;
+use film::Pixel;
+
+// Random numbers are drawn from a per thread generator that is restarted for every pixel
+// sample, so an image only depends on the seed and not on the thread scheduling.
+thread_local!(static RNG: RefCell<XorShiftRng> = RefCell::new(XorShiftRng::new_unseeded()));
+
+fn mix(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    h
+}
+
+pub fn start_sample(seed: u32, x: u32, y: u32, sample: u32) {
+    let a = mix(seed ^ mix(x.wrapping_add(mix(y))));
+    let b = mix(a ^ sample);
+    let state = [mix(b ^ 0x9e3779b9), mix(b ^ 0x7f4a7c15), mix(b ^ 0x94d049bb), mix(b) | 1];
+    RNG.with(|rng| *rng.borrow_mut() = XorShiftRng::from_seed(state));
+}
+
+pub fn next_float() -> Float {
+    RNG.with(|rng| rng.borrow_mut().gen::<Float>())
+}
+
+pub fn next_2d() -> (Float, Float) {
+    let s = next_float();
+    let t = next_float();
+    (s, t)
+}
+
+// Jittered grid of count samples over the unit square. When count does not fill the grid a
+// random subset of the cells is used, which keeps each sample uniformly distributed. The
+// cells are visited in order and each is kept with the probability of the samples still
+// needed among the cells left, so nothing has to be allocated.
+pub fn stratified_2d(count: u32) -> Stratified2d {
+    let nx = (count as Float).sqrt().ceil() as u32;
+    let ny = if nx > 0 { (count + nx - 1)/nx } else { 0 };
+    Stratified2d { nx: nx, ny: ny, cell: 0, remaining: count }
+}
+
+pub struct Stratified2d {
+    nx: u32,
+    ny: u32,
+    cell: u32,
+    remaining: u32
+}
+
+impl Iterator for Stratified2d {
+    type Item = (Float, Float);
+
+    fn next(&mut self) -> Option<(Float, Float)> {
+        while self.remaining > 0 {
+            let (x, y) = (self.cell % self.nx, self.cell/self.nx);
+            let left = self.nx*self.ny - self.cell;
+            self.cell += 1;
+            if left > self.remaining && next_float()*left as Float >= self.remaining as Float {
+                continue;
+            }
+            self.remaining -= 1;
+            let (s, t) = next_2d();
+            return Some(((x as Float + s)/self.nx as Float, (y as Float + t)/self.ny as Float));
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for Stratified2d {}
+
+// Keeps sampling a pixel until the relative standard error of its mean falls below the threshold
+#[derive(Debug, Clone)]
+pub struct AdaptiveSampler {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: Float
+}
+
+impl AdaptiveSampler {
+    pub fn done(&self, pixel: &Pixel) -> bool {
+        if pixel.samples >= self.max_samples {
+            return true;
+        }
+        if pixel.samples < self.min_samples.max(2) {
+            return false;
+        }
+        pixel.relative_error() <= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use super::*;
+
+    #[test]
+    fn stratified_samples_fill_distinct_cells() {
+        start_sample(1, 2, 3, 4);
+        for count in 0..20 {
+            let nx = (count as Float).sqrt().ceil() as usize;
+            let ny = if nx > 0 { (count + nx - 1)/nx } else { 0 };
+            let mut cells = vec![false; nx*ny];
+            assert_eq!(stratified_2d(count as u32).len(), count);
+            for (s, t) in stratified_2d(count as u32) {
+                assert!(s >= 0.0 && s < 1.0 && t >= 0.0 && t < 1.0);
+                let cell = (t*ny as Float) as usize*nx + (s*nx as Float) as usize;
+                assert!(!cells[cell]);
+                cells[cell] = true;
+            }
+            assert_eq!(cells.iter().filter(|c| **c).count(), count);
+        }
+    }
+}
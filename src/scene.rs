@@ -2,31 +2,74 @@ use std::rc::Rc;
 use std::vec::Vec;
 use std::boxed::Box;
 use light::Light;
+use medium::Medium;
 use shape::*;
 use types::*;
 use shader::*;
+use texture::*;
 use hit::HitInfo;
 use ray::Ray;
+use aov::Aov;
+use camera::View;
+use loader;
+use std::path::Path;
+use std::time::Instant;
+use stats;
+use stats::Timer;
+use colorspace::ColorSpace;
+use renderer::Integrator;
 // use std::num::abs;
 use nalgebra::{Vector3, Vector4, Matrix4, Norm, Cross, Dot, Inverse, Eye};
 
 
 pub struct Scene {
     pub shapes: Vec<Box<Shape>>,
-    pub lights: Vec<Box<Light>>
+    pub lights: Vec<Box<Light>>,
+    pub aovs: Vec<Aov>,  // Passes written next to the beauty image
+    pub view: Option<View>,
+    pub ao: AoSettings, // Used by the ambient occlusion and bent normal AOVs
+    pub media: Vec<Box<Medium>>,
+    pub fog: Option<usize>, // Medium filling the scene outside of closed medium boundaries
+    pub volume_depth: u32,  // Path depth up to which light scattered in media is traced further
+    pub spectral: bool, // Render with sampled wavelengths instead of RGB
+    pub integrator: Integrator,
+    pub working: ColorSpace,    // Space of the scene colors and of the rendering
+    pub output: Option<ColorSpace>  // Space of written images, untagged linear PNGs without it
     // pub elements: Vec<Vector3>
 }
 
 impl Scene {
+    pub fn empty() -> Scene {
+        Scene {
+            shapes: Vec::new(),
+            lights: Vec::new(),
+            aovs: Vec::new(),
+            view: None,
+            ao: AoSettings::new(),
+            media: Vec::new(),
+            fog: None,
+            volume_depth: 8,
+            spectral: false,
+            integrator: Integrator::Whitted,
+            working: ColorSpace::Linear,
+            output: None
+        }
+    }
+    pub fn load(path: &Path) -> Result<Scene, String> {
+        let start = Instant::now();
+        let scene = loader::load(path);
+        stats::time(Timer::Build, start.elapsed());
+        scene
+    }
     pub fn new() -> Scene {
         let gouraud_shader = Rc::new(
-                GouraudShader { color: Color::new(1.0, 0.0, 0.0) }
+                GouraudShader { color: Rc::new(ConstantTexture { color: Color::new(1.0, 0.0, 0.0) }) }
             );
         let gouraud_shader_green = Rc::new(
-                GouraudShader { color: Color::new(0.0, 1.0, 0.0) }
+                GouraudShader { color: Rc::new(ConstantTexture { color: Color::new(0.0, 1.0, 0.0) }) }
             );
         let ambient_occlusion_shader = Rc::new(
-                AmbientOcculusionShader { samples: 256, color: Color::new(0.7411, 0.7411, 0.7411) }
+                AmbientOcculusionShader { settings: AoSettings { samples: 256, ..AoSettings::new() }, color: Rc::new(ConstantTexture { color: Color::new(0.7411, 0.7411, 0.7411) }) }
             );
 
         let mut shapes: Vec<Box<Shape>> = Vec::new();
@@ -67,7 +110,17 @@ impl Scene {
         let mut lights: Vec<Box<Light>> = Vec::new();
         let mut scene = Scene {
             shapes: shapes,
-            lights: lights
+            lights: lights,
+            aovs: Vec::new(),
+            view: None,
+            ao: AoSettings::new(),
+            media: Vec::new(),
+            fog: None,
+            volume_depth: 8,
+            spectral: false,
+            integrator: Integrator::Whitted,
+            working: ColorSpace::Linear,
+            output: None
         };
         scene
     }
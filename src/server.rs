@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use scoped_threadpool::Pool;
+use types::*;
+use aov::Aov;
+use distributed::{Job, tiles};
+use colorspace::to_png;
+use film::{Accumulator, SplatBuffer};
+use http;
+use http::Request;
+use loader;
+use renderer::Renderer;
+use sampling::AdaptiveSampler;
+
+// Render queue behind a local HTTP API:
+//
+//   POST   /jobs?spp=16&width=640&height=480   submit a scene file as the request body
+//   POST   /jobs?dir=/path/to/scenes           resolve relative files of the scene in that directory
+//   GET    /jobs                               status of all jobs
+//   GET    /jobs/ID                            status with tiles done, samples per second and ETA
+//   DELETE /jobs/ID                            cancel a queued or running job
+//   GET    /jobs/ID/image                      PNG of the current or finished image
+//
+// Jobs are rendered one after another, tile by tile, by a single render thread. Scenes
+// submitted without an absolute dir may only reference files by absolute path.
+
+// Seconds between preview images of a running job
+const PREVIEW_INTERVAL: u64 = 1;
+
+// Largest image a job may render, the film of a larger one could exhaust the memory of the
+// whole server
+const MAX_PIXELS: u64 = 1 << 25;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed(String)
+}
+
+struct Entry {
+    id: u32,
+    name: String,
+    source: String,
+    dir: Option<PathBuf>,
+    job: Job,
+    tile_size: u32,
+    status: Status,
+    tiles_done: usize,
+    tiles_total: usize,
+    samples: u64,
+    elapsed: Duration,
+    cancel: bool,
+    image: Option<Vec<u8>>
+}
+
+impl Entry {
+    fn to_json(&self) -> String {
+        let seconds = self.elapsed.as_secs() as Float + self.elapsed.subsec_nanos() as Float*1e-9;
+        let rate = if seconds > 0.0 { self.samples as Float/seconds } else { 0.0 };
+        let eta = if self.tiles_done > 0 && self.status == Status::Running {
+            format!("{:.1}", seconds/self.tiles_done as Float*(self.tiles_total - self.tiles_done) as Float)
+        } else {
+            "null".to_string()
+        };
+        let (status, error) = match self.status {
+            Status::Queued => ("queued", "null".to_string()),
+            Status::Running => ("running", "null".to_string()),
+            Status::Done => ("done", "null".to_string()),
+            Status::Cancelled => ("cancelled", "null".to_string()),
+            Status::Failed(ref e) => ("failed", http::json_string(e))
+        };
+        format!("{{\"id\": {}, \"name\": {}, \"status\": \"{}\", \"width\": {}, \"height\": {}, \"tiles_done\": {}, \"tiles_total\": {}, \"samples\": {}, \"elapsed_seconds\": {:.1}, \"samples_per_second\": {:.0}, \"eta_seconds\": {}, \"error\": {}}}",
+            self.id, http::json_string(&self.name), status, self.job.width, self.job.height,
+            self.tiles_done, self.tiles_total, self.samples, seconds, rate, eta, error)
+    }
+}
+
+struct Jobs {
+    entries: Vec<Entry>,
+    next_id: u32
+}
+
+// The render thread marks a job failed when rendering it panics, the entries stay consistent
+fn lock(jobs: &Mutex<Jobs>) -> MutexGuard<Jobs> {
+    jobs.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn serve<F>(listener: TcpListener, mut log: F)
+    where F: FnMut(&str)
+{
+    let jobs = Arc::new(Mutex::new(Jobs { entries: Vec::new(), next_id: 1 }));
+    if let Ok(address) = listener.local_addr() {
+        log(&format!("Job server listening on http://{}/jobs", address));
+    }
+    {
+        let jobs = jobs.clone();
+        thread::spawn(move || render_jobs(&jobs));
+    }
+    for stream in listener.incoming() {
+        if let Ok(stream) = stream {
+            let jobs = jobs.clone();
+            thread::spawn(move || {
+                let _ = handle(stream, &jobs);
+            });
+        }
+    }
+}
+
+fn handle(mut stream: TcpStream, jobs: &Mutex<Jobs>) -> io::Result<()> {
+    let request = http::read_request(&stream)?;
+    let segments = request.segments();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["jobs"]) => {
+            match submit(&request, jobs) {
+                Ok(id) => http::respond_json(&mut stream, 201, &format!("{{\"id\": {}}}", id)),
+                Err(e) => http::respond_error(&mut stream, 400, &e)
+            }
+        },
+        ("GET", ["jobs"]) => {
+            let jobs = lock(jobs);
+            let list: Vec<String> = jobs.entries.iter().map(|e| e.to_json()).collect();
+            http::respond_json(&mut stream, 200, &format!("[{}]", list.join(", ")))
+        },
+        (method, ["jobs", id]) => {
+            let mut jobs = lock(jobs);
+            let entry = match id.parse().ok().and_then(|id: u32| jobs.entries.iter_mut().find(|e| e.id == id)) {
+                Some(entry) => entry,
+                None => return http::respond_error(&mut stream, 404, "No such job")
+            };
+            match method {
+                "GET" => http::respond_json(&mut stream, 200, &entry.to_json()),
+                "DELETE" => {
+                    match entry.status {
+                        Status::Queued => entry.status = Status::Cancelled,
+                        Status::Running => entry.cancel = true,
+                        _ => return http::respond_error(&mut stream, 409, "Job already finished")
+                    }
+                    http::respond_json(&mut stream, 200, &entry.to_json())
+                },
+                _ => http::respond_error(&mut stream, 405, "Method not allowed")
+            }
+        },
+        ("GET", ["jobs", id, "image"]) => {
+            let image = {
+                let jobs = lock(jobs);
+                id.parse().ok()
+                    .and_then(|id: u32| jobs.entries.iter().find(|e| e.id == id))
+                    .and_then(|e| e.image.clone())
+            };
+            match image {
+                Some(png) => http::respond(&mut stream, 200, "image/png", &png),
+                None => http::respond_error(&mut stream, 404, "No image yet")
+            }
+        },
+        _ => http::respond_error(&mut stream, 404, "Unknown endpoint")
+    }
+}
+
+fn submit(request: &Request, jobs: &Mutex<Jobs>) -> Result<u32, String> {
+    let source = String::from_utf8(request.body.clone()).map_err(|_| "Scene is not UTF-8".to_string())?;
+    let q = &request.query;
+    // The server's working directory means nothing to the client, relative files need a dir
+    let dir = match q.get("dir") {
+        Some(dir) if Path::new(dir).is_absolute() => Some(PathBuf::from(dir)),
+        Some(dir) => return Err(format!("Scene directory {} is not absolute", dir)),
+        None => None
+    };
+    // Reject broken scenes right away instead of failing in the queue
+    loader::parse(&source, dir.as_ref().map(|d| d.as_path()))?;
+
+    let samples = param(q, "spp", 1)?;
+    let adaptive = match q.get("adaptive") {
+        Some(_) => {
+            let min_samples = param(q, "min_spp", 4)?;
+            Some(AdaptiveSampler {
+                min_samples: min_samples,
+                max_samples: samples.max(min_samples),
+                threshold: param(q, "adaptive", 0.0)?
+            })
+        },
+        None => None
+    };
+    // The scene is parsed from the source instead of being loaded from a file
+    let job = Job {
+        scene: String::new(),
+        width: param(q, "width", 1024)?,
+        height: param(q, "height", 768)?,
+        seed: param(q, "seed", 0)?,
+        samples: samples,
+        adaptive: adaptive,
+        aovs: vec![Aov::Beauty]
+    };
+    if job.width == 0 || job.height == 0 {
+        return Err("Empty image".to_string());
+    }
+    if job.width as u64*job.height as u64 > MAX_PIXELS {
+        return Err(format!("Image larger than {} pixels", MAX_PIXELS));
+    }
+    let tile_size = param(q, "tile_size", 64)?;
+
+    // Only accepted jobs take an id
+    let mut jobs = lock(jobs);
+    let id = jobs.next_id;
+    jobs.next_id += 1;
+    jobs.entries.push(Entry {
+        id: id,
+        name: q.get("name").cloned().unwrap_or(format!("job {}", id)),
+        source: source,
+        dir: dir,
+        job: job,
+        tile_size: tile_size,
+        status: Status::Queued,
+        tiles_done: 0,
+        tiles_total: 0,
+        samples: 0,
+        elapsed: Duration::from_secs(0),
+        cancel: false,
+        image: None
+    });
+    Ok(id)
+}
+
+fn param<T: FromStr>(query: &HashMap<String, String>, key: &str, default: T) -> Result<T, String> {
+    match query.get(key) {
+        None => Ok(default),
+        Some(v) => v.parse().map_err(|_| format!("Invalid value {} for {}", v, key))
+    }
+}
+
+fn render_jobs(jobs: &Mutex<Jobs>) {
+    loop {
+        let next = {
+            let mut jobs = lock(jobs);
+            match jobs.entries.iter_mut().find(|e| e.status == Status::Queued) {
+                Some(entry) => {
+                    entry.status = Status::Running;
+                    Some((entry.id, entry.source.clone(), entry.dir.clone(), entry.job.clone(), entry.tile_size))
+                },
+                None => None
+            }
+        };
+        match next {
+            Some((id, source, dir, job, tile_size)) => {
+                let rendered = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    render_job(jobs, id, &source, dir.as_ref().map(|d| d.as_path()), &job, tile_size)
+                }));
+                if let Err(e) = rendered {
+                    let message = match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
+                        (Some(s), _) => s.to_string(),
+                        (_, Some(s)) => s.clone(),
+                        _ => "unknown error".to_string()
+                    };
+                    let mut jobs = lock(jobs);
+                    if let Some(entry) = jobs.entries.iter_mut().find(|e| e.id == id) {
+                        entry.status = Status::Failed(format!("Rendering panicked: {}", message));
+                    }
+                }
+            },
+            None => thread::sleep(Duration::from_millis(100))
+        }
+    }
+}
+
+fn render_job(jobs: &Mutex<Jobs>, id: u32, source: &str, dir: Option<&Path>, job: &Job, tile_size: u32) {
+    // Looks the job up again for every update, the list only ever grows
+    let update = |f: &mut FnMut(&mut Entry)| {
+        let mut jobs = lock(jobs);
+        if let Some(entry) = jobs.entries.iter_mut().find(|e| e.id == id) {
+            f(entry);
+        }
+    };
+    let scene = match loader::parse(source, dir) {
+        Ok(scene) => scene,
+        Err(e) => {
+            update(&mut |entry| entry.status = Status::Failed(e.clone()));
+            return;
+        }
+    };
+    let window = job.window(&scene);
+    let renderer = Renderer::new(&scene);
+    let mut pool = Pool::new(4);
+    let tiles = tiles(job, tile_size, None);
+    update(&mut |entry| entry.tiles_total = tiles.len());
+
+    let start = Instant::now();
+    let mut last_preview = start;
+    let mut accumulator = Accumulator::new(job.width, job.height, &job.aovs);
+    let mut splats = SplatBuffer::new(job.width, job.height);
+    for (i, tile) in tiles.iter().enumerate() {
+        let mut cancelled = false;
+        update(&mut |entry| cancelled = entry.cancel);
+        if cancelled {
+            update(&mut |entry| entry.status = Status::Cancelled);
+            return;
+        }
+        let mut pixels = Accumulator::new(tile.width, tile.height, &job.aovs);
+        window.render_tile(&mut pool, &renderer, &mut pixels, &mut splats, tile.x0, tile.y0, tile.first_sample, tile.samples);
+        accumulator.merge_tile(tile.x0, tile.y0, &pixels, tile.first_sample == 0);
+
+        let now = Instant::now();
+        let finished = i + 1 == tiles.len();
+        let image = if finished || now.duration_since(last_preview) >= Duration::from_secs(PREVIEW_INTERVAL) {
+            last_preview = now;
+            accumulator.add_splats(&splats);
+            splats.clear();
+            to_png(&accumulator.films()[0], scene.working, scene.output).ok()
+        } else {
+            None
+        };
+        let samples = accumulator.samples();
+        update(&mut |entry| {
+            entry.tiles_done = i + 1;
+            entry.samples = samples;
+            entry.elapsed = now.duration_since(start);
+            if image.is_some() {
+                entry.image = image.clone();
+            }
+            if finished {
+                entry.status = Status::Done;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use testing::request;
+    use super::*;
+
+    const SCENE: &'static str = "camera position 0 -10 3 front 0 1 -0.3 up 0 0 -1 fov 45
+shader grey diffuse color 0.74 0.74 0.74
+sphere position 0 0 0 radius 1 shader grey
+light point position 1.5 -1 5 intensity 40 40 40
+";
+
+    fn start() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, |_| {}));
+        address
+    }
+
+    fn json(address: &str, method: &str, target: &str, body: &str) -> (u16, String) {
+        let (status, body) = request(address, method, target, body);
+        (status, String::from_utf8(body).unwrap())
+    }
+
+    // Raw value of a key in a flat JSON object
+    fn field<'a>(json: &'a str, key: &str) -> &'a str {
+        let pattern = format!("\"{}\": ", key);
+        let start = json.find(&pattern).unwrap_or_else(|| panic!("No {} in {}", key, json)) + pattern.len();
+        let end = json[start..].find(|c| c == ',' || c == '}').unwrap();
+        &json[start..start + end]
+    }
+
+    fn submit(address: &str, query: &str, scene: &str) -> String {
+        let (status, body) = json(address, "POST", &format!("/jobs?{}", query), scene);
+        assert_eq!(status, 201, "{}", body);
+        field(&body, "id").to_string()
+    }
+
+    fn wait_for(address: &str, id: &str, status: &str) -> String {
+        for _ in 0..600 {
+            let (code, body) = json(address, "GET", &format!("/jobs/{}", id), "");
+            assert_eq!(code, 200);
+            if field(&body, "status") == format!("\"{}\"", status) {
+                return body;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("Job {} never became {}", id, status);
+    }
+
+    #[test]
+    fn submitted_jobs_are_rendered() {
+        let address = start();
+        let id = submit(&address, "width=16&height=12&tile_size=8&spp=2&name=small+test", SCENE);
+        let (status, body) = json(&address, "GET", "/jobs", "");
+        assert_eq!(status, 200);
+        assert!(body.starts_with('[') && body.contains("\"name\": \"small test\""), "{}", body);
+
+        let done = wait_for(&address, &id, "done");
+        assert_eq!(field(&done, "tiles_total"), "4");
+        assert_eq!(field(&done, "tiles_done"), "4");
+        assert_eq!(field(&done, "samples"), "384");
+        assert!(field(&done, "samples_per_second").parse::<Float>().unwrap() > 0.0, "{}", done);
+        assert_eq!(field(&done, "eta_seconds"), "null");
+        assert_eq!(field(&done, "error"), "null");
+
+        let (status, png) = request(&address, "GET", &format!("/jobs/{}/image", id), "");
+        assert_eq!(status, 200);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(json(&address, "DELETE", &format!("/jobs/{}", id), "").0, 409);
+    }
+
+    #[test]
+    fn running_and_queued_jobs_are_cancelled() {
+        let address = start();
+        let running = submit(&address, "width=1024&height=768&tile_size=8&spp=16", SCENE);
+        let queued = submit(&address, "width=16&height=12", SCENE);
+        wait_for(&address, &running, "running");
+        assert_eq!(json(&address, "GET", &format!("/jobs/{}/image", queued), "").0, 404);
+
+        let (status, body) = json(&address, "DELETE", &format!("/jobs/{}", queued), "");
+        assert_eq!(status, 200);
+        assert_eq!(field(&body, "status"), "\"cancelled\"");
+        assert_eq!(json(&address, "DELETE", &format!("/jobs/{}", running), "").0, 200);
+        let cancelled = wait_for(&address, &running, "cancelled");
+        assert!(field(&cancelled, "tiles_done").parse::<usize>().unwrap() < field(&cancelled, "tiles_total").parse().unwrap());
+        assert_eq!(field(&wait_for(&address, &queued, "cancelled"), "tiles_done"), "0");
+    }
+
+    #[test]
+    fn bad_requests_are_rejected() {
+        let address = start();
+        let (status, body) = json(&address, "POST", "/jobs", "sphere shader red\n");
+        assert_eq!(status, 400);
+        assert_eq!(body, "{\"error\": \"1: Unknown shader red\"}");
+        assert_eq!(json(&address, "POST", "/jobs?spp=many", SCENE).0, 400);
+        assert_eq!(json(&address, "POST", "/jobs?width=0", SCENE).0, 400);
+        assert_eq!(json(&address, "POST", "/jobs?width=100000&height=100000", SCENE).0, 400);
+        assert_eq!(json(&address, "POST", "/jobs?width=65536&height=65536", SCENE).0, 400);
+        assert_eq!(json(&address, "POST", "/jobs?tile_size=-8", SCENE).0, 400);
+        assert_eq!(json(&address, "GET", "/jobs/7", "").0, 404);
+        assert_eq!(json(&address, "GET", "/jobs/x/image", "").0, 404);
+        assert_eq!(json(&address, "PUT", "/jobs", "").0, 404);
+        assert_eq!(json(&address, "GET", "/jobs", "").1, "[]");
+        // Rejected jobs take no id
+        assert_eq!(submit(&address, "width=8&height=8", SCENE), "1");
+    }
+
+    #[test]
+    fn relative_files_resolve_against_the_given_directory() {
+        let dir = env::temp_dir().join(format!("raytracer-server-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("quad.obj"), "v -1 0 -1\nv 1 0 -1\nv 1 0 1\nv -1 0 1\nf 1 2 3 4\n").unwrap();
+        let scene = format!("{}mesh path quad.obj shader grey\n", SCENE);
+        let address = start();
+
+        let (status, body) = json(&address, "POST", "/jobs", &scene);
+        assert_eq!(status, 400);
+        assert!(body.contains("Relative path quad.obj needs a base directory"), "{}", body);
+        let (status, body) = json(&address, "POST", "/jobs?dir=scenes", &scene);
+        assert_eq!(status, 400);
+        assert!(body.contains("Scene directory scenes is not absolute"), "{}", body);
+
+        let id = submit(&address, &format!("width=8&height=8&dir={}", dir.display()), &scene);
+        wait_for(&address, &id, "done");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
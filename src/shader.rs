@@ -4,79 +4,528 @@ use types::*;
 use hit::HitInfo;
 use std::option::Option;
 use std::f64;
-use rand::{thread_rng, ThreadRng, Rng};
+use sampling;
+use stats;
+use stats::Counter;
 use warp::*;
 use std::f64::consts::*;
+use std::rc::Rc;
+use texture::Texture;
+use medium::{HenyeyGreenstein, attenuation};
+use spectrum::{Ior, LAMBDA_D, reflectance, unbounded, illuminant};
 
 use renderer::Renderer;
+use bsdf::{Bsdf, Lobe};
 
 pub trait Shader {
     fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color;
+    // Reflectance used for the albedo AOV
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+    // Direct lighting per scene light, in the order of the scene lights
+    fn shade_lights(&self, hit: &HitInfo, renderer: &Renderer) -> Vec<Color> {
+        Vec::new()
+    }
+    // Index of the scene medium enclosed by the shape, the shape is then an invisible boundary
+    fn interior(&self) -> Option<usize> {
+        None
+    }
+    // Scattering function for the integrators that also trace paths from the lights. Shaders
+    // without one are approximated as Lambertian with their albedo.
+    fn bsdf(&self, hit: &HitInfo) -> Bsdf {
+        Bsdf::new(hit, Lobe::Lambert(self.albedo(hit)))
+    }
 }
 
 pub struct GouraudShader {
-    pub color: Color
+    pub color: Rc<Texture>
 }
 
 impl Shader for GouraudShader {
     fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
         let n = Color::new(0.5, 0.5, 0.5)+hit.n*0.5;
-        let c = &self.color;
+        let c = &reflectance(&self.color.eval(hit), hit.wavelengths.as_ref());
         let r = n.x*c.x;
         let g = n.y*c.y;
         let b = n.z*c.z;
         Color::new(r,g,b)
     }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.color.eval(hit)
+    }
 }
 
 pub struct PhongShader {
-    color: Color
+    color: Rc<Texture>
 }
 
 impl Shader for PhongShader {
     fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
-        self.color
+        reflectance(&self.color.eval(hit), hit.wavelengths.as_ref())
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.color.eval(hit)
+    }
+}
+
+// Lambertian reflection of the scene lights
+pub struct DiffuseShader {
+    pub color: Rc<Texture>
+}
+
+impl Shader for DiffuseShader {
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        let mut c = Color::new(0.0, 0.0, 0.0);
+        for contribution in self.shade_lights(hit, renderer) {
+            c += contribution;
+        }
+        c
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.color.eval(hit)
+    }
+    fn shade_lights(&self, hit: &HitInfo, renderer: &Renderer) -> Vec<Color> {
+        lambert_lights(hit, renderer, reflectance(&self.color.eval(hit), hit.wavelengths.as_ref()))
+    }
+}
+
+// Light of each scene light reflected by a Lambertian surface
+fn lambert_lights(hit: &HitInfo, renderer: &Renderer, albedo: Color) -> Vec<Color> {
+    renderer.lights().iter().map(|light| {
+        let sample = light.sample(&hit.p);
+        let costheta = hit.n.dot(&sample.wi);
+        if costheta <= 0.0 || sample.pdf <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let ray = hit.spawn_ray_to(&(hit.p + sample.wi*sample.dist));
+        stats::count(Counter::ShadowRays);
+        let radiance = illuminant(&sample.radiance, hit.wavelengths.as_ref());
+        albedo*radiance*renderer.transmittance(&ray)*(costheta/(PI*sample.pdf))
+    }).collect()
+}
+
+// Invisible surface of a closed shape filled with a participating medium
+pub struct MediumShader {
+    pub medium: usize
+}
+
+impl Shader for MediumShader {
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        let dir = -hit.i;
+        let mut ray = hit.spawn_ray(dir);
+        // Crossing the boundary is not a bounce
+        ray.depth = hit.depth;
+        ray.medium = renderer.medium_after(hit, &dir);
+        renderer.render(&mut ray)
+    }
+    fn interior(&self) -> Option<usize> {
+        Some(self.medium)
     }
 }
 
 pub struct AmbientOcculusionShader {
-    pub samples: i32,
-    pub color: Color
+    pub settings: AoSettings,
+    pub color: Rc<Texture>
 }
 
 impl Shader for AmbientOcculusionShader {
     fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
-        let mut color = Color::new(0.0, 0.0, 0.0);
-        let mut rng = thread_rng();
-        let fsamples = self.samples as f64;
-
-        let axis = Vec3::new(0.0, 0.0, 1.0);
-        let rot = rotate_to(&axis, &hit.n);
-        for i in 0..self.samples {
-            let dir = sample_hit(hit, &mut rng);
-
-            let mut ray = Ray::new(
-                &hit.p, 
-                rot*dir, 
-                f64::EPSILON,
-                f64::INFINITY);
-            match renderer.intersect(&mut ray) {
-                None => {
-                    let costheta = axis.dot(&-dir);
-                    let pdf = get_pdf(costheta, WarpFunction::CosineHemisphere);
-                    color += self.color*costheta/pdf;
+        let color = reflectance(&self.color.eval(hit), hit.wavelengths.as_ref());
+        color*ambient_occlusion(hit, renderer, &self.settings).visibility
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.color.eval(hit)
+    }
+}
+
+// How much an occluder contributes depending on its distance relative to the max distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Falloff {
+    Constant,   // Any hit within the max distance occludes fully
+    Linear,
+    Quadratic,
+    Smooth      // Smoothstep
+}
+
+impl Falloff {
+    pub fn parse(name: &str) -> Option<Falloff> {
+        match name {
+            "constant" => Some(Falloff::Constant),
+            "linear" => Some(Falloff::Linear),
+            "quadratic" => Some(Falloff::Quadratic),
+            "smooth" => Some(Falloff::Smooth),
+            _ => None
+        }
+    }
+
+    // Occlusion of a hit at x times the max distance
+    pub fn eval(&self, x: Float) -> Float {
+        let x = x.max(0.0).min(1.0);
+        match *self {
+            Falloff::Constant => 1.0,
+            Falloff::Linear => 1.0 - x,
+            Falloff::Quadratic => (1.0 - x)*(1.0 - x),
+            Falloff::Smooth => 1.0 - x*x*(3.0 - 2.0*x)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AoSettings {
+    pub samples: u32,
+    pub max_distance: Float,    // Hits further away do not occlude
+    pub falloff: Falloff
+}
+
+impl AoSettings {
+    // Unbounded distance with 64 samples
+    pub fn new() -> AoSettings {
+        AoSettings {
+            samples: 64,
+            max_distance: f64::INFINITY,
+            falloff: Falloff::Constant
+        }
+    }
+}
+
+pub struct Occlusion {
+    pub visibility: Float,  // Cosine weighted unoccluded fraction of the hemisphere
+    pub bent_normal: Vec3   // Average unoccluded direction
+}
+
+// Ambient occlusion around the shading normal. The directions are drawn from a jittered
+// grid over the unit square, warped to the cosine weighted hemisphere.
+pub fn ambient_occlusion(hit: &HitInfo, renderer: &Renderer, settings: &AoSettings) -> Occlusion {
+    let mut visibility = 0.0;
+    let mut bent_normal = Vec3::new(0.0, 0.0, 0.0);
+
+    let rot = rotate_to(&Vec3::new(0.0, 0.0, 1.0), &hit.n);
+    for (s, t) in sampling::stratified_2d(settings.samples) {
+        let (dir, pdf) = sample_cosine(&rot, s, t);
+        if pdf <= 0.0 {
+            continue;
+        }
+        let mut ray = hit.spawn_ray(dir);
+        ray.tmax = settings.max_distance;
+        stats::count(Counter::AoRays);
+        let occlusion = match renderer.intersect(&mut ray) {
+            None => 0.0,
+            Some(occluder) => settings.falloff.eval(occluder.d/settings.max_distance)
+        };
+        visibility += (1.0 - occlusion)*dir.dot(&hit.n)/pdf;
+        bent_normal += dir*(1.0 - occlusion);
+    }
+    if settings.samples == 0 {
+        return Occlusion { visibility: 1.0, bent_normal: hit.n };
+    }
+    Occlusion {
+        visibility: visibility/(settings.samples as Float*PI),
+        bent_normal: if bent_normal.norm_squared() > 0.0 { bent_normal.normalize() } else { hit.n }
+    }
+}
+
+// Maximum number of specular bounces
+const MAX_DEPTH: u32 = 8;
+
+pub struct MirrorShader {
+    pub color: Rc<Texture>
+}
+
+impl Shader for MirrorShader {
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        if hit.depth >= MAX_DEPTH {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let mut ray = hit.spawn_reflected();
+        stats::count(Counter::SecondaryRays);
+        renderer.render(&mut ray)*reflectance(&self.color.eval(hit), hit.wavelengths.as_ref())
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.color.eval(hit)
+    }
+    fn bsdf(&self, hit: &HitInfo) -> Bsdf {
+        Bsdf::new(hit, Lobe::Mirror(self.color.eval(hit)))
+    }
+}
+
+// Smooth dielectric, ior is relative to the outside medium. With dispersion in spectral mode
+// the wavelengths refract into different directions, the refracted ray only keeps the hero
+// wavelength.
+pub struct GlassShader {
+    pub ior: Ior,
+    pub color: Rc<Texture>
+}
+
+impl Shader for GlassShader {
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        if hit.depth >= MAX_DEPTH {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let cos_i = hit.i.dot(&hit.n).abs();
+        let cos_i = if hit.is_exiting() { -cos_i } else { cos_i };
+        let wavelengths = match hit.wavelengths {
+            Some(wl) if self.ior.is_dispersive() => wl,
+            _ => [LAMBDA_D; 3]
+        };
+        let ior = self.ior.eval(wavelengths[0]);
+        // Every wavelength reflects into the same direction, with its own Fresnel term
+        let f = Color::new(
+            fresnel_dielectric(cos_i, ior),
+            fresnel_dielectric(cos_i, self.ior.eval(wavelengths[1])),
+            fresnel_dielectric(cos_i, self.ior.eval(wavelengths[2]))
+        );
+        let mut reflected = hit.spawn_reflected();
+        stats::count(Counter::SecondaryRays);
+        let mut c = renderer.render(&mut reflected)*f;
+        if let Some(mut refracted) = hit.spawn_refracted(ior) {
+            stats::count(Counter::SecondaryRays);
+            let mut weight = Color::new(1.0, 1.0, 1.0)*(1.0 - f.x);
+            if wavelengths[1] != wavelengths[0] {
+                // The hero wavelength stands in for all three
+                refracted.wavelengths = Some([wavelengths[0]; 3]);
+                weight = Color::new(3.0*(1.0 - f.x), 0.0, 0.0);
+            }
+            c += renderer.render(&mut refracted)*weight;
+        }
+        c*reflectance(&self.color.eval(hit), hit.wavelengths.as_ref())
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.color.eval(hit)
+    }
+    // Without dispersion, the bidirectional integrators render in RGB only
+    fn bsdf(&self, hit: &HitInfo) -> Bsdf {
+        Bsdf::new(hit, Lobe::Dielectric(self.color.eval(hit), self.ior.eval(LAMBDA_D)))
+    }
+}
+
+// Fresnel reflectance of unpolarized light, cos_i is measured against the outward normal
+pub fn fresnel_dielectric(cos_i: Float, ior: Float) -> Float {
+    let (cos_i, eta_i, eta_t) = if cos_i < 0.0 { (-cos_i, ior, 1.0) } else { (cos_i, 1.0, ior) };
+    let cos_i = cos_i.min(1.0);
+    let sin_t = eta_i/eta_t*(1.0 - cos_i*cos_i).max(0.0).sqrt();
+    if sin_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin_t*sin_t).max(0.0).sqrt();
+    let r_parl = (eta_t*cos_i - eta_i*cos_t)/(eta_t*cos_i + eta_i*cos_t);
+    let r_perp = (eta_i*cos_i - eta_t*cos_t)/(eta_i*cos_i + eta_t*cos_t);
+    (r_parl*r_parl + r_perp*r_perp)*0.5
+}
+
+// Subsurface scattering by a random walk through the inside of closed shapes. Light enters
+// diffusely behind a smooth dielectric surface, scatters in a homogeneous medium and leaves
+// where the walk reaches a surface with the same shader again, lit as a Lambertian surface.
+pub struct SubsurfaceShader {
+    pub albedo: Rc<Texture>,    // Overall reflectance of a thick slab
+    pub mfp: Color,     // Mean free path per channel
+    pub ior: Float,
+    pub phase: HenyeyGreenstein
+}
+
+// Scattering events before a walk counts as absorbed
+const MAX_WALK: u32 = 256;
+
+impl SubsurfaceShader {
+    // Walks from the surface into the shape, returns where it leaves again with the outward
+    // facing shading normal and the weight of the walk
+    fn walk<'r>(&self, hit: &HitInfo, renderer: &'r Renderer) -> Option<(HitInfo<'r>, Color)> {
+        let albedo = reflectance(&self.albedo.eval(hit), hit.wavelengths.as_ref());
+        let mfp = unbounded(&self.mfp, hit.wavelengths.as_ref());
+        let sigma_t = Color::new(1.0/mfp.x.max(1e-9), 1.0/mfp.y.max(1e-9), 1.0/mfp.z.max(1e-9));
+        let sigma_s = single_scattering_albedo(&albedo)*sigma_t;
+        let shader = hit.shape.shader() as *const Shader as *const u8;
+
+        let (s, t) = sampling::next_2d();
+        let (dir, _) = sample_cosine(&rotate_to(&Vec3::new(0.0, 0.0, 1.0), &-hit.outward()), s, t);
+        let mut ray = hit.spawn_ray(dir);
+        ray.depth = hit.depth;
+        let mut weight = Color::new(1.0, 1.0, 1.0);
+        for _ in 0..MAX_WALK {
+            // Distances are sampled for a channel chosen in proportion to the weight, which keeps
+            // the summed weight from growing
+            let total = weight.x + weight.y + weight.z;
+            if total <= 0.0 {
+                return None;
+            }
+            let p_channel = weight/total;
+            let u = sampling::next_float();
+            let channel = if u < p_channel.x { 0 } else if u < p_channel.x + p_channel.y { 1 } else { 2 };
+            let d = -(1.0 - sampling::next_float()).ln()/sigma_t[channel];
+            ray.tmax = d;
+            stats::count(Counter::SecondaryRays);
+            if let Some(mut exit) = renderer.intersect(&mut ray) {
+                if exit.shape.shader() as *const Shader as *const u8 != shader {
+                    return None;
                 }
-                Some(hit) => {}
+                let tr = attenuation(&sigma_t, exit.d);
+                weight = weight*tr/p_channel.dot(&tr);
+                exit.n = face_forward(&exit.n, &exit.outward());
+                exit.depth = hit.depth;
+                return Some((exit, weight));
             }
+            let tr = attenuation(&sigma_t, d);
+            weight = weight*sigma_s*tr/p_channel.dot(&(sigma_t*tr));
+            let p = ray.origin + ray.dir*d;
+            let (s, t) = sampling::next_2d();
+            let (dir, _) = self.phase.sample(&ray.dir, s, t);
+            let (medium, wavelengths) = (ray.medium, ray.wavelengths);
+            ray = Ray::new(&p, dir, 0.0, f64::INFINITY);
+            ray.depth = hit.depth;
+            ray.medium = medium;
+            ray.wavelengths = wavelengths;
         }
-        color / (fsamples*PI)
+        None
     }
 }
 
-fn sample_hit(hit: &HitInfo, rng: &mut ThreadRng) -> Vec3 {
-    let s: Float = rng.gen_range(0.0, 1.0);
-    let t: Float = rng.gen_range(0.0, 1.0);
-    warp_point(s, t, WarpFunction::CosineHemisphere)
+impl Shader for SubsurfaceShader {
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        if hit.is_exiting() {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        let f = fresnel_dielectric(hit.i.dot(&hit.n).abs(), self.ior);
+        let mut c = Color::new(0.0, 0.0, 0.0);
+        if hit.depth < MAX_DEPTH {
+            let mut reflected = hit.spawn_reflected();
+            stats::count(Counter::SecondaryRays);
+            c += renderer.render(&mut reflected)*f;
+        }
+        for contribution in self.shade_lights(hit, renderer) {
+            c += contribution*(1.0 - f);
+        }
+        c
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.albedo.eval(hit)
+    }
+    // Direct light leaving the surface after one walk, without the Fresnel factor
+    fn shade_lights(&self, hit: &HitInfo, renderer: &Renderer) -> Vec<Color> {
+        match self.walk(hit, renderer) {
+            None => renderer.lights().iter().map(|_| Color::new(0.0, 0.0, 0.0)).collect(),
+            Some((exit, weight)) => lambert_lights(&exit, renderer, weight)
+        }
+    }
+}
+
+// Single scattering albedo of the medium giving the multiple scattering albedo of a thick slab
+// Source: Chiang et al., Practical and Controllable Subsurface Scattering for Production Path Tracing
+fn single_scattering_albedo(albedo: &Color) -> Color {
+    let f = |a: Float| {
+        let a = a.max(0.0).min(1.0);
+        1.0 - (-5.09406*a + 2.61188*a*a - 4.31805*a*a*a).exp()
+    };
+    Color::new(f(albedo.x), f(albedo.y), f(albedo.z))
+}
+
+// Perturbs the shading normal with a tangent space normal map
+pub struct NormalMapShader {
+    pub shader: Rc<Shader>,
+    pub normal_map: Rc<Texture>,
+    pub strength: Float
+}
+
+impl NormalMapShader {
+    fn perturb<'a>(&self, hit: &HitInfo<'a>) -> HitInfo<'a> {
+        let m = self.normal_map.eval(hit)*2.0 - Vec3::new(1.0, 1.0, 1.0);
+        let (t, b) = hit.shading_frame();
+        let n = t*(m.x*self.strength) + b*(m.y*self.strength) + hit.n*m.z;
+        let mut perturbed = hit.clone();
+        if n.norm_squared() > 0.0 {
+            perturbed.n = face_forward(&n.normalize(), &hit.ng);
+        }
+        perturbed
+    }
+}
+
+impl Shader for NormalMapShader {
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        self.shader.shade(&self.perturb(hit), renderer)
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.shader.albedo(hit)
+    }
+    fn shade_lights(&self, hit: &HitInfo, renderer: &Renderer) -> Vec<Color> {
+        self.shader.shade_lights(&self.perturb(hit), renderer)
+    }
+    fn bsdf(&self, hit: &HitInfo) -> Bsdf {
+        self.shader.bsdf(&self.perturb(hit))
+    }
+}
+
+// Perturbs the shading normal by finite differences of a height field
+pub struct BumpMapShader {
+    pub shader: Rc<Shader>,
+    pub height: Rc<Texture>,
+    pub scale: Float
+}
+
+impl BumpMapShader {
+    fn perturb<'a>(&self, hit: &HitInfo<'a>) -> HitInfo<'a> {
+        let mut perturbed = hit.clone();
+        perturbed.n = bump_normal(hit, &*self.height, self.scale);
+        perturbed
+    }
+}
+
+impl Shader for BumpMapShader {
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        self.shader.shade(&self.perturb(hit), renderer)
+    }
+    fn albedo(&self, hit: &HitInfo) -> Color {
+        self.shader.albedo(hit)
+    }
+    fn shade_lights(&self, hit: &HitInfo, renderer: &Renderer) -> Vec<Color> {
+        self.shader.shade_lights(&self.perturb(hit), renderer)
+    }
+    fn bsdf(&self, hit: &HitInfo) -> Bsdf {
+        self.shader.bsdf(&self.perturb(hit))
+    }
+}
+
+const BUMP_DELTA: Float = 0.0005;
+
+fn bump_normal(hit: &HitInfo, height: &Texture, scale: Float) -> Vec3 {
+    let displace = height.eval_scalar(hit)*scale;
+
+    // Shift by half the pixel footprint, if known
+    let mut du = 0.5*(hit.dudx.abs() + hit.dudy.abs());
+    if du == 0.0 {
+        du = BUMP_DELTA;
+    }
+    let mut dv = 0.5*(hit.dvdx.abs() + hit.dvdy.abs());
+    if dv == 0.0 {
+        dv = BUMP_DELTA;
+    }
+
+    let mut shifted = hit.clone();
+    shifted.uv = hit.uv + Vec2::new(du, 0.0);
+    shifted.p = hit.p + hit.dpdu*du;
+    shifted.lp = hit.lp + hit.dpdu*du;
+    let u_displace = height.eval_scalar(&shifted)*scale;
+
+    shifted.uv = hit.uv + Vec2::new(0.0, dv);
+    shifted.p = hit.p + hit.dpdv*dv;
+    shifted.lp = hit.lp + hit.dpdv*dv;
+    let v_displace = height.eval_scalar(&shifted)*scale;
+
+    let dpdu = hit.dpdu + hit.n*((u_displace - displace)/du);
+    let dpdv = hit.dpdv + hit.n*((v_displace - displace)/dv);
+    let n = dpdu.cross(&dpdv);
+    if n.norm_squared() < 1e-20 {
+        return hit.n;
+    }
+    face_forward(&n.normalize(), &hit.n)
+}
+
+fn face_forward(n: &Vec3, reference: &Vec3) -> Vec3 {
+    if n.dot(reference) < 0.0 { -*n } else { *n }
+}
+
+// Cosine weighted direction around the z axis rotated by rot, and its density
+fn sample_cosine(rot: &Rotation3<Float>, s: Float, t: Float) -> (Vec3, Float) {
+    let dir = warp_point(s, t, WarpFunction::CosineHemisphere);
+    (*rot*dir, get_pdf(dir.z, WarpFunction::CosineHemisphere))
 }
 
 fn rotate_to(from: &Vec3, to: &Vec3) -> Rotation3<Float> {
@@ -99,4 +548,177 @@ fn rotate_to(from: &Vec3, to: &Vec3) -> Rotation3<Float> {
         axis.normalize_mut();
     }
     return Rotation3::new(axis*angle);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+    use nalgebra::{Norm, Dot, Cross};
+    use types::*;
+    use hit::HitInfo;
+    use chi2;
+    use chi2::Domain;
+    use std::rc::Rc;
+    use std::f64;
+    use ray::Ray;
+    use scene::Scene;
+    use renderer::Renderer;
+    use shape::{Shape, Sphere};
+    use texture::{Texture, ConstantTexture};
+    use testing::{SceneBuilder, grey};
+    use super::*;
+
+    #[test]
+    fn chi2_ambient_occlusion_sampling() {
+        let n = Vec3::new(0.3, -0.5, 0.8).normalize();
+        let rot = rotate_to(&Vec3::new(0.0, 0.0, 1.0), &n);
+        let result = chi2::test("ambient_occlusion", Domain::Sphere,
+            |s, t| {
+                let (dir, pdf) = sample_cosine(&rot, s, t);
+                assert!((pdf - dir.dot(&n).max(0.0)/PI).abs() < 1e-9);
+                Some(dir)
+            },
+            |v| v.dot(&n).max(0.0)/PI);
+        if let Err(e) = result {
+            panic!("{}", e);
+        }
+    }
+
+    // A unit sphere standing on the ground next to the shaded ground point
+    fn ao_scene() -> Scene {
+        SceneBuilder::new().sphere(Pnt3::new(0.0, 0.0, 0.0), 1.0, grey(1.0)).ground(grey(1.0)).build()
+    }
+
+    fn occlusion(renderer: &Renderer, settings: &AoSettings) -> Occlusion {
+        let mut ray = Ray::new(&Pnt3::new(2.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0, f64::INFINITY);
+        let hit = renderer.intersect(&mut ray).unwrap();
+        assert!(hit.object == 1);
+        ambient_occlusion(&hit, renderer, settings)
+    }
+
+    #[test]
+    fn ao_max_distance() {
+        let scene = ao_scene();
+        let renderer = Renderer::new(&scene);
+        let unbounded = occlusion(&renderer, &AoSettings { samples: 1024, ..AoSettings::new() });
+        assert!(unbounded.visibility < 0.95);
+        assert!(unbounded.bent_normal.x > 0.0);
+        assert!((unbounded.bent_normal.norm() - 1.0).abs() < 1e-9);
+        let bounded = occlusion(&renderer, &AoSettings { samples: 1024, max_distance: 0.5, falloff: Falloff::Constant });
+        assert!(bounded.visibility > 0.99);
+        let linear = occlusion(&renderer, &AoSettings { samples: 1024, max_distance: 4.0, falloff: Falloff::Linear });
+        let constant = occlusion(&renderer, &AoSettings { samples: 1024, max_distance: 4.0, falloff: Falloff::Constant });
+        assert!(constant.visibility < linear.visibility && linear.visibility < bounded.visibility);
+    }
+
+    #[test]
+    fn falloff_curves() {
+        for falloff in &[Falloff::Linear, Falloff::Quadratic, Falloff::Smooth] {
+            assert_eq!(falloff.eval(0.0), 1.0);
+            assert_eq!(falloff.eval(1.0), 0.0);
+            assert_eq!(falloff.eval(2.0), 0.0);
+            assert!(falloff.eval(0.3) > falloff.eval(0.6));
+        }
+        assert_eq!(Falloff::Constant.eval(0.9), 1.0);
+    }
+
+    // Height along the u coordinate
+    struct UTexture;
+
+    impl Texture for UTexture {
+        fn eval(&self, hit: &HitInfo) -> Color {
+            Color::new(hit.uv.x, hit.uv.x, hit.uv.x)
+        }
+    }
+
+    fn top_hit(sphere: &Sphere) -> HitInfo {
+        let mut ray = Ray::new(&Pnt3::new(0.1, 0.2, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.0, f64::INFINITY);
+        sphere.intersect(&mut ray).unwrap()
+    }
+
+    #[test]
+    fn normal_map_perturbation() {
+        let diffuse: Rc<Shader> = Rc::new(DiffuseShader { color: Rc::new(ConstantTexture { color: Color::new(1.0, 1.0, 1.0) }) });
+        let sphere = Sphere { position: Pnt3::new(0.0, 0.0, 0.0), radius: 1.0, shader: diffuse.clone() };
+        let hit = top_hit(&sphere);
+        let (t, _) = hit.shading_frame();
+        let mapped = |c: Color| NormalMapShader {
+            shader: diffuse.clone(),
+            normal_map: Rc::new(ConstantTexture { color: c }),
+            strength: 1.0
+        }.perturb(&hit);
+        // The flat normal map color keeps the normal, the geometric normal never changes
+        let flat = mapped(Color::new(0.5, 0.5, 1.0));
+        assert!((flat.n - hit.n).norm() < 1e-12);
+        let tilted = mapped(Color::new(1.0, 0.5, 1.0));
+        assert!((tilted.n - (t + hit.n).normalize()).norm() < 1e-12);
+        assert_eq!(tilted.ng, hit.ng);
+    }
+
+    #[test]
+    fn bump_map_perturbation() {
+        let diffuse: Rc<Shader> = Rc::new(DiffuseShader { color: Rc::new(ConstantTexture { color: Color::new(1.0, 1.0, 1.0) }) });
+        let sphere = Sphere { position: Pnt3::new(0.0, 0.0, 0.0), radius: 1.0, shader: diffuse };
+        let hit = top_hit(&sphere);
+        let constant = bump_normal(&hit, &ConstantTexture { color: Color::new(0.7, 0.7, 0.7) }, 2.0);
+        assert!((constant - hit.n).norm() < 1e-12);
+        // A height rising linearly along u tilts the normal against dp/du
+        let scale = 0.01;
+        let n = bump_normal(&hit, &UTexture, scale);
+        let expected = (hit.dpdu + hit.n*scale).cross(&hit.dpdv).normalize();
+        let expected = if expected.dot(&hit.n) < 0.0 { -expected } else { expected };
+        assert!((n - expected).norm() < 1e-9, "{:?} {:?}", n, expected);
+        assert!(n.dot(&hit.dpdu) < 0.0);
+    }
+
+    fn subsurface(albedo: Color, mfp: Color) -> SubsurfaceShader {
+        SubsurfaceShader {
+            albedo: Rc::new(ConstantTexture { color: albedo }),
+            mfp: mfp,
+            ior: 1.4,
+            phase: HenyeyGreenstein { g: 0.0 }
+        }
+    }
+
+    // Average weight of walks entering a sphere from the top, and the fraction of walks
+    // leaving it
+    fn reflectance(albedo: Color, mfp: Color, radius: Float, n: usize) -> (Color, Float) {
+        let mut scene = Scene::empty();
+        let shader: Rc<Shader> = Rc::new(subsurface(albedo, mfp));
+        scene.shapes.push(Box::new(Sphere { position: Pnt3::new(0.0, 0.0, -radius), radius: radius, shader: shader }));
+        let renderer = Renderer::new(&scene);
+        let mut ray = Ray::new(&Pnt3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0, f64::INFINITY);
+        let hit = renderer.intersect(&mut ray).unwrap();
+        let shader = subsurface(albedo, mfp);
+        let mut sum = Color::new(0.0, 0.0, 0.0);
+        let mut exits = 0;
+        for _ in 0..n {
+            if let Some((exit, weight)) = shader.walk(&hit, &renderer) {
+                assert!(exit.n.dot(&exit.outward()) > 0.0);
+                sum += weight;
+                exits += 1;
+            }
+        }
+        (sum/n as Float, exits as Float/n as Float)
+    }
+
+    #[test]
+    fn subsurface_white_albedo_keeps_energy() {
+        // Nearly all walks leave again, with the same weight in every channel
+        let (r, exits) = reflectance(Color::new(1.0, 1.0, 1.0), Color::new(0.1, 0.1, 0.1), 1.0, 2000);
+        assert!(exits > 0.95, "{}", exits);
+        for i in 0..3 {
+            assert!(r[i] > 0.95 && r[i] <= exits && (r[i] - r[0]).abs() < 1e-9, "{:?}", r);
+        }
+    }
+
+    #[test]
+    fn subsurface_slab_reflects_albedo() {
+        // A large sphere is a thick slab, channels with different mean free paths keep their albedo
+        let albedo = Color::new(0.9, 0.7, 0.4);
+        let (r, _) = reflectance(albedo, Color::new(1.0, 0.5, 0.25), 1000.0, 20000);
+        for i in 0..3 {
+            assert!((r[i] - albedo[i]).abs() < 0.05, "{:?}", r);
+        }
+    }
+}
@@ -8,11 +8,19 @@ use shader::Shader;
 use rand::Rng;
 use warp::*;
 use std::f64;
+use bvh::{Aabb, Bvh};
+use stats;
+use stats::Counter;
+use float::gamma;
 
 
 pub trait Shape: Sync {
-    fn intersect(&self, ray: &Ray) -> Option<HitInfo>;
+    // Nearest hit within the ray segment. Shapes made of several primitives may shorten the
+    // ray while searching, to cull the primitives behind a hit.
+    fn intersect(&self, ray: &mut Ray) -> Option<HitInfo>;
     fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color;
+    fn shader(&self) -> &Shader;
+    fn bounds(&self) -> Aabb;
 }
 
 pub struct Sphere {
@@ -22,56 +30,524 @@ pub struct Sphere {
 }
 
 impl Shape for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<HitInfo> {
-        let c = self.position;
+    // Roots are computed from the perpendicular distance of the center to the ray and with the
+    // stable form of the quadratic formula, which keeps the large ground sphere accurate.
+    // Source: Haines et al., Precision Improvements for Ray/Sphere Intersection
+    fn intersect(&self, ray: &mut Ray) -> Option<HitInfo> {
+        stats::count(Counter::SphereTests);
+        let r = self.radius;
         let o = ray.origin;
         let d = ray.dir;
-        let r2 = self.radius*self.radius;
-        let dk = (c-o).dot(&d);
-        let D2 = (c-o).norm_squared()-dk*dk;
-        if D2 > r2 {
+        let oc = o - self.position;
+        let oc_len = oc.norm();
+        let b = -oc.dot(&d);
+        let l = (oc + d*b).norm();
+        if l > r {
             return None;
         }
-        let f = Float::sqrt(r2-D2);
-        let t = dk;
-        let t1 = t+f;
-        let t2 = t-f;
+        let f = ((r - l)*(r + l)).sqrt();
+        let c = (oc_len - r)*(oc_len + r);
+        let q = if b >= 0.0 { b + f } else { b - f };
+        let roots = if q == 0.0 { [b, b] } else { [c/q, q] };
 
-        let t = t1.min(t2);
-        if t < ray.tmin && t.abs() > f64::EPSILON {
+        // Conservative bounds of the roots, hits that cannot be told apart from the ray
+        // origin are rejected
+        let length_error = gamma(4)*(oc_len + r) + gamma(1)*(o.to_vector().norm() + self.position.to_vector().norm());
+        let errors = [
+            2.0*length_error*(oc_len + r)/q.abs() + gamma(1)*roots[0].abs(),
+            2.0*length_error + gamma(1)*roots[1].abs()
+        ];
+
+        // Nearest root within the ray segment, the far one is hit from the inside
+        let (near, far) = if roots[0] <= roots[1] { (0, 1) } else { (1, 0) };
+        let valid = |i: usize| roots[i] - errors[i] > ray.tmin && roots[i] <= ray.tmax;
+        let (t, inside) = if valid(near) {
+            (roots[near], false)
+        } else if valid(far) {
+            (roots[far], roots[near] != roots[far])
+        } else {
             return None;
+        };
+
+        // Reprojecting the hit point onto the surface bounds its error by the few operations
+        // of the projection
+        let mut local = o + d*t - self.position;
+        local = local*(r/local.norm());
+        let p = self.position + local;
+        let outward = local/r;
+        let lp = local.to_point();
+        let (dpdu, dpdv) = sphere_tangents(&lp);
+        let n = if inside { -outward } else { outward };
+        let mut hit = HitInfo::new(&*self, t, -d, p, o, n, spherical_uv(&outward), lp, dpdu, dpdv);
+        hit.inside = inside;
+        hit.p_error = abs(&local)*gamma(5) + abs(&p.to_vector())*gamma(1);
+        hit.dndu = dpdu/self.radius;
+        hit.dndv = dpdv/self.radius;
+        if inside {
+            hit.dndu = -hit.dndu;
+            hit.dndv = -hit.dndv;
         }
-        if t > ray.tmax {
+        return Some(hit);
+    }
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        self.shader.shade(hit, renderer)
+    }
+    fn shader(&self) -> &Shader {
+        &*self.shader
+    }
+    fn bounds(&self) -> Aabb {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Aabb { min: self.position - r, max: self.position + r }
+    }
+}
+
+unsafe impl Sync for Sphere {}
+
+fn abs(v: &Vec3) -> Vec3 {
+    Vec3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+fn spherical_uv(n: &Vec3) -> Vec2 {
+    let mut phi = n.y.atan2(n.x);
+    if phi < 0.0 {
+        phi += 2.0*f64::consts::PI;
+    }
+    let theta = n.z.max(-1.0).min(1.0).acos();
+    Vec2::new(phi / (2.0*f64::consts::PI), theta / f64::consts::PI)
+}
+
+// Partial derivatives of the spherical parametrization
+fn sphere_tangents(lp: &Pnt3) -> (Vec3, Vec3) {
+    let pi = f64::consts::PI;
+    let dpdu = Vec3::new(-2.0*pi*lp.y, 2.0*pi*lp.x, 0.0);
+    let rxy = (lp.x*lp.x + lp.y*lp.y).sqrt();
+    let dpdv = if rxy > 0.0 {
+        let cos_phi = lp.x/rxy;
+        let sin_phi = lp.y/rxy;
+        Vec3::new(lp.z*cos_phi, lp.z*sin_phi, -rxy)*pi
+    } else {
+        Vec3::new(lp.z, 0.0, 0.0)*pi
+    };
+    (dpdu, dpdv)
+}
+
+pub struct Triangle {
+    pub v1: Pnt3,
+    pub v2: Pnt3,
+    pub v3: Pnt3,
+    pub n: Vec3,
+    pub normals: Option<[Vec3; 3]>, // Per vertex shading normals
+    pub uvs: Option<[Vec2; 3]>,
+    pub shader: Rc<Shader>
+}
+
+impl Triangle {
+    pub fn new(v1: Pnt3, v2: Pnt3, v3: Pnt3, shader: Rc<Shader>) -> Triangle {
+        let e1 = v2 - v1;
+        let e2 = v3 - v1;
+        let n = e1.cross(&e2).normalize();
+        Triangle {
+            v1: v1,
+            v2: v2,
+            v3: v3,
+            n: n,
+            normals: None,
+            uvs: None,
+            shader: shader
+        }
+    }
+
+    // Source: Physically based Rendering, Chapter Shapes, Section Triangle Meshes
+    fn tangents(&self) -> (Vec3, Vec3) {
+        let (uv1, uv2, uv3) = match self.uvs {
+            Some(uvs) => (uvs[0], uvs[1], uvs[2]),
+            None => (Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), Vec2::new(0.0, 1.0))
+        };
+        let duv13 = uv1 - uv3;
+        let duv23 = uv2 - uv3;
+        let dp13 = self.v1 - self.v3;
+        let dp23 = self.v2 - self.v3;
+        let det = duv13.x*duv23.y - duv13.y*duv23.x;
+        if det.abs() < 1e-12 {
+            return (self.v2 - self.v1, self.v3 - self.v1);
+        }
+        let inv = 1.0/det;
+        ((dp13*duv23.y - dp23*duv13.y)*inv, (dp23*duv13.x - dp13*duv23.x)*inv)
+    }
+}
+
+impl Shape for Triangle {
+    // Watertight test in a space where the ray starts at the origin and points along +z, with
+    // conservative bounds of the rounding error of t.
+    // Source: Physically based Rendering, Chapter Shapes, Section Triangle Meshes and Section
+    // Managing Rounding Error
+    fn intersect(&self, ray: &mut Ray) -> Option<HitInfo> {
+        stats::count(Counter::TriangleTests);
+        let d = ray.dir;
+
+        // Permute the largest component of the direction into z
+        let kz = if d.x.abs() > d.y.abs() {
+            if d.x.abs() > d.z.abs() { 0 } else { 2 }
+        } else if d.y.abs() > d.z.abs() { 1 } else { 2 };
+        let kx = (kz + 1) % 3;
+        let ky = (kx + 1) % 3;
+        let permute = |v: Vec3| Vec3::new(v[kx], v[ky], v[kz]);
+        let dp = permute(d);
+        let mut p0 = permute(self.v1 - ray.origin);
+        let mut p1 = permute(self.v2 - ray.origin);
+        let mut p2 = permute(self.v3 - ray.origin);
+
+        // Shear the direction onto the z axis, z is scaled once the hit is known
+        let sx = -dp.x/dp.z;
+        let sy = -dp.y/dp.z;
+        let sz = 1.0/dp.z;
+        for p in [&mut p0, &mut p1, &mut p2].iter_mut() {
+            p.x += sx*p.z;
+            p.y += sy*p.z;
+        }
+
+        // Edge functions, the ray passes inside if they share the sign
+        let e0 = p1.x*p2.y - p1.y*p2.x;
+        let e1 = p2.x*p0.y - p2.y*p0.x;
+        let e2 = p0.x*p1.y - p0.y*p1.x;
+        if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+            return None;
+        }
+        let det = e0 + e1 + e2;
+        if det == 0.0 {
+            return None;
+        }
+        p0.z *= sz;
+        p1.z *= sz;
+        p2.z *= sz;
+        let t_scaled = e0*p0.z + e1*p1.z + e2*p2.z;
+        if (det < 0.0 && (t_scaled >= 0.0 || t_scaled < ray.tmax*det))
+            || (det > 0.0 && (t_scaled <= 0.0 || t_scaled > ray.tmax*det)) {
+            return None;
+        }
+        let inv_det = 1.0/det;
+        let b1 = e1*inv_det;
+        let b2 = e2*inv_det;
+        let t = t_scaled*inv_det;
+
+        // Bound of the error in t, hits closer than that are self intersections
+        let max_x = p0.x.abs().max(p1.x.abs()).max(p2.x.abs());
+        let max_y = p0.y.abs().max(p1.y.abs()).max(p2.y.abs());
+        let max_z = p0.z.abs().max(p1.z.abs()).max(p2.z.abs());
+        let delta_x = gamma(5)*(max_x + max_z);
+        let delta_y = gamma(5)*(max_y + max_z);
+        let delta_z = gamma(3)*max_z;
+        let delta_e = 2.0*(gamma(2)*max_x*max_y + delta_y*max_x + delta_x*max_y);
+        let max_e = e0.abs().max(e1.abs()).max(e2.abs());
+        let t_error = 3.0*(gamma(3)*max_e*max_z + delta_e*max_z + delta_z*max_e)*inv_det.abs();
+        if t - t_error <= ray.tmin {
             return None;
         }
-        let p = o+t*d;
-        let n = (p-self.position)/self.radius;
-        return Some(HitInfo::new(&*self, t, -d, p, o, n));
+
+        // The hit point is interpolated from the vertices, which bounds its error tighter
+        // than stepping along the ray
+        let b0 = e0*inv_det;
+        let p = (self.v1.to_vector()*b0 + self.v2.to_vector()*b1 + self.v3.to_vector()*b2).to_point();
+        let p_error = (abs(&self.v1.to_vector())*b0 + abs(&self.v2.to_vector())*b1
+            + abs(&self.v3.to_vector())*b2)*gamma(7);
+        let uv = match self.uvs {
+            Some(uvs) => uvs[0]*b0 + uvs[1]*b1 + uvs[2]*b2,
+            None => Vec2::new(b1, b2)
+        };
+        let mut ng = self.n;
+        let n = match self.normals {
+            Some(normals) => {
+                let n = (normals[0]*b0 + normals[1]*b1 + normals[2]*b2).normalize();
+                // The geometric normal follows the orientation given by the shading normals
+                if ng.dot(&n) < 0.0 {
+                    ng = -ng;
+                }
+                n
+            },
+            None => ng
+        };
+        let (dpdu, dpdv) = self.tangents();
+        let mut hit = HitInfo::new(&*self, t, -d, p, ray.origin, n, uv, p, dpdu, dpdv);
+        hit.ng = ng;
+        hit.p_error = p_error;
+        hit.barycentric = Vec2::new(b1, b2);
+        Some(hit)
     }
     fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
         self.shader.shade(hit, renderer)
     }
+    fn shader(&self) -> &Shader {
+        &*self.shader
+    }
+    fn bounds(&self) -> Aabb {
+        Aabb { min: self.v1, max: self.v1 }.grow(&self.v2).grow(&self.v3)
+    }
 }
 
-unsafe impl Sync for Sphere {}
+unsafe impl Sync for Triangle {}
+
+// Triangles sharing a shader, with their own hierarchy. Hits report the triangle index as
+// primitive.
+pub struct Mesh {
+    pub triangles: Vec<Triangle>,
+    pub shader: Rc<Shader>,
+    bvh: Bvh,
+    bounds: Aabb
+}
+
+impl Mesh {
+    pub fn new(triangles: Vec<Triangle>, shader: Rc<Shader>) -> Mesh {
+        let bounds: Vec<Aabb> = triangles.iter().map(|t| t.bounds()).collect();
+        Mesh {
+            triangles: triangles,
+            shader: shader,
+            bvh: Bvh::new(&bounds),
+            bounds: bounds.iter().fold(Aabb::empty(), |a, b| a.union(b))
+        }
+    }
+}
+
+impl Shape for Mesh {
+    fn intersect(&self, ray: &mut Ray) -> Option<HitInfo> {
+        let mut value = None;
+        let triangles = &self.triangles;
+        self.bvh.traverse(ray, |i, ray| {
+            if let Some(mut hit) = triangles[i].intersect(ray) {
+                ray.tmax = hit.d;
+                hit.primitive = i;
+                value = Some(hit);
+            }
+        });
+        value.map(|mut hit| {
+            hit.shape = self;
+            hit
+        })
+    }
+    fn shade(&self, hit: &HitInfo, renderer: &Renderer) -> Color {
+        self.shader.shade(hit, renderer)
+    }
+    fn shader(&self) -> &Shader {
+        &*self.shader
+    }
+    fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+}
+
+unsafe impl Sync for Mesh {}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+    use nalgebra::{Dot, Norm};
+    use types::*;
+    use ray::Ray;
+    use warp::{warp_point, WarpFunction};
+    use testing::{grey, sphere};
+    use super::{Shape, Triangle, Mesh};
+
+    fn ray(origin: Pnt3, dir: Vec3) -> Ray {
+        Ray::new(&origin, dir, 0.0, f64::INFINITY)
+    }
+
+    fn assert_close(a: Float, b: Float) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn hit_from_outside() {
+        let s = sphere(Pnt3::new(0.0, 0.0, 0.0), 1.0, grey(1.0));
+        let hit = s.intersect(&mut ray(Pnt3::new(0.0, -5.0, 0.0), Vec3::new(0.0, 1.0, 0.0))).unwrap();
+        assert_close(hit.d, 4.0);
+        assert!(!hit.inside);
+        assert_close(hit.n.y, -1.0);
+        assert_close(hit.ng.y, -1.0);
+    }
+
+    #[test]
+    fn hit_from_inside() {
+        let s = sphere(Pnt3::new(0.0, 0.0, 0.0), 2.0, grey(1.0));
+        let hit = s.intersect(&mut ray(Pnt3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0))).unwrap();
+        assert_close(hit.d, 2.0);
+        assert!(hit.inside);
+        // Normals face the incoming ray
+        assert_close(hit.n.x, -1.0);
+        assert!(hit.ng.dot(&hit.i) > 0.0);
+        assert_close(hit.p.x, 2.0);
+    }
+
+    #[test]
+    fn hit_from_inside_off_center() {
+        let s = sphere(Pnt3::new(1.0, 2.0, 3.0), 1.0, grey(1.0));
+        let hit = s.intersect(&mut ray(Pnt3::new(1.0, 2.0, 3.5), Vec3::new(0.0, 0.0, -1.0))).unwrap();
+        assert_close(hit.d, 1.5);
+        assert!(hit.inside);
+        assert_close(hit.n.z, 1.0);
+    }
+
+    #[test]
+    fn sphere_behind_origin() {
+        let s = sphere(Pnt3::new(0.0, 0.0, 0.0), 1.0, grey(1.0));
+        assert!(s.intersect(&mut ray(Pnt3::new(0.0, 5.0, 0.0), Vec3::new(0.0, 1.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn miss() {
+        let s = sphere(Pnt3::new(0.0, 0.0, 0.0), 1.0, grey(1.0));
+        assert!(s.intersect(&mut ray(Pnt3::new(0.0, -5.0, 1.5), Vec3::new(0.0, 1.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn far_root_beyond_tmin() {
+        let s = sphere(Pnt3::new(0.0, 0.0, 0.0), 1.0, grey(1.0));
+        let mut r = ray(Pnt3::new(0.0, -5.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        r.tmin = 4.5;
+        let hit = s.intersect(&mut r).unwrap();
+        assert_close(hit.d, 6.0);
+        assert!(hit.inside);
+        assert_close(hit.n.y, -1.0);
+    }
+
+    #[test]
+    fn roots_beyond_tmax() {
+        let s = sphere(Pnt3::new(0.0, 0.0, 0.0), 1.0, grey(1.0));
+        let mut r = ray(Pnt3::new(0.0, -5.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        r.tmax = 3.5;
+        assert!(s.intersect(&mut r).is_none());
+        let mut r = ray(Pnt3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        r.tmax = 0.5;
+        assert!(s.intersect(&mut r).is_none());
+    }
+
+    #[test]
+    fn secondary_rays_leave_the_surface() {
+        // The ground sphere of the default scene
+        let s = sphere(Pnt3::new(0.0, 0.0, -10000001.0), 10000000.0, grey(1.0));
+        let dirs = [
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 1e-3),
+            Vec3::new(0.3, -0.7, 0.2),
+            Vec3::new(-1.0, 1.0, 1e-6)
+        ];
+        for x in 0..10 {
+            let origin = Pnt3::new(x as Float*0.37 - 2.0, -3.0 + x as Float*0.81, 5.0);
+            let hit = s.intersect(&mut ray(origin, Vec3::new(0.01, 0.02, -1.0))).unwrap();
+            assert!(!hit.inside);
+            for dir in &dirs {
+                assert!(s.intersect(&mut hit.spawn_ray(*dir)).is_none());
+            }
+            // Rays going into the surface find the far side
+            let through = s.intersect(&mut hit.spawn_ray(Vec3::new(0.0, 0.0, -1.0))).unwrap();
+            assert!(through.inside);
+            assert!(through.d > 1.0);
+        }
+    }
+
+    // Directions spread over the sphere
+    fn directions() -> Vec<Vec3> {
+        let mut dirs = Vec::new();
+        for i in 0..16 {
+            for j in 0..16 {
+                let s = (i as Float + 0.5)/16.0;
+                let t = (j as Float + 0.37)/16.0;
+                dirs.push(warp_point(s, t, WarpFunction::UniformSphere));
+            }
+        }
+        dirs
+    }
+
+    #[test]
+    fn no_self_intersections() {
+        let dirs = directions();
+        let ground = sphere(Pnt3::new(0.0, 0.0, -1e4 - 1.0), 1e4, grey(1.0));
+        for x in 0..20 {
+            let origin = Pnt3::new(x as Float*3.7 - 40.0, x as Float*1.3 - 10.0, 3.0);
+            let dir = Vec3::new(0.11*x as Float - 1.0, 0.9, -0.2 - 0.05*x as Float).normalize();
+            let hit = ground.intersect(&mut ray(origin, dir)).unwrap();
+            for w in &dirs {
+                let leaving = ground.intersect(&mut hit.spawn_ray(*w));
+                if w.dot(&hit.ng) > 0.0 {
+                    assert!(leaving.is_none(), "ground sphere {:?} {:?}", hit.p, w);
+                } else if let Some(through) = leaving {
+                    assert!(through.inside);
+                }
+            }
+        }
+
+        // Triangles of different sizes far from the origin, rays leave on either side
+        let triangles = [
+            Triangle::new(Pnt3::new(0.0, 0.0, 0.0), Pnt3::new(1.0, 0.0, 0.0), Pnt3::new(0.0, 1.0, 0.0), grey(0.5)),
+            Triangle::new(Pnt3::new(1e3, -2e3, 5e2), Pnt3::new(1.3e3, -1.9e3, 4e2), Pnt3::new(0.9e3, -1.7e3, 7e2), grey(0.5)),
+            Triangle::new(Pnt3::new(-3.1, 7.2, 0.3), Pnt3::new(-3.0, 7.2001, 0.31), Pnt3::new(-3.05, 7.3, 0.29), grey(0.5))
+        ];
+        for triangle in triangles.iter() {
+            let center = (triangle.v1.to_vector() + triangle.v2.to_vector() + triangle.v3.to_vector())/3.0;
+            for k in 0..8 {
+                let a = k as Float*0.1;
+                let target = (center*(1.0 - a) + triangle.v1.to_vector()*(a*0.5) + triangle.v2.to_vector()*(a*0.5)).to_point();
+                let origin = target + triangle.n*10.0 + Vec3::new(0.3, -0.2, 0.1)*(k as Float);
+                let hit = triangle.intersect(&mut ray(origin, (target - origin).normalize())).unwrap();
+                for w in &dirs {
+                    assert!(triangle.intersect(&mut hit.spawn_ray(*w)).is_none(), "triangle {:?} {:?}", hit.p, w);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn triangle_barycentrics() {
+        let t = Triangle::new(Pnt3::new(0.0, 0.0, 0.0), Pnt3::new(2.0, 0.0, 0.0), Pnt3::new(0.0, 2.0, 0.0), grey(0.5));
+        let hit = t.intersect(&mut ray(Pnt3::new(0.5, 0.25, 3.0), Vec3::new(0.0, 0.0, -1.0))).unwrap();
+        assert_close(hit.d, 3.0);
+        assert_close(hit.barycentric.x, 0.25);
+        assert_close(hit.barycentric.y, 0.125);
+        assert_close(hit.p.x, 0.5);
+        assert_close(hit.p.y, 0.25);
+        assert_close(hit.n.z.abs(), 1.0);
+        // Both sides are hit, outside of the edges nothing is
+        assert!(t.intersect(&mut ray(Pnt3::new(0.5, 0.25, -3.0), Vec3::new(0.0, 0.0, 1.0))).is_some());
+        assert!(t.intersect(&mut ray(Pnt3::new(1.5, 1.5, 3.0), Vec3::new(0.0, 0.0, -1.0))).is_none());
+        assert!(t.intersect(&mut ray(Pnt3::new(-0.1, 0.5, 3.0), Vec3::new(0.0, 0.0, -1.0))).is_none());
+        // Parallel rays and hits beyond the segment are missed
+        assert!(t.intersect(&mut ray(Pnt3::new(-1.0, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0))).is_none());
+        let mut r = ray(Pnt3::new(0.5, 0.25, 3.0), Vec3::new(0.0, 0.0, -1.0));
+        r.tmax = 2.5;
+        assert!(t.intersect(&mut r).is_none());
+    }
+
+    #[test]
+    fn triangle_interpolates_normals_and_uvs() {
+        let mut t = Triangle::new(Pnt3::new(0.0, 0.0, 0.0), Pnt3::new(1.0, 0.0, 0.0), Pnt3::new(0.0, 1.0, 0.0), grey(0.5));
+        t.uvs = Some([Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), Vec2::new(1.0, 1.0)]);
+        t.normals = Some([Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, -1.0)]);
+        let hit = t.intersect(&mut ray(Pnt3::new(0.5, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0))).unwrap();
+        assert_close(hit.uv.x, 0.75);
+        assert_close(hit.uv.y, 0.25);
+        // The geometric normal is flipped to the side of the shading normals
+        assert_close(hit.n.z, -1.0);
+        assert_close(hit.ng.z, -1.0);
+    }
+
+    #[test]
+    fn mesh_finds_nearest_triangle() {
+        // Stack of squares at z = 0, 1, .., 4, two triangles each
+        let mut triangles = Vec::new();
+        for k in 0..5 {
+            let z = k as Float;
+            let p = |x: Float, y: Float| Pnt3::new(x, y, z);
+            triangles.push(Triangle::new(p(-1.0, -1.0), p(1.0, -1.0), p(1.0, 1.0), grey(0.5)));
+            triangles.push(Triangle::new(p(-1.0, -1.0), p(1.0, 1.0), p(-1.0, 1.0), grey(0.5)));
+        }
+        let mesh = Mesh::new(triangles, grey(0.5));
+        let mut r = ray(Pnt3::new(-0.5, 0.5, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        let hit = mesh.intersect(&mut r).unwrap();
+        assert_close(hit.d, 6.0);
+        assert_eq!(hit.primitive, 9);
+        // The ray is shortened to the hit
+        assert_close(r.tmax, 6.0);
+        let mut r = ray(Pnt3::new(0.5, -0.5, -10.0), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(mesh.intersect(&mut r).unwrap().primitive, 0);
+        assert!(mesh.intersect(&mut ray(Pnt3::new(2.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0))).is_none());
+    }
+}
 
-// pub struct Triangle {
-//     pub v1: Pnt3,
-//     pub v2: Pnt3,
-//     pub v3: Pnt3,
-//     pub n: Vec3
-// }
-
-// impl Triangle {
-//     fn new(v1: Pnt3, v2: Pnt3, v3: Pnt3) -> Triangle {
-//         let e1 = v2 - v1;
-//         let e2 = v3 - v1;
-//         let n = e1.cross(&e2).normalize();
-//     }
-// }
-
-// impl Shape for Triangle {
-//     fn intersect(&self, ray: Ray) -> Option<HitInfo> {
-
-//     }
-// }
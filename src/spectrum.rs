@@ -0,0 +1,469 @@
+use std::f64;
+use std::sync::Once;
+use types::*;
+
+// Conversions between spectra and colors. Colors are linear sRGB with D65 white.
+//
+// In spectral mode a ray carries three wavelengths and the channels of its colors hold the
+// spectrum at these wavelengths instead of red, green and blue. RGB inputs are converted where
+// they enter the light transport, the film turns the samples into XYZ and then RGB.
+
+pub const LAMBDA_MIN: Float = 360.0;
+pub const LAMBDA_MAX: Float = 830.0;
+
+// CIE 1931 color matching functions at wavelength lambda in nm, multi lobe fit
+// Source: Wyman et al., Simple Analytic Approximations to the CIE XYZ Color Matching Functions
+pub fn cie_xyz(lambda: Float) -> Vec3 {
+    let g = |mu: Float, s1: Float, s2: Float| {
+        let t = (lambda - mu)/if lambda < mu { s1 } else { s2 };
+        (-0.5*t*t).exp()
+    };
+    Vec3::new(
+        1.056*g(599.8, 37.9, 31.0) + 0.362*g(442.0, 16.0, 26.7) - 0.065*g(501.1, 20.4, 26.2),
+        0.821*g(568.8, 46.9, 40.5) + 0.286*g(530.9, 16.3, 31.1),
+        1.217*g(437.0, 11.8, 36.0) + 0.681*g(459.0, 26.0, 13.8)
+    )
+}
+
+pub fn xyz_to_rgb(xyz: &Vec3) -> Color {
+    Color::new(
+        3.2404542*xyz.x - 1.5371385*xyz.y - 0.4985314*xyz.z,
+        -0.9692660*xyz.x + 1.8760108*xyz.y + 0.0415560*xyz.z,
+        0.0556434*xyz.x - 0.2040259*xyz.y + 1.0572252*xyz.z
+    )
+}
+
+// Spectral radiance of a black body at wavelength lambda in nm, W/(m^2 sr m)
+pub fn planck(lambda: Float, kelvin: Float) -> Float {
+    if kelvin <= 0.0 {
+        return 0.0;
+    }
+    let c = 299792458.0;
+    let h = 6.62606957e-34;
+    let kb = 1.3806488e-23;
+    let l = lambda*1e-9;
+    2.0*h*c*c/(l.powi(5)*((h*c/(l*kb*kelvin)).exp() - 1.0))
+}
+
+// Color of a black body, scaled so one at 6500K has unit luminance
+pub fn blackbody(kelvin: Float) -> Color {
+    to_rgb(&(blackbody_xyz(kelvin)/calibration().blackbody))
+}
+
+// Spectrum of a black body at the wavelengths, with the same scale as blackbody
+pub fn blackbody_spectrum(kelvin: Float, wavelengths: &Wavelengths) -> Color {
+    let b = |lambda: Float| planck(lambda, kelvin)/calibration().blackbody;
+    Color::new(b(wavelengths[0]), b(wavelengths[1]), b(wavelengths[2]))
+}
+
+fn blackbody_xyz(kelvin: Float) -> Vec3 {
+    let step = 5.0;
+    let mut xyz = Vec3::new(0.0, 0.0, 0.0);
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        xyz += cie_xyz(lambda)*(planck(lambda, kelvin)*step);
+        lambda += step;
+    }
+    xyz
+}
+
+// Wavelengths in nm of the channels of a spectral ray, the first is the hero wavelength
+pub type Wavelengths = [Float; 3];
+
+// Hero wavelength uniform over the visible range, the others evenly rotated from it
+// Source: Wilkie et al., Hero Wavelength Spectral Sampling
+pub fn sample_wavelengths(u: Float) -> Wavelengths {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let hero = LAMBDA_MIN + u*range;
+    let rotate = |j: Float| {
+        let lambda = hero + j*range/3.0;
+        if lambda > LAMBDA_MAX { lambda - range } else { lambda }
+    };
+    [hero, rotate(1.0), rotate(2.0)]
+}
+
+// Monte Carlo estimate of the XYZ color of a spectrum known at uniformly sampled wavelengths
+pub fn to_xyz(spectrum: &Color, wavelengths: &Wavelengths) -> Vec3 {
+    let weight = (LAMBDA_MAX - LAMBDA_MIN)/3.0;
+    (cie_xyz(wavelengths[0])*spectrum.x + cie_xyz(wavelengths[1])*spectrum.y
+        + cie_xyz(wavelengths[2])*spectrum.z)*weight
+}
+
+// Linear sRGB of an XYZ color, balanced so the white illuminant is exactly white
+pub fn to_rgb(xyz: &Vec3) -> Color {
+    let white = calibration().white;
+    let rgb = xyz_to_rgb(xyz);
+    Color::new(rgb.x/white.x, rgb.y/white.y, rgb.z/white.z)
+}
+
+// Spectrum of a reflectance in [0, 1] at the wavelengths, the color itself if there are none
+pub fn reflectance(rgb: &Color, wavelengths: Option<&Wavelengths>) -> Color {
+    match wavelengths {
+        None => *rgb,
+        Some(wl) => {
+            let c = table().coefficients(rgb);
+            Color::new(eval_sigmoid(&c, wl[0]), eval_sigmoid(&c, wl[1]), eval_sigmoid(&c, wl[2]))
+        }
+    }
+}
+
+// Spectrum of a nonnegative color without upper bound, such as a scattering coefficient
+pub fn unbounded(rgb: &Color, wavelengths: Option<&Wavelengths>) -> Color {
+    if wavelengths.is_none() {
+        return *rgb;
+    }
+    let scale = 2.0*rgb.x.max(rgb.y).max(rgb.z);
+    if scale <= 0.0 {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+    reflectance(&(*rgb/scale), wavelengths)*scale
+}
+
+// Spectrum of light with the given color, white light has the spectrum of the white illuminant
+pub fn illuminant(rgb: &Color, wavelengths: Option<&Wavelengths>) -> Color {
+    match wavelengths {
+        None => *rgb,
+        Some(wl) => unbounded(rgb, wavelengths)*Color::new(white(wl[0]), white(wl[1]), white(wl[2]))
+    }
+}
+
+// CIE standard illuminant D65 from 360nm to 830nm in steps of 10nm
+const D65: [Float; 48] = [
+    46.6383, 52.0891, 49.9755, 54.6482, 82.7549, 91.4860, 93.4318, 86.6823, 104.865, 117.008,
+    117.812, 114.861, 115.923, 108.811, 109.354, 107.802, 104.790, 107.689, 104.405, 104.046,
+    100.000, 96.3342, 95.7880, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268,
+    80.2146, 82.2778, 78.2842, 69.7213, 71.6091, 74.3490, 61.6040, 69.8856, 75.0870, 63.5927,
+    46.4182, 66.8054, 63.3828, 64.3040, 59.4519, 52.9511, 54.6998, 58.7698
+];
+
+fn d65(lambda: Float) -> Float {
+    let x = ((lambda - LAMBDA_MIN)/10.0).max(0.0).min((D65.len() - 1) as Float);
+    let i = (x as usize).min(D65.len() - 2);
+    D65[i] + (D65[i + 1] - D65[i])*(x - i as Float)
+}
+
+// D65 scaled to unit luminance, the spectrum of white light
+fn white(lambda: Float) -> Float {
+    d65(lambda)*calibration().illuminant
+}
+
+struct Calibration {
+    illuminant: Float,  // Scale of D65 to unit luminance
+    white: Color,       // RGB of the white illuminant before balancing
+    blackbody: Float    // Luminance of a black body at 6500K
+}
+
+impl Calibration {
+    fn new() -> Calibration {
+        let mut xyz = Vec3::new(0.0, 0.0, 0.0);
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            xyz += cie_xyz(lambda)*d65(lambda);
+            lambda += 1.0;
+        }
+        Calibration {
+            illuminant: 1.0/xyz.y,
+            white: xyz_to_rgb(&(xyz/xyz.y)),
+            blackbody: blackbody_xyz(6500.0).y
+        }
+    }
+}
+
+// The tables are built once on first use and live until the program ends
+fn calibration() -> &'static Calibration {
+    static INIT: Once = Once::new();
+    static mut CALIBRATION: *const Calibration = 0 as *const Calibration;
+    unsafe {
+        INIT.call_once(|| CALIBRATION = Box::into_raw(Box::new(Calibration::new())));
+        &*CALIBRATION
+    }
+}
+
+// Reflectance spectra are sigmoids of quadratic polynomials in the wavelength, whose
+// coefficients are fitted to RGB colors on a grid and interpolated in between
+// Source: Jakob and Hanika, A Low-Dimensional Function Space for Efficient Spectral Upsampling
+const TABLE_RES: usize = 24;
+const FIT_STEP: Float = 5.0;
+const FIT_ITERATIONS: usize = 50;
+
+fn sigmoid(x: Float) -> Float {
+    if x.is_infinite() {
+        return if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5 + x/(2.0*(1.0 + x*x).sqrt())
+}
+
+fn eval_sigmoid(c: &[Float; 3], lambda: Float) -> Float {
+    let t = (lambda - LAMBDA_MIN)/(LAMBDA_MAX - LAMBDA_MIN);
+    sigmoid((c[0]*t + c[1])*t + c[2])
+}
+
+// Coefficients by the largest channel, then its value and the ratios of the next two channels
+struct SigmoidTable {
+    scale: Vec<Float>,
+    coefficients: Vec<[Float; 3]>
+}
+
+fn table() -> &'static SigmoidTable {
+    static INIT: Once = Once::new();
+    static mut TABLE: *const SigmoidTable = 0 as *const SigmoidTable;
+    unsafe {
+        INIT.call_once(|| TABLE = Box::into_raw(Box::new(SigmoidTable::new())));
+        &*TABLE
+    }
+}
+
+// Fitting the table takes a moment, spectral renderers build it before the first sample
+pub fn prepare() {
+    table();
+}
+
+impl SigmoidTable {
+    fn new() -> SigmoidTable {
+        let n = TABLE_RES;
+        let smoothstep = |x: Float| x*x*(3.0 - 2.0*x);
+        let scale: Vec<Float> = (0..n).map(|k| smoothstep(smoothstep(k as Float/(n - 1) as Float))).collect();
+
+        // Reflectance samples map linearly to RGB under the white illuminant
+        let samples = ((LAMBDA_MAX - LAMBDA_MIN)/FIT_STEP) as usize + 1;
+        let fit: Vec<(Float, Color)> = (0..samples).map(|k| {
+            let lambda = LAMBDA_MIN + k as Float*FIT_STEP;
+            let t = (lambda - LAMBDA_MIN)/(LAMBDA_MAX - LAMBDA_MIN);
+            (t, to_rgb(&(cie_xyz(lambda)*(white(lambda)*FIT_STEP))))
+        }).collect();
+
+        let mut coefficients = vec![[0.0; 3]; 3*n*n*n];
+        for l in 0..3 {
+            for yi in 0..n {
+                for xi in 0..n {
+                    let x = xi as Float/(n - 1) as Float;
+                    let y = yi as Float/(n - 1) as Float;
+                    // Neighbouring values start from the previous solution, in both
+                    // directions from a medium brightness
+                    let start = n/5;
+                    let ranges: [Vec<usize>; 2] = [(start..n).collect(), (0..start).rev().collect()];
+                    for range in &ranges {
+                        let mut c = [0.0; 3];
+                        for &k in range {
+                            let z = scale[k];
+                            let mut rgb = Color::new(0.0, 0.0, 0.0);
+                            rgb[l] = z;
+                            rgb[(l + 1) % 3] = x*z;
+                            rgb[(l + 2) % 3] = y*z;
+                            c = fit_sigmoid(&fit, &rgb, c);
+                            coefficients[((l*n + k)*n + yi)*n + xi] = c;
+                        }
+                    }
+                }
+            }
+        }
+        SigmoidTable { scale: scale, coefficients: coefficients }
+    }
+
+    fn coefficients(&self, rgb: &Color) -> [Float; 3] {
+        let rgb = Color::new(rgb.x.max(0.0).min(1.0), rgb.y.max(0.0).min(1.0), rgb.z.max(0.0).min(1.0));
+        if rgb.x == rgb.y && rgb.y == rgb.z {
+            // Constant spectrum, the inverse of the sigmoid
+            let v = rgb.x;
+            let c = if v <= 0.0 { -f64::INFINITY } else if v >= 1.0 { f64::INFINITY } else { (v - 0.5)/(v*(1.0 - v)).sqrt() };
+            return [0.0, 0.0, c];
+        }
+        let n = TABLE_RES;
+        let l = if rgb.x >= rgb.y && rgb.x >= rgb.z { 0 } else if rgb.y >= rgb.z { 1 } else { 2 };
+        let z = rgb[l];
+        let x = rgb[(l + 1) % 3]/z*(n - 1) as Float;
+        let y = rgb[(l + 2) % 3]/z*(n - 1) as Float;
+        let xi = (x as usize).min(n - 2);
+        let yi = (y as usize).min(n - 2);
+        let zi = (self.scale.iter().position(|&s| s > z).unwrap_or(n) - 1).min(n - 2);
+        let (fx, fy) = (x - xi as Float, y - yi as Float);
+        let fz = (z - self.scale[zi])/(self.scale[zi + 1] - self.scale[zi]);
+        let mut c = [0.0; 3];
+        for dz in 0..2 {
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    let w = (if dx == 0 { 1.0 - fx } else { fx })*(if dy == 0 { 1.0 - fy } else { fy })
+                        *(if dz == 0 { 1.0 - fz } else { fz });
+                    let e = &self.coefficients[((l*n + zi + dz)*n + yi + dy)*n + xi + dx];
+                    for i in 0..3 {
+                        c[i] += e[i]*w;
+                    }
+                }
+            }
+        }
+        c
+    }
+}
+
+// Newton iterations on the coefficients until the sigmoid spectrum has the target color,
+// halving steps that do not reduce the residual
+fn fit_sigmoid(fit: &[(Float, Color)], target: &Color, start: [Float; 3]) -> [Float; 3] {
+    let residual = |c: &[Float; 3]| {
+        let mut r = -*target;
+        for &(t, ref w) in fit {
+            r += *w*sigmoid((c[0]*t + c[1])*t + c[2]);
+        }
+        r
+    };
+    let error = |r: &Color| r.x*r.x + r.y*r.y + r.z*r.z;
+    let mut c = start;
+    let mut r = residual(&c);
+    for _ in 0..FIT_ITERATIONS {
+        if error(&r) < 1e-12 {
+            break;
+        }
+        let mut jacobian = [[0.0; 3]; 3];
+        for &(t, ref w) in fit {
+            let x = (c[0]*t + c[1])*t + c[2];
+            let ds = 0.5/(1.0 + x*x).powf(1.5);
+            let dx = [t*t, t, 1.0];
+            for i in 0..3 {
+                for j in 0..3 {
+                    jacobian[i][j] += w[i]*ds*dx[j];
+                }
+            }
+        }
+        let step = match solve(&jacobian, &r) {
+            Some(step) => step,
+            None => break
+        };
+        let mut scale = 1.0;
+        loop {
+            let next = [c[0] - step[0]*scale, c[1] - step[1]*scale, c[2] - step[2]*scale];
+            let next_r = residual(&next);
+            if error(&next_r) < error(&r) {
+                c = next;
+                r = next_r;
+                break;
+            }
+            scale *= 0.5;
+            if scale < 1e-4 {
+                return c;
+            }
+        }
+    }
+    c
+}
+
+// Cramer's rule, as the Jacobians of dark colors are too small for the matrix inverse
+fn solve(m: &[[Float; 3]; 3], b: &Color) -> Option<[Float; 3]> {
+    let det3 = |a: [Float; 3], b: [Float; 3], c: [Float; 3]| {
+        a[0]*(b[1]*c[2] - b[2]*c[1]) - b[0]*(a[1]*c[2] - a[2]*c[1]) + c[0]*(a[1]*b[2] - a[2]*b[1])
+    };
+    let columns = [[m[0][0], m[1][0], m[2][0]], [m[0][1], m[1][1], m[2][1]], [m[0][2], m[1][2], m[2][2]]];
+    let b = [b.x, b.y, b.z];
+    let det = det3(columns[0], columns[1], columns[2]);
+    if det == 0.0 || !det.is_finite() {
+        return None;
+    }
+    let x = [det3(b, columns[1], columns[2])/det, det3(columns[0], b, columns[2])/det, det3(columns[0], columns[1], b)/det];
+    if x.iter().all(|v| v.is_finite()) { Some(x) } else { None }
+}
+
+// Index of refraction over the wavelength, constant when rendering in RGB
+#[derive(Debug, Clone, Copy)]
+pub enum Ior {
+    Constant(Float),
+    Cauchy(Float, Float),                   // A + B/lambda^2, lambda in micrometers
+    Sellmeier([Float; 3], [Float; 3])       // B and C coefficients, C in square micrometers
+}
+
+// Wavelength of the Fraunhofer d line, where indices of refraction are usually quoted
+pub const LAMBDA_D: Float = 587.56;
+
+impl Ior {
+    // Named glasses and the dispersion models followed by their coefficients
+    pub fn parse(name: &str, values: &[Float]) -> Option<Ior> {
+        match (name, values.len()) {
+            ("bk7", _) => Some(Ior::Sellmeier([1.03961212, 0.231792344, 1.01046945], [0.00600069867, 0.0200179144, 103.560653])),
+            ("fused_silica", _) => Some(Ior::Sellmeier([0.6961663, 0.4079426, 0.8974794], [0.0046791, 0.0135121, 97.9340])),
+            ("diamond", _) => Some(Ior::Sellmeier([4.3356, 0.3306, 0.0], [0.01124, 0.030625, 0.0])),
+            ("cauchy", n) if n >= 2 => Some(Ior::Cauchy(values[0], values[1])),
+            ("sellmeier", n) if n >= 6 => Some(Ior::Sellmeier([values[0], values[1], values[2]], [values[3], values[4], values[5]])),
+            _ => None
+        }
+    }
+
+    pub fn eval(&self, lambda: Float) -> Float {
+        let l2 = (lambda*1e-3)*(lambda*1e-3);
+        match *self {
+            Ior::Constant(n) => n,
+            Ior::Cauchy(a, b) => a + b/l2,
+            Ior::Sellmeier(b, c) => (1.0 + (0..3).map(|i| b[i]*l2/(l2 - c[i])).sum::<Float>()).sqrt()
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        match *self {
+            Ior::Constant(_) => false,
+            _ => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::*;
+    use super::*;
+
+    // Color of a reflectance spectrum lit by the white illuminant
+    fn reflected_color(rgb: &Color) -> Color {
+        let mut xyz = Vec3::new(0.0, 0.0, 0.0);
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            let wl = [lambda; 3];
+            xyz += cie_xyz(lambda)*(reflectance(rgb, Some(&wl)).x*white(lambda));
+            lambda += 1.0;
+        }
+        to_rgb(&xyz)
+    }
+
+    #[test]
+    fn reflectance_round_trip() {
+        let colors = [
+            Color::new(0.5, 0.5, 0.5), Color::new(0.9, 0.7, 0.4), Color::new(0.1, 0.6, 0.2),
+            Color::new(0.2, 0.3, 0.8), Color::new(0.8, 0.05, 0.1), Color::new(0.02, 0.03, 0.01),
+            Color::new(0.95, 0.9, 0.99), Color::new(0.4, 0.4, 0.0)
+        ];
+        for rgb in &colors {
+            let c = reflected_color(rgb);
+            for i in 0..3 {
+                assert!((c[i] - rgb[i]).abs() < 0.01, "{:?} {:?}", rgb, c);
+            }
+        }
+        // Reflectances stay within [0, 1]
+        for k in 0..100 {
+            let wl = sample_wavelengths(k as Float/100.0);
+            let r = reflectance(&Color::new(1.0, 0.0, 0.2), Some(&wl));
+            assert!(r.x >= 0.0 && r.y >= 0.0 && r.z >= 0.0 && r.x <= 1.0 && r.y <= 1.0 && r.z <= 1.0);
+        }
+    }
+
+    #[test]
+    fn white_light_is_white() {
+        // Estimate of the film color with stratified hero wavelengths
+        let n = 10000;
+        let mut rgb = Color::new(0.0, 0.0, 0.0);
+        for k in 0..n {
+            let wl = sample_wavelengths((k as Float + 0.5)/n as Float);
+            let l = illuminant(&Color::new(2.0, 2.0, 2.0), Some(&wl))*reflectance(&Color::new(0.5, 0.5, 0.5), Some(&wl));
+            rgb += to_rgb(&to_xyz(&l, &wl));
+        }
+        rgb = rgb/n as Float;
+        for i in 0..3 {
+            assert!((rgb[i] - 1.0).abs() < 0.005, "{:?}", rgb);
+        }
+        assert_eq!(illuminant(&Color::new(0.2, 0.3, 0.4), None), Color::new(0.2, 0.3, 0.4));
+    }
+
+    #[test]
+    fn dispersion() {
+        let bk7 = Ior::parse("bk7", &[]).unwrap();
+        assert!((bk7.eval(LAMBDA_D) - 1.5168).abs() < 1e-4);
+        assert!(bk7.eval(450.0) > bk7.eval(650.0));
+        let cauchy = Ior::parse("cauchy", &[1.5, 0.004]).unwrap();
+        assert!((cauchy.eval(500.0) - 1.516).abs() < 1e-12);
+        assert!(Ior::parse("cauchy", &[1.5]).is_none());
+        assert!(!Ior::Constant(1.5).is_dispersive());
+    }
+}
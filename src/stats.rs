@@ -0,0 +1,224 @@
+use std::cell::RefCell;
+use std::sync::Mutex;
+use std::time::Duration;
+use types::*;
+
+// Render statistics. Every thread counts into its own counters, which are added to the
+// global totals when the thread ends or calls flush.
+
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    CameraRays,
+    ShadowRays,
+    AoRays,
+    SecondaryRays,  // Reflection, refraction and volume scattering
+    SphereTests,
+    TriangleTests,
+    BvhNodes,
+    ShaderCalls
+}
+
+const COUNTERS: usize = 8;
+
+const NAMES: [&'static str; COUNTERS] = [
+    "camera_rays",
+    "shadow_rays",
+    "ao_rays",
+    "secondary_rays",
+    "sphere_tests",
+    "triangle_tests",
+    "bvh_nodes_visited",
+    "shader_invocations"
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Timer {
+    Build,
+    Render
+}
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub counters: [u64; COUNTERS],
+    pub build: Duration,
+    pub render: Duration
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            counters: [0; COUNTERS],
+            build: Duration::from_secs(0),
+            render: Duration::from_secs(0)
+        }
+    }
+
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.counters[counter as usize]
+    }
+
+    pub fn rays(&self) -> u64 {
+        self.get(Counter::CameraRays) + self.get(Counter::ShadowRays) + self.get(Counter::AoRays)
+            + self.get(Counter::SecondaryRays)
+    }
+
+    fn add(&mut self, other: &Stats) {
+        for (a, b) in self.counters.iter_mut().zip(other.counters.iter()) {
+            *a += *b;
+        }
+        self.build += other.build;
+        self.render += other.render;
+    }
+
+    fn rays_per_second(&self) -> Float {
+        let render = seconds(self.render);
+        if render > 0.0 { self.rays() as Float/render } else { 0.0 }
+    }
+
+    pub fn summary(&self) -> String {
+        let mut s = String::from("Render statistics\n");
+        s.push_str(&format!("  {:<20} {:>10.3}s\n", "build", seconds(self.build)));
+        s.push_str(&format!("  {:<20} {:>10.3}s\n", "render", seconds(self.render)));
+        for (name, value) in NAMES.iter().zip(self.counters.iter()) {
+            s.push_str(&format!("  {:<20} {:>11}\n", name, value));
+        }
+        s.push_str(&format!("  {:<20} {:>11.0}\n", "rays_per_second", self.rays_per_second()));
+        s
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut fields = vec![
+            format!("\"build_seconds\": {:.6}", seconds(self.build)),
+            format!("\"render_seconds\": {:.6}", seconds(self.render))
+        ];
+        for (name, value) in NAMES.iter().zip(self.counters.iter()) {
+            fields.push(format!("\"{}\": {}", name, value));
+        }
+        fields.push(format!("\"rays_per_second\": {:.0}", self.rays_per_second()));
+        format!("{{{}}}\n", fields.join(", "))
+    }
+}
+
+fn seconds(d: Duration) -> Float {
+    d.as_secs() as Float + d.subsec_nanos() as Float*1e-9
+}
+
+struct Local(Stats);
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        GLOBAL.lock().unwrap().add(&self.0);
+    }
+}
+
+static GLOBAL: Mutex<Stats> = Mutex::new(Stats {
+    counters: [0; COUNTERS],
+    build: Duration::from_secs(0),
+    render: Duration::from_secs(0)
+});
+
+thread_local!(static LOCAL: RefCell<Local> = RefCell::new(Local(Stats::new())));
+
+pub fn count(counter: Counter) {
+    add(counter, 1);
+}
+
+pub fn add(counter: Counter, n: u64) {
+    let _ = LOCAL.try_with(|local| local.borrow_mut().0.counters[counter as usize] += n);
+}
+
+pub fn time(timer: Timer, d: Duration) {
+    let _ = LOCAL.try_with(|local| {
+        let stats = &mut local.borrow_mut().0;
+        match timer {
+            Timer::Build => stats.build += d,
+            Timer::Render => stats.render += d
+        }
+    });
+}
+
+// Current count of the calling thread, differences give the cost of single operations
+pub fn local(counter: Counter) -> u64 {
+    LOCAL.try_with(|local| local.borrow().0.counters[counter as usize]).unwrap_or(0)
+}
+
+// Adds the counters of the calling thread to the totals
+pub fn flush() {
+    let _ = LOCAL.try_with(|local| {
+        let stats = &mut local.borrow_mut().0;
+        GLOBAL.lock().unwrap().add(stats);
+        *stats = Stats::new();
+    });
+}
+
+// Totals of all finished threads and the calling thread
+pub fn collect() -> Stats {
+    flush();
+    GLOBAL.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64;
+    use types::*;
+    use aov::Aov;
+    use ray::Ray;
+    use renderer::Renderer;
+    use scene::Scene;
+    use window::Window;
+    use testing;
+    use testing::{SceneBuilder, grey};
+    use super::*;
+
+    // A diffuse sphere lit by a point light
+    fn scene() -> Scene {
+        SceneBuilder::lit_sphere(grey(0.5)).build()
+    }
+
+    // Change of the counters of this thread while f runs
+    fn counted<F: FnOnce()>(f: F) -> Vec<u64> {
+        let counters = [Counter::ShadowRays, Counter::SphereTests, Counter::BvhNodes, Counter::ShaderCalls];
+        let before: Vec<u64> = counters.iter().map(|c| local(*c)).collect();
+        f();
+        counters.iter().zip(before).map(|(c, b)| local(*c) - b).collect()
+    }
+
+    #[test]
+    fn counts_of_a_single_ray() {
+        let scene = scene();
+        let renderer = Renderer::new(&scene);
+        let camera = Window::new(1, 1).camera;
+        let render = |dir: Vec3| {
+            let mut ray = Ray::new(&Pnt3::new(0.0, -3.0, 0.0), dir, 0.0, f64::INFINITY);
+            renderer.render_aovs(&mut ray, &[Aov::Beauty], &camera, &mut Vec::new(), None);
+        };
+        // The camera ray hits the sphere. The shadow ray towards the light visits the single
+        // leaf of the hierarchy too, but starts outside of its bounds and leaves them.
+        assert_eq!(counted(|| render(Vec3::new(0.0, 1.0, 0.0))), vec![1, 1, 2, 1]);
+        // A miss neither reaches the sphere nor shades
+        assert_eq!(counted(|| render(Vec3::new(0.0, -1.0, 0.0))), vec![0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn camera_rays_per_sample() {
+        let scene = scene();
+        let mut window = Window::new(6, 4);
+        window.samples = 3;
+        let before = local(Counter::CameraRays);
+        testing::draw_aovs(&window, &scene, &[Aov::Beauty]);
+        assert_eq!(local(Counter::CameraRays) - before, 6*4*3);
+    }
+
+    #[test]
+    fn totals_and_output() {
+        let mut stats = Stats::new();
+        stats.counters[Counter::CameraRays as usize] = 4;
+        stats.counters[Counter::ShadowRays as usize] = 3;
+        stats.counters[Counter::SphereTests as usize] = 100;
+        stats.render = Duration::from_secs(2);
+        assert_eq!(stats.rays(), 7);
+        assert!(stats.summary().contains("sphere_tests"));
+        let json = stats.to_json();
+        assert!(json.contains("\"camera_rays\": 4") && json.contains("\"rays_per_second\": 4"), "{}", json);
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::rc::Rc;
+use types::*;
+use aov::Aov;
+use film::{Accumulator, Film};
+use light::PointLight;
+use renderer::Renderer;
+use scene::Scene;
+use shader::{Shader, DiffuseShader, AmbientOcculusionShader, AoSettings};
+use shape::{Shape, Sphere};
+use texture::ConstantTexture;
+use window::Window;
+
+// Helpers shared by the tests of several modules
+
+pub fn diffuse(color: Color) -> Rc<Shader> {
+    Rc::new(DiffuseShader { color: Rc::new(ConstantTexture { color: color }) })
+}
+
+pub fn grey(c: Float) -> Rc<Shader> {
+    diffuse(Color::new(c, c, c))
+}
+
+pub fn sphere(position: Pnt3, radius: Float, shader: Rc<Shader>) -> Sphere {
+    Sphere { position: position, radius: radius, shader: shader }
+}
+
+pub fn ambient_occlusion(c: Float) -> Rc<Shader> {
+    Rc::new(AmbientOcculusionShader { settings: AoSettings::new(), color: Rc::new(ConstantTexture { color: Color::new(c, c, c) }) })
+}
+
+// Test scenes made of spheres, other shapes and point lights. Shapes keep the order in which
+// they are added, which is their object index.
+pub struct SceneBuilder {
+    scene: Scene
+}
+
+impl SceneBuilder {
+    pub fn new() -> SceneBuilder {
+        SceneBuilder { scene: Scene::empty() }
+    }
+
+    // A unit sphere at the origin lit by a point light in front of it and above
+    pub fn lit_sphere(shader: Rc<Shader>) -> SceneBuilder {
+        SceneBuilder::new()
+            .sphere(Pnt3::new(0.0, 0.0, 0.0), 1.0, shader)
+            .light(Pnt3::new(0.0, -5.0, 5.0), 20.0, 0)
+    }
+
+    pub fn sphere(self, position: Pnt3, radius: Float, shader: Rc<Shader>) -> SceneBuilder {
+        self.shape(Box::new(sphere(position, radius, shader)))
+    }
+
+    // Large sphere touching the plane z = -1 from below
+    pub fn ground(self, shader: Rc<Shader>) -> SceneBuilder {
+        self.sphere(Pnt3::new(0.0, 0.0, -1e4 - 1.0), 1e4, shader)
+    }
+
+    pub fn shape(mut self, shape: Box<Shape>) -> SceneBuilder {
+        self.scene.shapes.push(shape);
+        self
+    }
+
+    // White point light with the given intensity
+    pub fn light(mut self, position: Pnt3, intensity: Float, group: u32) -> SceneBuilder {
+        let intensity = Color::new(intensity, intensity, intensity);
+        self.scene.lights.push(Box::new(PointLight { position: position, intensity: intensity, group: group }));
+        self
+    }
+
+    pub fn build(self) -> Scene {
+        self.scene
+    }
+}
+
+// Renders one film per AOV, in the given order, in a single pass over the whole image
+pub fn draw_aovs(window: &Window, scene: &Scene, aovs: &[Aov]) -> Vec<Film> {
+    let mut accumulator = Accumulator::new(window.width, window.height, aovs);
+    let renderer: Renderer = Renderer::new(scene);
+    window.render_pass(&renderer, &mut accumulator, window.max_samples());
+    accumulator.films()
+}
+
+// Sends one HTTP request and returns the status code and body of the answer
+pub fn request(address: &str, method: &str, target: &str, body: &str) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}", method, target, address, body.len(), body).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..end]).into_owned();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, response[end + 4..].to_vec())
+}
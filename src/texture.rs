@@ -0,0 +1,276 @@
+use std::rc::Rc;
+use std::vec::Vec;
+use std::path::Path;
+use image;
+use image::ImageResult;
+use types::*;
+use hit::HitInfo;
+use noise;
+use mipmap::{MipMap, FilterMode};
+use colorspace::{ColorSpace, conversion};
+
+pub trait Texture {
+    fn eval(&self, hit: &HitInfo) -> Color;
+    fn eval_scalar(&self, hit: &HitInfo) -> Float {
+        let c = self.eval(hit);
+        (c.x + c.y + c.z) / 3.0
+    }
+}
+
+// Coordinates a procedural pattern is evaluated in
+#[derive(Debug, Clone, Copy)]
+pub enum TextureSpace {
+    UV,
+    Object,
+    World
+}
+
+fn texture_point(space: TextureSpace, scale: Float, hit: &HitInfo) -> Pnt3 {
+    let p = match space {
+        TextureSpace::UV => Pnt3::new(hit.uv.x, hit.uv.y, 0.0),
+        TextureSpace::Object => hit.lp,
+        TextureSpace::World => hit.p
+    };
+    Pnt3::new(p.x*scale, p.y*scale, p.z*scale)
+}
+
+// Piecewise linear mapping from [0, 1] to colors, the positions of the stops must be finite
+#[derive(Debug, Clone)]
+pub struct ColorRamp {
+    pub stops: Vec<(Float, Color)>
+}
+
+impl ColorRamp {
+    pub fn new(mut stops: Vec<(Float, Color)>) -> ColorRamp {
+        stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        ColorRamp {
+            stops: stops
+        }
+    }
+    pub fn between(a: Color, b: Color) -> ColorRamp {
+        ColorRamp::new(vec![(0.0, a), (1.0, b)])
+    }
+    pub fn eval(&self, t: Float) -> Color {
+        if self.stops.is_empty() {
+            return Color::new(t, t, t);
+        }
+        let first = &self.stops[0];
+        if t <= first.0 {
+            return first.1;
+        }
+        for w in self.stops.windows(2) {
+            let (t0, c0) = w[0];
+            let (t1, c1) = w[1];
+            if t <= t1 {
+                if t1 - t0 <= 0.0 {
+                    return c1;
+                }
+                let f = (t - t0) / (t1 - t0);
+                return c0*(1.0 - f) + c1*f;
+            }
+        }
+        self.stops[self.stops.len() - 1].1
+    }
+}
+
+pub struct ConstantTexture {
+    pub color: Color
+}
+
+impl Texture for ConstantTexture {
+    fn eval(&self, _hit: &HitInfo) -> Color {
+        self.color
+    }
+}
+
+// Image repeated outside of [0, 1]^2, filtered over the ray footprint
+pub struct ImageTexture {
+    pub mipmap: MipMap,
+    pub filter: FilterMode
+}
+
+impl ImageTexture {
+    // Pixels are decoded from the color space of the image and converted to the working space
+    pub fn open(path: &Path, filter: FilterMode, space: ColorSpace, working: ColorSpace) -> ImageResult<ImageTexture> {
+        let img = image::open(path)?.to_rgb();
+        let (width, height) = img.dimensions();
+        let m = conversion(space, working);
+        let pixels = img.pixels().map(|p| {
+            m*space.decode(&(Color::new(p.data[0] as Float, p.data[1] as Float, p.data[2] as Float)/255.0))
+        }).collect();
+        Ok(ImageTexture {
+            mipmap: MipMap::new(width, height, pixels),
+            filter: filter
+        })
+    }
+}
+
+impl Texture for ImageTexture {
+    fn eval(&self, hit: &HitInfo) -> Color {
+        let duvdx = Vec2::new(hit.dudx, hit.dvdx);
+        let duvdy = Vec2::new(hit.dudy, hit.dvdy);
+        self.mipmap.lookup(self.filter, &hit.uv, &duvdx, &duvdy)
+    }
+}
+
+pub struct CheckerTexture {
+    pub even: Rc<Texture>,
+    pub odd: Rc<Texture>,
+    pub space: TextureSpace,
+    pub scale: Float
+}
+
+impl Texture for CheckerTexture {
+    fn eval(&self, hit: &HitInfo) -> Color {
+        let p = texture_point(self.space, self.scale, hit);
+        let sum = p.x.floor() as i64 + p.y.floor() as i64 + p.z.floor() as i64;
+        if sum % 2 == 0 {
+            self.even.eval(hit)
+        } else {
+            self.odd.eval(hit)
+        }
+    }
+}
+
+pub struct NoiseTexture {
+    pub space: TextureSpace,
+    pub scale: Float,
+    pub ramp: ColorRamp
+}
+
+impl Texture for NoiseTexture {
+    fn eval(&self, hit: &HitInfo) -> Color {
+        let p = texture_point(self.space, self.scale, hit);
+        self.ramp.eval(0.5 + 0.5*noise::noise(&p))
+    }
+}
+
+pub struct FbmTexture {
+    pub space: TextureSpace,
+    pub scale: Float,
+    pub octaves: u32,
+    pub lacunarity: Float,
+    pub gain: Float,
+    pub ramp: ColorRamp
+}
+
+impl Texture for FbmTexture {
+    fn eval(&self, hit: &HitInfo) -> Color {
+        let p = texture_point(self.space, self.scale, hit);
+        let v = noise::fbm(&p, self.octaves, self.lacunarity, self.gain);
+        self.ramp.eval(0.5 + 0.5*v)
+    }
+}
+
+pub struct TurbulenceTexture {
+    pub space: TextureSpace,
+    pub scale: Float,
+    pub octaves: u32,
+    pub lacunarity: Float,
+    pub gain: Float,
+    pub ramp: ColorRamp
+}
+
+impl Texture for TurbulenceTexture {
+    fn eval(&self, hit: &HitInfo) -> Color {
+        let p = texture_point(self.space, self.scale, hit);
+        self.ramp.eval(noise::turbulence(&p, self.octaves, self.lacunarity, self.gain))
+    }
+}
+
+// Veins along x distorted by turbulence
+pub struct MarbleTexture {
+    pub space: TextureSpace,
+    pub scale: Float,
+    pub frequency: Float,
+    pub distortion: Float,
+    pub octaves: u32,
+    pub ramp: ColorRamp
+}
+
+impl Texture for MarbleTexture {
+    fn eval(&self, hit: &HitInfo) -> Color {
+        let p = texture_point(self.space, self.scale, hit);
+        let t = noise::turbulence(&p, self.octaves, 2.0, 0.5);
+        let v = (self.frequency*p.x + self.distortion*t).sin();
+        self.ramp.eval(0.5 + 0.5*v)
+    }
+}
+
+// Concentric rings around the z axis
+pub struct WoodTexture {
+    pub space: TextureSpace,
+    pub scale: Float,
+    pub rings: Float,
+    pub distortion: Float,
+    pub ramp: ColorRamp
+}
+
+impl Texture for WoodTexture {
+    fn eval(&self, hit: &HitInfo) -> Color {
+        let p = texture_point(self.space, self.scale, hit);
+        let r = (p.x*p.x + p.y*p.y).sqrt()*self.rings + self.distortion*noise::noise(&p);
+        self.ramp.eval(r - r.floor())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum VoronoiFeature {
+    F1,
+    F2,
+    F2MinusF1
+}
+
+pub struct VoronoiTexture {
+    pub space: TextureSpace,
+    pub scale: Float,
+    pub jitter: Float,
+    pub feature: VoronoiFeature,
+    pub ramp: ColorRamp
+}
+
+impl Texture for VoronoiTexture {
+    fn eval(&self, hit: &HitInfo) -> Color {
+        let p = texture_point(self.space, self.scale, hit);
+        // The cell search only ends for feature points within their cell
+        let (f1, f2) = noise::worley(&p, self.jitter.max(0.0).min(1.0));
+        let v = match self.feature {
+            VoronoiFeature::F1 => f1,
+            VoronoiFeature::F2 => f2,
+            VoronoiFeature::F2MinusF1 => f2 - f1
+        };
+        self.ramp.eval(v.min(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use types::*;
+    use loader;
+    use super::*;
+
+    #[test]
+    fn ramp_interpolates_sorted_stops() {
+        let ramp = ColorRamp::new(vec![
+            (1.0, Color::new(0.0, 0.0, 1.0)),
+            (0.0, Color::new(1.0, 0.0, 0.0)),
+            (0.5, Color::new(0.0, 1.0, 0.0))
+        ]);
+        assert_eq!(ramp.stops[0].0, 0.0);
+        assert_eq!(ramp.eval(-1.0), Color::new(1.0, 0.0, 0.0));
+        assert_eq!(ramp.eval(0.25), Color::new(0.5, 0.5, 0.0));
+        assert_eq!(ramp.eval(0.75), Color::new(0.0, 0.5, 0.5));
+        assert_eq!(ramp.eval(2.0), Color::new(0.0, 0.0, 1.0));
+        let c = ColorRamp::between(Color::new(0.0, 0.0, 0.0), Color::new(2.0, 4.0, 6.0)).eval(0.5);
+        assert_eq!(c, Color::new(1.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn ramp_stops_from_scene() {
+        let dir = Some(Path::new("."));
+        assert!(loader::parse("texture veins marble ramp 0 1 1 1 1 0 0 0\n", dir).is_ok());
+        assert!(loader::parse("texture veins marble ramp nan 1 1 1 1 0 0 0\n", dir).is_err());
+        assert!(loader::parse("texture veins marble ramp 0 1 1\n", dir).is_err());
+    }
+}
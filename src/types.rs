@@ -1,6 +1,7 @@
-use nalgebra::{Point3, Point4, Vector3, Vector4, Matrix4, Norm, Cross, Dot, Inverse, Eye};
+use nalgebra::{Point3, Point4, Vector2, Vector3, Vector4, Matrix4, Norm, Cross, Dot, Inverse, Eye};
 
 pub type Color = Vector3<f64>;
+pub type Vec2  = Vector2<f64>;
 pub type Vec3  = Vector3<f64>;
 pub type Vec4  = Vector4<f64>;
 pub type Pnt3  = Point3<f64>;
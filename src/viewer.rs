@@ -0,0 +1,459 @@
+use std::io;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+use nalgebra::{Cross, Norm};
+use scoped_threadpool::Pool;
+use types::*;
+use aov::Aov;
+use camera::View;
+use colorspace::to_png;
+use film::{Accumulator, SplatBuffer};
+use http;
+use renderer::Renderer;
+use scene::Scene;
+use window::Window;
+
+// Local web viewer. The frame is refined in passes of tiles, every update is announced to
+// the browser as a server-sent event, after which the page fetches the new image. Moving the
+// camera in the page restarts the progressive rendering.
+//
+//   GET  /              viewer page
+//   GET  /events        stream of render statistics, one event per new image
+//   GET  /image         current image as PNG
+//   GET  /camera        camera position, front and up
+//   POST /camera        ?position=x,y,z&front=x,y,z&up=x,y,z
+//   POST /restart       restart the rendering
+
+// Samples per pixel the viewer refines to, unless given on the command line
+pub const DEFAULT_SAMPLES: u32 = 256;
+
+const TILE_SIZE: u32 = 64;
+
+// Minimal time between two images sent to the browser
+const UPDATE_INTERVAL_MS: u64 = 250;
+
+struct State {
+    view: View,
+    generation: u64,    // Incremented on every camera change or restart
+    version: u64,       // Incremented on every new image
+    image: Vec<u8>,
+    stats: String
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+// Serves the viewer and renders until the process is stopped. Adaptive sampling is not
+// used, as the tiles of a pass are merged into the film.
+pub fn view<F>(listener: TcpListener, mut window: Window, scene: &Scene, mut log: F)
+    where F: FnMut(&str)
+{
+    let camera = &window.camera;
+    let state = State {
+        view: View {
+            position: camera.position,
+            front: camera.front,
+            up: camera.up,
+            angle: camera.angle
+        },
+        generation: 0,
+        version: 0,
+        image: Vec::new(),
+        stats: "{}".to_string()
+    };
+    let shared: Shared = Arc::new((Mutex::new(state), Condvar::new()));
+    if let Ok(address) = listener.local_addr() {
+        log(&format!("Viewer running on http://{}/", address));
+    }
+    {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let shared = shared.clone();
+                    thread::spawn(move || {
+                        let _ = handle(stream, &shared);
+                    });
+                }
+            }
+        });
+    }
+
+    window.adaptive = None;
+    let renderer = Renderer::new(scene);
+    let &(ref lock, ref changed) = &*shared;
+    loop {
+        let (generation, view) = {
+            let state = lock.lock().unwrap();
+            (state.generation, state.view.clone())
+        };
+        window.camera.set_up(view.up);
+        window.camera.set_front(view.front);
+        window.camera.set_position(&view.position);
+        let finished = refine(&window, scene, &renderer, &shared, generation);
+
+        // Wait for the next camera change once the image is converged
+        if finished {
+            let mut state = lock.lock().unwrap();
+            while state.generation == generation {
+                state = changed.wait(state).unwrap();
+            }
+        }
+    }
+}
+
+// Renders passes until the sample target, returns false if the camera changed before
+fn refine(window: &Window, scene: &Scene, renderer: &Renderer, shared: &Shared, generation: u64) -> bool {
+    let &(ref lock, ref changed) = &**shared;
+    let start = Instant::now();
+    let mut last_update = start;
+    let mut accumulator = Accumulator::new(window.width, window.height, &[Aov::Beauty]);
+    let mut splats = SplatBuffer::new(window.width, window.height);
+    let target = window.max_samples();
+    let pass_samples = window.progressive.pass_samples.max(1);
+    let tiles_x = (window.width + TILE_SIZE - 1)/TILE_SIZE;
+    let tiles_y = (window.height + TILE_SIZE - 1)/TILE_SIZE;
+    let mut pool = Pool::new(4);
+    let mut first_sample = 0;
+    let mut pass = 0;
+    while first_sample < target {
+        let samples = pass_samples.min(target - first_sample);
+        pass += 1;
+        for tile in 0..tiles_x*tiles_y {
+            if lock.lock().unwrap().generation != generation {
+                return false;
+            }
+            let x0 = (tile % tiles_x)*TILE_SIZE;
+            let y0 = (tile / tiles_x)*TILE_SIZE;
+            let mut pixels = Accumulator::new(TILE_SIZE.min(window.width - x0), TILE_SIZE.min(window.height - y0), &[Aov::Beauty]);
+            window.render_tile(&mut pool, renderer, &mut pixels, &mut splats, x0, y0, first_sample, samples);
+            accumulator.merge_tile(x0, y0, &pixels, first_sample == 0);
+
+            let now = Instant::now();
+            let last = tile + 1 == tiles_x*tiles_y;
+            if last || now.duration_since(last_update) >= Duration::from_millis(UPDATE_INTERVAL_MS) {
+                last_update = now;
+                let elapsed = now.duration_since(start);
+                let seconds = elapsed.as_secs() as Float + elapsed.subsec_nanos() as Float*1e-9;
+                let total = accumulator.samples();
+                let stats = format!("{{\"pass\": {}, \"spp\": {}, \"target_spp\": {}, \"tiles_done\": {}, \"tiles_total\": {}, \"samples\": {}, \"elapsed_seconds\": {:.1}, \"samples_per_second\": {:.0}, \"done\": {}}}",
+                    pass, first_sample + samples, target, tile + 1, tiles_x*tiles_y, total, seconds,
+                    if seconds > 0.0 { total as Float/seconds } else { 0.0 },
+                    last && first_sample + samples >= target);
+                accumulator.add_splats(&splats);
+                splats.clear();
+                let image = to_png(&accumulator.films()[0], scene.working, scene.output).unwrap_or(Vec::new());
+                let mut state = lock.lock().unwrap();
+                if state.generation != generation {
+                    return false;
+                }
+                state.image = image;
+                state.stats = stats;
+                state.version += 1;
+                changed.notify_all();
+            }
+        }
+        first_sample += samples;
+    }
+    true
+}
+
+fn handle(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    let &(ref lock, ref changed) = &**shared;
+    let request = http::read_request(&stream)?;
+    let segments = request.segments();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", []) => http::respond(&mut stream, 200, "text/html; charset=utf-8", PAGE.as_bytes()),
+        ("GET", ["image"]) => {
+            let image = lock.lock().unwrap().image.clone();
+            http::respond(&mut stream, 200, "image/png", &image)
+        },
+        ("GET", ["camera"]) => {
+            let view = lock.lock().unwrap().view.clone();
+            http::respond_json(&mut stream, 200, &format!("{{\"position\": [{}, {}, {}], \"front\": [{}, {}, {}], \"up\": [{}, {}, {}]}}",
+                view.position.x, view.position.y, view.position.z,
+                view.front.x, view.front.y, view.front.z,
+                view.up.x, view.up.y, view.up.z))
+        },
+        ("POST", ["camera"]) => {
+            let q = &request.query;
+            let (position, front, up) = match (vector(&q.get("position")), vector(&q.get("front")), vector(&q.get("up"))) {
+                (Some(position), Some(front), Some(up)) => (position, front, up),
+                _ => return http::respond_error(&mut stream, 400, "Expected position, front and up as x,y,z")
+            };
+            // The camera transform is singular for a zero front or up, or an up along front
+            if front.cross(&up).norm() <= 1e-9*front.norm()*up.norm() {
+                return http::respond_error(&mut stream, 400, "Front and up must be nonzero and not parallel");
+            }
+            let mut state = lock.lock().unwrap();
+            state.view.position = position.to_point();
+            state.view.front = front;
+            state.view.up = up;
+            state.generation += 1;
+            changed.notify_all();
+            http::respond_json(&mut stream, 200, "{}")
+        },
+        ("POST", ["restart"]) => {
+            let mut state = lock.lock().unwrap();
+            state.generation += 1;
+            changed.notify_all();
+            http::respond_json(&mut stream, 200, "{}")
+        },
+        ("GET", ["events"]) => events(stream, shared),
+        _ => http::respond_error(&mut stream, 404, "Unknown endpoint")
+    }
+}
+
+// Server-sent events, until the browser closes the connection
+fn events(mut stream: TcpStream, shared: &Shared) -> io::Result<()> {
+    let &(ref lock, ref changed) = &**shared;
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n")?;
+    let mut version = 0;
+    loop {
+        let message = {
+            let mut state = lock.lock().unwrap();
+            if state.version == version {
+                state = changed.wait_timeout(state, Duration::from_secs(15)).unwrap().0;
+            }
+            if state.version == version {
+                // Keeps the connection alive and notices closed connections
+                ": ping\n\n".to_string()
+            } else {
+                version = state.version;
+                format!("data: {{\"version\": {}, \"stats\": {}}}\n\n", version, state.stats)
+            }
+        };
+        stream.write_all(message.as_bytes())?;
+        stream.flush()?;
+    }
+}
+
+fn vector(value: &Option<&String>) -> Option<Vec3> {
+    let values: Vec<Float> = match *value {
+        Some(v) => v.split(',').filter_map(|x| x.trim().parse().ok()).collect(),
+        None => return None
+    };
+    if values.len() != 3 || values.iter().any(|v| !v.is_finite()) {
+        return None;
+    }
+    Some(Vec3::new(values[0], values[1], values[2]))
+}
+
+const PAGE: &'static str = r#"<!DOCTYPE html>
+<html>
+<head>
+<title>raytracer</title>
+<style>
+body { background: #222; color: #ddd; font-family: monospace; margin: 1em; }
+canvas { cursor: grab; display: block; max-width: 100%; }
+button { margin-top: 0.5em; }
+</style>
+</head>
+<body>
+<canvas id="canvas"></canvas>
+<div id="stats">Waiting for the first image</div>
+<button id="restart">Restart</button>
+<div>Drag to orbit, scroll to zoom</div>
+<script>
+var canvas = document.getElementById('canvas');
+var context = canvas.getContext('2d');
+var stats = document.getElementById('stats');
+var camera = null;
+var target = null;
+
+function add(a, b) { return [a[0] + b[0], a[1] + b[1], a[2] + b[2]]; }
+function sub(a, b) { return [a[0] - b[0], a[1] - b[1], a[2] - b[2]]; }
+function scale(a, s) { return [a[0]*s, a[1]*s, a[2]*s]; }
+function dot(a, b) { return a[0]*b[0] + a[1]*b[1] + a[2]*b[2]; }
+function cross(a, b) { return [a[1]*b[2] - a[2]*b[1], a[2]*b[0] - a[0]*b[2], a[0]*b[1] - a[1]*b[0]]; }
+function normalize(a) { return scale(a, 1/Math.sqrt(dot(a, a))); }
+// Rodrigues' rotation of v around the unit axis k
+function rotate(v, k, angle) {
+    var c = Math.cos(angle), s = Math.sin(angle);
+    return add(add(scale(v, c), scale(cross(k, v), s)), scale(k, dot(k, v)*(1 - c)));
+}
+
+function send() {
+    var q = 'position=' + camera.position.join(',') + '&front=' + camera.front.join(',') + '&up=' + camera.up.join(',');
+    fetch('/camera?' + q, { method: 'POST' });
+}
+
+fetch('/camera').then(function(r) { return r.json(); }).then(function(c) {
+    camera = c;
+    // Orbit around the point the camera looks at, at the distance of the origin
+    var distance = Math.max(Math.sqrt(dot(c.position, c.position)), 1);
+    target = add(c.position, scale(normalize(c.front), distance));
+});
+
+var drag = null;
+canvas.addEventListener('mousedown', function(e) { drag = [e.clientX, e.clientY]; });
+window.addEventListener('mouseup', function() {
+    if (drag && camera) { send(); }
+    drag = null;
+});
+window.addEventListener('mousemove', function(e) {
+    if (!drag || !camera) { return; }
+    var dx = (e.clientX - drag[0])*0.01, dy = (e.clientY - drag[1])*0.01;
+    drag = [e.clientX, e.clientY];
+    var up = normalize(camera.up);
+    var right = normalize(cross(camera.front, up));
+    var offset = rotate(rotate(sub(camera.position, target), up, -dx), right, -dy);
+    camera.up = rotate(up, right, -dy);
+    camera.position = add(target, offset);
+    camera.front = normalize(scale(offset, -1));
+});
+canvas.addEventListener('wheel', function(e) {
+    if (!camera) { return; }
+    e.preventDefault();
+    var offset = scale(sub(camera.position, target), e.deltaY > 0 ? 1.1 : 1/1.1);
+    camera.position = add(target, offset);
+    send();
+});
+document.getElementById('restart').addEventListener('click', function() {
+    fetch('/restart', { method: 'POST' });
+});
+
+new EventSource('/events').onmessage = function(e) {
+    var update = JSON.parse(e.data);
+    var s = update.stats;
+    stats.textContent = 'pass ' + s.pass + ', ' + s.spp + '/' + s.target_spp + ' spp, tile ' + s.tiles_done + '/' + s.tiles_total +
+        ', ' + s.elapsed_seconds + 's, ' + Math.round(s.samples_per_second) + ' samples/s' + (s.done ? ', done' : '');
+    var image = new Image();
+    image.onload = function() {
+        canvas.width = image.width;
+        canvas.height = image.height;
+        context.drawImage(image, 0, 0);
+    };
+    image.src = '/image?v=' + update.version;
+};
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use testing::{SceneBuilder, grey, request};
+    use super::*;
+
+    fn shared() -> Shared {
+        let view = View {
+            position: Pnt3::new(0.0, -10.0, 0.0),
+            front: Vec3::new(0.0, 1.0, 0.0),
+            up: Vec3::new(0.0, 0.0, 1.0),
+            angle: 45.0
+        };
+        let state = State { view: view, generation: 0, version: 0, image: Vec::new(), stats: "{}".to_string() };
+        Arc::new((Mutex::new(state), Condvar::new()))
+    }
+
+    // Answers requests on the given state without rendering
+    fn start(shared: &Shared) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let shared = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let shared = shared.clone();
+                thread::spawn(move || handle(stream.unwrap(), &shared));
+            }
+        });
+        address
+    }
+
+    // Next event of a converged image
+    fn finished<I: Iterator<Item=String>>(events: &mut I) -> String {
+        events.find(|e| e.contains("\"done\": true")).unwrap()
+    }
+
+    fn version(event: &str) -> u64 {
+        let start = event.find("\"version\": ").unwrap() + 11;
+        let end = start + event[start..].find(',').unwrap();
+        event[start..end].parse().unwrap()
+    }
+
+    #[test]
+    fn vectors() {
+        let parse = |s: &str| vector(&Some(&s.to_string()));
+        assert_eq!(parse("1, 2,-3.5"), Some(Vec3::new(1.0, 2.0, -3.5)));
+        assert_eq!(parse("0,0,0"), Some(Vec3::new(0.0, 0.0, 0.0)));
+        assert_eq!(parse("1,2"), None);
+        assert_eq!(parse("1,2,x"), None);
+        assert_eq!(parse("1,2,3,4"), None);
+        assert_eq!(parse("inf,0,0"), None);
+        assert_eq!(vector(&None), None);
+    }
+
+    #[test]
+    fn camera_changes_start_a_new_generation() {
+        let shared = shared();
+        let address = start(&shared);
+        let (status, camera) = request(&address, "GET", "/camera", "");
+        assert_eq!(status, 200);
+        assert_eq!(String::from_utf8(camera).unwrap(), "{\"position\": [0, -10, 0], \"front\": [0, 1, 0], \"up\": [0, 0, 1]}");
+
+        assert_eq!(request(&address, "POST", "/camera?position=1,2,3&front=0,1,0&up=0,0,2", "").0, 200);
+        {
+            let state = shared.0.lock().unwrap();
+            assert_eq!(state.generation, 1);
+            assert_eq!(state.view.position, Pnt3::new(1.0, 2.0, 3.0));
+            assert_eq!(state.view.up, Vec3::new(0.0, 0.0, 2.0));
+        }
+        for query in &["position=1,2&front=0,1,0&up=0,0,1", "position=1,2,3&front=0,1,0", "position=1,2,3&front=0,0,0&up=0,0,1",
+                       "position=1,2,3&front=0,1,0&up=0,0,0", "position=1,2,3&front=0,1,0&up=0,-2,0", "position=1,2,3&front=0,nan,0&up=0,0,1"] {
+            assert_eq!(request(&address, "POST", &format!("/camera?{}", query), "").0, 400, "{}", query);
+        }
+        assert_eq!(shared.0.lock().unwrap().generation, 1);
+
+        assert_eq!(request(&address, "POST", "/restart", "").0, 200);
+        assert_eq!(shared.0.lock().unwrap().generation, 2);
+        assert_eq!(shared.0.lock().unwrap().view.position, Pnt3::new(1.0, 2.0, 3.0));
+        assert!(String::from_utf8(request(&address, "GET", "/", "").1).unwrap().contains("<canvas"));
+        assert_eq!(request(&address, "GET", "/scene", "").0, 404);
+    }
+
+    #[test]
+    fn refine_stops_on_camera_change() {
+        let scene = SceneBuilder::lit_sphere(grey(0.5)).build();
+        let renderer = Renderer::new(&scene);
+        let mut window = Window::new(16, 12);
+        window.samples = 2;
+        let shared = shared();
+        shared.0.lock().unwrap().generation = 1;
+        assert!(!refine(&window, &scene, &renderer, &shared, 0));
+        assert_eq!(shared.0.lock().unwrap().version, 0);
+
+        assert!(refine(&window, &scene, &renderer, &shared, 1));
+        let state = shared.0.lock().unwrap();
+        assert!(state.version > 0);
+        assert!(state.stats.contains("\"spp\": 2, \"target_spp\": 2") && state.stats.contains("\"done\": true"), "{}", state.stats);
+        assert_eq!(&state.image[..8], b"\x89PNG\r\n\x1a\n");
+    }
+
+    #[test]
+    fn images_are_announced() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut window = Window::new(16, 12);
+            window.samples = 2;
+            view(listener, window, &SceneBuilder::lit_sphere(grey(0.5)).build(), |_| {});
+        });
+        let mut stream = TcpStream::connect(&address).unwrap();
+        stream.write_all(b"GET /events HTTP/1.1\r\n\r\n").unwrap();
+        let mut events = BufReader::new(stream).lines()
+            .map(|line| line.unwrap())
+            .filter(|line| line.starts_with("data: "));
+        let first = finished(&mut events);
+        let (status, png) = request(&address, "GET", "/image", "");
+        assert_eq!(status, 200);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // Moving the camera renders the image again
+        assert_eq!(request(&address, "POST", "/camera?position=0,-8,0&front=0,1,0&up=0,0,1", "").0, 200);
+        assert!(version(&finished(&mut events)) > version(&first));
+    }
+}
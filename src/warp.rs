@@ -1,9 +1,9 @@
 use types::*;
-use std::f64::*;
 use std::f64::consts::*;
 
 // Source: Physically based Rendering, Chapter Monte Carlo Integration
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WarpFunction {
     UniformHemisphere,
     UniformSphere,
@@ -12,12 +12,17 @@ pub enum WarpFunction {
     CosineHemisphere,
     UniformTriangle,
 
-    Uniform_Square,
-    Uniform_Cylinder,
-    Uniform_Sphere_Cap,
-    Phong_Hemisphere,
+    Uniform_Square,             // [-1, 1]^2
+    Uniform_Cylinder,           // Unit radius, z in [-1, 1]
+    Uniform_Sphere_Cap(Float),  // Directions with cos theta above the given value
+    Phong_Hemisphere(Float),    // Normalized modified Phong lobe with the given exponent, including the cosine
+    PowerCosine(Float),         // cos^n theta
+    GGX(Float),                 // Microfacet normals, distributed as D(h) cos theta for roughness alpha
+    Beckmann(Float)
 }
 
+// Density of a sampled direction with the given cos theta, for warps to the sphere whose
+// density only depends on the angle to the z axis, and the constant density of the others
 pub fn get_pdf(costheta: Float, warp_function: WarpFunction) -> Float {
     match warp_function {
          WarpFunction::UniformHemisphere => 1.0/(2.0*PI),
@@ -27,7 +32,39 @@ pub fn get_pdf(costheta: Float, warp_function: WarpFunction) -> Float {
             costheta*1.0/PI
         },
         WarpFunction::UniformTriangle => 2.0,
-        _ => 1.0
+        WarpFunction::Uniform_Square => 0.25,
+        WarpFunction::Uniform_Cylinder => 1.0/(4.0*PI),
+        WarpFunction::Uniform_Sphere_Cap(cos_max) => {
+            if costheta < cos_max {
+                return 0.0;
+            }
+            1.0/(2.0*PI*(1.0 - cos_max))
+        },
+        WarpFunction::Phong_Hemisphere(exponent) => get_pdf(costheta, WarpFunction::PowerCosine(exponent + 1.0)),
+        WarpFunction::PowerCosine(exponent) => {
+            if costheta <= 0.0 {
+                return 0.0;
+            }
+            (exponent + 1.0)/(2.0*PI)*costheta.powf(exponent)
+        },
+        WarpFunction::GGX(alpha) => {
+            if costheta <= 0.0 {
+                return 0.0;
+            }
+            let cos2 = costheta*costheta;
+            let tan2 = (1.0 - cos2)/cos2;
+            let a2 = alpha*alpha;
+            a2/(PI*cos2*costheta*(a2 + tan2)*(a2 + tan2))
+        },
+        WarpFunction::Beckmann(alpha) => {
+            if costheta <= 0.0 {
+                return 0.0;
+            }
+            let cos2 = costheta*costheta;
+            let tan2 = (1.0 - cos2)/cos2;
+            let a2 = alpha*alpha;
+            (-tan2/a2).exp()/(PI*a2*cos2*costheta)
+        }
     }
 }
 
@@ -78,15 +115,43 @@ pub fn warp_point(s: Float, t: Float, warp_function: WarpFunction) -> Vec3 {
                 t * su1,
                 0.0)
         }
-        _ => {
+        WarpFunction::Uniform_Square => {
             v.x = 2.0*s - 1.0;
             v.y = 2.0*t - 1.0;
             v.z = 0.0;
+        },
+        WarpFunction::Uniform_Cylinder => {
+            let phi = 2.0 * PI * t;
+            v = Vec3::new(Float::cos(phi), Float::sin(phi), 2.0*s - 1.0);
+        },
+        WarpFunction::Uniform_Sphere_Cap(cos_max) => {
+            let z = 1.0 - s*(1.0 - cos_max);
+            v = spherical_direction(z, 2.0 * PI * t);
+        },
+        WarpFunction::Phong_Hemisphere(exponent) => {
+            v = warp_point(s, t, WarpFunction::PowerCosine(exponent + 1.0));
+        },
+        WarpFunction::PowerCosine(exponent) => {
+            let z = s.powf(1.0/(exponent + 1.0));
+            v = spherical_direction(z, 2.0 * PI * t);
+        },
+        WarpFunction::GGX(alpha) => {
+            let tan2 = alpha*alpha*s/(1.0 - s);
+            v = spherical_direction(1.0/(1.0 + tan2).sqrt(), 2.0 * PI * t);
+        },
+        WarpFunction::Beckmann(alpha) => {
+            let tan2 = -alpha*alpha*(1.0 - s).ln();
+            v = spherical_direction(1.0/(1.0 + tan2).sqrt(), 2.0 * PI * t);
         }
     }
     v
 }
 
+fn spherical_direction(costheta: Float, phi: Float) -> Vec3 {
+    let sintheta = Float::max(0.0, 1.0 - costheta*costheta).sqrt();
+    Vec3::new(sintheta * Float::cos(phi), sintheta * Float::sin(phi), costheta)
+}
+
 fn compute_concentric_map_rho_theta(u1: Float, u2: Float, rho: &mut Float, theta: &mut Float) {
     let sx = 2.0 * u1 - 1.0;
     let sy = 2.0 * u2 - 1.0;
@@ -125,4 +190,521 @@ fn compute_concentric_map_rho_theta(u1: Float, u2: Float, rho: &mut Float, theta
     }
     *rho = r;
     *theta = t * (PI/4.0);
-}
\ No newline at end of file
+}
+
+// Densities of the warped points and the samples that map to them, for testing the warps
+#[cfg(test)]
+pub mod inverse {
+    use types::*;
+    use std::f64::consts::*;
+    use super::{WarpFunction, get_pdf};
+
+    // Density of the warped point v, zero outside of the domain of the warp
+    pub fn pdf(v: &Vec3, warp_function: WarpFunction) -> Float {
+        let on_plane = v.z == 0.0;
+        let inside = match warp_function {
+            WarpFunction::UniformSphere | WarpFunction::Uniform_Sphere_Cap(_) => true,
+            WarpFunction::UniformHemisphere | WarpFunction::CosineHemisphere => v.z >= 0.0,
+            WarpFunction::Phong_Hemisphere(_) | WarpFunction::PowerCosine(_)
+                | WarpFunction::GGX(_) | WarpFunction::Beckmann(_) => v.z >= 0.0,
+            WarpFunction::UniformDisk | WarpFunction::ConcentricDisk => on_plane && v.x*v.x + v.y*v.y <= 1.0,
+            WarpFunction::UniformTriangle => on_plane && v.x >= 0.0 && v.y >= 0.0 && v.x + v.y <= 1.0,
+            WarpFunction::Uniform_Square => on_plane && v.x.abs() <= 1.0 && v.y.abs() <= 1.0,
+            WarpFunction::Uniform_Cylinder => v.z.abs() <= 1.0
+        };
+        if !inside {
+            return 0.0;
+        }
+        get_pdf(v.z, warp_function)
+    }
+
+    // Sample that warp_point maps to v, for points inside the domain of the warp
+    pub fn inverse_warp(v: &Vec3, warp_function: WarpFunction) -> (Float, Float) {
+        let t = azimuth(v)/(2.0*PI);
+        match warp_function {
+            WarpFunction::UniformSphere => ((1.0 - v.z)*0.5, t),
+            WarpFunction::UniformHemisphere => (v.z, t),
+            WarpFunction::UniformDisk => (v.x*v.x + v.y*v.y, t),
+            WarpFunction::ConcentricDisk | WarpFunction::CosineHemisphere => inverse_concentric_map(v.x, v.y),
+            WarpFunction::UniformTriangle => {
+                let su1 = 1.0 - v.x;
+                (su1*su1, if su1 > 0.0 { v.y/su1 } else { 0.0 })
+            },
+            WarpFunction::Uniform_Square => ((v.x + 1.0)*0.5, (v.y + 1.0)*0.5),
+            WarpFunction::Uniform_Cylinder => ((v.z + 1.0)*0.5, t),
+            WarpFunction::Uniform_Sphere_Cap(cos_max) => ((1.0 - v.z)/(1.0 - cos_max), t),
+            WarpFunction::Phong_Hemisphere(exponent) => inverse_warp(v, WarpFunction::PowerCosine(exponent + 1.0)),
+            WarpFunction::PowerCosine(exponent) => (v.z.max(0.0).powf(exponent + 1.0), t),
+            WarpFunction::GGX(alpha) => {
+                let tan2 = tan2_theta(v);
+                (tan2/(alpha*alpha + tan2), t)
+            },
+            WarpFunction::Beckmann(alpha) => (1.0 - (-tan2_theta(v)/(alpha*alpha)).exp(), t)
+        }
+    }
+
+    // Angle around the z axis in [0, 2 pi)
+    pub fn azimuth(v: &Vec3) -> Float {
+        let phi = v.y.atan2(v.x);
+        if phi < 0.0 { phi + 2.0*PI } else { phi }
+    }
+
+    pub fn tan2_theta(v: &Vec3) -> Float {
+        let cos2 = v.z*v.z;
+        Float::max(0.0, 1.0 - cos2)/cos2
+    }
+
+    // Inverse of the concentric mapping, each quarter of the disk comes from one triangle of
+    // the square between its center and an edge
+    pub fn inverse_concentric_map(x: Float, y: Float) -> (Float, Float) {
+        let r = (x*x + y*y).sqrt();
+        if r == 0.0 {
+            return (0.5, 0.5);
+        }
+        // Angle in units of pi/4 within (-1, 7]
+        let mut k = y.atan2(x)/(PI/4.0);
+        if k <= -1.0 {
+            k += 8.0;
+        }
+        let (sx, sy) = if k <= 1.0 {
+            (r, r*k)
+        } else if k <= 3.0 {
+            (r*(2.0 - k), r)
+        } else if k <= 5.0 {
+            (-r, r*(4.0 - k))
+        } else {
+            (r*(k - 6.0), -r)
+        };
+        ((sx + 1.0)*0.5, (sy + 1.0)*0.5)
+    }
+}
+
+// Uniform sampling of the solid angle of triangles and rectangles, and its inverse. Nothing
+// in the renderer samples polygons by solid angle, so they are only built for the tests.
+#[cfg(test)]
+pub mod spherical {
+    use nalgebra::{Norm, Cross, Dot};
+    use types::*;
+    use std::f64::*;
+    use std::f64::consts::*;
+
+    // Angle between two unit vectors, accurate for nearly parallel vectors as well
+    pub fn angle_between(a: &Vec3, b: &Vec3) -> Float {
+        if a.dot(b) < 0.0 {
+            PI - 2.0*((*a + *b).norm()*0.5).min(1.0).asin()
+        } else {
+            2.0*((*b - *a).norm()*0.5).min(1.0).asin()
+        }
+    }
+
+    // Component of v perpendicular to the unit vector w
+    pub fn gram_schmidt(v: &Vec3, w: &Vec3) -> Vec3 {
+        *v - *w*v.dot(w)
+    }
+
+    // Uniform sampling of the solid angle of the triangle v as seen from p. Returns the unit
+    // direction and its density, None if the triangle is degenerate as seen from p.
+    // Source: Arvo, Stratified Sampling of Spherical Triangles, and Physically based Rendering,
+    // Fourth Edition, Chapter Sampling Algorithms, Section Sampling Spherical Triangles
+    pub fn sample_spherical_triangle(v: &[Pnt3; 3], p: &Pnt3, s: Float, t: Float) -> Option<(Vec3, Float)> {
+        let (a, b, c, alpha, beta, gamma) = match spherical_triangle(v, p) {
+            Some(triangle) => triangle,
+            None => return None
+        };
+        let area = alpha + beta + gamma - PI;
+        if area <= 0.0 {
+            return None;
+        }
+
+        // Uniformly sample the area of the subtriangle a b c' and find c' on the arc from a to c
+        let area_s = s*area + PI;
+        let (cos_alpha, sin_alpha) = (alpha.cos(), alpha.sin());
+        let sin_phi = area_s.sin()*cos_alpha - area_s.cos()*sin_alpha;
+        let cos_phi = area_s.cos()*cos_alpha + area_s.sin()*sin_alpha;
+        let k1 = cos_phi + cos_alpha;
+        let k2 = sin_phi - sin_alpha*a.dot(&b);
+        let cos_b = (k2 + (k2*cos_phi - k1*sin_phi)*cos_alpha)/((k2*sin_phi + k1*cos_phi)*sin_alpha);
+        if !cos_b.is_finite() {
+            return None;
+        }
+        let cos_b = cos_b.max(-1.0).min(1.0);
+        let sin_b = (1.0 - cos_b*cos_b).max(0.0).sqrt();
+        let cp = a*cos_b + gram_schmidt(&c, &a).normalize()*sin_b;
+
+        // Sample the arc from b to c'
+        let cos_theta = 1.0 - t*(1.0 - cp.dot(&b));
+        let sin_theta = (1.0 - cos_theta*cos_theta).max(0.0).sqrt();
+        let w = b*cos_theta + gram_schmidt(&cp, &b).normalize()*sin_theta;
+        Some((w.normalize(), 1.0/area))
+    }
+
+    // Sample that sample_spherical_triangle maps to the direction w
+    pub fn invert_spherical_triangle(v: &[Pnt3; 3], p: &Pnt3, w: &Vec3) -> (Float, Float) {
+        let (a, b, c, alpha, beta, gamma) = match spherical_triangle(v, p) {
+            Some(triangle) => triangle,
+            None => return (0.5, 0.5)
+        };
+
+        // Point c' where the arc from b through w meets the arc from a to c
+        let mut cp = b.cross(w).cross(&c.cross(&a)).normalize();
+        if cp.dot(&(a + c)) < 0.0 {
+            cp = -cp;
+        }
+
+        let s = if a.dot(&cp) > 0.99999847691 {
+            0.0
+        } else {
+            let n_ab = a.cross(&b).normalize();
+            let n_cpb = cp.cross(&b);
+            let n_acp = a.cross(&cp);
+            if n_cpb.norm_squared() == 0.0 || n_acp.norm_squared() == 0.0 {
+                return (0.5, 0.5);
+            }
+            let n_cpb = n_cpb.normalize();
+            let n_acp = n_acp.normalize();
+            let area_s = alpha + angle_between(&n_ab, &n_cpb) + angle_between(&n_acp, &-n_cpb) - PI;
+            area_s/(alpha + beta + gamma - PI)
+        };
+        let t = (1.0 - w.dot(&b))/(1.0 - cp.dot(&b));
+        (s.max(0.0).min(1.0), t.max(0.0).min(1.0))
+    }
+
+    // Directions to the vertices and the angles at them
+    fn spherical_triangle(v: &[Pnt3; 3], p: &Pnt3) -> Option<(Vec3, Vec3, Vec3, Float, Float, Float)> {
+        let a = (v[0] - *p).normalize();
+        let b = (v[1] - *p).normalize();
+        let c = (v[2] - *p).normalize();
+        let n_ab = a.cross(&b);
+        let n_bc = b.cross(&c);
+        let n_ca = c.cross(&a);
+        if n_ab.norm_squared() == 0.0 || n_bc.norm_squared() == 0.0 || n_ca.norm_squared() == 0.0 {
+            return None;
+        }
+        let n_ab = n_ab.normalize();
+        let n_bc = n_bc.normalize();
+        let n_ca = n_ca.normalize();
+        let alpha = angle_between(&n_ab, &-n_ca);
+        let beta = angle_between(&n_bc, &-n_ab);
+        let gamma = angle_between(&n_ca, &-n_bc);
+        Some((a, b, c, alpha, beta, gamma))
+    }
+
+    // Parallelogram corner + x*ex + y*ey with perpendicular edges, seen from p in a frame where
+    // the rectangle lies in the plane z = z0 < 0 and spans [x0, x1] x [y0, y1]
+    struct SphericalRectangle {
+        frame: [Vec3; 3],
+        x0: Float,
+        x1: Float,
+        y0: Float,
+        y1: Float,
+        z0: Float,
+        b0: Float,
+        b1: Float,
+        k: Float,           // Angle offset of the first sample dimension
+        solid_angle: Float
+    }
+
+    impl SphericalRectangle {
+        fn new(corner: &Pnt3, ex: &Vec3, ey: &Vec3, p: &Pnt3) -> SphericalRectangle {
+            let exl = ex.norm();
+            let eyl = ey.norm();
+            let x = *ex/exl;
+            let y = *ey/eyl;
+            let mut z = x.cross(&y);
+            let d = *corner - *p;
+            let mut z0 = d.dot(&z);
+            if z0 > 0.0 {
+                z = -z;
+                z0 = -z0;
+            }
+            let x0 = d.dot(&x);
+            let y0 = d.dot(&y);
+            let x1 = x0 + exl;
+            let y1 = y0 + eyl;
+
+            // Normals of the planes through p and the edges, and the angles between them
+            let v00 = Vec3::new(x0, y0, z0);
+            let v01 = Vec3::new(x0, y1, z0);
+            let v10 = Vec3::new(x1, y0, z0);
+            let v11 = Vec3::new(x1, y1, z0);
+            let n0 = v00.cross(&v10).normalize();
+            let n1 = v10.cross(&v11).normalize();
+            let n2 = v11.cross(&v01).normalize();
+            let n3 = v01.cross(&v00).normalize();
+            let g0 = angle_between(&-n0, &n1);
+            let g1 = angle_between(&-n1, &n2);
+            let g2 = angle_between(&-n2, &n3);
+            let g3 = angle_between(&-n3, &n0);
+            SphericalRectangle {
+                frame: [x, y, z],
+                x0: x0,
+                x1: x1,
+                y0: y0,
+                y1: y1,
+                z0: z0,
+                b0: n0.z,
+                b1: n2.z,
+                k: -(g2 + g3),
+                solid_angle: g0 + g1 + g2 + g3 - 2.0*PI
+            }
+        }
+
+        // x coordinate reached by the first sample dimension
+        fn x(&self, s: Float) -> Float {
+            let au = s*self.solid_angle + self.k;
+            let fu = (au.cos()*self.b0 - self.b1)/au.sin();
+            let cu = (1.0/(fu*fu + self.b0*self.b0).sqrt()).min(1.0 - EPSILON);
+            let cu = if fu < 0.0 { -cu } else { cu };
+            let xu = -(cu*self.z0)/(1.0 - cu*cu).max(0.0).sqrt();
+            xu.max(self.x0).min(self.x1)
+        }
+
+        fn h(&self, xu: Float) -> (Float, Float, Float) {
+            let dd = (xu*xu + self.z0*self.z0).sqrt();
+            let h0 = self.y0/(dd*dd + self.y0*self.y0).sqrt();
+            let h1 = self.y1/(dd*dd + self.y1*self.y1).sqrt();
+            (dd, h0, h1)
+        }
+
+        fn to_world(&self, v: &Vec3) -> Vec3 {
+            self.frame[0]*v.x + self.frame[1]*v.y + self.frame[2]*v.z
+        }
+
+        fn to_local(&self, v: &Vec3) -> Vec3 {
+            Vec3::new(v.dot(&self.frame[0]), v.dot(&self.frame[1]), v.dot(&self.frame[2]))
+        }
+    }
+
+    // Uniform sampling of the solid angle of the rectangle corner + [0, 1] ex + [0, 1] ey with
+    // perpendicular edges as seen from p. Returns the unit direction and its density.
+    // Source: Urena et al., An Area-Preserving Parametrization for Spherical Rectangles
+    pub fn sample_spherical_rectangle(corner: &Pnt3, ex: &Vec3, ey: &Vec3, p: &Pnt3, s: Float, t: Float) -> Option<(Vec3, Float)> {
+        let rect = SphericalRectangle::new(corner, ex, ey, p);
+        if !(rect.solid_angle > 0.0) {
+            return None;
+        }
+        let xu = rect.x(s);
+        let (dd, h0, h1) = rect.h(xu);
+        let hv = h0 + t*(h1 - h0);
+        let hv2 = hv*hv;
+        let yv = if hv2 < 1.0 - 1e-6 { hv*dd/(1.0 - hv2).sqrt() } else { rect.y1 };
+        let w = rect.to_world(&Vec3::new(xu, yv, rect.z0));
+        Some((w.normalize(), 1.0/rect.solid_angle))
+    }
+
+    // Sample that sample_spherical_rectangle maps to the direction w
+    pub fn invert_spherical_rectangle(corner: &Pnt3, ex: &Vec3, ey: &Vec3, p: &Pnt3, w: &Vec3) -> (Float, Float) {
+        let rect = SphericalRectangle::new(corner, ex, ey, p);
+        let local = rect.to_local(w);
+        if !(rect.solid_angle > 0.0) || local.z >= 0.0 {
+            return (0.5, 0.5);
+        }
+        // Point on the rectangle in the direction w
+        let xu = local.x*rect.z0/local.z;
+        let yv = local.y*rect.z0/local.z;
+
+        // First dimension, the angle au solves b0 cos(au) - fu sin(au) = b1 with both fu and cu
+        // taking the sign of xu
+        let cu = xu/(xu*xu + rect.z0*rect.z0).sqrt();
+        let fu2 = (1.0/(cu*cu) - rect.b0*rect.b0).max(0.0);
+        let fu = if xu < 0.0 { -fu2.sqrt() } else { fu2.sqrt() };
+        let r = (rect.b0*rect.b0 + fu*fu).sqrt();
+        let phi = fu.atan2(rect.b0);
+        let delta = (rect.b1/r).max(-1.0).min(1.0).acos();
+        let mut s = 0.0;
+        let mut best = INFINITY;
+        for au in &[delta - phi, -delta - phi] {
+            // au is taken from [k, k + 2 pi), which contains the whole range of the sampler
+            let mut au = *au;
+            while au >= rect.k + 2.0*PI {
+                au -= 2.0*PI;
+            }
+            while au < rect.k {
+                au += 2.0*PI;
+            }
+            let candidate = ((au - rect.k)/rect.solid_angle).max(0.0).min(1.0);
+            let error = (rect.x(candidate) - xu).abs();
+            if error < best {
+                best = error;
+                s = candidate;
+            }
+        }
+
+        // Second dimension
+        let (dd, h0, h1) = rect.h(xu.max(rect.x0).min(rect.x1));
+        let hv = yv/(dd*dd + yv*yv).sqrt();
+        let t = if h1 != h0 { (hv - h0)/(h1 - h0) } else { 0.5 };
+        (s, t.max(0.0).min(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Norm, Cross, Dot};
+    use types::*;
+    use chi2;
+    use chi2::Domain;
+    use super::*;
+    use super::inverse::*;
+    use super::spherical::*;
+
+    fn check(warp_function: WarpFunction, domain: Domain) {
+        let name = format!("{:?}", warp_function).to_lowercase().replace("(", "_").replace(")", "");
+        let result = chi2::test(&name, domain,
+            |s, t| Some(warp_point(s, t, warp_function)),
+            |v| pdf(v, warp_function));
+        if let Err(e) = result {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn chi2_sphere_warps() {
+        check(WarpFunction::UniformSphere, Domain::Sphere);
+        check(WarpFunction::UniformHemisphere, Domain::Sphere);
+        check(WarpFunction::CosineHemisphere, Domain::Sphere);
+        check(WarpFunction::Uniform_Sphere_Cap(0.6), Domain::Sphere);
+        check(WarpFunction::Uniform_Sphere_Cap(-0.3), Domain::Sphere);
+    }
+
+    #[test]
+    fn chi2_lobes() {
+        check(WarpFunction::Phong_Hemisphere(10.0), Domain::Sphere);
+        check(WarpFunction::PowerCosine(0.0), Domain::Sphere);
+        check(WarpFunction::PowerCosine(4.0), Domain::Sphere);
+        check(WarpFunction::GGX(0.3), Domain::Sphere);
+        check(WarpFunction::GGX(0.8), Domain::Sphere);
+        check(WarpFunction::Beckmann(0.3), Domain::Sphere);
+        check(WarpFunction::Beckmann(0.8), Domain::Sphere);
+    }
+
+    #[test]
+    fn chi2_planar_warps() {
+        check(WarpFunction::Uniform_Square, Domain::Plane);
+        check(WarpFunction::UniformDisk, Domain::Plane);
+        check(WarpFunction::ConcentricDisk, Domain::Plane);
+        check(WarpFunction::UniformTriangle, Domain::Plane);
+        check(WarpFunction::Uniform_Cylinder, Domain::Cylinder);
+    }
+
+    #[test]
+    fn chi2_spherical_triangle() {
+        let v = [Pnt3::new(1.0, -0.5, 2.0), Pnt3::new(-1.0, 0.3, 1.5), Pnt3::new(0.2, 1.0, 0.4)];
+        let p = Pnt3::new(0.1, 0.0, -0.3);
+        let area = 1.0/sample_spherical_triangle(&v, &p, 0.5, 0.5).unwrap().1;
+        let result = chi2::test("spherical_triangle", Domain::Sphere,
+            |s, t| sample_spherical_triangle(&v, &p, s, t).map(|(w, _)| w),
+            |w| {
+                // Moeller-Trumbore test of the ray from p towards w
+                let e1 = v[1] - v[0];
+                let e2 = v[2] - v[0];
+                let q = w.cross(&e2);
+                let inv = 1.0/e1.dot(&q);
+                let o = p - v[0];
+                let b1 = o.dot(&q)*inv;
+                let r = o.cross(&e1);
+                let b2 = w.dot(&r)*inv;
+                let t = e2.dot(&r)*inv;
+                if b1 >= 0.0 && b2 >= 0.0 && b1 + b2 <= 1.0 && t > 0.0 { 1.0/area } else { 0.0 }
+            });
+        if let Err(e) = result {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn chi2_spherical_rectangle() {
+        let corner = Pnt3::new(-1.0, -0.5, 2.0);
+        let ex = Vec3::new(2.0, 0.0, 0.5);
+        let ey = Vec3::new(0.0, 1.5, 0.0);
+        let p = Pnt3::new(0.1, 0.0, -0.3);
+        let solid_angle = 1.0/sample_spherical_rectangle(&corner, &ex, &ey, &p, 0.5, 0.5).unwrap().1;
+        let n = ex.cross(&ey);
+        let result = chi2::test("spherical_rectangle", Domain::Sphere,
+            |s, t| sample_spherical_rectangle(&corner, &ex, &ey, &p, s, t).map(|(w, _)| w),
+            |w| {
+                let t = (corner - p).dot(&n)/w.dot(&n);
+                let q = p + *w*t - corner;
+                let x = q.dot(&ex)/ex.norm_squared();
+                let y = q.dot(&ey)/ey.norm_squared();
+                if t > 0.0 && x >= 0.0 && x <= 1.0 && y >= 0.0 && y <= 1.0 { 1.0/solid_angle } else { 0.0 }
+            });
+        if let Err(e) = result {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn inverse_warps() {
+        let warps = [
+            WarpFunction::UniformSphere, WarpFunction::UniformHemisphere, WarpFunction::CosineHemisphere,
+            WarpFunction::UniformDisk, WarpFunction::ConcentricDisk, WarpFunction::UniformTriangle,
+            WarpFunction::Uniform_Square, WarpFunction::Uniform_Cylinder, WarpFunction::Uniform_Sphere_Cap(0.3),
+            WarpFunction::Phong_Hemisphere(5.0), WarpFunction::PowerCosine(3.0), WarpFunction::GGX(0.3),
+            WarpFunction::Beckmann(0.4)
+        ];
+        let v = [Pnt3::new(1.0, -0.5, 2.0), Pnt3::new(-1.0, 0.3, 1.5), Pnt3::new(0.2, 1.0, 0.4)];
+        let corner = Pnt3::new(-1.0, -0.5, 2.0);
+        let ex = Vec3::new(2.0, 0.0, 0.5);
+        let ey = Vec3::new(0.0, 1.5, 0.0);
+        let p = Pnt3::new(0.1, 0.0, -0.3);
+        for i in 1..32 {
+            for j in 1..32 {
+                let (s, t) = (i as Float/32.0, j as Float/32.0);
+                for warp_function in warps.iter() {
+                    let (s2, t2) = inverse_warp(&warp_point(s, t, *warp_function), *warp_function);
+                    assert!((s - s2).abs() < 1e-9 && (t - t2).abs() < 1e-9, "{:?} {} {}", warp_function, s, t);
+                }
+                let w = sample_spherical_triangle(&v, &p, s, t).unwrap().0;
+                let (s2, t2) = invert_spherical_triangle(&v, &p, &w);
+                assert!((s - s2).abs() < 1e-9 && (t - t2).abs() < 1e-9);
+                let w = sample_spherical_rectangle(&corner, &ex, &ey, &p, s, t).unwrap().0;
+                let (s2, t2) = invert_spherical_rectangle(&corner, &ex, &ey, &p, &w);
+                assert!((s - s2).abs() < 1e-9 && (t - t2).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn angles_of_nearly_parallel_vectors() {
+        let a = Vec3::new(1.0, 0.0, 0.0);
+        let b = Vec3::new(1.0, 1e-9, 0.0).normalize();
+        assert!((angle_between(&a, &b) - 1e-9).abs() < 1e-15);
+        assert!((angle_between(&a, &-b) - (PI - 1e-9)).abs() < 1e-12);
+        assert!((angle_between(&a, &Vec3::new(0.0, 0.0, 1.0)) - PI/2.0).abs() < 1e-12);
+        let v = gram_schmidt(&Vec3::new(0.3, 2.0, -1.0), &b);
+        assert!(v.dot(&b).abs() < 1e-12);
+    }
+
+    #[test]
+    fn spherical_polygon_solid_angles() {
+        // One octant of the sphere around the origin
+        let v = [Pnt3::new(1.0, 0.0, 0.0), Pnt3::new(0.0, 1.0, 0.0), Pnt3::new(0.0, 0.0, 1.0)];
+        let (_, pdf) = sample_spherical_triangle(&v, &Pnt3::new(0.0, 0.0, 0.0), 0.3, 0.7).unwrap();
+        assert!((1.0/pdf - PI/2.0).abs() < 1e-9);
+
+        // Square with half size 1 at distance 1, a sixth of the sphere
+        let corner = Pnt3::new(-1.0, -1.0, -1.0);
+        let ex = Vec3::new(2.0, 0.0, 0.0);
+        let ey = Vec3::new(0.0, 2.0, 0.0);
+        let (w, pdf) = sample_spherical_rectangle(&corner, &ex, &ey, &Pnt3::new(0.0, 0.0, 0.0), 0.5, 0.5).unwrap();
+        assert!((1.0/pdf - 4.0*PI/6.0).abs() < 1e-9);
+        assert!((w - Vec3::new(0.0, 0.0, -1.0)).norm() < 1e-9);
+
+        // Degenerate triangle seen edge on
+        let v = [Pnt3::new(1.0, 0.0, 0.0), Pnt3::new(2.0, 0.0, 0.0), Pnt3::new(3.0, 0.0, 0.0)];
+        assert!(sample_spherical_triangle(&v, &Pnt3::new(0.0, 0.0, 0.0), 0.5, 0.5).is_none());
+    }
+
+    #[test]
+    fn azimuth_and_concentric_inverse() {
+        assert_eq!(azimuth(&Vec3::new(1.0, 0.0, 0.0)), 0.0);
+        assert!((azimuth(&Vec3::new(0.0, -1.0, 0.0)) - 1.5*PI).abs() < 1e-12);
+        assert!((tan2_theta(&Vec3::new(1.0, 0.0, 1.0).normalize()) - 1.0).abs() < 1e-12);
+        assert_eq!(inverse_concentric_map(0.0, 0.0), (0.5, 0.5));
+        let (s, t) = inverse_concentric_map(1.0, 0.0);
+        assert!((s - 1.0).abs() < 1e-12 && (t - 0.5).abs() < 1e-12);
+        let (s, t) = inverse_concentric_map(0.0, -1.0);
+        assert!((s - 0.5).abs() < 1e-12 && t.abs() < 1e-12);
+    }
+}
@@ -1,11 +1,20 @@
 use camera::Camera;
 use scene::Scene;
 use renderer::Renderer;
-use image::{ImageBuffer, Rgb};
 use std::vec::Vec;
 use types::*;
+use aov::Aov;
+use film::{Accumulator, Splat, SplatBuffer};
+use sampling;
+use sampling::AdaptiveSampler;
+use stats;
+use stats::{Counter, Timer};
+use checkpoint::Checkpoint;
+use colorspace::to_png;
 
-use std::sync::{Arc, Barrier};
+use std::sync::{Arc, Barrier, Mutex};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 // use threadpool::ThreadPool;
 use scoped_threadpool::Pool;
 
@@ -13,7 +22,46 @@ use scoped_threadpool::Pool;
 pub struct Window {
     pub camera: Camera,
     pub width: u32,
-    pub height: u32
+    pub height: u32,
+    pub samples: u32,   // Samples per pixel, unless sampling adaptively
+    pub adaptive: Option<AdaptiveSampler>,
+    pub seed: u32,
+    pub progressive: Progressive
+}
+
+// Settings of a rendering that is refined in passes
+#[derive(Debug, Clone)]
+pub struct Progressive {
+    pub pass_samples: u32,                  // Samples per pixel added by every pass
+    pub time_budget: Option<Duration>,      // Checked after every pass
+    pub noise_target: Option<Float>,        // Mean relative error over all pixels
+    pub write_interval: Option<Duration>,   // Update after every pass if not set
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_interval: Option<Duration>   // Checkpoint after every pass if not set
+}
+
+impl Progressive {
+    pub fn new() -> Progressive {
+        Progressive {
+            pass_samples: 1,
+            time_budget: None,
+            noise_target: None,
+            write_interval: None,
+            checkpoint: None,
+            checkpoint_interval: None
+        }
+    }
+}
+
+// State of a progressive rendering after a pass
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub pass: u32,
+    pub samples: u64,
+    pub elapsed: Duration,
+    pub error: Float,
+    pub done: bool,
+    pub checkpoint_error: Option<String>    // Set if the checkpoint of this pass could not be written
 }
 
 impl Window {
@@ -21,28 +69,184 @@ impl Window {
         let mut window = Window{
             camera: Camera::new(width, height),
             width: width,
-            height: height
+            height: height,
+            samples: 1,
+            adaptive: None,
+            seed: 0,
+            progressive: Progressive::new()
         };
         window
     }
-    pub fn draw_as_image(&self, scene: &Scene) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
-        let mut imgbuf = ImageBuffer::new(self.width, self.height);        
-        let mut pool = Pool::new(4);
+    // Renders in passes and hands the current image, encoded as PNG in the output space of the
+    // scene, to update after every pass or write interval
+    pub fn draw_as_png<F>(&self, scene: &Scene, mut update: F) -> Vec<u8>
+        where F: FnMut(Vec<u8>, &Progress)
+    {
+        let png = |accumulator: &Accumulator| {
+            to_png(&accumulator.films()[0], scene.working, scene.output).unwrap_or(Vec::new())
+        };
+        let accumulator = self.draw_progressive(scene, &[Aov::Beauty], |accumulator, progress| {
+            update(png(accumulator), progress);
+        });
+        png(&accumulator)
+    }
+    // Adds passes to the accumulator until the sample target, the time budget or the noise
+    // target is reached. The final pass is always handed to update.
+    pub fn draw_progressive<F>(&self, scene: &Scene, aovs: &[Aov], update: F) -> Accumulator
+        where F: FnMut(&Accumulator, &Progress)
+    {
+        let checkpoint = Checkpoint::new(self.width, self.height, aovs, self.seed);
+        self.resume_progressive(scene, checkpoint, update)
+    }
+    // Continues a progressive rendering from a checkpoint
+    pub fn resume_progressive<F>(&self, scene: &Scene, mut state: Checkpoint, mut update: F) -> Accumulator
+        where F: FnMut(&Accumulator, &Progress)
+    {
+        let start = Instant::now();
+        let resumed = state.elapsed;
+        let mut last_update = start;
+        let mut last_checkpoint = start;
         let renderer: Renderer = Renderer::new(scene);
-        let renderer_ref: &Renderer = &renderer;
-        let camera_ref: &Camera = &self.camera;
-        pool.scoped(|scope| {
-            for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
-                scope.execute(move || {
-                    let mut ray = camera_ref.generate_ray(x, y);
-                    let color: Color = renderer_ref.render(&mut ray)*255.0;
-                    // println!("{:?}", color);
-                    *pixel = Rgb([color.x as u8, color.y as u8, color.z as u8]);
-                });
+        let target = self.max_samples();
+        loop {
+            state.pass_samples = state.pass_samples.saturating_add(self.progressive.pass_samples.max(1)).min(target);
+            let taken = self.render_pass(&renderer, &mut state.accumulator, state.pass_samples);
+            state.pass += 1;
+
+            let now = Instant::now();
+            state.elapsed = resumed + now.duration_since(start);
+            let error = state.accumulator.relative_error();
+            let mut done = state.pass_samples >= target || taken == 0;
+            if let Some(budget) = self.progressive.time_budget {
+                done = done || state.elapsed >= budget;
+            }
+            if let Some(noise) = self.progressive.noise_target {
+                done = done || error <= noise;
             }
-        });
 
-        imgbuf
+            let mut checkpoint_error = None;
+            if let Some(ref path) = self.progressive.checkpoint {
+                let write = match self.progressive.checkpoint_interval {
+                    Some(interval) => now.duration_since(last_checkpoint) >= interval,
+                    None => true
+                };
+                if done || write {
+                    if let Err(e) = state.write(path) {
+                        checkpoint_error = Some(format!("Could not write checkpoint {}: {}", path.display(), e));
+                    }
+                    last_checkpoint = now;
+                }
+            }
+            let write = match self.progressive.write_interval {
+                Some(interval) => now.duration_since(last_update) >= interval,
+                None => true
+            };
+            if done || write || checkpoint_error.is_some() {
+                let progress = Progress {
+                    pass: state.pass,
+                    samples: state.accumulator.samples(),
+                    elapsed: state.elapsed,
+                    error: error,
+                    done: done,
+                    checkpoint_error: checkpoint_error
+                };
+                update(&state.accumulator, &progress);
+                last_update = now;
+            }
+            if done {
+                break;
+            }
+        }
+        state.accumulator
+    }
+    // Samples every pixel up to the given count, or until the adaptive sampler is satisfied.
+    // Returns the number of samples taken.
+    pub fn render_pass(&self, renderer: &Renderer, accumulator: &mut Accumulator, samples: u32) -> u64 {
+        let mut splats = SplatBuffer::new(accumulator.width, accumulator.height);
+        let taken = self.render_tile(&mut Pool::new(4), renderer, accumulator, &mut splats, 0, 0, 0, samples);
+        accumulator.add_splats(&splats);
+        taken
+    }
+    // Like render_pass for an accumulator covering the pixels starting at (x0, y0), whose
+    // samples are numbered from first_sample on. Light paths splat into the buffer for the
+    // whole image, which the caller adds to the accumulator of the image once it is done
+    // with it. Callers rendering many tiles keep the pool.
+    pub fn render_tile(&self, pool: &mut Pool, renderer: &Renderer, accumulator: &mut Accumulator, splats: &mut SplatBuffer,
+                       x0: u32, y0: u32, first_sample: u32, samples: u32) -> u64 {
+        let start = Instant::now();
+        let before = accumulator.samples();
+        let camera_ref: &Camera = &self.camera;
+        let adaptive_ref: &Option<AdaptiveSampler> = &self.adaptive;
+        let seed = self.seed;
+        // Footprint of one of the samples of a pixel, kept from shrinking to nothing when the
+        // sample count is unbounded or adaptive sampling stops early (as in PBRT)
+        let scale = (1.0/(self.max_samples() as Float).sqrt()).max(0.125);
+        // A single sample per pixel goes through the pixel corner like generate_ray, more
+        // samples are jittered over the pixel
+        let jitter = self.max_samples() > 1;
+        let aovs = accumulator.aovs.clone();
+        let aovs_ref: &[Aov] = &aovs;
+        let width = accumulator.width as usize;
+        // Light paths may reach any pixel of the image
+        let shared_splats = Mutex::new(&mut *splats);
+        let splats_ref = &shared_splats;
+        // Progressive photon mapping traces a photon map per sample index, so the pixels are
+        // sampled one index after the other. The other integrators take all samples at once.
+        let mut limits = vec![samples];
+        let mut first = 0;
+        if renderer.progressive() {
+            first = accumulator.pixels.iter().map(|pixel| pixel.samples).min().unwrap_or(samples);
+            limits = (first + 1..samples + 1).collect();
+        }
+        // The photon maps are shared with the other tiles of the same samples
+        let photon_maps = renderer.photon_maps(pool, seed, first_sample + first, first_sample + samples);
+        for limit in limits {
+            let photons = if renderer.progressive() { photon_maps.get((limit - first - 1) as usize) } else { photon_maps.first() };
+            let photons_ref = photons.map(|map| &**map);
+            pool.scoped(|scope| {
+                for (y, row) in accumulator.pixels.chunks_mut(width).enumerate() {
+                    scope.execute(move || {
+                        let y = y0 + y as u32;
+                        let mut row_splats: Vec<Splat> = Vec::new();
+                        for (x, pixel) in row.iter_mut().enumerate() {
+                            let x = x0 + x as u32;
+                            while pixel.samples < limit {
+                                if let Some(ref adaptive) = *adaptive_ref {
+                                    if adaptive.done(pixel) {
+                                        break;
+                                    }
+                                }
+                                // Every sample has its own random sequence, so passes do not change the image
+                                sampling::start_sample(seed, x, y, first_sample + pixel.samples);
+                                let (u, v) = if jitter { sampling::next_2d() } else { (0.0, 0.0) };
+                                let mut ray = camera_ref.generate_sample_ray(x, y, u, v);
+                                ray.scale_differentials(scale);
+                                pixel.add(aovs_ref, &renderer.render_aovs(&mut ray, aovs_ref, camera_ref, &mut row_splats, photons_ref));
+                            }
+                        }
+                        if !row_splats.is_empty() {
+                            let mut splats = splats_ref.lock().unwrap();
+                            for splat in &row_splats {
+                                splats.add(splat);
+                            }
+                        }
+                    });
+                }
+            });
+        }
+        let taken = accumulator.samples() - before;
+        // Every camera sample traces one light path
+        splats.light_paths += taken;
+        stats::add(Counter::CameraRays, taken);
+        stats::time(Timer::Render, start.elapsed());
+        taken
+    }
+    // Samples per pixel of the finished rendering
+    pub fn max_samples(&self) -> u32 {
+        match self.adaptive {
+            Some(ref adaptive) => adaptive.max_samples.max(1),
+            None => self.samples.max(1)
+        }
     }
     pub fn draw(&self, scene: &Scene) {
         for x in 0..self.width {
@@ -53,4 +257,39 @@ impl Window {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use aov::Aov;
+    use scene::Scene;
+    use testing::{SceneBuilder, grey};
+    use super::*;
+
+    fn scene() -> Scene {
+        SceneBuilder::lit_sphere(grey(0.5)).build()
+    }
+
+    #[test]
+    fn targets_without_sample_count() {
+        // Unbounded sample count, the noise target ends the rendering once every pixel has
+        // two samples
+        let mut window = Window::new(8, 6);
+        window.samples = ::std::u32::MAX;
+        window.progressive.noise_target = Some(1e3);
+        let mut passes = Vec::new();
+        let accumulator = window.draw_progressive(&scene(), &[Aov::Beauty], |_, progress| passes.push(progress.clone()));
+        assert_eq!(passes.len(), 2);
+        assert!(passes[1].done && !passes[0].done);
+        assert!(accumulator.pixels.iter().all(|p| p.samples == 2));
+
+        window.progressive.noise_target = None;
+        window.progressive.time_budget = Some(Duration::from_millis(50));
+        let mut last = None;
+        window.draw_progressive(&scene(), &[Aov::Beauty], |_, progress| last = Some(progress.clone()));
+        let last = last.unwrap();
+        assert!(last.done && last.pass > 1 && last.elapsed >= Duration::from_millis(50));
+    }
+}
+
@@ -0,0 +1,72 @@
+extern crate image;
+
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Child, Stdio};
+
+// Renders a scene with a coordinator and two worker processes on localhost and compares the
+// image against the one rendered by a single process
+
+const SCENE: &'static str = "camera position 0 -10 3 front 0 1 -0.3 up 0 0 -1 fov 45
+shader grey diffuse color 0.74 0.74 0.74
+shader red diffuse color 0.8 0.1 0.1
+sphere position -1.5 0 0 radius 1 shader grey
+sphere position 1.5 0 0.5 radius 1 shader red
+sphere position 0 0 -10001 radius 10000 shader grey
+light point position 1.5 -1 5 intensity 40 40 40
+";
+
+fn raytracer(dir: &Path, args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_raytracer"))
+        .args(args)
+        .current_dir(dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+fn finish(child: Child) {
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+}
+
+fn directory(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("raytracer-processes-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn worker_processes_match_local_rendering() {
+    let root = directory("scene");
+    let scene = root.join("test.scene");
+    fs::write(&scene, SCENE).unwrap();
+    let scene = scene.to_str().unwrap();
+
+    let local = directory("local");
+    finish(raytracer(&local, &["--scene", scene, "--spp", "1"]));
+
+    // Free port for the coordinator, the workers retry until it listens
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let distributed = directory("distributed");
+    let workers: Vec<Child> = (0..2).map(|_| raytracer(&distributed, &["--worker", &address])).collect();
+    let coordinator = raytracer(&distributed, &["--scene", scene, "--spp", "1", "--coordinator", &address]);
+    finish(coordinator);
+    for worker in workers {
+        finish(worker);
+    }
+
+    let a = image::open(&local.join("rendering.png")).unwrap().to_rgb();
+    let b = image::open(&distributed.join("rendering.png")).unwrap().to_rgb();
+    for dir in &[root, local, distributed] {
+        fs::remove_dir_all(dir).unwrap();
+    }
+    assert_eq!(a.dimensions(), b.dimensions());
+    for (p, q) in a.pixels().zip(b.pixels()) {
+        for c in 0..3 {
+            assert!((p[c] as i32 - q[c] as i32).abs() <= 1, "{:?} {:?}", p, q);
+        }
+    }
+}